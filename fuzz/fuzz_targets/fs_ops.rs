@@ -0,0 +1,10 @@
+#![no_main]
+
+// Apply arbitrary file system operations to the transient storage, see `stable_fs::fuzzing`.
+// Run with `cargo fuzz run fs_ops` from the repository root.
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    stable_fs::fuzzing::run_ops(data);
+});
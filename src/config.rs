@@ -0,0 +1,189 @@
+// Typed configuration files stored as CBOR.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    error::Error,
+    fs::{FdStat, FileSystem, OpenFlags, RemoveOptions, RenameFlags},
+    storage::types::FileSize,
+};
+
+// The suffix of the file the new contents are written to before replacing the configuration file.
+const TEMP_SUFFIX: &str = ".tmp";
+
+// The largest configuration file that is loaded.
+pub const MAX_CONFIG_SIZE: FileSize = 1024 * 1024;
+
+pub struct Config;
+
+impl Config {
+    // Read and decode the configuration file `path` of the root folder, files larger than `MAX_CONFIG_SIZE`
+    // fail with `FileTooLarge`.
+    pub fn load<T: DeserializeOwned>(fs: &mut FileSystem, path: &str) -> Result<T, Error> {
+        let root = fs.root_fd();
+        let fd = fs.open_or_create(root, path, FdStat::default(), OpenFlags::empty(), 0)?;
+
+        let content = fs.metadata(fd).and_then(|stat| {
+            if stat.size > MAX_CONFIG_SIZE {
+                return Err(Error::FileTooLarge);
+            }
+            let mut buf = vec![0; stat.size as usize];
+            fs.read(fd, &mut buf).map(|_| buf)
+        });
+        fs.close(fd)?;
+
+        ciborium::de::from_reader(content?.as_slice()).map_err(|_| Error::DataCorrupted)
+    }
+
+    // Load the configuration, a missing file is read as the default value, modify it and store it back.
+    // The new contents are written to a temporary file which then replaces the configuration file,
+    // so a failure leaves the old configuration in place. Returns the stored value.
+    pub fn update<T, F>(fs: &mut FileSystem, path: &str, ctime: u64, modify: F) -> Result<T, Error>
+    where
+        T: Serialize + DeserializeOwned + Default,
+        F: FnOnce(&mut T),
+    {
+        let mut config = match Self::load::<T>(fs, path) {
+            Ok(config) => config,
+            Err(Error::NotFound) => T::default(),
+            Err(err) => return Err(err),
+        };
+
+        modify(&mut config);
+
+        let mut content = Vec::new();
+        ciborium::ser::into_writer(&config, &mut content).map_err(|_| Error::DataCorrupted)?;
+
+        let temp_path = format!("{path}{TEMP_SUFFIX}");
+        let root = fs.root_fd();
+
+        if let Err(err) = Self::replace(fs, path, &temp_path, &content, ctime) {
+            let _ = fs.remove(root, &temp_path, RemoveOptions::file());
+            return Err(err);
+        }
+
+        Ok(config)
+    }
+
+    fn replace(
+        fs: &mut FileSystem,
+        path: &str,
+        temp_path: &str,
+        content: &[u8],
+        ctime: u64,
+    ) -> Result<(), Error> {
+        let root = fs.root_fd();
+        fs.apply_file(root, temp_path, content, ctime)?;
+
+        // the configuration file is replaced in one step, there is always an old or a new version
+        let fd = fs.rename_with_flags(root, temp_path, root, path, RenameFlags::empty())?;
+        fs.close(fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        error::Error,
+        fs::{FdStat, OpenFlags},
+        test_utils::test_fs,
+    };
+
+    use super::{Config, MAX_CONFIG_SIZE};
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct Settings {
+        name: String,
+        limit: u32,
+    }
+
+    #[test]
+    fn update_and_load() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        assert_eq!(
+            Config::load::<Settings>(&mut fs, "cfg/settings.cbor"),
+            Err(Error::NotFound)
+        );
+
+        let fd = fs.create_dir(root, "cfg", FdStat::default(), 0).unwrap();
+        fs.close(fd).unwrap();
+
+        let stored = Config::update(&mut fs, "cfg/settings.cbor", 0, |cfg: &mut Settings| {
+            cfg.name = "assets".to_string();
+        })
+        .unwrap();
+        assert_eq!(stored.limit, 0);
+
+        Config::update(&mut fs, "cfg/settings.cbor", 0, |cfg: &mut Settings| {
+            cfg.limit += 10;
+        })
+        .unwrap();
+
+        let settings: Settings = Config::load(&mut fs, "cfg/settings.cbor").unwrap();
+        assert_eq!(
+            settings,
+            Settings {
+                name: "assets".to_string(),
+                limit: 10
+            }
+        );
+
+        // no temporary file is left behind
+        assert_eq!(fs.list_dir(fs.root_fd()).unwrap().len(), 1);
+        let dir = fs
+            .open_or_create(root, "cfg", FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+        assert_eq!(fs.list_dir(dir).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn failed_update_keeps_the_old_config() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        Config::update(&mut fs, "settings.cbor", 0, |cfg: &mut Settings| {
+            cfg.limit = 1
+        })
+        .unwrap();
+
+        // an opened configuration file cannot be replaced
+        let fd = fs
+            .open_or_create(
+                root,
+                "settings.cbor",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+        assert_eq!(
+            Config::update(&mut fs, "settings.cbor", 0, |cfg: &mut Settings| cfg
+                .limit =
+                2),
+            Err(Error::CannotRemoveOpenedNode)
+        );
+        fs.close(fd).unwrap();
+
+        let settings: Settings = Config::load(&mut fs, "settings.cbor").unwrap();
+        assert_eq!(settings.limit, 1);
+        assert_eq!(fs.list_dir(root).unwrap().len(), 1);
+
+        fs.apply_file(root, "settings.cbor", b"\xff\xff", 0)
+            .unwrap();
+        assert_eq!(
+            Config::load::<Settings>(&mut fs, "settings.cbor"),
+            Err(Error::DataCorrupted)
+        );
+
+        let large = vec![0; MAX_CONFIG_SIZE as usize + 1];
+        fs.apply_file(root, "settings.cbor", &large, 0).unwrap();
+        assert_eq!(
+            Config::load::<Settings>(&mut fs, "settings.cbor"),
+            Err(Error::FileTooLarge)
+        );
+    }
+}
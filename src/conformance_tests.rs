@@ -0,0 +1,169 @@
+// Corner cases of the strict POSIX mode, adapted from the filesystem tests of wasi-testsuite.
+
+use ic_stable_structures::DefaultMemoryImpl;
+
+use crate::{
+    error::Error,
+    fs::{FdStat, FileSystem, MountOptions, OpenFlags, Whence},
+    storage::stable::StableStorage,
+    test_utils::{test_fs, test_fs_strict_posix},
+};
+
+#[test]
+fn fd_write_to_directory() {
+    let mut fs = test_fs_strict_posix();
+    let root_fd = fs.root_fd();
+
+    let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+
+    assert_eq!(fs.write(dir_fd, &[1, 2, 3]), Err(Error::IsDirectory));
+
+    let mut buf = [0u8; 3];
+    assert_eq!(fs.read(dir_fd, &mut buf), Err(Error::IsDirectory));
+}
+
+#[test]
+fn fd_seek_directory() {
+    let mut fs = test_fs_strict_posix();
+    let root_fd = fs.root_fd();
+
+    assert_eq!(
+        fs.seek(root_fd, 0, Whence::SET),
+        Err(Error::InvalidFileDescriptor)
+    );
+}
+
+#[test]
+fn path_open_file_as_directory() {
+    let mut fs = test_fs_strict_posix();
+    let root_fd = fs.root_fd();
+
+    let fd = fs
+        .create_file(root_fd, "file", FdStat::default(), 0)
+        .unwrap();
+
+    let res = fs.open_or_create(root_fd, "file", FdStat::default(), OpenFlags::DIRECTORY, 0);
+    assert_eq!(res, Err(Error::NotADirectory));
+
+    let res = fs.create_file(fd, "nested", FdStat::default(), 0);
+    assert_eq!(res, Err(Error::NotADirectory));
+}
+
+#[test]
+fn unlink_file_while_open() {
+    let mut fs = test_fs_strict_posix();
+    let root_fd = fs.root_fd();
+
+    let fd = fs
+        .create_file(root_fd, "file", FdStat::default(), 0)
+        .unwrap();
+    fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+
+    fs.remove_file(root_fd, "file").unwrap();
+
+    // the name is gone
+    let res = fs.open_or_create(root_fd, "file", FdStat::default(), OpenFlags::empty(), 0);
+    assert_eq!(res, Err(Error::NotFound));
+
+    // the data is still accessible through the opened descriptor
+    let mut buf = [0u8; 5];
+    fs.seek(fd, 0, Whence::SET).unwrap();
+    assert_eq!(fs.read(fd, &mut buf), Ok(5));
+    assert_eq!(buf, [1, 2, 3, 4, 5]);
+    assert_eq!(fs.metadata(fd).unwrap().link_count, 0);
+
+    let node = fs.metadata(fd).unwrap().node;
+    fs.close(fd).unwrap();
+
+    // the data is released on close
+    assert_eq!(fs.metadata_from_node(node), Err(Error::NotFound));
+}
+
+#[test]
+fn remove_directory_while_open() {
+    let mut fs = test_fs_strict_posix();
+    let root_fd = fs.root_fd();
+
+    let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+    let node = fs.metadata(dir_fd).unwrap().node;
+
+    fs.remove_dir(root_fd, "dir").unwrap();
+    assert!(fs.metadata_from_node(node).is_ok());
+
+    // nothing is created in the removed directory
+    assert_eq!(
+        fs.create_file(dir_fd, "file", FdStat::default(), 0),
+        Err(Error::NoSuchFileOrDirectory)
+    );
+    assert_eq!(
+        fs.create_dir(dir_fd, "sub", FdStat::default(), 0),
+        Err(Error::NoSuchFileOrDirectory)
+    );
+
+    fs.close(dir_fd).unwrap();
+    assert_eq!(fs.metadata_from_node(node), Err(Error::NotFound));
+}
+
+#[test]
+fn unlinked_nodes_are_reclaimed_on_mount() {
+    let memory = DefaultMemoryImpl::default();
+    let options = MountOptions {
+        strict_posix: true,
+        ..Default::default()
+    };
+    let mut fs =
+        FileSystem::new_with_options(Box::new(StableStorage::new(memory.clone())), options)
+            .unwrap();
+    let root_fd = fs.root_fd();
+
+    let fd = fs
+        .create_file(root_fd, "file", FdStat::default(), 0)
+        .unwrap();
+    fs.write(fd, &[1; 5000]).unwrap();
+    let file = fs.metadata(fd).unwrap().node;
+    fs.remove_file(root_fd, "file").unwrap();
+
+    let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+    let dir = fs.metadata(dir_fd).unwrap().node;
+    fs.remove_dir(root_fd, "dir").unwrap();
+
+    let unnamed = fs.create_unnamed(root_fd, FdStat::default(), 0).unwrap();
+    let linked = fs.create_unnamed(root_fd, FdStat::default(), 0).unwrap();
+    fs.link_fd(linked, root_fd, "linked").unwrap();
+    let unnamed = fs.metadata(unnamed).unwrap().node;
+
+    // the descriptors are lost with the instance, e.g. in an upgrade
+    drop(fs);
+    let mut fs =
+        FileSystem::new_with_options(Box::new(StableStorage::new(memory)), options).unwrap();
+    for node in [file, dir, unnamed] {
+        assert_eq!(fs.metadata_from_node(node), Err(Error::NotFound));
+    }
+    let root_fd = fs.root_fd();
+    assert!(fs
+        .open_or_create(root_fd, "linked", FdStat::default(), OpenFlags::empty(), 0)
+        .is_ok());
+    assert_eq!(fs.reclaim_orphans(), 0);
+}
+
+#[test]
+fn default_mode_keeps_legacy_errors() {
+    let mut fs = test_fs();
+    let root_fd = fs.root_fd();
+
+    let fd = fs
+        .create_file(root_fd, "file", FdStat::default(), 0)
+        .unwrap();
+
+    assert_eq!(fs.write(root_fd, &[1]), Err(Error::InvalidFileType));
+    assert_eq!(
+        fs.open_or_create(root_fd, "file", FdStat::default(), OpenFlags::DIRECTORY, 0),
+        Err(Error::InvalidFileType)
+    );
+    assert_eq!(
+        fs.remove_file(root_fd, "file"),
+        Err(Error::CannotRemoveOpenedNode)
+    );
+
+    fs.close(fd).unwrap();
+}
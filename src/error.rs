@@ -1,17 +1,118 @@
+// The error codes are stable across the crate versions: a variant keeps its code and the new variants
+// get the next free codes. The code 0 is left for a success.
 #[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+#[repr(u32)]
 pub enum Error {
-    NotFound,
-    InvalidOffset,
-    InvalidFileType,
-    InvalidFileName,
-    InvalidFileDescriptor,
-    InvalidBufferLength,
-    InvalidOpenFlags,
-    InvalidFdFlags,
-    FileAlreadyExists,
-    NameTooLong,
-    DirectoryNotEmpty,
-    ExpectedToRemoveFile,
-    ExpectedToRemoveDirectory,
-    CannotRemoveOpenedNode,
+    NotFound = 1,
+    InvalidOffset = 2,
+    InvalidFileType = 3,
+    InvalidFileName = 4,
+    InvalidFileDescriptor = 5,
+    InvalidBufferLength = 6,
+    InvalidOpenFlags = 7,
+    InvalidFdFlags = 8,
+    InvalidRemoveOptions = 9,
+    InvalidRenameFlags = 10,
+    InvalidMetadata = 11,
+    InvalidMountOptions = 12,
+    FileAlreadyExists = 13,
+    NameTooLong = 14,
+    DirectoryNotEmpty = 15,
+    ExpectedToRemoveFile = 16,
+    ExpectedToRemoveDirectory = 17,
+    CannotRemoveOpenedNode = 18,
+    CannotMoveDirectoryIntoItself = 19,
+    IsDirectory = 20,
+    NotADirectory = 21,
+    FileTooLarge = 22,
+    TooManySymbolicLinks = 23,
+    DirectoryFull = 24,
+    SandboxViolation = 25,
+    DataCorrupted = 26,
+    IntegrityCheckFailed = 27,
+    StorageFull = 28,
+    PathTooDeep = 29,
+    StorageTooSmall = 30,
+    UnsupportedStorageLayout = 31,
+    StaleFileHandle = 32,
+    ReadOnlyFileSystem = 33,
+    UpgradeInProgress = 34,
+    AccessDenied = 35,
+    TooManyOpenFiles = 36,
+    PermissionDenied = 37,
+    NoSuchFileOrDirectory = 38,
+}
+
+impl From<Error> for u32 {
+    fn from(err: Error) -> Self {
+        err as u32
+    }
+}
+
+// An unknown code is returned back.
+impl TryFrom<u32> for Error {
+    type Error = u32;
+
+    fn try_from(code: u32) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(Error::NotFound),
+            2 => Ok(Error::InvalidOffset),
+            3 => Ok(Error::InvalidFileType),
+            4 => Ok(Error::InvalidFileName),
+            5 => Ok(Error::InvalidFileDescriptor),
+            6 => Ok(Error::InvalidBufferLength),
+            7 => Ok(Error::InvalidOpenFlags),
+            8 => Ok(Error::InvalidFdFlags),
+            9 => Ok(Error::InvalidRemoveOptions),
+            10 => Ok(Error::InvalidRenameFlags),
+            11 => Ok(Error::InvalidMetadata),
+            12 => Ok(Error::InvalidMountOptions),
+            13 => Ok(Error::FileAlreadyExists),
+            14 => Ok(Error::NameTooLong),
+            15 => Ok(Error::DirectoryNotEmpty),
+            16 => Ok(Error::ExpectedToRemoveFile),
+            17 => Ok(Error::ExpectedToRemoveDirectory),
+            18 => Ok(Error::CannotRemoveOpenedNode),
+            19 => Ok(Error::CannotMoveDirectoryIntoItself),
+            20 => Ok(Error::IsDirectory),
+            21 => Ok(Error::NotADirectory),
+            22 => Ok(Error::FileTooLarge),
+            23 => Ok(Error::TooManySymbolicLinks),
+            24 => Ok(Error::DirectoryFull),
+            25 => Ok(Error::SandboxViolation),
+            26 => Ok(Error::DataCorrupted),
+            27 => Ok(Error::IntegrityCheckFailed),
+            28 => Ok(Error::StorageFull),
+            29 => Ok(Error::PathTooDeep),
+            30 => Ok(Error::StorageTooSmall),
+            31 => Ok(Error::UnsupportedStorageLayout),
+            32 => Ok(Error::StaleFileHandle),
+            33 => Ok(Error::ReadOnlyFileSystem),
+            34 => Ok(Error::UpgradeInProgress),
+            35 => Ok(Error::AccessDenied),
+            36 => Ok(Error::TooManyOpenFiles),
+            37 => Ok(Error::PermissionDenied),
+            38 => Ok(Error::NoSuchFileOrDirectory),
+            code => Err(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_codes_round_trip() {
+        assert_eq!(u32::from(Error::NotFound), 1);
+        assert_eq!(u32::from(Error::NoSuchFileOrDirectory), 38);
+
+        for code in 1..=38 {
+            let err = Error::try_from(code).unwrap();
+            assert_eq!(u32::from(err), code);
+        }
+        assert_eq!(Error::try_from(0), Err(0));
+        assert_eq!(Error::try_from(39), Err(39));
+    }
 }
@@ -1,98 +1,520 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    ops::{Deref, DerefMut, Range},
+};
+
+use sha2::{Digest, Sha256};
+
 use crate::{
     error::Error,
+    path::{components, Component},
     runtime::{
+        budget::{io_cost, removal_cost, BudgetGuard, BUDGET_SLICE_SIZE},
+        changelog::DirChangelog,
         dir::Dir,
         fd::{FdEntry, FdTable},
-        file::File,
-        structure_helpers::{create_hard_link, find_node, rm_dir_entry},
+        file::{File, WriteUndo},
+        merkle::node_hash,
+        proc::{
+            collect_tree_stats, fds_text, mounts_text, stats_text, version_text, ProcContent,
+            ProcFile, TreeStats,
+        },
+        structure_helpers::{
+            add_dir_entry, check_no_symlinks, collect_expired, collect_files, create_hard_link,
+            create_node, create_path, find_escaping_alias, find_existing_dir, find_node,
+            get_parent_node, is_ancestor_or_self, leaves_boundary, move_dir_entry,
+            read_alias_target, resolve_alias, rm_dir_entry, rm_node_data, swap_dir_entries,
+            MAX_ALIAS_HOPS,
+        },
+        sync::{collect_manifest, collect_metadata_manifest, diff_dir, manifest_deviations},
+        watch::Watch,
     },
     storage::{
-        types::{DirEntry, DirEntryIndex, FileSize, FileType, Metadata, Node},
+        cached::CachedStorage,
+        types::{
+            DirEntry, DirEntryIndex, FileChunkIndex, FileSize, FileType, HttpMetadata, Metadata,
+            Node, Times, FILE_CHUNK_SIZE, MAX_FILE_NAME, MAX_FILE_SIZE,
+        },
         Storage,
     },
 };
 
-pub use crate::runtime::fd::Fd;
+#[cfg(feature = "write-stats")]
+use crate::runtime::write_stats::WriteStatsTable;
+
+pub use crate::runtime::fd::{Fd, FdProvenance, FdView};
+
+pub use crate::runtime::policy::Policy;
+
+pub use crate::storage::types::{
+    ChunkZeroing, Collation, FsLimits, NameNormalizer, PhysicalWrites, RecoveryReport,
+    ReusableSpace, StorageClass,
+};
 
 pub use crate::runtime::types::{
-    DstBuf, DstIoVec, FdFlags, FdStat, OpenFlags, SrcBuf, SrcIoVec, Whence,
+    AccessMode, Advice, Budgeted, CachePolicy, ChangeKind, CollisionPolicy, Continuation,
+    DeviationKind, DirChange, DirChanges, DstBuf, DstIoVec, EntryPosition, EvictionPolicy, FdFlags,
+    FdStat, FdStatBuilder, FileHandle, FileStat, LayoutInfo, ListedEntry, ManifestDeviation,
+    ManifestEntry, MerkleHash, MetadataManifest, MetadataManifestEntry, MountOptions, OpenFlags,
+    Page, PathAudit, PathViolation, Projection, ReadPattern, RemovalReport, RemoveOptions,
+    RenameFlags, Rights, SandboxMode, SnapshotToken, SparseExtent, SparseFile, SrcBuf, SrcIoVec,
+    SyncDiff, SyncManifest, TimeConfig, TimeResolution, WasiFdStat, WatchEvent, WatchId, Whence,
+    WriteAmplification, WriteFailure, WriteStats,
 };
 
+// The directory in the root the snapshots are mounted in.
+const SNAPSHOT_MOUNT_DIR: &str = ".snapshots";
+
+// The number of the latest sandbox violations kept.
+const MAX_PATH_AUDIT_RECORDS: usize = 1000;
+
+// A file tracked by a cache directory.
+struct CacheMember {
+    cache_dir: Node,
+    // the entry the file is evicted by
+    parent: Node,
+    name: String,
+    size: FileSize,
+}
+
+// A read-only copy of a directory tree mounted in the snapshot directory.
+struct SnapshotMount {
+    root: Node,
+    // the nodes of the copy, including its root
+    nodes: BTreeSet<Node>,
+}
+
+// Rejects the changes of the mounted snapshots, it is checked before the policy set by the user.
+struct ReadOnlyGuard<'a> {
+    mounts: &'a BTreeMap<String, SnapshotMount>,
+    // the whole tree is read-only in the upgrade mode
+    upgrading: bool,
+    storage: &'a dyn Storage,
+}
+
+impl ReadOnlyGuard<'_> {
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.upgrading {
+            return Err(Error::UpgradeInProgress);
+        }
+        Ok(())
+    }
+
+    fn check(&self, node: Node) -> Result<(), Error> {
+        self.check_writable()?;
+        if self
+            .mounts
+            .values()
+            .any(|mount| mount.nodes.contains(&node))
+        {
+            return Err(Error::ReadOnlyFileSystem);
+        }
+        Ok(())
+    }
+}
+
+impl Policy for ReadOnlyGuard<'_> {
+    fn before_create(&mut self, parent: Node, path: &str, _: FileType) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check(find_existing_dir(parent, path, self.storage)?)
+    }
+
+    fn before_write(&mut self, node: Node, _: FileSize, _: FileSize) -> Result<(), Error> {
+        self.check(node)
+    }
+
+    // a snapshot cannot be removed or moved together with a directory containing it either
+    fn before_remove(&mut self, parent: Node, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        let Ok(node) = find_node(parent, path, self.storage) else {
+            return Ok(());
+        };
+        self.check(node)?;
+        for mount in self.mounts.values() {
+            if is_ancestor_or_self(node, mount.root, self.storage)? {
+                return Err(Error::ReadOnlyFileSystem);
+            }
+        }
+        Ok(())
+    }
+}
+
+// A frozen file version, its chunks are copied into an unlinked node only right before they are changed.
+// The chunks not copied are read from the file itself. The tokens frozen with no change in between share it.
+struct FrozenFile {
+    node: Node,
+    size: FileSize,
+    // the node keeping the copied chunks, it is an orphan reclaimed on the next mount
+    copy: Option<Node>,
+    // the indices of the copied chunks, also of the chunks that were not stored
+    copied: BTreeSet<FileChunkIndex>,
+    // the number of the tokens reading the version
+    tokens: usize,
+}
+
 // The main class implementing the API to work with the file system.
 pub struct FileSystem {
     root_fd: Fd,
     fd_table: FdTable,
+    options: MountOptions,
+    // cached Merkle hashes of the file contents
+    merkle_cache: BTreeMap<Node, MerkleHash>,
+    // the root hash certified before the publish batches in progress, and their nesting depth
+    published_hash: Option<MerkleHash>,
+    publish_depth: u32,
+    // descriptors kept open when an fd scope ends
+    persistent_fds: BTreeSet<Fd>,
+    // directories used as bounded caches
+    cache_policies: BTreeMap<Node, CachePolicy>,
+    // the files tracked by the cache directories, and the total size of the tracked files of each directory
+    cache_members: BTreeMap<Node, CacheMember>,
+    cache_sizes: BTreeMap<Node, FileSize>,
+    // the logical time of the last read or write of the cached files
+    last_access: BTreeMap<Node, u64>,
+    access_clock: u64,
+    // changelogs of the tracked directories
+    changelogs: BTreeMap<Node, DirChangelog>,
+    // the watched paths and the nodes whose contents were changed since they were last polled
+    watches: BTreeMap<WatchId, Watch>,
+    written_nodes: BTreeSet<Node>,
+    next_watch: u64,
+    // the directory the introspection files are served in and its path
+    proc_dir: Option<Node>,
+    proc_path: Option<String>,
+    // the tree stats of the introspection files, kept until the tree is changed
+    proc_stats: Option<TreeStats>,
+    // the mounted snapshots by their name
+    snapshot_mounts: BTreeMap<String, SnapshotMount>,
+    // the tree cannot be changed between `enter_upgrade_mode` and `exit_upgrade_mode`
+    upgrading: bool,
+    // the directory the aliases must not lead out of in the sandbox mode
+    sandbox_boundary: Option<Node>,
+    // the recorded sandbox violations
+    path_audit: RefCell<VecDeque<PathAudit>>,
+    // the frozen file versions by the number of the first token reading them
+    frozen: BTreeMap<u64, FrozenFile>,
+    // the version read with a token
+    snapshots: BTreeMap<SnapshotToken, u64>,
+    next_snapshot: u64,
+    // the rules checked before the tree is changed
+    policy: Option<Box<dyn Policy>>,
+    // the caller's ID of the current operations
+    span: Option<u64>,
+    #[cfg(feature = "write-stats")]
+    write_stats: WriteStatsTable,
+    // the file bytes written since the write amplification was reset
+    logical_bytes_written: u64,
+    // the storage counters when the write amplification was reset
+    physical_writes_base: PhysicalWrites,
     pub storage: Box<dyn Storage>,
 }
 
 impl FileSystem {
     // Create a new file system hosted on a given storage implementation.
     pub fn new(storage: Box<dyn Storage>) -> Result<Self, Error> {
-        let mut fd_table = FdTable::new();
+        Self::builder().storage(storage).build()
+    }
 
-        if storage.get_version() == 0 {
-            return Ok(Self {
-                root_fd: 0,
-                fd_table,
-                storage,
-            });
-        }
+    // Get a read-only view of the file system, e.g. for the query calls.
+    pub fn reader(&self) -> FsReader<'_> {
+        FsReader { fs: self }
+    }
+
+    // Start configuring a file system, see `FileSystemBuilder`.
+    pub fn builder() -> FileSystemBuilder {
+        FileSystemBuilder::default()
+    }
 
-        let root_node = storage.root_node();
-        let root_entry = Dir::new(root_node, FdStat::default(), &*storage)?;
-        let root_fd = fd_table.open(FdEntry::Dir(root_entry));
+    // Create a new file system hosted on a given storage implementation using the mount options provided.
+    pub fn new_with_options(
+        storage: Box<dyn Storage>,
+        options: MountOptions,
+    ) -> Result<Self, Error> {
+        Self::builder().storage(storage).options(options).build()
+    }
 
-        Ok(Self {
-            root_fd,
-            fd_table,
+    // Mount the file system with the options checked by `FileSystemBuilder::build`.
+    fn mount(storage: Box<dyn Storage>, options: MountOptions) -> Result<Self, Error> {
+        let mut fs = Self {
+            root_fd: Fd::new(0),
+            fd_table: FdTable::new(),
+            options,
+            merkle_cache: BTreeMap::new(),
+            published_hash: None,
+            publish_depth: 0,
+            persistent_fds: BTreeSet::new(),
+            cache_policies: BTreeMap::new(),
+            cache_members: BTreeMap::new(),
+            cache_sizes: BTreeMap::new(),
+            last_access: BTreeMap::new(),
+            access_clock: 0,
+            changelogs: BTreeMap::new(),
+            watches: BTreeMap::new(),
+            written_nodes: BTreeSet::new(),
+            next_watch: 0,
+            proc_dir: None,
+            proc_path: None,
+            proc_stats: None,
+            snapshot_mounts: BTreeMap::new(),
+            upgrading: false,
+            sandbox_boundary: None,
+            path_audit: RefCell::new(VecDeque::new()),
+            frozen: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
+            next_snapshot: 0,
+            policy: None,
+            span: None,
+            #[cfg(feature = "write-stats")]
+            write_stats: WriteStatsTable::default(),
+            logical_bytes_written: 0,
+            physical_writes_base: PhysicalWrites::default(),
             storage,
-        })
+        };
+
+        fs.storage.set_chunk_zeroing(fs.options.chunk_zeroing);
+        if fs.storage.get_version() == 0 {
+            return Ok(fs);
+        }
+
+        let root_node = fs.storage.root_node();
+        let root_entry = Dir::new(root_node, FdStat::default(), fs.storage.as_ref())?;
+        fs.root_fd = fs.fd_table.open(root_entry.into())?;
+
+        fs.mount_name_normalizer()?;
+        fs.mount_collation()?;
+        fs.mount_limits()?;
+        fs.reclaim_orphans();
+        fs.reset_write_amplification();
+
+        Ok(fs)
+    }
+
+    // Use the name normalizer stored with the file system, or store the requested one if there is none yet.
+    // A normalizer is only stored while the tree is empty, the names already stored may not be normalized.
+    fn mount_name_normalizer(&mut self) -> Result<(), Error> {
+        let root_node = self.storage.root_node();
+        let mut metadata = self.storage.get_metadata(root_node)?;
+        let requested = self.options.name_normalizer;
+
+        if metadata.name_normalizer.is_exact() {
+            if !requested.is_exact() {
+                if metadata.first_dir_entry.is_some() {
+                    return Err(Error::InvalidMountOptions);
+                }
+                metadata.name_normalizer = requested;
+                self.storage.put_metadata(root_node, metadata);
+            }
+        } else if requested.is_exact() {
+            self.options.name_normalizer = metadata.name_normalizer;
+        } else if requested != metadata.name_normalizer {
+            return Err(Error::InvalidMountOptions);
+        }
+
+        if self.options.name_normalizer == NameNormalizer::Custom
+            && self.options.custom_normalizer.is_none()
+        {
+            return Err(Error::InvalidMountOptions);
+        }
+
+        Ok(())
+    }
+
+    // Use the collation stored with the file system, or store the requested one if there is none yet.
+    fn mount_collation(&mut self) -> Result<(), Error> {
+        let root_node = self.storage.root_node();
+        let mut metadata = self.storage.get_metadata(root_node)?;
+        let requested = self.options.collation;
+
+        if metadata.collation.is_binary() {
+            if !requested.is_binary() {
+                metadata.collation = requested;
+                self.storage.put_metadata(root_node, metadata);
+            }
+        } else if requested.is_binary() {
+            self.options.collation = metadata.collation;
+        } else if requested != metadata.collation {
+            return Err(Error::InvalidMountOptions);
+        }
+
+        if self.options.collation == Collation::Custom && self.options.custom_collation.is_none() {
+            return Err(Error::InvalidMountOptions);
+        }
+
+        Ok(())
+    }
+
+    // Use the limits stored with the file system, or store the requested ones if there are none yet.
+    // A limit requested when another one is stored is rejected, the tree was built within the stored one.
+    fn mount_limits(&mut self) -> Result<(), Error> {
+        let root_node = self.storage.root_node();
+        let mut metadata = self.storage.get_metadata(root_node)?;
+        let stored = metadata.limits;
+
+        let mount_limit = |stored: Option<u64>, requested: Option<u64>| match (stored, requested) {
+            (Some(stored), Some(requested)) if stored != requested => {
+                Err(Error::InvalidMountOptions)
+            }
+            (stored, requested) => Ok(stored.or(requested)),
+        };
+        let max_path_depth = self.options.max_path_depth.map(|depth| depth as u64);
+        let limits = FsLimits {
+            max_dir_entries: mount_limit(stored.max_dir_entries, self.options.max_dir_entries)?,
+            max_path_depth: mount_limit(stored.max_path_depth, max_path_depth)?,
+        };
+        self.options.max_dir_entries = limits.max_dir_entries;
+        self.options.max_path_depth = limits.max_path_depth.map(|depth| depth as usize);
+
+        if limits != stored {
+            metadata.limits = limits;
+            self.storage.put_metadata(root_node, metadata);
+        }
+
+        Ok(())
+    }
+
+    // Store the limits of the mount options as the limits of the file system.
+    fn store_limits(&mut self) {
+        let root_node = self.storage.root_node();
+        let Ok(mut metadata) = self.storage.get_metadata(root_node) else {
+            return;
+        };
+        let limits = FsLimits {
+            max_dir_entries: self.options.max_dir_entries,
+            max_path_depth: self.options.max_path_depth.map(|depth| depth as u64),
+        };
+        if metadata.limits != limits {
+            metadata.limits = limits;
+            self.storage.put_metadata(root_node, metadata);
+        }
+    }
+
+    // Get the current time from the clock of the mount options, `None` without a clock.
+    pub fn now(&self) -> Option<u64> {
+        self.options.clock.map(|clock| clock())
+    }
+
+    // Get the mount options of the file system.
+    pub fn options(&self) -> MountOptions {
+        self.options
+    }
+
+    // Update the mount options of the file system, the name normalizer and the collation are kept as they were mounted.
+    // The limits are stored for the later mounts.
+    pub fn set_options(&mut self, options: MountOptions) {
+        let name_normalizer = self.options.name_normalizer;
+        let collation = self.options.collation;
+        self.options = options;
+        self.options.name_normalizer = name_normalizer;
+        self.options.collation = collation;
+        self.storage.set_chunk_zeroing(options.chunk_zeroing);
+        if self.storage.get_version() != 0 {
+            self.store_limits();
+        }
     }
 
     pub fn get_storage_version(&self) -> u32 {
         self.storage.get_version()
     }
 
+    // Get the chunk size, the name and size limits and the storage version the file system runs with.
+    pub fn layout_info(&self) -> LayoutInfo {
+        LayoutInfo {
+            chunk_size: FILE_CHUNK_SIZE,
+            max_file_name: MAX_FILE_NAME,
+            max_file_size: MAX_FILE_SIZE,
+            storage_version: self.storage.get_version(),
+            max_dir_entries: self.options.max_dir_entries,
+            max_path_depth: self.options.max_path_depth,
+            max_alias_hops: self.options.max_alias_hops.unwrap_or(MAX_ALIAS_HOPS),
+        }
+    }
+
     // Get the file descriptor of the root folder.
     pub fn root_fd(&self) -> Fd {
         self.root_fd
     }
 
+    // Get where a descriptor was opened: the caller location and the path. It is recorded in the debug builds only.
+    pub fn fd_provenance(&self, fd: Fd) -> Option<&FdProvenance> {
+        self.fd_table.provenance(fd)
+    }
+
     // Get the path of the root folder.
     pub fn root_path(&self) -> &str {
         "/"
     }
 
-    // Reassign a file descriptor to a new number, the source descriptor is closed in the process.
-    // If the destination descriptor is busy, it is closed in the process.
+    // Reassign a file descriptor to a new number, like WASI `fd_renumber`, the source descriptor is closed in the process.
+    // If the destination descriptor is busy, it is closed first, the root descriptor cannot be replaced.
     pub fn renumber(&mut self, from: Fd, to: Fd) -> Result<(), Error> {
-        self.fd_table.renumber(from, to)
+        self.get_node(from)?;
+        if from == to {
+            return Ok(());
+        }
+        if to == self.root_fd {
+            return Err(Error::InvalidFileDescriptor);
+        }
+        if self.fd_table.get(to).is_some() {
+            self.close(to)?;
+        }
+
+        self.fd_table.renumber(from, to)?;
+        if from == self.root_fd {
+            self.root_fd = to;
+        }
+
+        let persistent = self.persistent_fds.remove(&from);
+        if persistent {
+            self.persistent_fds.insert(to);
+        } else {
+            self.persistent_fds.remove(&to);
+        }
+
+        Ok(())
     }
 
     fn get_node(&self, fd: Fd) -> Result<Node, Error> {
         match self.fd_table.get(fd) {
-            Some(FdEntry::File(file)) => Ok(file.node),
-            Some(FdEntry::Dir(dir)) => Ok(dir.node),
+            Some(entry) => Ok(entry.node()),
             None => Err(Error::NotFound),
         }
     }
 
     fn get_file(&self, fd: Fd) -> Result<File, Error> {
         match self.fd_table.get(fd) {
-            Some(FdEntry::File(file)) => Ok(file.clone()),
-            Some(FdEntry::Dir(_)) => Err(Error::InvalidFileType),
+            // the introspection files are only read and seeked
+            Some(FdEntry::Proc { .. }) => Err(Error::InvalidFileDescriptor),
+            Some(entry) => entry
+                .file()
+                .ok_or_else(|| self.file_type_error(FileType::RegularFile)),
             None => Err(Error::NotFound),
         }
     }
 
+    // The error reported when the node has a different type than `expected`.
+    fn file_type_error(&self, expected: FileType) -> Error {
+        if !self.options.strict_posix {
+            return Error::InvalidFileType;
+        }
+
+        match expected {
+            FileType::Directory => Error::NotADirectory,
+            _ => Error::IsDirectory,
+        }
+    }
+
     fn put_file(&mut self, fd: Fd, file: File) {
-        self.fd_table.update(fd, FdEntry::File(file))
+        self.fd_table.update(fd, file.into())
     }
 
     fn get_dir(&self, fd: Fd) -> Result<Dir, Error> {
         match self.fd_table.get(fd) {
-            Some(FdEntry::Dir(dir)) => Ok(dir.clone()),
-            Some(FdEntry::File(_)) => Err(Error::InvalidFileType),
+            Some(entry) => entry
+                .dir()
+                .ok_or_else(|| self.file_type_error(FileType::Directory)),
             None => Err(Error::NotFound),
         }
     }
@@ -102,13 +524,245 @@ impl FileSystem {
         self.get_dir(fd)?.get_entry(index, self.storage.as_ref())
     }
 
+    // List all entries of a directory.
+    pub fn list_dir(&self, fd: Fd) -> Result<Vec<DirEntry>, Error> {
+        self.get_dir(fd)?
+            .list_entries(self.options.dot_entries, self.storage.as_ref())
+    }
+
+    // List all entries of a directory ordered by their names with the collation of the mount,
+    // the "." and ".." entries come first.
+    pub fn list_dir_sorted(&self, fd: Fd) -> Result<Vec<DirEntry>, Error> {
+        let mut entries = self.list_dir(fd)?;
+        let dots = if self.options.dot_entries { 2 } else { 0 };
+        let name = |entry: &DirEntry| {
+            String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]).into_owned()
+        };
+        entries[dots..].sort_by(|a, b| self.options.compare_names(&name(a), &name(b)));
+        Ok(entries)
+    }
+
+    // Iterate over the entries of a directory without the "." and ".." entries.
+    pub fn read_dir(&self, fd: Fd) -> Result<ReadDir<'_>, Error> {
+        self.readdir_from(fd, 0)
+    }
+
+    // Iterate over the entries of a directory following the entry with the `cookie` of an earlier iteration,
+    // the cookie 0 starts with the first entry. Like POSIX `readdir`, the entries removed in between are not
+    // returned and the others are returned once, even if the entry of the cookie was removed.
+    pub fn readdir_from(&self, fd: Fd, cookie: DirEntryIndex) -> Result<ReadDir<'_>, Error> {
+        let dir = self.get_dir(fd)?;
+        let first = self.storage.get_metadata(dir.node)?.first_dir_entry;
+
+        // the entries are appended with growing indices, the ones after a removed entry have greater indices
+        let (next, after) = if cookie == 0 {
+            (first, 0)
+        } else {
+            match self.storage.get_direntry(dir.node, cookie) {
+                Ok(entry) => (entry.next_entry, 0),
+                Err(_) => (first, cookie),
+            }
+        };
+
+        Ok(ReadDir {
+            fs: self,
+            dir: dir.node,
+            next,
+            after,
+            cookie,
+        })
+    }
+
+    // List a page of the directory entries with the data selected by `projection`, the node of an entry
+    // is read only if its stats or its hash are selected.
+    pub fn list_dir_with(
+        &mut self,
+        fd: Fd,
+        projection: Projection,
+        page: Page,
+    ) -> Result<Vec<ListedEntry>, Error> {
+        let entries: Vec<DirEntry> = self
+            .list_dir(fd)?
+            .into_iter()
+            .skip(page.start)
+            .take(page.limit)
+            .collect();
+
+        let mut stats = match projection {
+            Projection::WithMetadata => {
+                let nodes: Vec<Node> = entries.iter().map(|entry| entry.node).collect();
+                self.metadata_many(&nodes)
+            }
+            _ => Vec::new(),
+        }
+        .into_iter();
+
+        entries
+            .into_iter()
+            .map(|entry| {
+                let name = &entry.name.bytes[..entry.name.length as usize];
+                let mut listed = ListedEntry {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    node: entry.node,
+                    stat: None,
+                    hash: None,
+                };
+                match projection {
+                    Projection::NamesOnly => {}
+                    Projection::WithMetadata => {
+                        let metadata = stats.next().ok_or(Error::NotFound)??;
+                        listed.stat = Some(FileStat::from(&metadata));
+                    }
+                    Projection::WithHashes => {
+                        listed.hash = Some(node_hash(
+                            entry.node,
+                            self.storage.as_ref(),
+                            &mut self.merkle_cache,
+                        )?);
+                    }
+                }
+                Ok(listed)
+            })
+            .collect()
+    }
+
+    // List at most `max` entries of a directory following the ones listed before through the same descriptor.
+    pub fn list_dir_next(&mut self, fd: Fd, max: usize) -> Result<Vec<DirEntry>, Error> {
+        let entries = self.list_dir(fd)?;
+
+        let Some(FdEntry::Dir { cursor, .. }) = self.fd_table.get_mut(fd) else {
+            return Err(Error::NotFound);
+        };
+        let next: Vec<DirEntry> = entries
+            .into_iter()
+            .skip(*cursor as usize)
+            .take(max)
+            .collect();
+        *cursor += next.len() as u64;
+
+        Ok(next)
+    }
+
+    // Restart listing the directory entries by `list_dir_next` from the first entry.
+    pub fn rewind_dir(&mut self, fd: Fd) -> Result<(), Error> {
+        self.get_dir(fd)?;
+        if let Some(FdEntry::Dir { cursor, .. }) = self.fd_table.get_mut(fd) {
+            *cursor = 0;
+        }
+        Ok(())
+    }
+
+    // Start recording the changes of the directory entries, at most `capacity` changes are kept.
+    // The changelog is kept in heap memory and is not persisted.
+    pub fn enable_changelog(&mut self, fd: Fd, capacity: usize) -> Result<(), Error> {
+        let dir = self.get_dir(fd)?;
+        let changelog = DirChangelog::new(dir.node, capacity, self.storage.as_ref())?;
+        self.changelogs.insert(dir.node, changelog);
+        Ok(())
+    }
+
+    // Stop recording the changes of the directory entries.
+    pub fn disable_changelog(&mut self, fd: Fd) -> Result<(), Error> {
+        let dir = self.get_dir(fd)?;
+        self.changelogs.remove(&dir.node);
+        Ok(())
+    }
+
+    // Get the changes of the directory entries made after the changelog `generation`, start with generation 0.
+    pub fn changes_since(&mut self, fd: Fd, generation: u64) -> Result<DirChanges, Error> {
+        let dir = self.get_dir(fd)?;
+        let changelog = self.changelogs.get_mut(&dir.node).ok_or(Error::NotFound)?;
+
+        Ok(changelog.changes_since(generation))
+    }
+
+    // Start watching the `path` relative to the root directory, it does not need to exist yet.
+    // The changes are collected by `poll_watches`, the watches are kept in heap memory and are not persisted.
+    pub fn watch(&mut self, path: &str) -> Result<WatchId, Error> {
+        let root = self.storage.root_node();
+        let path = self.user_path(root, path)?.into_owned();
+        let watch = Watch::new(root, path, self.storage.as_ref())?;
+
+        let id = WatchId(self.next_watch);
+        self.next_watch += 1;
+        self.watches.insert(id, watch);
+        Ok(id)
+    }
+
+    // Stop watching a path.
+    pub fn unwatch(&mut self, id: WatchId) -> Result<(), Error> {
+        self.watches.remove(&id).ok_or(Error::NotFound)?;
+        if self.watches.is_empty() {
+            self.written_nodes.clear();
+        }
+        Ok(())
+    }
+
+    // Get the changes of the watched paths made since the last poll, e.g. from a canister timer.
+    // A path created, written or replaced several times between two polls is reported once.
+    pub fn poll_watches(&mut self) -> Result<Vec<WatchEvent>, Error> {
+        let root = self.storage.root_node();
+        let written = std::mem::take(&mut self.written_nodes);
+        let mut events = Vec::new();
+
+        for (id, watch) in self.watches.iter_mut() {
+            for kind in watch.poll(root, &written, self.storage.as_ref())? {
+                events.push(WatchEvent {
+                    watch: *id,
+                    path: watch.path.clone(),
+                    kind,
+                });
+            }
+        }
+
+        Ok(events)
+    }
+
+    // Move the entry `name` of the directory `fd` to another position in the directory listing order.
+    // The new entries are always added at the end.
+    pub fn move_entry(&mut self, fd: Fd, name: &str, position: EntryPosition) -> Result<(), Error> {
+        let dir = self.get_dir(fd)?;
+        self.read_only_guard().check(dir.node)?;
+        let name = self.options.normalize_path(name);
+        let anchor;
+        let position = match position {
+            EntryPosition::Before(other) => {
+                anchor = self.options.normalize_path(other);
+                EntryPosition::Before(&anchor)
+            }
+            EntryPosition::After(other) => {
+                anchor = self.options.normalize_path(other);
+                EntryPosition::After(&anchor)
+            }
+            position => position,
+        };
+        move_dir_entry(dir.node, name.as_bytes(), position, self.storage.as_mut())
+    }
+
+    // Get the directory change stamp, it changes each time an entry is added to or removed from the directory.
+    pub fn dir_generation(&self, fd: Fd) -> Result<u64, Error> {
+        let dir = self.get_dir(fd)?;
+        Ok(self.storage.get_metadata(dir.node)?.dir_generation)
+    }
+
     fn put_dir(&mut self, fd: Fd, dir: Dir) {
-        self.fd_table.update(fd, FdEntry::Dir(dir))
+        // the listing position of the descriptor is kept
+        let cursor = self.fd_table.get(fd).map_or(0, |entry| entry.cursor());
+        let entry = FdEntry::Dir {
+            node: dir.node,
+            cursor,
+            stat: dir.stat,
+        };
+        self.fd_table.update(fd, entry)
     }
 
     // Read file's `fd` contents into `dst`.
     pub fn read(&mut self, fd: Fd, dst: &mut [u8]) -> Result<FileSize, Error> {
-        let mut file = self.get_file(fd)?;
+        if let Some(content) = self.fd_table.get_mut(fd).and_then(FdEntry::proc_content) {
+            return Ok(content.read(dst));
+        }
+        let mut file = self.get_readable_file(fd)?;
+        self.touch(file.node);
         let read_size = file.read_with_cursor(dst, self.storage.as_mut())?;
         self.put_file(fd, file);
         Ok(read_size)
@@ -116,15 +770,246 @@ impl FileSystem {
 
     // Write `src` contents into a file.
     pub fn write(&mut self, fd: Fd, src: &[u8]) -> Result<FileSize, Error> {
-        let mut file = self.get_file(fd)?;
-        let written_size = file.write_with_cursor(src, self.storage.as_mut())?;
+        let mut file = self.get_cursor_file(fd)?;
+        self.check_policy(|policy| {
+            policy.before_write(file.node, file.cursor, src.len() as FileSize)
+        })?;
+        self.preserve_snapshots(
+            file.node,
+            file.cursor..file.cursor.saturating_add(src.len() as FileSize),
+        )?;
+        self.invalidate_hash(file.node);
+        let written_size = self.write_buffers(&file, file.cursor, &[src], None)?;
+        file.cursor += written_size;
+        let node = file.node;
         self.put_file(fd, file);
+        self.file_written(node);
+        Ok(written_size)
+    }
+
+    // Change the size of an opened file like `ftruncate`, the cursor is not moved. A shrunk file loses its chunks
+    // past the new end, a grown file reads as zeros past its old end without storing them.
+    pub fn set_size(&mut self, fd: Fd, size: FileSize) -> Result<(), Error> {
+        let file = self.get_writable_file(fd)?;
+        let old_size = self.storage.get_metadata(file.node)?.size;
+        self.check_policy(|policy| {
+            policy.before_write(file.node, old_size.min(size), size.saturating_sub(old_size))
+        })?;
+        self.preserve_snapshots(file.node, old_size.min(size)..FileSize::MAX)?;
+        self.invalidate_hash(file.node);
+        file.set_size(size, self.storage.as_mut())?;
+        self.file_written(file.node);
+        Ok(())
+    }
+
+    // Read into `dst` like `read` in the slices of BUDGET_SLICE_SIZE bytes, stopping between the slices
+    // before the instruction budget of the mount options runs out. The cursor is moved past the bytes read,
+    // a partial result reports them and the rest of the buffer is read by the next call.
+    pub fn read_budgeted(&mut self, fd: Fd, dst: &mut [u8]) -> Result<Budgeted<FileSize>, Error> {
+        let mut guard = BudgetGuard::new(self.options.instruction_budget);
+        let mut read_size = 0;
+
+        for slice in dst.chunks_mut(BUDGET_SLICE_SIZE) {
+            let cost = io_cost(slice.len());
+            if !guard.allows(cost) {
+                return Ok(Budgeted::Partial {
+                    continuation: Continuation { done: read_size },
+                });
+            }
+            let len = slice.len() as FileSize;
+            let size = self.read(fd, slice)?;
+            guard.charge(cost);
+            read_size += size;
+            if size < len {
+                break;
+            }
+        }
+
+        Ok(Budgeted::Complete(read_size))
+    }
+
+    // Write `src` like `write` in the slices of BUDGET_SLICE_SIZE bytes, stopping between the slices
+    // before the instruction budget of the mount options runs out. The cursor is moved past the bytes written,
+    // a partial result reports them and the rest of the buffer is written by the next call.
+    pub fn write_budgeted(&mut self, fd: Fd, src: &[u8]) -> Result<Budgeted<FileSize>, Error> {
+        let mut guard = BudgetGuard::new(self.options.instruction_budget);
+        let mut written_size = 0;
+
+        for slice in src.chunks(BUDGET_SLICE_SIZE) {
+            let cost = io_cost(slice.len());
+            if !guard.allows(cost) {
+                return Ok(Budgeted::Partial {
+                    continuation: Continuation { done: written_size },
+                });
+            }
+            let size = self.write(fd, slice)?;
+            guard.charge(cost);
+            written_size += size;
+            if size < slice.len() as FileSize {
+                break;
+            }
+        }
+
+        Ok(Budgeted::Complete(written_size))
+    }
+
+    // Read up to `len` bytes at `offset` from the file `path` in the `parent` folder without opening it.
+    // The file metadata is read once, the result is cut at the end of file.
+    pub fn read_path(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        offset: FileSize,
+        len: FileSize,
+    ) -> Result<Vec<u8>, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        if let Some(proc_file) = self.proc_file_at(dir.node, path) {
+            let text = self.proc_text(proc_file)?.into_bytes();
+            let start = usize::try_from(offset)
+                .unwrap_or(usize::MAX)
+                .min(text.len());
+            let end = usize::try_from(len)
+                .unwrap_or(usize::MAX)
+                .min(text.len() - start)
+                + start;
+            return Ok(text[start..end].to_vec());
+        }
+        let node = find_node(dir.node, path, self.storage.as_ref())?;
+
+        let metadata = self.storage.get_metadata(node)?;
+        let file = self.path_file(node, &metadata)?;
+        self.touch(node);
+
+        let len = len.min(metadata.size.saturating_sub(offset));
+        let mut buf = vec![0; usize::try_from(len).map_err(|_| Error::InvalidBufferLength)?];
+        file.read_with_size(offset, &mut buf, metadata.size, self.storage.as_ref())?;
+        Ok(buf)
+    }
+
+    // Write `data` at `offset` into the file `path` in the `parent` folder without opening it, the file is
+    // created at `ctime` if it is missing and `create` is set. The file metadata is read and written once.
+    pub fn write_path(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        offset: FileSize,
+        data: &[u8],
+        create: bool,
+        ctime: u64,
+    ) -> Result<FileSize, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        if self.proc_file_at(dir.node, path).is_some() {
+            return Err(Error::InvalidFileDescriptor);
+        }
+
+        let node = match find_node(dir.node, path, self.storage.as_ref()) {
+            Ok(node) => node,
+            Err(Error::NotFound) if create => {
+                self.check_dir_capacity(dir.node, path)?;
+                self.check_policy(|policy| {
+                    policy.before_create(dir.node, path, FileType::RegularFile)
+                })?;
+                let child =
+                    dir.create_file(path, FdStat::default(), self.storage.as_mut(), ctime)?;
+                self.record_entry_change(dir.node, path);
+                self.register_cache_member(dir.node, path, child.node)?;
+                self.put_dir(parent, dir);
+                child.node
+            }
+            Err(err) => return Err(err),
+        };
+
+        let metadata = self.storage.get_metadata(node)?;
+        let file = self.path_file(node, &metadata)?;
+        self.check_policy(|policy| policy.before_write(node, offset, data.len() as FileSize))?;
+        self.preserve_snapshots(node, offset..offset.saturating_add(data.len() as FileSize))?;
+        self.invalidate_hash(node);
+        let written_size = self.write_buffers(&file, offset, &[data], Some(metadata))?;
+        self.file_written(node);
         Ok(written_size)
     }
 
+    // Get a file handle of a node accessed by path, without a descriptor.
+    fn path_file(&self, node: Node, metadata: &Metadata) -> Result<File, Error> {
+        match metadata.file_type {
+            FileType::RegularFile => Ok(File {
+                node,
+                cursor: 0,
+                stat: FdStat::default(),
+                read_pattern: ReadPattern::default(),
+            }),
+            FileType::Directory => Err(Error::IsDirectory),
+            FileType::SymbolicLink => Err(Error::InvalidFileType),
+        }
+    }
+
+    // Load the chunks of the files `paths` in the `parent` folder into the storage cache, e.g. in `post_upgrade`,
+    // so that the first reads of the critical files are not cold. The directories get their entries loaded and
+    // the missing paths are skipped. Returns the number of the file chunks requested.
+    pub fn prewarm(&mut self, parent: Fd, paths: &[&str]) -> Result<FileSize, Error> {
+        let dir = self.get_dir(parent)?;
+        let mut chunks = 0;
+
+        for path in paths {
+            let path = self.user_path(dir.node, path)?;
+            let node = match find_node(dir.node, &path, self.storage.as_ref()) {
+                Ok(node) => node,
+                Err(Error::NotFound) => continue,
+                Err(err) => return Err(err),
+            };
+
+            let metadata = self.storage.get_metadata(node)?;
+            match metadata.file_type {
+                FileType::RegularFile if metadata.size > 0 => {
+                    let count = metadata.size.div_ceil(FILE_CHUNK_SIZE as FileSize);
+                    let count = FileChunkIndex::try_from(count).unwrap_or(FileChunkIndex::MAX);
+                    self.storage.prefetch_filechunks(node, 0, count);
+                    chunks += count as FileSize;
+                }
+                FileType::Directory => self.storage.prefetch_direntries(node, usize::MAX),
+                _ => {}
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    // Keep the chunks of an opened file in the storage cache so that its reads do not depend on the other
+    // traffic, e.g. for `index.html`. The chunks are loaded now and stay cached until `unpin`, the file
+    // is unpinned when it is removed. Storages without a chunk cache ignore it.
+    pub fn pin(&mut self, fd: Fd) -> Result<(), Error> {
+        let file = self.get_file(fd)?;
+        let metadata = self.storage.get_metadata(file.node)?;
+        self.storage.set_pinned(file.node, true);
+
+        let count = metadata.size.div_ceil(FILE_CHUNK_SIZE as FileSize);
+        let count = FileChunkIndex::try_from(count).unwrap_or(FileChunkIndex::MAX);
+        self.storage.prefetch_filechunks(file.node, 0, count);
+        Ok(())
+    }
+
+    // Let the cached chunks of a pinned file be evicted again.
+    pub fn unpin(&mut self, fd: Fd) -> Result<(), Error> {
+        let file = self.get_file(fd)?;
+        self.storage.set_pinned(file.node, false);
+        Ok(())
+    }
+
     // Read file into a vector of buffers.
     pub fn read_vec(&mut self, fd: Fd, dst: DstIoVec) -> Result<FileSize, Error> {
-        let mut file = self.get_file(fd)?;
+        if let Some(content) = self.fd_table.get_mut(fd).and_then(FdEntry::proc_content) {
+            let mut read_size = 0;
+            for buf in dst {
+                let buf = unsafe { std::slice::from_raw_parts_mut(buf.buf, buf.len) };
+                read_size += content.read(buf);
+            }
+            return Ok(read_size);
+        }
+
+        let mut file = self.get_readable_file(fd)?;
+        self.touch(file.node);
         let mut read_size = 0;
         for buf in dst {
             let buf = unsafe { std::slice::from_raw_parts_mut(buf.buf, buf.len) };
@@ -142,7 +1027,8 @@ impl FileSystem {
         dst: DstIoVec,
         offset: FileSize,
     ) -> Result<FileSize, Error> {
-        let file = self.get_file(fd)?;
+        let file = self.get_readable_file(fd)?;
+        self.touch(file.node);
         let mut read_size = 0;
         for buf in dst {
             let rbuf = unsafe { std::slice::from_raw_parts_mut(buf.buf, buf.len) };
@@ -157,14 +1043,20 @@ impl FileSystem {
 
     // Write a vector of buffers into a file at a given offset, the file cursor is updated.
     pub fn write_vec(&mut self, fd: Fd, src: SrcIoVec) -> Result<FileSize, Error> {
-        let mut file = self.get_file(fd)?;
-        let mut written_size = 0;
-        for buf in src {
-            let buf = unsafe { std::slice::from_raw_parts(buf.buf, buf.len) };
-            let size = file.write_with_cursor(buf, self.storage.as_mut())?;
-            written_size += size;
-        }
+        let mut file = self.get_cursor_file(fd)?;
+        let size = src.iter().map(|buf| buf.len as FileSize).sum();
+        self.check_policy(|policy| policy.before_write(file.node, file.cursor, size))?;
+        self.preserve_snapshots(file.node, file.cursor..file.cursor.saturating_add(size))?;
+        self.invalidate_hash(file.node);
+        let bufs: Vec<&[u8]> = src
+            .iter()
+            .map(|buf| unsafe { std::slice::from_raw_parts(buf.buf, buf.len) })
+            .collect();
+        let written_size = self.write_buffers(&file, file.cursor, &bufs, None)?;
+        file.cursor += written_size;
+        let node = file.node;
         self.put_file(fd, file);
+        self.file_written(node);
         Ok(written_size)
     }
 
@@ -175,583 +1067,7160 @@ impl FileSystem {
         src: SrcIoVec,
         offset: FileSize,
     ) -> Result<FileSize, Error> {
-        let file = self.get_file(fd)?;
+        let file = self.get_writable_file(fd)?;
+        let size = src.iter().map(|buf| buf.len as FileSize).sum();
+        self.check_policy(|policy| policy.before_write(file.node, offset, size))?;
+        self.preserve_snapshots(file.node, offset..offset.saturating_add(size))?;
+        self.invalidate_hash(file.node);
+        let bufs: Vec<&[u8]> = src
+            .iter()
+            .map(|buf| unsafe { std::slice::from_raw_parts(buf.buf, buf.len) })
+            .collect();
+        let written_size = self.write_buffers(&file, offset, &bufs, None)?;
+        let node = file.node;
+        self.put_file(fd, file);
+        self.file_written(node);
+        Ok(written_size)
+    }
+
+    // Write the buffers one after another starting at `offset`. A failed storage write is handled as selected
+    // by `MountOptions::write_failure`: the written part is kept and returned as a short write, or rolled back.
+    // The file metadata read by the caller is used for the first buffer.
+    fn write_buffers(
+        &mut self,
+        file: &File,
+        offset: FileSize,
+        bufs: &[&[u8]],
+        mut metadata: Option<Metadata>,
+    ) -> Result<FileSize, Error> {
+        let mut undo = match self.options.write_failure {
+            WriteFailure::KeepPartial => None,
+            WriteFailure::Rollback => Some(WriteUndo::new(file.node, self.storage.as_ref())?),
+        };
+
         let mut written_size = 0;
-        for buf in src {
-            let buf = unsafe { std::slice::from_raw_parts(buf.buf, buf.len) };
-            let size = file.write_with_offset(written_size + offset, buf, self.storage.as_mut())?;
+        for buf in bufs {
+            let position = offset.saturating_add(written_size);
+            let (size, result) = file.write_partial(
+                position,
+                buf,
+                metadata.take(),
+                undo.as_mut(),
+                self.storage.as_mut(),
+            );
+            self.record_write(file.node, position, size);
             written_size += size;
+
+            if let Err(err) = result {
+                return match undo {
+                    Some(undo) => undo.restore(self.storage.as_mut()).and(Err(err)),
+                    None if written_size > 0 => {
+                        self.logical_bytes_written += written_size;
+                        Ok(written_size)
+                    }
+                    None => Err(err),
+                };
+            }
         }
-        self.put_file(fd, file);
+
+        self.logical_bytes_written += written_size;
         Ok(written_size)
     }
 
     // Position file cursor to a given position.
     pub fn seek(&mut self, fd: Fd, delta: i64, whence: Whence) -> Result<FileSize, Error> {
+        if self.options.strict_posix {
+            if let Some(FdEntry::Dir { .. }) = self.fd_table.get(fd) {
+                return Err(Error::InvalidFileDescriptor);
+            }
+        }
+
+        if let Some(content) = self.fd_table.get_mut(fd).and_then(FdEntry::proc_content) {
+            return content.seek(delta, whence);
+        }
+
         let mut file = self.get_file(fd)?;
         let pos = file.seek(delta, whence, self.storage.as_mut())?;
         self.put_file(fd, file);
         Ok(pos)
     }
 
-    // Get the current file cursor position.
-    pub fn tell(&mut self, fd: Fd) -> Result<FileSize, Error> {
-        let file = self.get_file(fd)?;
-        let pos = file.tell();
-        Ok(pos)
+    // Read several `(offset, len)` ranges of a file in one call, the file cursor is NOT updated.
+    pub fn read_ranges(
+        &mut self,
+        fd: Fd,
+        ranges: &[(FileSize, FileSize)],
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let file = self.get_readable_file(fd)?;
+        self.touch(file.node);
+        file.read_ranges(ranges, self.storage.as_mut())
     }
 
-    // Close the opened file and release the corresponding file descriptor.
-    pub fn close(&mut self, fd: Fd) -> Result<(), Error> {
-        self.fd_table.close(fd).ok_or(Error::NotFound)?;
-
-        Ok(())
+    // Replace the contents of the file `dst` with the contents of `src`, the holes of `src` are preserved.
+    // Copying a file onto itself, e.g. through two descriptors of the same file, changes nothing.
+    pub fn copy_file(&mut self, src: Fd, dst: Fd) -> Result<FileSize, Error> {
+        let src = self.get_readable_file(src)?;
+        let dst = self.get_writable_file(dst)?;
+        let size = self.storage.get_metadata(src.node)?.size;
+        if src.node == dst.node {
+            return Ok(size);
+        }
+        self.check_policy(|policy| policy.before_write(dst.node, 0, size))?;
+        self.preserve_snapshots(dst.node, 0..FileSize::MAX)?;
+        self.invalidate_hash(dst.node);
+        let size = dst.copy_from(&src, self.storage.as_mut())?;
+        self.file_written(dst.node);
+        Ok(size)
     }
 
-    // Get the metadata for a given file descriptor
-    pub fn metadata(&self, fd: Fd) -> Result<Metadata, Error> {
-        let node = self.get_node(fd)?;
-        self.storage.get_metadata(node)
+    // Export the file contents without its holes.
+    pub fn export_sparse(&self, fd: Fd) -> Result<SparseFile, Error> {
+        let file = self.get_readable_file(fd)?;
+        file.export_sparse(self.storage.as_ref())
     }
 
-    // find metadata for a given file descriptor.
-    pub fn metadata_from_node(&self, node: Node) -> Result<Metadata, Error> {
-        self.storage.get_metadata(node)
+    // Replace the file contents with an exported sparse file.
+    pub fn import_sparse(&mut self, fd: Fd, sparse: &SparseFile) -> Result<(), Error> {
+        let file = self.get_writable_file(fd)?;
+        self.check_policy(|policy| policy.before_write(file.node, 0, sparse.size))?;
+        self.preserve_snapshots(file.node, 0..FileSize::MAX)?;
+        self.invalidate_hash(file.node);
+        file.import_sparse(sparse, self.storage.as_mut())?;
+        self.file_written(file.node);
+        Ok(())
     }
 
-    // update metadata of a given file descriptor
-    pub fn set_metadata(&mut self, fd: Fd, metadata: Metadata) -> Result<(), Error> {
-        let node = self.get_node(fd)?;
-        self.storage.put_metadata(node, metadata);
+    // Freeze the current version of an opened file, it can be read with the returned token until it is released,
+    // even if the file is rewritten in between. Only the chunks changed afterwards are copied, right before the change.
+    // A frozen file counts as opened without taking a descriptor. The tokens are kept in heap memory, the copied
+    // chunks are reclaimed on the next mount.
+    pub fn freeze_node(&mut self, fd: Fd) -> Result<SnapshotToken, Error> {
+        let file = self.get_file(fd)?;
+        let size = self.storage.get_metadata(file.node)?.size;
+
+        let token = SnapshotToken(self.next_snapshot);
+        self.next_snapshot += 1;
+
+        // nothing was copied for a version with the same size, the file was not changed since it was frozen
+        let shared = self
+            .frozen
+            .iter_mut()
+            .find(|(_, frozen)| {
+                frozen.node == file.node && frozen.size == size && frozen.copied.is_empty()
+            })
+            .map(|(version, frozen)| {
+                frozen.tokens += 1;
+                *version
+            });
+        let version = match shared {
+            Some(version) => version,
+            None => {
+                self.fd_table.pin(file.node);
+                self.frozen.insert(
+                    token.0,
+                    FrozenFile {
+                        node: file.node,
+                        size,
+                        copy: None,
+                        copied: BTreeSet::new(),
+                        tokens: 1,
+                    },
+                );
+                token.0
+            }
+        };
+        self.snapshots.insert(token, version);
 
-        Ok(())
+        Ok(token)
     }
 
-    // Update access time.
-    pub fn set_accessed_time(&mut self, fd: Fd, time: u64) -> Result<(), Error> {
-        let node = self.get_node(fd)?;
-        let mut metadata = self.storage.get_metadata(node)?;
+    fn frozen_file(&self, token: SnapshotToken) -> Result<&FrozenFile, Error> {
+        let version = self.snapshots.get(&token).ok_or(Error::NotFound)?;
+        self.frozen.get(version).ok_or(Error::NotFound)
+    }
 
-        metadata.times.accessed = time;
+    // Get the size of a frozen file version.
+    pub fn snapshot_size(&self, token: SnapshotToken) -> Result<FileSize, Error> {
+        Ok(self.frozen_file(token)?.size)
+    }
 
-        self.storage.put_metadata(node, metadata);
+    // Read a frozen file version at the given offset.
+    pub fn read_snapshot(
+        &mut self,
+        token: SnapshotToken,
+        offset: FileSize,
+        buf: &mut [u8],
+    ) -> Result<FileSize, Error> {
+        let frozen = self.frozen_file(token)?;
+        let len = frozen
+            .size
+            .saturating_sub(offset)
+            .min(buf.len() as FileSize);
+
+        // the chunks are read either from the copy or from the file
+        let mut reads = Vec::new();
+        let mut pos = offset;
+        while pos < offset + len {
+            let index = (pos / FILE_CHUNK_SIZE as FileSize) as FileChunkIndex;
+            let end = ((index as FileSize + 1) * FILE_CHUNK_SIZE as FileSize).min(offset + len);
+            let node = match frozen.copy {
+                Some(copy) if frozen.copied.contains(&index) => copy,
+                _ => frozen.node,
+            };
+            reads.push((node, pos, end));
+            pos = end;
+        }
 
-        Ok(())
-    }
+        for (node, start, end) in reads {
+            let file = File::new(node, FdStat::default(), self.storage.as_ref())?;
+            let dst = &mut buf[(start - offset) as usize..(end - offset) as usize];
+            let read = file.read_with_offset(start, dst, self.storage.as_mut())?;
+            dst[read as usize..].fill(0);
+        }
 
-    // Update modification time.
-    pub fn set_modified_time(&mut self, fd: Fd, time: u64) -> Result<(), Error> {
-        let node = self.get_node(fd)?;
-        let mut metadata = self.storage.get_metadata(node)?;
+        Ok(len)
+    }
 
-        metadata.times.modified = time;
+    // Release a frozen file version, the copied chunks are removed with the last token reading it.
+    pub fn release_snapshot(&mut self, token: SnapshotToken) -> Result<(), Error> {
+        let version = self.snapshots.remove(&token).ok_or(Error::NotFound)?;
+        let frozen = self.frozen.get_mut(&version).ok_or(Error::NotFound)?;
+        frozen.tokens -= 1;
+        if frozen.tokens > 0 {
+            return Ok(());
+        }
 
-        self.storage.put_metadata(node, metadata);
+        let frozen = self.frozen.remove(&version).ok_or(Error::NotFound)?;
+        if let Some(copy) = frozen.copy {
+            let metadata = self.storage.get_metadata(copy)?;
+            rm_node_data(copy, &metadata, self.storage.as_mut());
+        }
 
+        self.fd_table.unpin(frozen.node);
+        self.release_unlinked(frozen.node);
         Ok(())
     }
 
-    // Get file or directory stats.
-    pub fn get_stat(&self, fd: Fd) -> Result<(FileType, FdStat), Error> {
-        match self.fd_table.get(fd) {
-            None => Err(Error::NotFound),
-            Some(FdEntry::File(file)) => Ok((FileType::RegularFile, file.stat)),
-            Some(FdEntry::Dir(dir)) => Ok((FileType::Directory, dir.stat)),
+    // Mount a read-only copy of the current tree of the directory `dir` as "/.snapshots/`name`", so that an older
+    // version can be served while a new one is staged. The files are copied, the copies share their chunks only
+    // when the storage deduplicates them. The copy is persisted, its protection is kept in heap memory.
+    pub fn mount_snapshot(&mut self, dir: Fd, name: &str, ctime: u64) -> Result<(), Error> {
+        let src = self.get_dir(dir)?.node;
+        self.read_only_guard().check_writable()?;
+        let name = self.options.normalize_path(name).into_owned();
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(Error::InvalidFileName);
+        }
+        if self.snapshot_mounts.contains_key(&name) {
+            return Err(Error::FileAlreadyExists);
         }
-    }
 
-    // Update stats of a given file.
-    pub fn set_stat(&mut self, fd: Fd, stat: FdStat) -> Result<(), Error> {
-        match self.fd_table.get(fd) {
-            Some(FdEntry::File(file)) => {
-                let mut file = file.clone();
-                file.stat = stat;
-                self.put_file(fd, file);
-                Ok(())
-            }
-            Some(FdEntry::Dir(dir)) => {
-                let mut dir = dir.clone();
-                dir.stat = stat;
-                self.put_dir(fd, dir);
-                Ok(())
+        let root = self.root_fd;
+        let mounts = match self.open_or_create(
+            root,
+            SNAPSHOT_MOUNT_DIR,
+            FdStat::default(),
+            OpenFlags::DIRECTORY,
+            0,
+        ) {
+            Err(Error::NotFound) => {
+                self.create_dir(root, SNAPSHOT_MOUNT_DIR, FdStat::default(), ctime)?
             }
-            None => Err(Error::NotFound),
+            result => result?,
+        };
+        let mounts_node = self.get_node(mounts)?;
+        let mount = self.create_dir(mounts, &name, FdStat::default(), ctime);
+        self.close(mounts)?;
+        let mount = mount?;
+
+        let mount_node = self.get_node(mount)?;
+        let mut nodes = BTreeSet::from([mount_node]);
+        let result = self.copy_snapshot_tree(src, mount, mounts_node, &mut nodes, ctime);
+        self.close(mount)?;
+
+        if let Err(err) = result {
+            let _ = self.apply_remove(root, &format!("{SNAPSHOT_MOUNT_DIR}/{name}"));
+            return Err(err);
         }
+
+        self.snapshot_mounts.insert(
+            name,
+            SnapshotMount {
+                root: mount_node,
+                nodes,
+            },
+        );
+        Ok(())
     }
 
-    // Get metadata of a file with name `path` in a given folder.
-    pub fn open_metadata(&self, parent: Fd, path: &str) -> Result<Metadata, Error> {
-        let dir = self.get_dir(parent)?;
-        let node = find_node(dir.node, path, self.storage.as_ref())?;
-        self.storage.get_metadata(node)
+    // Remove a snapshot mounted by `mount_snapshot` together with its copy of the tree.
+    pub fn unmount_snapshot(&mut self, name: &str) -> Result<(), Error> {
+        let name = self.options.normalize_path(name).into_owned();
+        let mount = self.snapshot_mounts.remove(&name).ok_or(Error::NotFound)?;
+
+        let result = self.apply_remove(self.root_fd, &format!("{SNAPSHOT_MOUNT_DIR}/{name}"));
+        if result.is_err() {
+            self.snapshot_mounts.insert(name, mount);
+        }
+        result
     }
 
-    // Opens of creates a new file.
-    pub fn open_or_create(
+    // Get the names of the mounted snapshots.
+    pub fn snapshot_mounts(&self) -> Vec<String> {
+        self.snapshot_mounts.keys().cloned().collect()
+    }
+
+    // Copy the entries of the directory `src` into the directory `dst`, the directory `skip` is left out.
+    // The nodes of the copies are added to `nodes`.
+    fn copy_snapshot_tree(
         &mut self,
-        parent: Fd,
-        path: &str,
-        stat: FdStat,
-        flags: OpenFlags,
+        src: Node,
+        dst: Fd,
+        skip: Node,
+        nodes: &mut BTreeSet<Node>,
         ctime: u64,
-    ) -> Result<Fd, Error> {
-        let dir = self.get_dir(parent)?;
+    ) -> Result<(), Error> {
+        let dst_node = self.get_node(dst)?;
+        let mut next_index = self.storage.get_metadata(src)?.first_dir_entry;
+
+        while let Some(index) = next_index {
+            let entry = self.storage.get_direntry(src, index)?;
+            next_index = entry.next_entry;
+            if entry.node == skip {
+                continue;
+            }
 
-        match find_node(dir.node, path, self.storage.as_ref()) {
-            Ok(node) => self.open(node, stat, flags),
-            Err(Error::NotFound) => {
-                if !flags.contains(OpenFlags::CREATE) {
-                    return Err(Error::NotFound);
+            let name = &entry.name.bytes[..entry.name.length as usize];
+            let name = String::from_utf8_lossy(name).into_owned();
+            let metadata = self.storage.get_metadata(entry.node)?;
+
+            let copy = match metadata.file_type {
+                FileType::Directory => {
+                    let fd = self.create_dir(dst, &name, FdStat::default(), ctime)?;
+                    let copy = self.get_node(fd)?;
+                    let result = self.copy_snapshot_tree(entry.node, fd, skip, nodes, ctime);
+                    self.close(fd)?;
+                    result?;
+                    copy
                 }
-                if flags.contains(OpenFlags::DIRECTORY) {
-                    return Err(Error::InvalidFileType);
+                FileType::RegularFile => {
+                    let fd = self.create_file(dst, &name, FdStat::default(), ctime)?;
+                    let copy = self.get_file(fd)?;
+                    let file = File::new(entry.node, FdStat::default(), self.storage.as_ref())?;
+                    let result = copy.copy_from(&file, self.storage.as_mut());
+                    self.close(fd)?;
+                    result?;
+                    copy.node
                 }
-                self.create_file(parent, path, stat, ctime)
-            }
-            Err(err) => Err(err),
+                FileType::SymbolicLink => {
+                    let target = read_alias_target(entry.node, self.storage.as_ref())?;
+                    self.create_alias(dst, &name, &target, ctime)?;
+                    find_node(dst_node, &name, self.storage.as_ref())?
+                }
+            };
+
+            // the copies keep the times and the serving parameters of the originals
+            let mut copy_metadata = self.storage.get_metadata(copy)?;
+            copy_metadata.times = metadata.times;
+            copy_metadata.http = metadata.http;
+            self.storage.put_metadata(copy, copy_metadata);
+            nodes.insert(copy);
         }
+
+        Ok(())
     }
 
-    // Opens a file and return its new file descriptor.
-    pub fn open(&mut self, node: Node, stat: FdStat, flags: OpenFlags) -> Result<Fd, Error> {
-        if flags.contains(OpenFlags::EXCLUSIVE) {
-            return Err(Error::FileAlreadyExists);
-        }
-        let metadata = self.storage.get_metadata(node)?;
-        match metadata.file_type {
-            FileType::Directory => {
-                let dir = Dir::new(node, stat, self.storage.as_mut())?;
-                let fd = self.fd_table.open(FdEntry::Dir(dir));
-                Ok(fd)
+    // Copy the chunks of the frozen versions of a file overlapping the byte `range` before it is changed, a chunk
+    // is copied once for each version.
+    fn preserve_snapshots(&mut self, node: Node, range: Range<FileSize>) -> Result<(), Error> {
+        let versions: Vec<u64> = self
+            .frozen
+            .iter()
+            .filter(|(_, frozen)| {
+                frozen.node == node && !range.is_empty() && range.start < frozen.size
+            })
+            .map(|(version, _)| *version)
+            .collect();
+
+        let chunk_size = FILE_CHUNK_SIZE as FileSize;
+        for version in versions {
+            let frozen = &self.frozen[&version];
+            let first = (range.start / chunk_size) as FileChunkIndex;
+            let last = ((range.end.min(frozen.size) - 1) / chunk_size) as FileChunkIndex;
+            let missing: Vec<FileChunkIndex> = (first..=last)
+                .filter(|index| !frozen.copied.contains(index))
+                .collect();
+            if missing.is_empty() {
+                continue;
             }
-            FileType::RegularFile => {
-                if flags.contains(OpenFlags::DIRECTORY) {
-                    return Err(Error::InvalidFileType);
+
+            let copy = match frozen.copy {
+                Some(copy) => copy,
+                None => {
+                    let copy = self.storage.new_node();
+                    let mut metadata = self.storage.get_metadata(node)?;
+                    metadata.node = copy;
+                    metadata.link_count = 0;
+                    metadata.size = frozen.size;
+                    metadata.generation = self.storage.node_generation();
+                    self.storage.put_metadata(copy, metadata);
+                    self.storage.add_orphan(copy);
+                    self.frozen.get_mut(&version).unwrap().copy = Some(copy);
+                    copy
                 }
-                let file = File::new(node, stat, self.storage.as_mut())?;
-                if flags.contains(OpenFlags::TRUNCATE) {
-                    file.truncate(self.storage.as_mut())?;
+            };
+
+            for index in missing {
+                // a chunk that was not stored is read as zeros from the copy too
+                if self.storage.read_filechunk(node, index, 0, &mut []).is_ok() {
+                    let chunk = self.storage.get_filechunk(node, index)?.into_owned();
+                    self.storage.write_filechunk(copy, index, 0, &chunk)?;
                 }
-                let fd = self.fd_table.open(FdEntry::File(file));
-                Ok(fd)
+                self.frozen.get_mut(&version).unwrap().copied.insert(index);
             }
-            FileType::SymbolicLink => unimplemented!("Symbolic links are not supported yet"),
         }
+
+        Ok(())
     }
 
-    // Create a new file named `path` in the given `parent` folder.
-    pub fn create_file(
+    // Replace the contents of the file `dst_path` with the contents of the files `src_paths` one after another,
+    // the sources are removed. The chunks of a source starting at a chunk boundary are moved instead of copied.
+    // The contents are put together in an unnamed file linked at `dst_path` only on success, a failed call
+    // leaves the destination and the sources as they were.
+    pub fn concat(
         &mut self,
         parent: Fd,
-        path: &str,
-        stat: FdStat,
+        dst_path: &str,
+        src_paths: &[&str],
         ctime: u64,
-    ) -> Result<Fd, Error> {
+    ) -> Result<FileSize, Error> {
         let dir = self.get_dir(parent)?;
 
-        let child = dir.create_file(path, stat, self.storage.as_mut(), ctime)?;
+        // the sources are checked before anything is changed
+        let mut paths = Vec::with_capacity(src_paths.len());
+        let mut sources: Vec<File> = Vec::with_capacity(src_paths.len());
+        let mut size: FileSize = 0;
+        for path in src_paths {
+            let path = self.user_path(dir.node, path)?.into_owned();
+            let node = find_node(dir.node, &path, self.storage.as_ref())?;
+
+            let metadata = self.storage.get_metadata(node)?;
+            if metadata.file_type != FileType::RegularFile {
+                return Err(Error::InvalidFileType);
+            }
+            size += metadata.size;
+            if size > MAX_FILE_SIZE {
+                return Err(Error::FileTooLarge);
+            }
+            if self.fd_table.node_refcount().contains_key(&node) {
+                return Err(Error::CannotRemoveOpenedNode);
+            }
+            self.read_only_guard().check(node)?;
+            if sources.iter().any(|src| src.node == node) {
+                return Err(Error::InvalidFileName);
+            }
 
-        let child_fd = self.fd_table.open(FdEntry::File(child));
-        self.put_dir(parent, dir);
-        Ok(child_fd)
-    }
+            sources.push(File::new(node, FdStat::default(), self.storage.as_ref())?);
+            paths.push(path);
+        }
 
-    // Delete a file by name `path` in the given file folder.
-    pub fn remove_file(&mut self, parent: Fd, path: &str) -> Result<(), Error> {
-        let dir = self.get_dir(parent)?;
-        dir.remove_file(path, self.fd_table.node_refcount(), self.storage.as_mut())
+        if let Ok(node) = find_node(
+            dir.node,
+            &self.user_path(dir.node, dst_path)?,
+            self.storage.as_ref(),
+        ) {
+            if sources.iter().any(|src| src.node == node) {
+                return Err(Error::InvalidFileName);
+            }
+        }
+
+        let fd = self.create_unnamed(parent, FdStat::default(), ctime)?;
+
+        let result = self.concat_unnamed(fd, parent, dst_path, &sources, size);
+        // the data of a file left unlinked is removed when it is closed
+        let closed = self.close(fd);
+        let size = result?;
+        closed?;
+
+        for path in paths {
+            self.remove(parent, &path, RemoveOptions::file())?;
+        }
+
+        Ok(size)
     }
 
-    // Create a new directory named `path` in the given `parent` folder.
-    pub fn create_dir(
+    // Split the file `src_path` into the files `{src_path}.000`, `{src_path}.001`, ... of `part_size` bytes each,
+    // the last part gets the rest and the source is removed, an empty source gives one empty part.
+    // Returns the paths of the parts. If the split fails, the parts are removed and the source is kept.
+    // The chunks of a part starting and ending at a chunk boundary are moved instead of copied.
+    pub fn split(
         &mut self,
         parent: Fd,
-        path: &str,
-        stat: FdStat,
+        src_path: &str,
+        part_size: FileSize,
         ctime: u64,
-    ) -> Result<Fd, Error> {
+    ) -> Result<Vec<String>, Error> {
+        if part_size == 0 {
+            return Err(Error::InvalidBufferLength);
+        }
+
         let dir = self.get_dir(parent)?;
-        let child = dir.create_dir(path, stat, self.storage.as_mut(), ctime)?;
-        let child_fd = self.fd_table.open(FdEntry::Dir(child));
-        self.put_dir(parent, dir);
-        Ok(child_fd)
+        let path = self.user_path(dir.node, src_path)?.into_owned();
+        let node = find_node(dir.node, &path, self.storage.as_ref())?;
+
+        let metadata = self.storage.get_metadata(node)?;
+        if metadata.file_type != FileType::RegularFile || metadata.region {
+            return Err(Error::InvalidFileType);
+        }
+        if self.fd_table.node_refcount().contains_key(&node) {
+            return Err(Error::CannotRemoveOpenedNode);
+        }
+        // an empty source gives one empty part
+        let part_count = metadata.size.div_ceil(part_size).max(1);
+        let part_paths: Vec<String> = (0..part_count).map(|i| format!("{path}.{i:03}")).collect();
+
+        let mut fds = Vec::with_capacity(part_paths.len());
+        for part_path in &part_paths {
+            match self.create_file(parent, part_path, FdStat::default(), ctime) {
+                Ok(fd) => fds.push(fd),
+                Err(err) => {
+                    // the source is untouched, the created parts are removed
+                    for (fd, part_path) in fds.into_iter().zip(&part_paths) {
+                        self.close(fd)?;
+                        self.remove(parent, part_path, RemoveOptions::file())?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        let result = self.split_into(node, &fds, part_size);
+        for fd in fds {
+            self.close(fd)?;
+        }
+        if let Err(err) = result {
+            // the source got its chunks back, the parts are removed
+            for part_path in &part_paths {
+                self.remove(parent, part_path, RemoveOptions::file())?;
+            }
+            return Err(err);
+        }
+
+        self.remove(parent, &path, RemoveOptions::file())?;
+
+        Ok(part_paths)
     }
 
-    // Delete a directory by name `path` in the given file folder.
-    pub fn remove_dir(&mut self, parent: Fd, path: &str) -> Result<(), Error> {
-        let dir = self.get_dir(parent)?;
-        dir.remove_dir(path, self.fd_table.node_refcount(), self.storage.as_mut())
+    fn split_into(&mut self, node: Node, fds: &[Fd], part_size: FileSize) -> Result<(), Error> {
+        let src = File::new(node, FdStat::default(), self.storage.as_ref())?;
+
+        let mut parts = Vec::with_capacity(fds.len());
+        for fd in fds {
+            parts.push(self.get_writable_file(*fd)?);
+        }
+
+        self.invalidate_hash(src.node);
+        let mut moved = Vec::new();
+        if let Err(err) = src.split(&parts, part_size, &mut moved, self.storage.as_mut()) {
+            src.restore_split(&moved, self.storage.as_mut());
+            return Err(err);
+        }
+
+        for part in parts {
+            let size = self.storage.get_metadata(part.node)?.size;
+            self.record_write(part.node, 0, size);
+            self.file_written(part.node);
+        }
+
+        Ok(())
     }
 
-    // Create a hard link to an existing file.
-    pub fn create_hard_link(
+    fn concat_unnamed(
         &mut self,
-        old_fd: Fd,
-        old_path: &str,
-        new_fd: Fd,
-        new_path: &str,
-    ) -> Result<Fd, Error> {
-        let src_dir = self.get_dir(old_fd)?;
-        let dst_dir = self.get_dir(new_fd)?;
+        fd: Fd,
+        parent: Fd,
+        dst_path: &str,
+        sources: &[File],
+        size: FileSize,
+    ) -> Result<FileSize, Error> {
+        let dst = self.get_writable_file(fd)?;
+        self.check_policy(|policy| policy.before_write(dst.node, 0, size))?;
 
-        create_hard_link(
-            dst_dir.node,
-            new_path,
-            src_dir.node,
-            old_path,
-            false,
-            self.storage.as_mut(),
-        )?;
+        for src in sources {
+            self.invalidate_hash(src.node);
+        }
 
-        let node = find_node(dst_dir.node, new_path, self.storage.as_ref())?;
+        let mut moved = Vec::new();
+        let result = dst
+            .concat(sources, &mut moved, self.storage.as_mut())
+            .and_then(|size| self.link_file(fd, parent, dst_path, true).map(|_| size));
+        let size = match result {
+            Ok(size) => size,
+            Err(err) => {
+                // the sources get their chunks back, the unnamed file is dropped by the caller
+                dst.restore_chunks(&moved, self.storage.as_mut());
+                return Err(err);
+            }
+        };
 
-        self.open(node, FdStat::default(), OpenFlags::empty())
+        self.record_write(dst.node, 0, size);
+        self.file_written(dst.node);
+
+        Ok(size)
     }
 
-    // Rename a file.
-    pub fn rename(
+    // Read a whole file chunk by its index, the chunk data is not copied if the storage keeps it in heap memory.
+    // The file cursor is NOT updated.
+    pub fn read_chunk(&mut self, fd: Fd, index: FileChunkIndex) -> Result<Cow<'_, [u8]>, Error> {
+        let file = self.get_readable_file(fd)?;
+        self.touch(file.node);
+        file.read_chunk(index, self.storage.as_mut())
+    }
+
+    // Compute the Merkle hash of a file or of a whole directory subtree, including the entry names and file contents.
+    // Equal trees give equal hashes independently of the order their entries were created in.
+    pub fn merkle_hash(&mut self, fd: Fd) -> Result<MerkleHash, Error> {
+        let node = self.get_node(fd)?;
+        node_hash(node, self.storage.as_ref(), &mut self.merkle_cache)
+    }
+
+    // Get the certified hash of the root directory. Between `begin_publish` and `end_publish` it stays the hash
+    // of the tree before the batch, so the batch is never observed half written.
+    pub fn certified_hash(&mut self) -> Result<MerkleHash, Error> {
+        match self.published_hash {
+            Some(hash) => Ok(hash),
+            None => self.root_hash(),
+        }
+    }
+
+    // Start a batch of writes certified together, the batches can be nested.
+    pub fn begin_publish(&mut self) -> Result<(), Error> {
+        if self.publish_depth == 0 {
+            self.published_hash = Some(self.root_hash()?);
+        }
+        self.publish_depth += 1;
+        Ok(())
+    }
+
+    // End a batch of writes, the end of the outermost batch hashes the changed files once and certifies the new tree.
+    pub fn end_publish(&mut self) -> Result<MerkleHash, Error> {
+        self.publish_depth = self.publish_depth.saturating_sub(1);
+        if self.publish_depth > 0 {
+            return self.certified_hash();
+        }
+        self.published_hash = None;
+        self.root_hash()
+    }
+
+    fn root_hash(&mut self) -> Result<MerkleHash, Error> {
+        node_hash(
+            self.storage.root_node(),
+            self.storage.as_ref(),
+            &mut self.merkle_cache,
+        )
+    }
+
+    // Get the manifest of a directory subtree to compare it with another file system.
+    pub fn manifest(&mut self, fd: Fd) -> Result<SyncManifest, Error> {
+        let dir = self.get_dir(fd)?;
+        let mut manifest = SyncManifest::default();
+        collect_manifest(
+            dir.node,
+            "",
+            self.storage.as_ref(),
+            &mut self.merkle_cache,
+            &mut manifest,
+        )?;
+        Ok(manifest)
+    }
+
+    // Export the sizes, the hashes and the times of all the nodes in the directory subtree `fd`,
+    // so that the tree can be audited later with `verify_against_manifest`.
+    pub fn export_metadata_manifest(&mut self, fd: Fd) -> Result<MetadataManifest, Error> {
+        let dir = self.get_dir(fd)?;
+        let mut manifest = MetadataManifest::default();
+        collect_metadata_manifest(
+            dir.node,
+            "",
+            self.storage.as_ref(),
+            &mut self.merkle_cache,
+            &mut manifest,
+        )?;
+        Ok(manifest)
+    }
+
+    // Compare the directory subtree `fd` with a previously exported metadata manifest, sorted by the manifest path.
+    // The paths missing in the tree are followed by the paths missing in the manifest.
+    pub fn verify_against_manifest(
         &mut self,
-        old_fd: Fd,
-        old_path: &str,
-        new_fd: Fd,
-        new_path: &str,
-    ) -> Result<Fd, Error> {
-        let src_dir = self.get_dir(old_fd)?;
-        let dst_dir = self.get_dir(new_fd)?;
+        fd: Fd,
+        manifest: &MetadataManifest,
+    ) -> Result<Vec<ManifestDeviation>, Error> {
+        let current = self.export_metadata_manifest(fd)?;
+        Ok(manifest_deviations(manifest, &current))
+    }
 
-        // create a new link
-        create_hard_link(
-            dst_dir.node,
-            new_path,
-            src_dir.node,
-            old_path,
-            true,
-            self.storage.as_mut(),
+    // Find the minimal set of changes turning the tree described by `remote_manifest` into the directory `fd`.
+    pub fn diff_against(
+        &mut self,
+        fd: Fd,
+        remote_manifest: &SyncManifest,
+    ) -> Result<SyncDiff, Error> {
+        let dir = self.get_dir(fd)?;
+        let mut diff = SyncDiff::default();
+        diff_dir(
+            dir.node,
+            "",
+            self.storage.as_ref(),
+            &mut self.merkle_cache,
+            remote_manifest,
+            &mut diff,
         )?;
+        Ok(diff)
+    }
 
-        // now unlink the older version
-        let (node, _metadata) = rm_dir_entry(
-            src_dir.node,
-            old_path,
-            None,
-            self.fd_table.node_refcount(),
-            self.storage.as_mut(),
+    // Apply a transferred file from a sync diff, the missing folders are created.
+    pub fn apply_file(
+        &mut self,
+        dir: Fd,
+        path: &str,
+        content: &[u8],
+        ctime: u64,
+    ) -> Result<(), Error> {
+        let fd = self.open_or_create(
+            dir,
+            path,
+            FdStat::default(),
+            OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            ctime,
         )?;
 
-        self.open(node, FdStat::default(), OpenFlags::empty())
+        let result = self.write(fd, content);
+        self.close(fd)?;
+        result.map(|_| ())
     }
 
-    #[cfg(test)]
-    pub(crate) fn get_test_storage(&mut self) -> &mut dyn Storage {
-        self.storage.as_mut()
+    // Write `data` into a new unnamed file and check that its SHA-256 hash is `expected_sha256`, only then
+    // the file replaces `path` in one step. On a mismatch or a failure the unnamed file is dropped and
+    // nothing else changes.
+    pub fn publish(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        data: &[u8],
+        expected_sha256: &[u8; 32],
+        ctime: u64,
+    ) -> Result<(), Error> {
+        let fd = self.create_unnamed(parent, FdStat::default(), ctime)?;
+
+        let result = self.publish_unnamed(fd, parent, path, data, expected_sha256);
+        // the data of a file left unlinked is removed when it is closed
+        let closed = self.close(fd);
+
+        result.and(closed)
     }
 
-    #[cfg(test)]
+    fn publish_unnamed(
+        &mut self,
+        fd: Fd,
+        parent: Fd,
+        path: &str,
+        data: &[u8],
+        expected_sha256: &[u8; 32],
+    ) -> Result<(), Error> {
+        // the hash is computed from the stored contents
+        self.write(fd, data)?;
+        let stored = self.read_ranges(fd, &[(0, data.len() as FileSize)])?;
+
+        let hash: [u8; 32] = Sha256::digest(&stored[0]).into();
+        if hash != *expected_sha256 {
+            return Err(Error::IntegrityCheckFailed);
+        }
+
+        self.link_file(fd, parent, path, true)
+    }
+
+    // Apply a created directory from a sync diff.
+    pub fn apply_dir(&mut self, dir: Fd, path: &str, ctime: u64) -> Result<(), Error> {
+        let parent = self.get_dir(dir)?;
+        let path = &self.user_path(parent.node, path)?;
+
+        match find_node(parent.node, path, self.storage.as_ref()) {
+            Ok(node) => match self.storage.get_metadata(node)?.file_type {
+                FileType::Directory => Ok(()),
+                _ => Err(Error::FileAlreadyExists),
+            },
+            Err(Error::NotFound) => {
+                let fd = self.create_dir(dir, path, FdStat::default(), ctime)?;
+                self.close(fd)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Apply a removed path from a sync diff, directories are removed with all their contents.
+    pub fn apply_remove(&mut self, dir: Fd, path: &str) -> Result<(), Error> {
+        let options = RemoveOptions {
+            must_be_dir: None,
+            must_be_empty: false,
+            recursive: true,
+            follow_symlinks: false,
+            zeroing: None,
+        };
+
+        match self.remove(dir, path, options) {
+            Err(Error::NotFound) => Ok(()),
+            result => result,
+        }
+    }
+
+    // Count a file write in the write statistics.
+    #[allow(unused_variables)]
+    fn record_write(&mut self, node: Node, offset: FileSize, len: FileSize) {
+        #[cfg(feature = "write-stats")]
+        self.write_stats.record(node, offset, len);
+    }
+
+    // Get the `top_n` file nodes with the most chunks written since the statistics were reset.
+    #[cfg(feature = "write-stats")]
+    pub fn hot_files(&self, top_n: usize) -> Vec<(Node, WriteStats)> {
+        self.write_stats.hot_files(top_n)
+    }
+
+    // Reset the write statistics.
+    #[cfg(feature = "write-stats")]
+    pub fn reset_write_stats(&mut self) {
+        self.write_stats.clear();
+    }
+
+    // Get the space of the records removed since the storage was created. The memory does not shrink after
+    // a removal, the space is taken by the records written later.
+    pub fn reusable_space(&self) -> ReusableSpace {
+        self.storage.reusable_space()
+    }
+
+    // Get the file bytes written and the bytes the storage wrote since the file system was mounted
+    // or the counters were reset. The storages keeping the data in heap memory report no physical writes.
+    pub fn write_amplification(&self) -> WriteAmplification {
+        WriteAmplification {
+            logical_bytes: self.logical_bytes_written,
+            physical: self
+                .storage
+                .physical_writes()
+                .since(&self.physical_writes_base),
+        }
+    }
+
+    pub fn reset_write_amplification(&mut self) {
+        self.logical_bytes_written = 0;
+        self.physical_writes_base = self.storage.physical_writes();
+    }
+
+    // Forget the cached hash of a changed file.
+    fn invalidate_hash(&mut self, node: Node) {
+        self.merkle_cache.remove(&node);
+        self.proc_stats = None;
+        if !self.watches.is_empty() {
+            self.written_nodes.insert(node);
+        }
+        for changelog in self.changelogs.values_mut() {
+            changelog.node_written(node);
+        }
+    }
+
+    // Record the changed entries along the `path` in the changelogs of the tracked directories, the tree stats
+    // of the introspection files are collected again.
+    fn record_entry_change(&mut self, parent: Node, path: &str) {
+        self.proc_stats = None;
+        if self.changelogs.is_empty() {
+            return;
+        }
+
+        let mut dir = parent;
+        for name in path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+        {
+            let node = find_node(dir, name, self.storage.as_ref()).ok();
+            if name != ".." {
+                if let Some(changelog) = self.changelogs.get_mut(&dir) {
+                    changelog.entry_changed(name, node);
+                }
+            }
+            match node {
+                Some(node) => dir = node,
+                None => break,
+            }
+        }
+    }
+
+    // Set the expected access pattern of reads from the file.
+    pub fn set_read_pattern(&mut self, fd: Fd, pattern: ReadPattern) -> Result<(), Error> {
+        let mut file = self.get_file(fd)?;
+        file.read_pattern = pattern;
+        self.put_file(fd, file);
+        Ok(())
+    }
+
+    // Reserve the chunks of the range `offset..offset + len` of an opened file like WASI `fd_allocate`, so that
+    // the writes into the range cannot fail on a memory growth. The file is extended to the end of the range.
+    // With the deduplication enabled the reserved chunks share one stored chunk and are not a reservation.
+    pub fn allocate(&mut self, fd: Fd, offset: FileSize, len: FileSize) -> Result<(), Error> {
+        let file = self.get_writable_file(fd)?;
+        self.check_policy(|policy| policy.before_write(file.node, offset, len))?;
+        self.preserve_snapshots(file.node, offset..offset.saturating_add(len))?;
+        self.invalidate_hash(file.node);
+        file.allocate(offset, len, self.storage.as_mut())?;
+        self.file_written(file.node);
+        Ok(())
+    }
+
+    // Give the storage cache a hint about the reads of the range `offset..offset + len` of an opened file,
+    // like WASI `fd_advise`. The sequential and random advices set the read pattern of the descriptor.
+    pub fn advise(
+        &mut self,
+        fd: Fd,
+        offset: FileSize,
+        len: FileSize,
+        advice: Advice,
+    ) -> Result<(), Error> {
+        let file = self.get_file(fd)?;
+        let size = self.storage.get_metadata(file.node)?.size;
+        let end = offset.saturating_add(len).min(size);
+        let first = offset / FILE_CHUNK_SIZE as FileSize;
+        let count = end
+            .div_ceil(FILE_CHUNK_SIZE as FileSize)
+            .saturating_sub(first);
+        let first = FileChunkIndex::try_from(first).unwrap_or(FileChunkIndex::MAX);
+        let count = FileChunkIndex::try_from(count).unwrap_or(FileChunkIndex::MAX);
+
+        match advice {
+            Advice::Normal | Advice::Random => self.set_read_pattern(fd, ReadPattern::Random)?,
+            Advice::Sequential => self.set_read_pattern(fd, ReadPattern::Sequential)?,
+            Advice::WillNeed => self.storage.prefetch_filechunks(file.node, first, count),
+            Advice::DontNeed => self.storage.evict_filechunks(file.node, first, count),
+            Advice::NoReuse => {}
+        }
+        Ok(())
+    }
+
+    // Get the current file cursor position.
+    pub fn tell(&mut self, fd: Fd) -> Result<FileSize, Error> {
+        if let Some(content) = self.fd_table.get_mut(fd).and_then(FdEntry::proc_content) {
+            return Ok(content.cursor);
+        }
+        let file = self.get_file(fd)?;
+        let pos = file.tell();
+        Ok(pos)
+    }
+
+    // Close the opened file and release the corresponding file descriptor.
+    pub fn close(&mut self, fd: Fd) -> Result<(), Error> {
+        let node = self.get_node(fd)?;
+
+        self.fd_table.close(fd).ok_or(Error::NotFound)?;
+        self.persistent_fds.remove(&fd);
+        self.release_unlinked(node);
+
+        Ok(())
+    }
+
+    // Remove the data of an unlinked node once it is not opened anymore.
+    fn release_unlinked(&mut self, node: Node) {
+        if !self.fd_table.node_refcount().contains_key(&node) {
+            if let Ok(metadata) = self.storage.get_metadata(node) {
+                if metadata.link_count == 0 {
+                    rm_node_data(node, &metadata, self.storage.as_mut());
+                    self.invalidate_hash(node);
+                    #[cfg(feature = "write-stats")]
+                    self.write_stats.remove(node);
+                }
+            }
+        }
+    }
+
+    // Get the state of an open descriptor.
+    pub fn fd_view(&self, fd: Fd) -> Result<FdView, Error> {
+        self.fd_table
+            .get(fd)
+            .map(|entry| entry.view(fd))
+            .ok_or(Error::NotFound)
+    }
+
+    // Iterate over the states of all the open descriptors in the ascending order, the root descriptor included.
+    pub fn fd_views(&self) -> impl Iterator<Item = FdView> + '_ {
+        self.fd_table.iter().map(|(fd, entry)| entry.view(fd))
+    }
+
+    // Iterate over all the open file descriptors with their type, node and flags, the root descriptor included.
+    pub fn open_fds(&self) -> impl Iterator<Item = (Fd, FileType, Node, FdFlags)> + '_ {
+        self.fd_views()
+            .map(|view| (view.fd, view.file_type, view.node, view.stat.flags))
+    }
+
+    // Serve the read-only introspection files "version", "stats", "fds" and "mounts" in the directory `path`,
+    // it is created if missing. The files are not stored: their contents are generated when they are opened by
+    // `open_or_create` or read by `read_path`, and are kept by the descriptor. The descriptors are read, seeked
+    // and asked for their metadata, the other file operations fail with `InvalidFileDescriptor`.
+    // The files are not listed in the directory and the mount is not persisted.
+    pub fn mount_proc(&mut self, parent: Fd, path: &str, ctime: u64) -> Result<(), Error> {
+        let dir =
+            match self.open_or_create(parent, path, FdStat::default(), OpenFlags::DIRECTORY, 0) {
+                Err(Error::NotFound) => self.create_dir(parent, path, FdStat::default(), ctime)?,
+                result => result?,
+            };
+        let node = self.get_node(dir);
+        self.close(dir)?;
+
+        self.proc_dir = Some(node?);
+        self.proc_path = Some(format!("/{}", path.trim_start_matches('/')));
+
+        Ok(())
+    }
+
+    // Get the introspection file at `path`, if the path leads to one.
+    fn proc_file_at(&self, parent: Node, path: &str) -> Option<ProcFile> {
+        let proc_dir = self.proc_dir?;
+        let (dir_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let dir = match dir_path {
+            "" => parent,
+            dir_path => find_node(parent, dir_path, self.storage.as_ref()).ok()?,
+        };
+        if dir != proc_dir {
+            return None;
+        }
+        ProcFile::ALL
+            .into_iter()
+            .find(|proc_file| proc_file.name() == name)
+    }
+
+    // Open a descriptor of an introspection file with its contents generated now.
+    #[track_caller]
+    fn open_proc(
+        &mut self,
+        proc_file: ProcFile,
+        stat: FdStat,
+        flags: OpenFlags,
+        path: &str,
+    ) -> Result<Fd, Error> {
+        if flags.contains(OpenFlags::EXCLUSIVE) {
+            return Err(Error::FileAlreadyExists);
+        }
+        if flags.contains(OpenFlags::DIRECTORY) {
+            return Err(self.file_type_error(FileType::Directory));
+        }
+
+        let content = ProcContent::new(self.proc_text(proc_file)?);
+        let dir = self.proc_dir.ok_or(Error::NotFound)?;
+        let fd = self.fd_table.open(FdEntry::Proc { dir, content, stat })?;
+        self.fd_table.set_path(fd, path);
+        Ok(fd)
+    }
+
+    // Generate the contents of an introspection file, the tree stats are collected once until the tree changes.
+    fn proc_text(&mut self, proc_file: ProcFile) -> Result<String, Error> {
+        let text = match proc_file {
+            ProcFile::Version => version_text(self.storage.get_version()),
+            ProcFile::Stats => {
+                let stats = match self.proc_stats {
+                    Some(stats) => stats,
+                    None => {
+                        let mut stats = TreeStats::default();
+                        let root = self.storage.root_node();
+                        collect_tree_stats(root, self.storage.as_ref(), &mut stats)?;
+                        self.proc_stats = Some(stats);
+                        stats
+                    }
+                };
+                stats_text(&stats, self.open_fds().count(), &self.reusable_space())
+            }
+            ProcFile::Fds => fds_text(self.open_fds()),
+            ProcFile::Mounts => mounts_text(
+                self.proc_path.as_deref().unwrap_or_default(),
+                &self.options,
+                &self.snapshot_mounts(),
+            ),
+        };
+
+        Ok(text)
+    }
+
+    // Use a directory as a bounded cache: once the files inside it take more than `max_bytes`,
+    // writing into them evicts other files chosen by the eviction policy, the removals are checked by the policy.
+    // The files already in the directory are tracked from the start, the ones moved into it once they are opened.
+    // The policy and the sizes of the tracked files are kept in heap memory, set it again after an upgrade.
+    pub fn set_cache_policy(
+        &mut self,
+        dir: Fd,
+        max_bytes: FileSize,
+        eviction: EvictionPolicy,
+    ) -> Result<(), Error> {
+        let dir = self.get_dir(dir)?;
+        let mut files = Vec::new();
+        collect_files(dir.node, self.storage.as_ref(), &mut files)?;
+
+        self.cache_policies.insert(
+            dir.node,
+            CachePolicy {
+                max_bytes,
+                eviction,
+            },
+        );
+        self.cache_sizes.entry(dir.node).or_insert(0);
+
+        for (parent, name, metadata) in files {
+            // the files of a nested cache directory stay with it
+            if let Some(member) = self.cache_members.get(&metadata.node) {
+                if member.cache_dir != dir.node
+                    && self.cache_policies.contains_key(&member.cache_dir)
+                {
+                    continue;
+                }
+            }
+            self.add_cache_member(dir.node, parent, name, metadata.node, metadata.size);
+        }
+        Ok(())
+    }
+
+    // Stop using the directory as a bounded cache.
+    pub fn clear_cache_policy(&mut self, dir: Fd) -> Result<(), Error> {
+        let dir = self.get_dir(dir)?;
+        self.cache_policies.remove(&dir.node);
+        self.cache_sizes.remove(&dir.node);
+        self.cache_members
+            .retain(|_, member| member.cache_dir != dir.node);
+        Ok(())
+    }
+
+    // Remember the cache directory of a file opened by `path` from `parent_node`.
+    fn register_cache_member(
+        &mut self,
+        parent_node: Node,
+        path: &str,
+        node: Node,
+    ) -> Result<(), Error> {
+        if self.cache_policies.is_empty() {
+            return Ok(());
+        }
+
+        let root_node = self.storage.root_node();
+        let parent = find_existing_dir(parent_node, path, self.storage.as_ref())?;
+        let name = path
+            .rsplit('/')
+            .find(|name| !name.is_empty())
+            .unwrap_or(path);
+        let mut dir_node = parent;
+
+        loop {
+            if self.cache_policies.contains_key(&dir_node) {
+                match self.cache_members.get_mut(&node) {
+                    Some(member) if member.cache_dir == dir_node => {
+                        member.parent = parent;
+                        member.name = name.to_string();
+                    }
+                    _ => {
+                        let size = self.storage.get_metadata(node)?.size;
+                        self.add_cache_member(dir_node, parent, name.to_string(), node, size);
+                    }
+                }
+                self.touch(node);
+                return Ok(());
+            }
+            if dir_node == root_node {
+                return Ok(());
+            }
+            dir_node = get_parent_node(dir_node, self.storage.as_ref())?;
+        }
+    }
+
+    fn add_cache_member(
+        &mut self,
+        cache_dir: Node,
+        parent: Node,
+        name: String,
+        node: Node,
+        size: FileSize,
+    ) {
+        self.forget_cache_member(node);
+        self.cache_members.insert(
+            node,
+            CacheMember {
+                cache_dir,
+                parent,
+                name,
+                size,
+            },
+        );
+        *self.cache_sizes.entry(cache_dir).or_insert(0) += size;
+    }
+
+    fn forget_cache_member(&mut self, node: Node) -> Option<CacheMember> {
+        let member = self.cache_members.remove(&node)?;
+        self.last_access.remove(&node);
+        if let Some(total) = self.cache_sizes.get_mut(&member.cache_dir) {
+            *total = total.saturating_sub(member.size);
+        }
+        Some(member)
+    }
+
+    // Forget the tracked files that are no longer found by their entries, e.g. after the entries were removed.
+    fn forget_removed_cache_members(&mut self) {
+        if self.cache_members.is_empty() {
+            return;
+        }
+
+        let removed: Vec<Node> = self
+            .cache_members
+            .iter()
+            .filter(|(node, member)| {
+                find_node(member.parent, &member.name, self.storage.as_ref()).ok() != Some(**node)
+            })
+            .map(|(node, _)| *node)
+            .collect();
+
+        for node in removed {
+            self.forget_cache_member(node);
+        }
+    }
+
+    // Record an access to a file of a cache directory.
+    fn touch(&mut self, node: Node) {
+        if self.cache_members.contains_key(&node) {
+            self.access_clock += 1;
+            self.last_access.insert(node, self.access_clock);
+        }
+    }
+
+    // Update the size of the written file and evict the files from its cache directory if it exceeds its limit.
+    // A failed eviction does not fail the write, the files are evicted by the next write.
+    fn file_written(&mut self, node: Node) {
+        self.touch(node);
+
+        let Some(member) = self.cache_members.get(&node) else {
+            return;
+        };
+        let (cache_dir, old_size) = (member.cache_dir, member.size);
+        let Ok(metadata) = self.storage.get_metadata(node) else {
+            return;
+        };
+
+        if let Some(member) = self.cache_members.get_mut(&node) {
+            member.size = metadata.size;
+        }
+        if let Some(total) = self.cache_sizes.get_mut(&cache_dir) {
+            *total = total.saturating_sub(old_size) + metadata.size;
+        }
+
+        let _ = self.evict(cache_dir, node);
+    }
+
+    // Remove the files of the cache directory until it fits its limit, the `written` file is kept.
+    fn evict(&mut self, cache_dir: Node, written: Node) -> Result<(), Error> {
+        let Some(policy) = self.cache_policies.get(&cache_dir).copied() else {
+            return Ok(());
+        };
+        let over_limit =
+            |fs: &Self| fs.cache_sizes.get(&cache_dir).copied().unwrap_or(0) > policy.max_bytes;
+        if !over_limit(self) {
+            return Ok(());
+        }
+
+        let mut candidates = Vec::new();
+        for (node, member) in self.cache_members.iter() {
+            if member.cache_dir != cache_dir
+                || *node == written
+                || self.fd_table.node_refcount().contains_key(node)
+            {
+                continue;
+            }
+            let accessed = self
+                .storage
+                .get_metadata(*node)
+                .map_or(0, |m| m.times.accessed);
+            let last_access = self.last_access.get(node).copied().unwrap_or(0);
+            candidates.push(((last_access, accessed), *node));
+        }
+
+        match policy.eviction {
+            EvictionPolicy::Lru => candidates.sort(),
+        }
+
+        for (_, node) in candidates {
+            if !over_limit(self) {
+                break;
+            }
+            let Some(member) = self.cache_members.get(&node) else {
+                continue;
+            };
+            let (parent, name) = (member.parent, member.name.clone());
+
+            // the entry of the file was removed or replaced since it was tracked
+            if find_node(parent, &name, self.storage.as_ref()).ok() != Some(node) {
+                self.forget_cache_member(node);
+                continue;
+            }
+            if self
+                .check_policy(|policy| policy.before_remove(parent, &name))
+                .is_err()
+            {
+                continue;
+            }
+
+            let dir = Dir::new(parent, FdStat::default(), self.storage.as_ref())?;
+            dir.remove(
+                &name,
+                RemoveOptions::file(),
+                self.fd_table.node_refcount(),
+                false,
+                self.storage.as_mut(),
+            )?;
+            self.record_entry_change(parent, &name);
+            self.forget_cache_member(node);
+        }
+
+        Ok(())
+    }
+
+    // Close all the file descriptors except the root and the ones listed in `keep`, returns the number of closed descriptors.
+    pub fn close_all_except(&mut self, keep: &[Fd]) -> usize {
+        let to_close: Vec<Fd> = self
+            .fd_table
+            .iter()
+            .map(|(fd, _)| fd)
+            .filter(|fd| *fd != self.root_fd && !keep.contains(fd))
+            .collect();
+
+        for fd in to_close.iter() {
+            let _ = self.close(*fd);
+        }
+
+        to_close.len()
+    }
+
+    // Mark the file descriptor to be kept open or closed once an fd scope ends.
+    pub fn set_persistent(&mut self, fd: Fd, persistent: bool) -> Result<(), Error> {
+        self.get_node(fd)?;
+
+        if persistent {
+            self.persistent_fds.insert(fd);
+        } else {
+            self.persistent_fds.remove(&fd);
+        }
+
+        Ok(())
+    }
+
+    // Open or create a file, the returned handle closes the file when dropped.
+    #[track_caller]
+    pub fn open_file(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        flags: OpenFlags,
+        ctime: u64,
+    ) -> Result<OpenFile<'_>, Error> {
+        let fd = self.open_or_create(parent, path, stat, flags, ctime)?;
+
+        if let Err(err) = self.get_file(fd) {
+            let _ = self.close(fd);
+            return Err(err);
+        }
+
+        Ok(OpenFile { fs: self, fd })
+    }
+
+    // Open a directory, the returned handle closes the directory when dropped.
+    #[track_caller]
+    pub fn open_dir(&mut self, parent: Fd, path: &str) -> Result<OpenDir<'_>, Error> {
+        let fd = self.open_or_create(parent, path, FdStat::default(), OpenFlags::DIRECTORY, 0)?;
+        Ok(OpenDir { fs: self, fd })
+    }
+
+    // Get a typed handle of an opened directory, other descriptors are rejected.
+    pub fn dir_handle(&self, fd: Fd) -> Result<DirHandle, Error> {
+        self.get_dir(fd)?;
+        Ok(DirHandle(fd))
+    }
+
+    // Get the handle of the root directory.
+    pub fn root_dir(&self) -> DirHandle {
+        DirHandle(self.root_fd)
+    }
+
+    // Start a scope of an operation, once it ends all the descriptors not marked as persistent are closed.
+    pub fn fd_scope(&mut self) -> FdScope<'_> {
+        FdScope { fs: self }
+    }
+
+    // Attach the caller's request or span ID to the following operations until the returned guard is dropped,
+    // the ID is included in the recorded events. The spans can be nested, the previous ID is restored.
+    pub fn span(&mut self, span_id: u64) -> SpanScope<'_> {
+        let previous = self.span.replace(span_id);
+        SpanScope { fs: self, previous }
+    }
+
+    // Get the ID of the current span.
+    pub fn current_span(&self) -> Option<u64> {
+        self.span
+    }
+
+    // Get the stats of a file or a directory for a given file descriptor.
+    pub fn metadata(&self, fd: Fd) -> Result<FileStat, Error> {
+        if let Some(FdEntry::Proc { dir, content, .. }) = self.fd_table.get(fd) {
+            return Ok(FileStat {
+                node: *dir,
+                generation: 0,
+                file_type: FileType::RegularFile,
+                link_count: 0,
+                size: content.size(),
+                times: Times::default(),
+            });
+        }
+        let node = self.get_node(fd)?;
+        let metadata = self.storage.get_metadata(node)?;
+        Ok(FileStat::from(&metadata))
+    }
+
+    // Get the stats of a file or a directory for a given node.
+    pub fn metadata_from_node(&self, node: Node) -> Result<FileStat, Error> {
+        let metadata = self.storage.get_metadata(node)?;
+        Ok(FileStat::from(&metadata))
+    }
+
+    // Get a handle of the node opened as `fd` to be kept by the caller, see `open_handle`.
+    pub fn file_handle(&self, fd: Fd) -> Result<FileHandle, Error> {
+        let node = self.get_node(fd)?;
+        let metadata = self.storage.get_metadata(node)?;
+        Ok(FileHandle {
+            node,
+            generation: metadata.generation,
+        })
+    }
+
+    // Open the node referenced by a handle, the handle of a removed node fails with `StaleFileHandle`.
+    #[track_caller]
+    pub fn open_handle(
+        &mut self,
+        handle: FileHandle,
+        stat: FdStat,
+        flags: OpenFlags,
+    ) -> Result<Fd, Error> {
+        match self.storage.get_metadata(handle.node) {
+            Ok(metadata) if metadata.generation == handle.generation => {}
+            Ok(_) | Err(Error::NotFound) => return Err(Error::StaleFileHandle),
+            Err(err) => return Err(err),
+        }
+        self.open(handle.node, stat, flags)
+    }
+
+    // Get the internal metadata records of several nodes in one batch, in the order of `nodes`.
+    pub fn metadata_many(&self, nodes: &[Node]) -> Vec<Result<Metadata, Error>> {
+        self.storage.get_metadata_many(nodes)
+    }
+
+    // Get the internal metadata record for a given file descriptor.
+    pub fn raw_metadata(&self, fd: Fd) -> Result<Metadata, Error> {
+        let node = self.get_node(fd)?;
+        self.storage.get_metadata(node)
+    }
+
+    // update metadata of a given file descriptor
+    pub fn set_metadata(&mut self, fd: Fd, metadata: Metadata) -> Result<(), Error> {
+        let node = self.get_node(fd)?;
+        self.read_only_guard().check(node)?;
+        self.preserve_snapshots(node, 0..FileSize::MAX)?;
+        self.storage.put_metadata(node, metadata);
+        self.invalidate_hash(node);
+
+        Ok(())
+    }
+
+    // Get the HTTP metadata of a file or a directory.
+    pub fn http_metadata(&self, fd: Fd) -> Result<Option<HttpMetadata>, Error> {
+        let node = self.get_node(fd)?;
+        Ok(self.storage.get_metadata(node)?.http)
+    }
+
+    // Set the HTTP metadata of a file or a directory, `None` removes it.
+    pub fn set_http_metadata(&mut self, fd: Fd, http: Option<HttpMetadata>) -> Result<(), Error> {
+        self.update_metadata(fd, |metadata| metadata.http = http)
+    }
+
+    // Get the memory keeping the contents of a file.
+    pub fn storage_class(&self, fd: Fd) -> Result<StorageClass, Error> {
+        let node = self.get_node(fd)?;
+        Ok(self.storage.get_metadata(node)?.storage_class)
+    }
+
+    // Keep the contents of a file in stable or in heap memory, the files created later in a directory take its class.
+    // The contents are only kept apart by a storage supporting it, like `TieredStorage`.
+    pub fn set_storage_class(&mut self, fd: Fd, class: StorageClass) -> Result<(), Error> {
+        let node = self.get_node(fd)?;
+        self.read_only_guard().check(node)?;
+        self.storage.set_storage_class(node, class)
+    }
+
+    // Update access time.
+    pub fn set_accessed_time(&mut self, fd: Fd, time: u64) -> Result<(), Error> {
+        self.update_metadata(fd, |metadata| metadata.times.accessed = time)
+    }
+
+    // Update modification time.
+    pub fn set_modified_time(&mut self, fd: Fd, time: u64) -> Result<(), Error> {
+        self.update_metadata(fd, |metadata| metadata.times.modified = time)
+    }
+
+    // Read, modify and store the metadata of a node in one step.
+    // The identity, the link count and the directory structure fields cannot be changed this way, the update is
+    // rejected if they are. A new file size is applied like `set_size`, the chunks past a shorter size are removed.
+    pub fn update_metadata(
+        &mut self,
+        fd: Fd,
+        update: impl FnOnce(&mut Metadata),
+    ) -> Result<(), Error> {
+        let node = self.get_node(fd)?;
+        self.read_only_guard().check(node)?;
+        let old = self.storage.get_metadata(node)?;
+
+        let mut metadata = old.clone();
+        update(&mut metadata);
+
+        let structure_changed = metadata.node != old.node
+            || metadata.file_type != old.file_type
+            || metadata.link_count != old.link_count
+            || metadata.generation != old.generation
+            || metadata.parent != old.parent
+            || metadata.first_dir_entry != old.first_dir_entry
+            || metadata.last_dir_entry != old.last_dir_entry
+            || metadata.next_dir_entry != old.next_dir_entry
+            || metadata.dir_generation != old.dir_generation
+            || metadata.storage_class != old.storage_class
+            || (old.file_type == FileType::Directory && metadata.size != old.size);
+
+        if structure_changed {
+            return Err(Error::InvalidMetadata);
+        }
+        if metadata.size > MAX_FILE_SIZE {
+            return Err(Error::FileTooLarge);
+        }
+
+        if metadata.size != old.size {
+            self.preserve_snapshots(node, metadata.size.min(old.size)..FileSize::MAX)?;
+            self.invalidate_hash(node);
+            let file = File::new(node, FdStat::default(), self.storage.as_ref())?;
+            file.set_size(metadata.size, self.storage.as_mut())?;
+        }
+
+        self.storage.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
+    // Set the time after which the file is removed by `expire_now`, `None` keeps the file until it is removed.
+    pub fn set_expiry(&mut self, fd: Fd, expires_at: Option<u64>) -> Result<(), Error> {
+        self.get_file(fd)?;
+        self.update_metadata(fd, |metadata| metadata.expires_at = expires_at)
+    }
+
+    // Get the expiry time of a file.
+    pub fn expiry(&self, fd: Fd) -> Result<Option<u64>, Error> {
+        let node = self.get_node(fd)?;
+        Ok(self.storage.get_metadata(node)?.expires_at)
+    }
+
+    // Remove the files expired by the time of the clock of the mount options, like `expire_now`.
+    // Fails with `InvalidMountOptions` if the file system was mounted without a clock.
+    pub fn expire(&mut self) -> Result<usize, Error> {
+        let now = self.now().ok_or(Error::InvalidMountOptions)?;
+        self.expire_now(now)
+    }
+
+    // Remove all the files that expire at `now` or earlier, returns the number of removed entries.
+    // The opened files and the files whose removal the policy rejects are kept for a later sweep.
+    pub fn expire_now(&mut self, now: u64) -> Result<usize, Error> {
+        let mut removed = 0;
+
+        for path in self.expired_paths(now)? {
+            if self.remove_expired(&path)? {
+                removed += 1;
+            }
+        }
+
+        self.forget_removed_cache_members();
+        Ok(removed)
+    }
+
+    // Remove the expired files like `expire_now`, stopping between the removals before the instruction budget
+    // of the mount options runs out. The next call continues with the files left.
+    pub fn expire_now_budgeted(&mut self, now: u64) -> Result<Budgeted<usize>, Error> {
+        let mut guard = BudgetGuard::new(self.options.instruction_budget);
+        let mut removed = 0;
+
+        for path in self.expired_paths(now)? {
+            if !guard.allows(removal_cost()) {
+                self.forget_removed_cache_members();
+                return Ok(Budgeted::Partial {
+                    continuation: Continuation {
+                        done: removed as u64,
+                    },
+                });
+            }
+            if self.remove_expired(&path)? {
+                guard.charge(removal_cost());
+                removed += 1;
+            }
+        }
+
+        self.forget_removed_cache_members();
+        Ok(Budgeted::Complete(removed))
+    }
+
+    fn expired_paths(&self, now: u64) -> Result<Vec<String>, Error> {
+        self.read_only_guard().check_writable()?;
+        let mut expired = Vec::new();
+        collect_expired(
+            self.storage.root_node(),
+            "",
+            now,
+            self.storage.as_ref(),
+            &mut expired,
+        )?;
+        Ok(expired)
+    }
+
+    // Remove an expired file, the opened files and the files the policy keeps are left until a later sweep.
+    fn remove_expired(&mut self, path: &str) -> Result<bool, Error> {
+        let root = self.storage.root_node();
+        if self
+            .check_policy(|policy| policy.before_remove(root, path))
+            .is_err()
+        {
+            return Ok(false);
+        }
+
+        let dir = Dir::new(root, FdStat::default(), self.storage.as_ref())?;
+        match dir.remove(
+            path,
+            RemoveOptions::file(),
+            self.fd_table.node_refcount(),
+            false,
+            self.storage.as_mut(),
+        ) {
+            Ok(()) => {
+                self.record_entry_change(root, path);
+                Ok(true)
+            }
+            Err(Error::CannotRemoveOpenedNode) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Report the files `expire_now` would remove, nothing is changed.
+    pub fn expire_now_dry_run(&self, now: u64) -> Result<RemovalReport, Error> {
+        let mut expired = Vec::new();
+        collect_expired(
+            self.storage.root_node(),
+            "",
+            now,
+            self.storage.as_ref(),
+            &mut expired,
+        )?;
+
+        let root = Dir::new(
+            self.storage.root_node(),
+            FdStat::default(),
+            self.storage.as_ref(),
+        )?;
+        let mut report = RemovalReport::default();
+
+        for path in expired {
+            match root.remove_dry_run(
+                &path,
+                RemoveOptions::file(),
+                self.fd_table.node_refcount(),
+                false,
+                self.storage.as_ref(),
+            ) {
+                Ok(removed) => {
+                    report.paths.extend(removed.paths);
+                    report.files += removed.files;
+                    report.bytes += removed.bytes;
+                }
+                Err(Error::CannotRemoveOpenedNode) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(report)
+    }
+
+    // Get file or directory stats in the form of the WASI `fd_fdstat_get` result.
+    pub fn get_stat(&self, fd: Fd) -> Result<WasiFdStat, Error> {
+        match self.fd_table.get(fd) {
+            None => Err(Error::NotFound),
+            Some(entry) => Ok(WasiFdStat::new(entry.file_type(), entry.stat())),
+        }
+    }
+
+    // Update stats of a given file, the access of the descriptor can be narrowed but not widened.
+    pub fn set_stat(&mut self, fd: Fd, stat: FdStat) -> Result<(), Error> {
+        match self.fd_table.get_mut(fd) {
+            Some(entry) => {
+                if !stat.access.within(entry.stat().access) {
+                    return Err(Error::AccessDenied);
+                }
+                entry.set_stat(stat);
+                Ok(())
+            }
+            None => Err(Error::NotFound),
+        }
+    }
+
+    // Get stats of a file with name `path` in a given folder.
+    pub fn open_metadata(&self, parent: Fd, path: &str) -> Result<FileStat, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        let node = find_node(dir.node, path, self.storage.as_ref())?;
+        self.metadata_from_node(node)
+    }
+
+    // Opens of creates a new file.
+    #[track_caller]
+    pub fn open_or_create(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        flags: OpenFlags,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+
+        if flags.contains(OpenFlags::NOFOLLOW_ANY) {
+            check_no_symlinks(dir.node, path, self.storage.as_ref())?;
+        }
+        if let Some(proc_file) = self.proc_file_at(dir.node, path) {
+            return self.open_proc(proc_file, stat, flags, path);
+        }
+
+        match find_node(dir.node, path, self.storage.as_ref()) {
+            Ok(node) => {
+                let fd = self.open(node, stat, flags)?;
+                self.fd_table.set_path(fd, path);
+                self.register_cache_member(dir.node, path, node)?;
+                Ok(fd)
+            }
+            Err(Error::NotFound) => {
+                if !flags.contains(OpenFlags::CREATE) {
+                    return Err(Error::NotFound);
+                }
+                if flags.contains(OpenFlags::DIRECTORY) {
+                    return Err(Error::InvalidFileType);
+                }
+                self.create_file(parent, path, stat, ctime)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Opens a file and return its new file descriptor.
+    #[track_caller]
+    pub fn open(&mut self, node: Node, stat: FdStat, flags: OpenFlags) -> Result<Fd, Error> {
+        if flags.contains(OpenFlags::EXCLUSIVE) {
+            return Err(Error::FileAlreadyExists);
+        }
+        let metadata = self.storage.get_metadata(node)?;
+        match metadata.file_type {
+            FileType::Directory => {
+                let dir = Dir::new(node, stat, self.storage.as_mut())?;
+                if let Some(count) = self.options.dir_prefetch {
+                    self.storage.prefetch_direntries(node, count);
+                }
+                let fd = self.fd_table.open(dir.into())?;
+                Ok(fd)
+            }
+            FileType::RegularFile => {
+                if flags.contains(OpenFlags::DIRECTORY) {
+                    return Err(self.file_type_error(FileType::Directory));
+                }
+                let file = File::new(node, stat, self.storage.as_mut())?;
+                if flags.contains(OpenFlags::TRUNCATE) {
+                    if !stat.access.can_write() {
+                        return Err(Error::AccessDenied);
+                    }
+                    self.read_only_guard().check(node)?;
+                    self.preserve_snapshots(node, 0..FileSize::MAX)?;
+                    file.truncate(self.storage.as_mut())?;
+                    self.invalidate_hash(node);
+                }
+                let fd = self.fd_table.open(file.into())?;
+                Ok(fd)
+            }
+            // aliases are resolved by path, they cannot be opened
+            FileType::SymbolicLink => Err(Error::InvalidFileType),
+        }
+    }
+
+    // Create a new file named `path` in the given `parent` folder, or open the existing file with that name
+    // as chosen by `on_collision`. Only a regular file is opened, any other existing node is an error.
+    #[track_caller]
+    pub fn create_file_with(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        on_collision: CollisionPolicy,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        let flags = match on_collision {
+            CollisionPolicy::Error => return self.create_file(parent, path, stat, ctime),
+            CollisionPolicy::Truncate => OpenFlags::TRUNCATE,
+            CollisionPolicy::OpenExisting => OpenFlags::empty(),
+        };
+
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        match find_node(dir.node, path, self.storage.as_ref()) {
+            Ok(node) => {
+                if self.storage.get_metadata(node)?.file_type != FileType::RegularFile {
+                    return Err(Error::FileAlreadyExists);
+                }
+                let fd = self.open(node, stat, flags)?;
+                self.fd_table.set_path(fd, path);
+                self.register_cache_member(dir.node, path, node)?;
+                Ok(fd)
+            }
+            Err(Error::NotFound) => self.create_file(parent, path, stat, ctime),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Create a new file named `path` in the given `parent` folder.
+    #[track_caller]
+    pub fn create_file(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        self.check_dir_capacity(dir.node, path)?;
+        self.check_policy(|policy| policy.before_create(dir.node, path, FileType::RegularFile))?;
+
+        let child = dir.create_file(path, stat, self.storage.as_mut(), ctime)?;
+        self.record_entry_change(dir.node, path);
+        self.register_cache_member(dir.node, path, child.node)?;
+
+        let child_fd = self.fd_table.open(child.into())?;
+        self.fd_table.set_path(child_fd, path);
+        self.put_dir(parent, dir);
+        Ok(child_fd)
+    }
+
+    // Create a region file of a fixed `size` with all its chunks stored, so that the writes into it never need
+    // new memory. The offsets of a region map directly to its chunks, it is not extended by the writes past
+    // its end and cannot be resized. The created file is removed if its chunks cannot be stored.
+    pub fn create_region(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        size: FileSize,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        let fd = self.create_file(parent, path, stat, ctime)?;
+        let file = self.get_file(fd)?;
+
+        if let Err(err) = file.allocate(0, size, self.storage.as_mut()) {
+            self.close(fd)?;
+            self.remove(parent, path, RemoveOptions::file())?;
+            return Err(err);
+        }
+
+        let mut metadata = self.storage.get_metadata(file.node)?;
+        metadata.region = true;
+        self.storage.put_metadata(file.node, metadata);
+        self.file_written(file.node);
+        Ok(fd)
+    }
+
+    // Get the size of a region file, `None` for the other files.
+    pub fn region_size(&self, fd: Fd) -> Result<Option<FileSize>, Error> {
+        let file = self.get_file(fd)?;
+        let metadata = self.storage.get_metadata(file.node)?;
+        Ok(metadata.region.then_some(metadata.size))
+    }
+
+    // Create a file without a name, like `O_TMPFILE`, to be filled and then linked into place with `link_fd`.
+    // The `parent` folder is only checked to be a directory. The file data is removed when the returned
+    // descriptor is closed without linking the file, it is left unreachable in the storage by an upgrade.
+    #[track_caller]
+    pub fn create_unnamed(&mut self, parent: Fd, stat: FdStat, ctime: u64) -> Result<Fd, Error> {
+        self.get_dir(parent)?;
+        self.read_only_guard().check_writable()?;
+
+        let node = create_node(None, FileType::RegularFile, 0, self.storage.as_mut(), ctime);
+        // the node is reclaimed on the next mount unless it is linked before
+        self.storage.add_orphan(node);
+        let file = File::new(node, stat, self.storage.as_ref())?;
+        self.fd_table.open(file.into())
+    }
+
+    // Link the file opened as `fd` under `path` in the `parent` folder, the missing folders on the path are created.
+    // An unnamed file becomes a regular one, a named file gets one more hard link.
+    pub fn link_fd(&mut self, fd: Fd, parent: Fd, path: &str) -> Result<(), Error> {
+        self.link_file(fd, parent, path, false)
+    }
+
+    // Link an opened file like `link_fd`, an existing file at `path` is replaced in the same step if `replace` is set.
+    fn link_file(&mut self, fd: Fd, parent: Fd, path: &str, replace: bool) -> Result<(), Error> {
+        let file = self.get_file(fd)?;
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        self.check_policy(|policy| policy.before_create(dir.node, path, FileType::RegularFile))?;
+
+        let replaced = match find_node(dir.node, path, self.storage.as_ref()) {
+            Err(Error::NotFound) => None,
+            Ok(node) if replace => Some(node),
+            Ok(_) => return Err(Error::FileAlreadyExists),
+            Err(err) => return Err(err),
+        };
+        match replaced {
+            Some(node) => {
+                if self.storage.get_metadata(node)?.file_type == FileType::Directory {
+                    return Err(Error::IsDirectory);
+                }
+                self.read_only_guard().before_remove(dir.node, path)?;
+                self.check_policy(|policy| policy.before_remove(dir.node, path))?;
+                dir.remove_dry_run(
+                    path,
+                    RemoveOptions::file(),
+                    self.fd_table.node_refcount(),
+                    self.options.strict_posix,
+                    self.storage.as_ref(),
+                )?;
+            }
+            None => self.check_dir_capacity(dir.node, path)?,
+        }
+
+        // everything was checked, the replaced file is removed right before the new link is added
+        if replaced.is_some() {
+            dir.remove(
+                path,
+                RemoveOptions::file(),
+                self.fd_table.node_refcount(),
+                self.options.strict_posix,
+                self.storage.as_mut(),
+            )?;
+            self.forget_removed_cache_members();
+        }
+
+        let mut metadata = self.storage.get_metadata(file.node)?;
+        let (dir_node, leaf_name) = create_path(
+            dir.node,
+            path,
+            None,
+            metadata.times.created,
+            self.storage.as_mut(),
+        )?;
+        add_dir_entry(
+            dir_node,
+            file.node,
+            leaf_name.as_bytes(),
+            self.storage.as_mut(),
+        )?;
+
+        metadata.link_count += 1;
+        if metadata.link_count == 1 {
+            self.storage.remove_orphan(file.node);
+        }
+        self.storage.put_metadata(file.node, metadata);
+        self.record_entry_change(dir.node, path);
+        self.register_cache_member(dir.node, path, file.node)?;
+        self.fd_table.set_path(fd, path);
+        Ok(())
+    }
+
+    // Delete a file by name `path` in the given file folder.
+    pub fn remove_file(&mut self, parent: Fd, path: &str) -> Result<(), Error> {
+        self.remove(parent, path, RemoveOptions::file())
+    }
+
+    // Create a new directory named `path` in the given `parent` folder.
+    #[track_caller]
+    pub fn create_dir(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        self.check_dir_capacity(dir.node, path)?;
+        self.check_policy(|policy| policy.before_create(dir.node, path, FileType::Directory))?;
+        let child = dir.create_dir(path, stat, self.storage.as_mut(), ctime)?;
+        self.record_entry_change(dir.node, path);
+        let child_fd = self.fd_table.open(child.into())?;
+        self.fd_table.set_path(child_fd, path);
+        self.put_dir(parent, dir);
+        Ok(child_fd)
+    }
+
+    // Create the directory `path` in the `parent` folder together with its missing parent directories, like
+    // `mkdir -p`, and open it. An existing directory is opened, an existing file fails with `FileAlreadyExists`.
+    #[track_caller]
+    pub fn create_dir_all(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+
+        match find_node(dir.node, path, self.storage.as_ref()) {
+            Ok(node) => {
+                if self.storage.get_metadata(node)?.file_type != FileType::Directory {
+                    return Err(Error::FileAlreadyExists);
+                }
+                let fd = self.open(node, stat, OpenFlags::DIRECTORY)?;
+                self.fd_table.set_path(fd, path);
+                Ok(fd)
+            }
+            Err(Error::NotFound) => self.create_dir(parent, path, stat, ctime),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Create an alias `path` in the `parent` folder pointing to the `target` path, e.g. "index" -> "/index.html".
+    pub fn create_alias(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        target: &str,
+        ctime: u64,
+    ) -> Result<(), Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        self.check_path_depth(target)?;
+        let target = &self.options.normalize_path(target);
+        self.check_dir_capacity(dir.node, path)?;
+        self.check_policy(|policy| policy.before_create(dir.node, path, FileType::SymbolicLink))?;
+        dir.create_alias(path, target, self.storage.as_mut(), ctime)?;
+        self.record_entry_change(dir.node, path);
+        self.put_dir(parent, dir);
+        Ok(())
+    }
+
+    // Get the target of the alias `path` in the `parent` folder.
+    pub fn read_alias(&self, parent: Fd, path: &str) -> Result<String, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        let node = find_node(dir.node, path, self.storage.as_ref())?;
+        read_alias_target(node, self.storage.as_ref())
+    }
+
+    // Follow the aliases from `path` and return the path of the file or the directory they lead to.
+    // Alias targets are resolved relative to the `parent` folder, a path that is not an alias is returned unchanged.
+    pub fn resolve_alias(&self, parent: Fd, path: &str) -> Result<String, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.user_path(dir.node, path)?;
+        let max_hops = self.options.max_alias_hops.unwrap_or(MAX_ALIAS_HOPS);
+
+        if self.options.sandbox != SandboxMode::Off {
+            let boundary = self.sandbox_boundary.unwrap_or(self.storage.root_node());
+            if let Some(alias) =
+                find_escaping_alias(dir.node, path, boundary, max_hops, self.storage.as_ref())?
+            {
+                self.audit_path(dir.node, &alias, PathViolation::AliasEscape)?;
+            }
+        }
+        resolve_alias(dir.node, path, max_hops, self.storage.as_ref())
+    }
+
+    // Delete a directory by name `path` in the given file folder.
+    pub fn remove_dir(&mut self, parent: Fd, path: &str) -> Result<(), Error> {
+        self.remove(parent, path, RemoveOptions::dir())
+    }
+
+    // Delete a directory by name `path` in the given file folder together with all its contents,
+    // the chunks and the metadata of the removed nodes are released unless they have other links.
+    pub fn remove_dir_all(&mut self, parent: Fd, path: &str) -> Result<(), Error> {
+        self.remove(parent, path, RemoveOptions::dir_all())
+    }
+
+    // Delete a file or a directory by name `path` in the given file folder.
+    // With `follow_symlinks` an alias at `path` is resolved and the node it leads to is removed, the alias is kept.
+    pub fn remove(&mut self, parent: Fd, path: &str, options: RemoveOptions) -> Result<(), Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.removed_path(parent, dir.node, path, options)?;
+        self.check_policy(|policy| policy.before_remove(dir.node, path))?;
+
+        if let Some(zeroing) = options.zeroing {
+            self.storage.set_chunk_zeroing(zeroing);
+        }
+        let result = dir.remove(
+            path,
+            options,
+            self.fd_table.node_refcount(),
+            self.options.strict_posix,
+            self.storage.as_mut(),
+        );
+        if options.zeroing.is_some() {
+            self.storage.set_chunk_zeroing(self.options.chunk_zeroing);
+        }
+        self.record_entry_change(dir.node, path);
+        self.forget_removed_cache_members();
+        result
+    }
+
+    // The path of the entry removed with the options, the target of an alias when the aliases are followed.
+    fn removed_path<'a>(
+        &self,
+        parent: Fd,
+        dir_node: Node,
+        path: &'a str,
+        options: RemoveOptions,
+    ) -> Result<Cow<'a, str>, Error> {
+        if options.follow_symlinks {
+            self.resolve_alias(parent, path).map(Cow::Owned)
+        } else {
+            self.user_path(dir_node, path)
+        }
+    }
+
+    // Overwrite with zeros at most `max` of the chunks freed with `ChunkZeroing::Deferred`, e.g. in a timer,
+    // returns the number of the chunks zeroed.
+    pub fn zero_freed_chunks(&mut self, max: usize) -> usize {
+        self.storage.zero_freed_chunks(max)
+    }
+
+    // Check the removal of `path` like `remove` does and report the nodes it would remove, nothing is changed.
+    pub fn remove_dry_run(
+        &self,
+        parent: Fd,
+        path: &str,
+        options: RemoveOptions,
+    ) -> Result<RemovalReport, Error> {
+        let dir = self.get_dir(parent)?;
+        let path = &self.removed_path(parent, dir.node, path, options)?;
+        dir.remove_dry_run(
+            path,
+            options,
+            self.fd_table.node_refcount(),
+            self.options.strict_posix,
+            self.storage.as_ref(),
+        )
+    }
+
+    // Create a hard link to an existing file.
+    #[track_caller]
+    pub fn create_hard_link(
+        &mut self,
+        old_fd: Fd,
+        old_path: &str,
+        new_fd: Fd,
+        new_path: &str,
+    ) -> Result<Fd, Error> {
+        let src_dir = self.get_dir(old_fd)?;
+        let dst_dir = self.get_dir(new_fd)?;
+        let old_path = &self.user_path(src_dir.node, old_path)?;
+        let new_path = &self.user_path(dst_dir.node, new_path)?;
+        self.check_dir_capacity(dst_dir.node, new_path)?;
+
+        let file_type = self
+            .storage
+            .get_metadata(find_node(src_dir.node, old_path, self.storage.as_ref())?)?
+            .file_type;
+        self.check_policy(|policy| policy.before_create(dst_dir.node, new_path, file_type))?;
+
+        create_hard_link(
+            dst_dir.node,
+            new_path,
+            src_dir.node,
+            old_path,
+            false,
+            self.storage.as_mut(),
+        )?;
+        self.record_entry_change(dst_dir.node, new_path);
+
+        let node = find_node(dst_dir.node, new_path, self.storage.as_ref())?;
+
+        let fd = self.open(node, FdStat::default(), OpenFlags::empty())?;
+        self.fd_table.set_path(fd, new_path);
+        Ok(fd)
+    }
+
+    // Rename a file. In the strict POSIX mode an existing destination is replaced, otherwise it is kept
+    // and the renaming fails with `FileAlreadyExists`.
+    #[track_caller]
+    pub fn rename(
+        &mut self,
+        old_fd: Fd,
+        old_path: &str,
+        new_fd: Fd,
+        new_path: &str,
+    ) -> Result<Fd, Error> {
+        let flags = if self.options.strict_posix {
+            RenameFlags::empty()
+        } else {
+            RenameFlags::NOREPLACE
+        };
+        self.rename_with_flags(old_fd, old_path, new_fd, new_path, flags)
+    }
+
+    // Rename a file or a directory, the flags choose what happens to an existing destination:
+    // it is replaced by default, kept with `NOREPLACE` or exchanged with the source with `EXCHANGE`.
+    // A replaced directory must be empty. Returns a descriptor of the node now at `new_path`.
+    #[track_caller]
+    pub fn rename_with_flags(
+        &mut self,
+        old_fd: Fd,
+        old_path: &str,
+        new_fd: Fd,
+        new_path: &str,
+        flags: RenameFlags,
+    ) -> Result<Fd, Error> {
+        if flags.contains(RenameFlags::NOREPLACE | RenameFlags::EXCHANGE) {
+            return Err(Error::InvalidRenameFlags);
+        }
+
+        let src_dir = self.get_dir(old_fd)?;
+        let dst_dir = self.get_dir(new_fd)?;
+        let old_path = &self.user_path(src_dir.node, old_path)?;
+        let new_path = &self.user_path(dst_dir.node, new_path)?;
+
+        let src_node = find_node(src_dir.node, old_path, self.storage.as_ref())?;
+        let file_type = self.storage.get_metadata(src_node)?.file_type;
+        self.read_only_guard()
+            .before_remove(src_dir.node, old_path)?;
+
+        if flags.contains(RenameFlags::EXCHANGE) {
+            let (_, node) = self.exchange(src_dir.node, old_path, dst_dir.node, new_path, true)?;
+            let fd = self.open(node, FdStat::default(), OpenFlags::empty())?;
+            self.fd_table.set_path(fd, new_path);
+            return Ok(fd);
+        }
+
+        self.check_policy(|policy| policy.before_create(dst_dir.node, new_path, file_type))?;
+
+        let replaced = match find_node(dst_dir.node, new_path, self.storage.as_ref()) {
+            Ok(node) if !flags.contains(RenameFlags::NOREPLACE) => Some(node),
+            Ok(_) => return Err(Error::FileAlreadyExists),
+            Err(Error::NotFound) => None,
+            Err(err) => return Err(err),
+        };
+
+        let src_parent = find_existing_dir(src_dir.node, old_path, self.storage.as_ref())?;
+        let dst_parent = find_existing_dir(dst_dir.node, new_path, self.storage.as_ref())?;
+
+        match replaced {
+            // renaming a file onto its own hard link leaves both names
+            Some(node) if node == src_node => {
+                let fd = self.open(node, FdStat::default(), OpenFlags::empty())?;
+                self.fd_table.set_path(fd, new_path);
+                return Ok(fd);
+            }
+            Some(node) => {
+                let is_dir = file_type == FileType::Directory;
+                let dst_is_dir = self.storage.get_metadata(node)?.file_type == FileType::Directory;
+                match (is_dir, dst_is_dir) {
+                    (false, true) => return Err(Error::IsDirectory),
+                    (true, false) => return Err(Error::NotADirectory),
+                    _ => {}
+                }
+                // the checks of the new link are done before the destination is removed
+                if is_dir && is_ancestor_or_self(src_node, dst_parent, self.storage.as_ref())? {
+                    return Err(Error::CannotMoveDirectoryIntoItself);
+                }
+                self.check_policy(|policy| policy.before_remove(dst_dir.node, new_path))?;
+
+                let options = RemoveOptions {
+                    must_be_dir: Some(is_dir),
+                    must_be_empty: true,
+                    recursive: false,
+                    follow_symlinks: false,
+                    zeroing: None,
+                };
+                dst_dir.remove(
+                    new_path,
+                    options,
+                    self.fd_table.node_refcount(),
+                    self.options.strict_posix,
+                    self.storage.as_mut(),
+                )?;
+            }
+            // renaming within a directory keeps the number of its entries
+            None if src_parent != dst_parent => self.check_dir_capacity(dst_dir.node, new_path)?,
+            None => {}
+        }
+
+        // create a new link
+        create_hard_link(
+            dst_dir.node,
+            new_path,
+            src_dir.node,
+            old_path,
+            true,
+            self.storage.as_mut(),
+        )?;
+
+        // now unlink the older version
+        let (node, _metadata) = rm_dir_entry(
+            src_dir.node,
+            old_path,
+            None,
+            self.fd_table.node_refcount(),
+            self.storage.as_mut(),
+        )?;
+        self.record_entry_change(dst_dir.node, new_path);
+        self.record_entry_change(src_dir.node, old_path);
+
+        // the file follows the cache policy of its new folder
+        self.forget_removed_cache_members();
+        self.register_cache_member(dst_dir.node, new_path, node)?;
+
+        let fd = self.open(node, FdStat::default(), OpenFlags::empty())?;
+        self.fd_table.set_path(fd, new_path);
+        Ok(fd)
+    }
+
+    // Exchange the files at `path_a` and `path_b` in one step, so that the readers of both paths
+    // always find one complete version or the other. The opened descriptors keep their files.
+    pub fn swap(
+        &mut self,
+        parent_a: Fd,
+        path_a: &str,
+        parent_b: Fd,
+        path_b: &str,
+    ) -> Result<(), Error> {
+        let dir_a = self.get_dir(parent_a)?;
+        let dir_b = self.get_dir(parent_b)?;
+        let path_a = &self.user_path(dir_a.node, path_a)?;
+        let path_b = &self.user_path(dir_b.node, path_b)?;
+
+        self.exchange(dir_a.node, path_a, dir_b.node, path_b, false)?;
+        Ok(())
+    }
+
+    // Exchange two directory entries, the folders are only exchanged as a part of renaming.
+    // Returns the nodes now at `path_a` and `path_b`.
+    fn exchange(
+        &mut self,
+        dir_a: Node,
+        path_a: &str,
+        dir_b: Node,
+        path_b: &str,
+        is_renaming: bool,
+    ) -> Result<(Node, Node), Error> {
+        let mut guard = self.read_only_guard();
+        guard.before_remove(dir_a, path_a)?;
+        guard.before_remove(dir_b, path_b)?;
+
+        // both entries are replaced like by a rename: the policy sees each node created at the other path
+        // and each of the replaced nodes removed
+        let node_a = find_node(dir_a, path_a, self.storage.as_ref())?;
+        let node_b = find_node(dir_b, path_b, self.storage.as_ref())?;
+        let type_a = self.storage.get_metadata(node_a)?.file_type;
+        let type_b = self.storage.get_metadata(node_b)?.file_type;
+        self.check_policy(|policy| policy.before_create(dir_b, path_b, type_a))?;
+        self.check_policy(|policy| policy.before_create(dir_a, path_a, type_b))?;
+        self.check_policy(|policy| policy.before_remove(dir_b, path_b))?;
+        self.check_policy(|policy| policy.before_remove(dir_a, path_a))?;
+
+        let (node_a, node_b) = swap_dir_entries(
+            dir_a,
+            path_a,
+            dir_b,
+            path_b,
+            is_renaming,
+            self.storage.as_mut(),
+        )?;
+
+        // the files follow the cache policies of their new folders
+        for (dir, path, node) in [(dir_a, path_a, node_a), (dir_b, path_b, node_b)] {
+            self.record_entry_change(dir, path);
+            self.forget_cache_member(node);
+            self.register_cache_member(dir, path, node)?;
+        }
+
+        Ok((node_a, node_b))
+    }
+
+    // Get an opened file to write into, the introspection files are read-only.
+    fn get_writable_file(&self, fd: Fd) -> Result<File, Error> {
+        let file = self.get_file(fd)?;
+
+        if !file.stat.access.can_write() {
+            return Err(Error::AccessDenied);
+        }
+
+        Ok(file)
+    }
+
+    // Get an opened file to read from, the files opened only for writing cannot be read.
+    fn get_readable_file(&self, fd: Fd) -> Result<File, Error> {
+        let file = self.get_file(fd)?;
+
+        if !file.stat.access.can_read() {
+            return Err(Error::AccessDenied);
+        }
+
+        Ok(file)
+    }
+
+    // Get an opened file to write into at its cursor, the cursor of a file opened for appending
+    // is moved to the end of file first.
+    fn get_cursor_file(&self, fd: Fd) -> Result<File, Error> {
+        let mut file = self.get_writable_file(fd)?;
+
+        if file.stat.flags.contains(FdFlags::APPEND) {
+            file.cursor = self.storage.get_metadata(file.node)?.size;
+        }
+
+        Ok(file)
+    }
+
+    // Audit a path given by the user and apply the name normalizer to it.
+    fn user_path<'a>(&self, dir_node: Node, path: &'a str) -> Result<Cow<'a, str>, Error> {
+        self.check_path_depth(path)?;
+
+        if self.options.sandbox != SandboxMode::Off {
+            let parts = components(path);
+            if parts.is_absolute() {
+                self.audit_path(dir_node, path, PathViolation::AbsolutePath)?;
+            }
+            // the sandboxed paths are resolved strictly, their ".." elements are recorded but never followed
+            if parts.into_iter().any(|part| part == Component::ParentDir) {
+                self.audit_path(dir_node, path, PathViolation::ParentReference)?;
+                return Err(Error::InvalidFileName);
+            }
+        }
+
+        let path = self.options.normalize_path(path);
+
+        // the directories the path walks through are checked by their ancestry, not by the path text
+        if self.options.sandbox != SandboxMode::Off {
+            if let Some(boundary) = self.sandbox_boundary {
+                if leaves_boundary(dir_node, &path, boundary, self.storage.as_ref())? {
+                    self.audit_path(dir_node, &path, PathViolation::BoundaryEscape)?;
+                }
+            }
+        }
+
+        Ok(path)
+    }
+
+    // Reject a path with more elements than the mount allows before resolving any of them.
+    fn check_path_depth(&self, path: &str) -> Result<(), Error> {
+        if let Some(max_depth) = self.options.max_path_depth {
+            if components(path).nth(max_depth).is_some() {
+                return Err(Error::PathTooDeep);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Record a path resolution violating the sandbox, it is rejected if the sandbox is enforced.
+    fn audit_path(&self, dir: Node, path: &str, violation: PathViolation) -> Result<(), Error> {
+        let mut audit = self.path_audit.borrow_mut();
+        if audit.len() >= MAX_PATH_AUDIT_RECORDS {
+            audit.pop_front();
+        }
+
+        let rejected = self.options.sandbox == SandboxMode::Enforce;
+        audit.push_back(PathAudit {
+            dir,
+            path: path.to_string(),
+            violation,
+            rejected,
+            span: self.span,
+        });
+
+        if rejected {
+            return Err(Error::SandboxViolation);
+        }
+
+        Ok(())
+    }
+
+    // Take the records that cannot be decoded out of the storage, so that the rest of the tree stays readable.
+    // The corrupted metadata is replaced with empty nodes and the corrupted directory entries are unlinked,
+    // the report keeps their raw bytes.
+    pub fn recover(&mut self) -> RecoveryReport {
+        self.merkle_cache.clear();
+        self.storage.recover()
+    }
+
+    // Remove the data of the nodes kept without directory entries, e.g. the files unlinked while opened before an
+    // upgrade, it is done on mount. The nodes still opened or linked again are kept, returns the number of the
+    // nodes removed.
+    pub fn reclaim_orphans(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for node in self.storage.orphans() {
+            if self.fd_table.node_refcount().contains_key(&node) {
+                continue;
+            }
+
+            match self.storage.get_metadata(node) {
+                Ok(metadata) if metadata.link_count == 0 => {
+                    rm_node_data(node, &metadata, self.storage.as_mut());
+                    self.invalidate_hash(node);
+                    #[cfg(feature = "write-stats")]
+                    self.write_stats.remove(node);
+                    reclaimed += 1;
+                }
+                _ => self.storage.remove_orphan(node),
+            }
+        }
+
+        reclaimed
+    }
+
+    // Set the directory the paths and the aliases resolved inside it must not lead out of, by default it is the
+    // root directory.
+    pub fn set_sandbox_boundary(&mut self, fd: Fd) -> Result<(), Error> {
+        let dir = self.get_dir(fd)?;
+        self.sandbox_boundary = Some(dir.node);
+        Ok(())
+    }
+
+    // Take the recorded path resolutions violating the sandbox, only the latest records are kept.
+    pub fn take_path_audit(&mut self) -> Vec<PathAudit> {
+        self.path_audit.get_mut().drain(..).collect()
+    }
+
+    // Set the rules checked before the tree is changed, replaces the previous policy.
+    pub fn set_policy(&mut self, policy: Box<dyn Policy>) {
+        self.policy = Some(policy);
+    }
+
+    // Make the tree read-only around an upgrade, e.g. from `pre_upgrade`, the changes then fail
+    // with `UpgradeInProgress` while the reads still work. The storages write through to the memory,
+    // so only the publish batches in progress are ended to certify the tree as it is saved.
+    pub fn enter_upgrade_mode(&mut self) -> Result<MerkleHash, Error> {
+        self.upgrading = true;
+        self.publish_depth = 0;
+        self.published_hash = None;
+        self.root_hash()
+    }
+
+    // Allow changing the tree again after `enter_upgrade_mode`.
+    pub fn exit_upgrade_mode(&mut self) {
+        self.upgrading = false;
+    }
+
+    pub fn is_upgrading(&self) -> bool {
+        self.upgrading
+    }
+
+    // Remove the policy and return it.
+    pub fn take_policy(&mut self) -> Option<Box<dyn Policy>> {
+        self.policy.take()
+    }
+
+    fn check_policy(
+        &mut self,
+        check: impl Fn(&mut dyn Policy) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if self.upgrading || !self.snapshot_mounts.is_empty() {
+            check(&mut self.read_only_guard())?;
+        }
+        match self.policy.as_mut() {
+            Some(policy) => check(policy.as_mut()),
+            None => Ok(()),
+        }
+    }
+
+    fn read_only_guard(&self) -> ReadOnlyGuard<'_> {
+        ReadOnlyGuard {
+            mounts: &self.snapshot_mounts,
+            upgrading: self.upgrading,
+            storage: self.storage.as_ref(),
+        }
+    }
+
+    // Check that a new entry `path` can be added under the `dir_node` directory without exceeding the entry limit.
+    // Only the deepest existing directory on the path gains an entry, the missing directories are created empty.
+    fn check_dir_capacity(&self, dir_node: Node, path: &str) -> Result<(), Error> {
+        let Some(max_entries) = self.options.max_dir_entries else {
+            return Ok(());
+        };
+
+        // the existing entries are reported by the operation itself
+        if find_node(dir_node, path, self.storage.as_ref()).is_ok() {
+            return Ok(());
+        }
+
+        let parent = find_existing_dir(dir_node, path, self.storage.as_ref())?;
+        let metadata = self.storage.get_metadata(parent)?;
+
+        if metadata.file_type == FileType::Directory && metadata.size >= max_entries {
+            return Err(Error::DirectoryFull);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get_test_storage(&mut self) -> &mut dyn Storage {
+        self.storage.as_mut()
+    }
+
+    #[cfg(test)]
     pub(crate) fn get_test_file(&self, fd: Fd) -> File {
         self.get_file(fd).unwrap()
     }
-}
+}
+
+// A guard closing the file descriptors that are not persistent when dropped, the root descriptor is never closed.
+pub struct FdScope<'a> {
+    fs: &'a mut FileSystem,
+}
+
+impl Deref for FdScope<'_> {
+    type Target = FileSystem;
+
+    fn deref(&self) -> &FileSystem {
+        self.fs
+    }
+}
+
+impl DerefMut for FdScope<'_> {
+    fn deref_mut(&mut self) -> &mut FileSystem {
+        self.fs
+    }
+}
+
+impl Drop for FdScope<'_> {
+    fn drop(&mut self) {
+        let keep: Vec<Fd> = self.fs.persistent_fds.iter().copied().collect();
+        self.fs.close_all_except(&keep);
+    }
+}
+
+// A guard restoring the previous span ID when dropped.
+pub struct SpanScope<'a> {
+    fs: &'a mut FileSystem,
+    previous: Option<u64>,
+}
+
+impl Deref for SpanScope<'_> {
+    type Target = FileSystem;
+
+    fn deref(&self) -> &FileSystem {
+        self.fs
+    }
+}
+
+impl DerefMut for SpanScope<'_> {
+    fn deref_mut(&mut self) -> &mut FileSystem {
+        self.fs
+    }
+}
+
+impl Drop for SpanScope<'_> {
+    fn drop(&mut self) {
+        self.fs.span = self.previous;
+    }
+}
+
+// Builder of a file system with all its mount options, validated when the file system is built.
+// Options stored with the file system, like the name normalizer, are checked against the stored ones.
+#[derive(Default)]
+pub struct FileSystemBuilder {
+    storage: Option<Box<dyn Storage>>,
+    options: MountOptions,
+    chunk_size: Option<usize>,
+    cache_chunks: Option<usize>,
+    policy: Option<Box<dyn Policy>>,
+}
+
+impl FileSystemBuilder {
+    // The storage the file system is hosted on, required.
+    pub fn storage(mut self, storage: Box<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    // Replace all the mount options, the options set before are discarded.
+    pub fn options(mut self, options: MountOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    // The expected chunk size, it is fixed by the storage layout and a different size is rejected.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    // Keep up to `chunks` recently used file chunks in heap memory.
+    pub fn cache_chunks(mut self, chunks: usize) -> Self {
+        self.cache_chunks = Some(chunks);
+        self
+    }
+
+    pub fn dot_entries(mut self, dot_entries: bool) -> Self {
+        self.options.dot_entries = dot_entries;
+        self
+    }
+
+    pub fn strict_posix(mut self, strict_posix: bool) -> Self {
+        self.options.strict_posix = strict_posix;
+        self
+    }
+
+    // The unit and origin of the stored times.
+    pub fn times(mut self, times: TimeConfig) -> Self {
+        self.options.times = times;
+        self
+    }
+
+    // The source of the current time in the unit of the stored times.
+    pub fn clock(mut self, clock: fn() -> u64) -> Self {
+        self.options.clock = Some(clock);
+        self
+    }
+
+    pub fn max_dir_entries(mut self, max_entries: u64) -> Self {
+        self.options.max_dir_entries = Some(max_entries);
+        self
+    }
+
+    pub fn max_path_depth(mut self, max_depth: usize) -> Self {
+        self.options.max_path_depth = Some(max_depth);
+        self
+    }
+
+    pub fn max_alias_hops(mut self, max_hops: usize) -> Self {
+        self.options.max_alias_hops = Some(max_hops);
+        self
+    }
+
+    // The file name normalization, e.g. `NameNormalizer::Lowercase` for case insensitive names.
+    pub fn name_normalizer(mut self, name_normalizer: NameNormalizer) -> Self {
+        self.options.name_normalizer = name_normalizer;
+        self
+    }
+
+    // A custom file name normalizer, it must return the same result when applied again.
+    pub fn custom_normalizer(mut self, normalize: fn(&str) -> String) -> Self {
+        self.options.name_normalizer = NameNormalizer::Custom;
+        self.options.custom_normalizer = Some(normalize);
+        self
+    }
+
+    // The order of the names in the sorted listings, e.g. `Collation::AsciiCaseInsensitive`.
+    pub fn collation(mut self, collation: Collation) -> Self {
+        self.options.collation = collation;
+        self
+    }
+
+    // A custom order of the names in the sorted listings, it must be a total order.
+    pub fn custom_collation(mut self, compare: fn(&str, &str) -> Ordering) -> Self {
+        self.options.collation = Collation::Custom;
+        self.options.custom_collation = Some(compare);
+        self
+    }
+
+    pub fn sandbox(mut self, sandbox: SandboxMode) -> Self {
+        self.options.sandbox = sandbox;
+        self
+    }
+
+    pub fn write_failure(mut self, write_failure: WriteFailure) -> Self {
+        self.options.write_failure = write_failure;
+        self
+    }
+
+    pub fn instruction_budget(mut self, max_instructions: u64) -> Self {
+        self.options.instruction_budget = Some(max_instructions);
+        self
+    }
+
+    pub fn dir_prefetch(mut self, entries: usize) -> Self {
+        self.options.dir_prefetch = Some(entries);
+        self
+    }
+
+    pub fn chunk_zeroing(mut self, zeroing: ChunkZeroing) -> Self {
+        self.options.chunk_zeroing = zeroing;
+        self
+    }
+
+    // The rules checked before the tree is changed.
+    pub fn policy(mut self, policy: Box<dyn Policy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    // Check the configuration and mount the file system.
+    pub fn build(self) -> Result<FileSystem, Error> {
+        let Some(storage) = self.storage else {
+            return Err(Error::InvalidMountOptions);
+        };
+        if self.chunk_size.is_some_and(|size| size != FILE_CHUNK_SIZE)
+            || self.cache_chunks == Some(0)
+            || self.options.max_dir_entries == Some(0)
+            || self.options.max_path_depth == Some(0)
+        {
+            return Err(Error::InvalidMountOptions);
+        }
+
+        let storage = match self.cache_chunks {
+            Some(chunks) => Box::new(CachedStorage::new(storage, chunks)),
+            None => storage,
+        };
+
+        let mut fs = FileSystem::mount(storage, self.options)?;
+        fs.policy = self.policy;
+        Ok(fs)
+    }
+}
+
+// An opened file closed when the handle is dropped.
+pub struct OpenFile<'fs> {
+    fs: &'fs mut FileSystem,
+    fd: Fd,
+}
+
+impl OpenFile<'_> {
+    // Get the file descriptor of the file.
+    pub fn fd(&self) -> Fd {
+        self.fd
+    }
+
+    // Read the file contents at the cursor into `dst`.
+    pub fn read(&mut self, dst: &mut [u8]) -> Result<FileSize, Error> {
+        self.fs.read(self.fd, dst)
+    }
+
+    // Write `src` into the file at the cursor.
+    pub fn write(&mut self, src: &[u8]) -> Result<FileSize, Error> {
+        self.fs.write(self.fd, src)
+    }
+
+    // Position the file cursor.
+    pub fn seek(&mut self, delta: i64, whence: Whence) -> Result<FileSize, Error> {
+        self.fs.seek(self.fd, delta, whence)
+    }
+
+    // Get the file cursor position.
+    pub fn tell(&mut self) -> Result<FileSize, Error> {
+        self.fs.tell(self.fd)
+    }
+
+    // Change the file size.
+    pub fn set_size(&mut self, size: FileSize) -> Result<(), Error> {
+        self.fs.set_size(self.fd, size)
+    }
+
+    // Get the file stats.
+    pub fn metadata(&self) -> Result<FileStat, Error> {
+        self.fs.metadata(self.fd)
+    }
+
+    // Keep the file open and return its descriptor, it has to be closed manually.
+    pub fn into_fd(self) -> Fd {
+        let fd = self.fd;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for OpenFile<'_> {
+    fn drop(&mut self) {
+        let _ = self.fs.close(self.fd);
+    }
+}
+
+// Iterator over the entries of a directory, yields their names, nodes and file types.
+pub struct ReadDir<'fs> {
+    fs: &'fs FileSystem,
+    dir: Node,
+    next: Option<DirEntryIndex>,
+    // The entries with lower indices are skipped when resuming after a removed entry.
+    after: DirEntryIndex,
+    cookie: DirEntryIndex,
+}
+
+impl ReadDir<'_> {
+    // The cookie of the last returned entry, to resume the iteration by `readdir_from`.
+    pub fn cookie(&self) -> DirEntryIndex {
+        self.cookie
+    }
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = Result<(String, Node, FileType), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let index = self.next?;
+            let entry = match self.fs.storage.get_direntry(self.dir, index) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    self.next = None;
+                    return Some(Err(err));
+                }
+            };
+            self.next = entry.next_entry;
+            if index <= self.after {
+                continue;
+            }
+
+            self.cookie = index;
+            let name = &entry.name.bytes[..entry.name.length as usize];
+            let name = String::from_utf8_lossy(name).into_owned();
+            let result = self
+                .fs
+                .storage
+                .get_metadata(entry.node)
+                .map(|metadata| (name, entry.node, metadata.file_type));
+            return Some(result);
+        }
+    }
+}
+
+// An opened directory closed when the handle is dropped.
+pub struct OpenDir<'fs> {
+    fs: &'fs mut FileSystem,
+    fd: Fd,
+}
+
+impl OpenDir<'_> {
+    // Get the file descriptor of the directory.
+    pub fn fd(&self) -> Fd {
+        self.fd
+    }
+
+    // List the directory entries.
+    pub fn list(&self) -> Result<Vec<DirEntry>, Error> {
+        self.fs.list_dir(self.fd)
+    }
+
+    // Get the directory stats.
+    pub fn metadata(&self) -> Result<FileStat, Error> {
+        self.fs.metadata(self.fd)
+    }
+
+    // Open or create a file inside this directory.
+    #[track_caller]
+    pub fn open_file(
+        &mut self,
+        path: &str,
+        stat: FdStat,
+        flags: OpenFlags,
+        ctime: u64,
+    ) -> Result<OpenFile<'_>, Error> {
+        self.fs.open_file(self.fd, path, stat, flags, ctime)
+    }
+
+    // Open a directory inside this directory.
+    #[track_caller]
+    pub fn open_dir(&mut self, path: &str) -> Result<OpenDir<'_>, Error> {
+        self.fs.open_dir(self.fd, path)
+    }
+
+    // Keep the directory open and return its descriptor, it has to be closed manually.
+    pub fn into_fd(self) -> Fd {
+        let fd = self.fd;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for OpenDir<'_> {
+    fn drop(&mut self) {
+        let _ = self.fs.close(self.fd);
+    }
+}
+
+// A descriptor known to be an opened directory, the paths given to its methods are relative to it.
+// Unlike `OpenDir` it does not borrow the file system, it is passed to each call and the handle is closed manually.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DirHandle(Fd);
+
+impl DirHandle {
+    // Get the file descriptor of the directory.
+    pub fn fd(self) -> Fd {
+        self.0
+    }
+
+    // Create a new file in this directory.
+    #[track_caller]
+    pub fn create_file(
+        self,
+        fs: &mut FileSystem,
+        path: &str,
+        stat: FdStat,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        fs.create_file(self.0, path, stat, ctime)
+    }
+
+    // Create a new directory in this directory.
+    #[track_caller]
+    pub fn create_dir(
+        self,
+        fs: &mut FileSystem,
+        path: &str,
+        stat: FdStat,
+        ctime: u64,
+    ) -> Result<DirHandle, Error> {
+        fs.create_dir(self.0, path, stat, ctime).map(DirHandle)
+    }
+
+    // Open or create a file or a directory in this directory.
+    #[track_caller]
+    pub fn open(
+        self,
+        fs: &mut FileSystem,
+        path: &str,
+        stat: FdStat,
+        flags: OpenFlags,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        fs.open_or_create(self.0, path, stat, flags, ctime)
+    }
+
+    // Open a directory in this directory.
+    #[track_caller]
+    pub fn open_dir(self, fs: &mut FileSystem, path: &str) -> Result<DirHandle, Error> {
+        fs.open_or_create(self.0, path, FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .map(DirHandle)
+    }
+
+    // List the entries of this directory.
+    pub fn read_dir(self, fs: &FileSystem) -> Result<Vec<DirEntry>, Error> {
+        fs.list_dir(self.0)
+    }
+
+    // Remove a file or a directory in this directory.
+    pub fn remove(
+        self,
+        fs: &mut FileSystem,
+        path: &str,
+        options: RemoveOptions,
+    ) -> Result<(), Error> {
+        fs.remove(self.0, path, options)
+    }
+
+    // Close the directory, the handle cannot be used afterwards.
+    pub fn close(self, fs: &mut FileSystem) -> Result<(), Error> {
+        fs.close(self.0)
+    }
+}
+
+// A read-only view of the file system for the query calls, the paths given to its methods are relative to the root.
+// It cannot change the tree, so it does not record the accesses of the cache directories nor fill the hash cache,
+// and the synthetic introspection files are read as they were last refreshed.
+#[derive(Clone, Copy)]
+pub struct FsReader<'fs> {
+    fs: &'fs FileSystem,
+}
+
+impl FsReader<'_> {
+    // Get the stats of a file or a directory.
+    pub fn metadata(&self, path: &str) -> Result<FileStat, Error> {
+        let node = self.find(path)?;
+        self.fs.metadata_from_node(node)
+    }
+
+    // Check whether there is a file, a directory or an alias at the path.
+    pub fn exists(&self, path: &str) -> bool {
+        self.find(path).is_ok()
+    }
+
+    // Read at most `len` bytes of a file starting at `offset`.
+    pub fn read(&self, path: &str, offset: FileSize, len: FileSize) -> Result<Vec<u8>, Error> {
+        let node = self.find(path)?;
+        let metadata = self.fs.storage.get_metadata(node)?;
+        let file = self.fs.path_file(node, &metadata)?;
+
+        let len = len.min(metadata.size.saturating_sub(offset));
+        let mut buf = vec![0; usize::try_from(len).map_err(|_| Error::InvalidBufferLength)?];
+        file.read_with_size(offset, &mut buf, metadata.size, self.fs.storage.as_ref())?;
+        Ok(buf)
+    }
+
+    // List the entries of a directory.
+    pub fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        let node = self.find(path)?;
+        Dir::new(node, FdStat::default(), self.fs.storage.as_ref())?
+            .list_entries(self.fs.options.dot_entries, self.fs.storage.as_ref())
+    }
+
+    // Get the target of an alias.
+    pub fn read_alias(&self, path: &str) -> Result<String, Error> {
+        let node = self.find(path)?;
+        read_alias_target(node, self.fs.storage.as_ref())
+    }
+
+    // Get the HTTP metadata of a file or a directory.
+    pub fn http_metadata(&self, path: &str) -> Result<Option<HttpMetadata>, Error> {
+        let node = self.find(path)?;
+        Ok(self.fs.storage.get_metadata(node)?.http)
+    }
+
+    // An empty path is the root directory.
+    fn find(&self, path: &str) -> Result<Node, Error> {
+        let root = self.fs.storage.root_node();
+        if path.is_empty() {
+            return Ok(root);
+        }
+        let path = &self.fs.user_path(root, path)?;
+        find_node(root, path, self.fs.storage.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        error::Error,
+        fs::{DstBuf, FdFlags, SrcBuf},
+        runtime::{
+            budget::BUDGET_SLICE_SIZE,
+            structure_helpers::find_node,
+            types::{FdStat, OpenFlags, RemoveOptions},
+        },
+        storage::types::{DirEntry, FileType, HttpMetadata, Node, FILE_CHUNK_SIZE},
+        test_utils::{fs_with_tree, test_fs, test_fs_transient, FaultyStorage, MockClock},
+    };
+
+    use std::{cell::Cell, rc::Rc};
+
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    use crate::storage::{stable::StableStorage, transient::TransientStorage};
+
+    use ic_stable_structures::Storable;
+
+    use super::{
+        Budgeted, ChangeKind, Collation, Continuation, EntryPosition, EvictionPolicy, Fd,
+        FileHandle, FileSystem, MountOptions, NameNormalizer, Page, PathViolation, PhysicalWrites,
+        Policy, Projection, ReadPattern, ReusableSpace, Rights, SandboxMode, TimeConfig,
+        TimeResolution, WatchEvent, WriteFailure,
+    };
+
+    #[test]
+    fn get_root_info() {
+        let fs = test_fs();
+
+        let fd = fs.root_fd();
+        let path = fs.root_path();
+
+        assert!(fd == Fd::new(3));
+        assert!(path == "/");
+    }
+
+    #[test]
+    fn create_file() {
+        let mut fs = test_fs();
+
+        let file = fs
+            .create_file(fs.root_fd(), "test.txt", Default::default(), 0)
+            .unwrap();
+
+        assert!(file > fs.root_fd());
+    }
+
+    #[test]
+    fn create_dir() {
+        let mut fs = test_fs();
+
+        let dir = fs
+            .create_dir(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let fd = fs
+            .create_file(dir, "file.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, "Hello, world!".as_bytes()).unwrap();
+
+        let dir = fs
+            .open_or_create(
+                fs.root_fd(),
+                "test",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+
+        let fd = fs
+            .open_or_create(dir, "file.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+
+        let mut buf = [0; 13];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, "Hello, world!".as_bytes());
+    }
+
+    #[test]
+    fn create_file_creates_a_few_files() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        fs.create_file(dir, "test1.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.create_file(dir, "test2.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.create_file(dir, "test3.txt", FdStat::default(), 0)
+            .unwrap();
+
+        let meta = fs.raw_metadata(fs.root_fd()).unwrap();
+
+        let entry_index = meta.first_dir_entry.unwrap();
+
+        let entry1 = fs.get_direntry(fs.root_fd(), entry_index).unwrap();
+        let entry2 = fs.get_direntry(fs.root_fd(), entry_index + 1).unwrap();
+        let entry3 = fs.get_direntry(fs.root_fd(), entry_index + 2).unwrap();
+
+        assert_eq!(entry1.prev_entry, None);
+        assert_eq!(entry1.next_entry, Some(entry_index + 1));
+
+        assert_eq!(entry2.prev_entry, Some(entry_index));
+        assert_eq!(entry2.next_entry, Some(entry_index + 2));
+
+        assert_eq!(entry3.prev_entry, Some(entry_index + 1));
+        assert_eq!(entry3.next_entry, None);
+    }
+
+    #[test]
+    fn close_file_fd_reused() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        fs.create_file(dir, "test1.txt", FdStat::default(), 0)
+            .unwrap();
+        let fd2 = fs
+            .create_file(dir, "test2.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.create_file(dir, "test3.txt", FdStat::default(), 0)
+            .unwrap();
+
+        fs.close(fd2).unwrap();
+
+        let fd4 = fs
+            .create_file(dir, "test4.txt", FdStat::default(), 0)
+            .unwrap();
+
+        assert_eq!(fd2, fd4);
+    }
+
+    #[test]
+    fn fd_renumber() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let fd1 = fs
+            .create_file(dir, "test1.txt", FdStat::default(), 0)
+            .unwrap();
+        let fd2 = fs
+            .create_file(dir, "test2.txt", FdStat::default(), 0)
+            .unwrap();
+
+        let entry1 = fs.get_node(fd1);
+
+        fs.renumber(fd1, fd2).unwrap();
+
+        assert!(fs.get_node(fd1).is_err());
+        assert_eq!(fs.get_node(fd2), entry1);
+    }
+
+    #[test]
+    fn fd_renumber_to_any_number() {
+        let mut fs = test_fs();
+        let dir = fs.root_fd();
+
+        let fd1 = fs.create_file(dir, "a.txt", FdStat::default(), 0).unwrap();
+        let fd2 = fs.create_file(dir, "b.txt", FdStat::default(), 0).unwrap();
+        let node1 = fs.get_node(fd1).unwrap();
+
+        // a number never given out, the following descriptors do not collide with it
+        let target = Fd::new(fd2.raw() + 2);
+        fs.renumber(fd1, target).unwrap();
+        assert_eq!(fs.get_node(target), Ok(node1));
+        let mut fds = vec![];
+        for i in 0..4 {
+            let fd = fs
+                .create_file(dir, &format!("f{i}"), FdStat::default(), 0)
+                .unwrap();
+            assert_ne!(fd, target);
+            fds.push(fd);
+        }
+
+        // a number closed before is taken out of the free numbers
+        fs.close(fd2).unwrap();
+        fs.renumber(target, fd2).unwrap();
+        let fd = fs.create_file(dir, "g", FdStat::default(), 0).unwrap();
+        assert_ne!(fd, fd2);
+        assert_eq!(fs.get_node(fd2), Ok(node1));
+
+        fs.renumber(fd2, fd2).unwrap();
+        assert_eq!(fs.renumber(Fd::new(500), fd), Err(Error::NotFound));
+        assert_eq!(fs.renumber(fd, dir), Err(Error::InvalidFileDescriptor));
+
+        // the root descriptor can be moved
+        let root = Fd::new(100);
+        fs.renumber(dir, root).unwrap();
+        assert_eq!(fs.root_fd(), root);
+        write_test_file(&mut fs, "c.txt", b"c");
+    }
+
+    #[test]
+    fn seek_and_write() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        fs.seek(fd, 24, super::Whence::SET).unwrap();
+
+        fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+
+        let meta = fs.metadata(fd).unwrap();
+
+        assert_eq!(meta.size, 29);
+
+        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+        let mut buf = [42u8; 29];
+        let rr = fs.read(fd, &mut buf).unwrap();
+        assert_eq!(rr, 29);
+        assert_eq!(
+            buf,
+            [
+                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+                4, 5
+            ]
+        );
+
+        fs.close(fd).unwrap();
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        let mut buf = [42u8; 29];
+        let rr = fs.read(fd, &mut buf).unwrap();
+        assert_eq!(rr, 29);
+        assert_eq!(
+            buf,
+            [
+                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+                4, 5
+            ]
+        );
+
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn read_and_write_vec() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let write_content1 = "This is a sample file content.";
+        let write_content2 = "1234567890";
+
+        let write_content = [
+            SrcBuf {
+                buf: write_content1.as_ptr(),
+                len: write_content1.len(),
+            },
+            SrcBuf {
+                buf: write_content2.as_ptr(),
+                len: write_content2.len(),
+            },
+        ];
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        fs.write_vec(fd, write_content.as_ref()).unwrap();
+
+        let meta = fs.metadata(fd).unwrap();
+        assert_eq!(meta.size, 40);
+
+        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+
+        let mut read_content1 = String::from("......................");
+        let mut read_content2 = String::from("......................");
+
+        let read_content = [
+            DstBuf {
+                buf: read_content1.as_mut_ptr(),
+                len: read_content1.len(),
+            },
+            DstBuf {
+                buf: read_content2.as_mut_ptr(),
+                len: read_content2.len(),
+            },
+        ];
+
+        fs.read_vec(fd, &read_content).unwrap();
+
+        assert_eq!("This is a sample file ", read_content1);
+        assert_eq!("content.1234567890....", read_content2);
+
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn read_and_write_vec_with_offset() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let write_content1 = "This is a sample file content.";
+        let write_content2 = "1234567890";
+
+        let write_content = [
+            SrcBuf {
+                buf: write_content1.as_ptr(),
+                len: write_content1.len(),
+            },
+            SrcBuf {
+                buf: write_content2.as_ptr(),
+                len: write_content2.len(),
+            },
+        ];
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        fs.write_vec_with_offset(fd, write_content.as_ref(), 2)
+            .unwrap();
+
+        let meta = fs.metadata(fd).unwrap();
+        assert_eq!(meta.size, 42);
+
+        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+
+        let mut read_content1 = String::from("......................");
+        let mut read_content2 = String::from("......................");
+
+        let read_content = [
+            DstBuf {
+                buf: read_content1.as_mut_ptr(),
+                len: read_content1.len(),
+            },
+            DstBuf {
+                buf: read_content2.as_mut_ptr(),
+                len: read_content2.len(),
+            },
+        ];
+
+        fs.read_vec_with_offset(fd, &read_content, 1).unwrap();
+
+        assert_eq!("\0This is a sample file", read_content1);
+        assert_eq!(" content.1234567890...", read_content2);
+
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn write_and_read_empty_vectors() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        // a zero-length write still creates the file, but leaves it empty
+        assert_eq!(fs.write_vec(fd, &[]), Ok(0));
+        let empty = [SrcBuf {
+            buf: [].as_ptr(),
+            len: 0,
+        }];
+        assert_eq!(fs.write_vec_with_offset(fd, &empty, 10), Ok(0));
+        assert_eq!(fs.metadata(fd).unwrap().size, 0);
+        assert_eq!(fs.tell(fd), Ok(0));
+
+        fs.write(fd, &[1, 2, 3]).unwrap();
+
+        let mut buf = [0u8; 3];
+        let dst = [DstBuf {
+            buf: buf.as_mut_ptr(),
+            len: buf.len(),
+        }];
+
+        // reading at the end of file returns 0 bytes
+        assert_eq!(fs.read_vec(fd, &dst), Ok(0));
+        assert_eq!(fs.read_vec(fd, &[]), Ok(0));
+        assert_eq!(fs.read_vec_with_offset(fd, &dst, 0), Ok(3));
+        assert_eq!(buf, [1, 2, 3]);
+    }
+
+    #[test]
+    fn read_with_sequential_pattern() {
+        use crate::storage::{cached::CachedStorage, transient::TransientStorage};
+
+        let storage = CachedStorage::new(Box::new(TransientStorage::new()), 16);
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+
+        let dir = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+        fs.write(fd, &[7; 10_000]).unwrap();
+        fs.seek(fd, 0, super::Whence::SET).unwrap();
+
+        fs.set_read_pattern(fd, ReadPattern::Sequential).unwrap();
+
+        let mut buf = [0u8; 10_000];
+        assert_eq!(fs.read(fd, &mut buf), Ok(10_000));
+        assert!(buf.iter().all(|b| *b == 7));
+
+        assert_eq!(
+            fs.set_read_pattern(dir, ReadPattern::Sequential),
+            Err(Error::InvalidFileType)
+        );
+    }
+
+    #[test]
+    fn merkle_hash_of_equal_trees() {
+        let mut fs1 = test_fs_transient();
+        let mut fs2 = test_fs_transient();
+
+        for (fs, names) in [(&mut fs1, ["a", "b"]), (&mut fs2, ["b", "a"])] {
+            let root = fs.root_fd();
+            let dir = fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+            for name in names {
+                let fd = fs.create_file(dir, name, FdStat::default(), 0).unwrap();
+                fs.write(fd, name.as_bytes()).unwrap();
+                fs.close(fd).unwrap();
+            }
+        }
+
+        let hash1 = fs1.merkle_hash(fs1.root_fd()).unwrap();
+        assert_eq!(hash1, fs2.merkle_hash(fs2.root_fd()).unwrap());
+
+        // renaming a file changes the hash
+        let root = fs2.root_fd();
+        let fd = fs2.rename(root, "dir/a", root, "dir/c").unwrap();
+        fs2.close(fd).unwrap();
+        assert_ne!(hash1, fs2.merkle_hash(root).unwrap());
+    }
+
+    #[test]
+    fn merkle_hash_changes_on_write() {
+        let mut fs = test_fs_transient();
+        let root = fs.root_fd();
+
+        let fd = fs
+            .create_file(root, "test.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, &[1; 5000]).unwrap();
+
+        let file_hash = fs.merkle_hash(fd).unwrap();
+        let root_hash = fs.merkle_hash(root).unwrap();
+        assert_eq!(file_hash, fs.merkle_hash(fd).unwrap());
+
+        fs.write_vec_with_offset(
+            fd,
+            &[SrcBuf {
+                buf: [2u8].as_ptr(),
+                len: 1,
+            }],
+            4500,
+        )
+        .unwrap();
+
+        assert_ne!(file_hash, fs.merkle_hash(fd).unwrap());
+        assert_ne!(root_hash, fs.merkle_hash(root).unwrap());
+    }
+
+    fn write_test_file(fs: &mut FileSystem, path: &str, content: &[u8]) {
+        let root = fs.root_fd();
+        fs.apply_file(root, path, content, 0).unwrap();
+    }
+
+    fn read_test_file(fs: &mut FileSystem, path: &str) -> Vec<u8> {
+        let root = fs.root_fd();
+        let fd = fs
+            .open_or_create(root, path, FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        let size = fs.metadata(fd).unwrap().size;
+        let mut buf = vec![0; size as usize];
+        fs.read(fd, &mut buf).unwrap();
+        fs.close(fd).unwrap();
+        buf
+    }
+
+    #[test]
+    fn sync_two_file_systems() {
+        let mut local = test_fs_transient();
+        let mut remote = test_fs_transient();
+
+        write_test_file(&mut local, "assets/same.txt", b"same");
+        write_test_file(&mut local, "assets/changed.txt", b"new");
+        write_test_file(&mut local, "static/a/b.txt", b"b");
+        write_test_file(&mut local, "conflict/file.txt", b"file");
+
+        write_test_file(&mut remote, "assets/same.txt", b"same");
+        write_test_file(&mut remote, "assets/changed.txt", b"old");
+        write_test_file(&mut remote, "assets/removed.txt", b"removed");
+        write_test_file(&mut remote, "conflict", b"not a folder");
+
+        let remote_root = remote.root_fd();
+        let remote_manifest = remote.manifest(remote_root).unwrap();
+
+        let local_root = local.root_fd();
+        let diff = local.diff_against(local_root, &remote_manifest).unwrap();
+
+        assert_eq!(diff.remove, vec!["assets/removed.txt", "conflict"]);
+        assert_eq!(diff.create_dirs, vec!["conflict", "static", "static/a"]);
+        assert_eq!(
+            diff.transfer,
+            vec!["assets/changed.txt", "conflict/file.txt", "static/a/b.txt"]
+        );
+
+        for path in &diff.remove {
+            remote.apply_remove(remote_root, path).unwrap();
+        }
+        for path in &diff.create_dirs {
+            remote.apply_dir(remote_root, path, 0).unwrap();
+        }
+        for path in &diff.transfer {
+            let content = read_test_file(&mut local, path);
+            remote.apply_file(remote_root, path, &content, 0).unwrap();
+        }
+
+        assert_eq!(
+            local.merkle_hash(local_root).unwrap(),
+            remote.merkle_hash(remote_root).unwrap()
+        );
+
+        let remote_manifest = remote.manifest(remote_root).unwrap();
+        assert!(local
+            .diff_against(local_root, &remote_manifest)
+            .unwrap()
+            .is_empty());
+        assert_eq!(local.manifest(local_root).unwrap(), remote_manifest);
+    }
+
+    #[test]
+    fn http_metadata_is_stored_with_file() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let fd = fs
+            .create_file(root, "index.html", FdStat::default(), 0)
+            .unwrap();
+        assert_eq!(fs.http_metadata(fd), Ok(None));
+
+        let http = HttpMetadata {
+            cache_control: Some("max-age=3600".to_string()),
+            content_encoding: Some("gzip".to_string()),
+            content_type: None,
+            headers: vec![("x-frame-options".to_string(), "DENY".to_string())],
+        };
+        fs.set_http_metadata(fd, Some(http.clone())).unwrap();
+        fs.close(fd).unwrap();
+
+        let fd = fs
+            .open_or_create(root, "index.html", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        let stored = fs.http_metadata(fd).unwrap().unwrap();
+        assert_eq!(stored, http);
+        assert_eq!(
+            stored.response_headers(),
+            vec![
+                ("cache-control".to_string(), "max-age=3600".to_string()),
+                ("content-encoding".to_string(), "gzip".to_string()),
+                ("x-frame-options".to_string(), "DENY".to_string()),
+            ]
+        );
+
+        fs.set_http_metadata(fd, None).unwrap();
+        assert_eq!(fs.http_metadata(fd), Ok(None));
+    }
+
+    #[test]
+    fn resolve_aliases() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        write_test_file(&mut fs, "index.html", b"<html/>");
+        write_test_file(&mut fs, "docs/new-path.html", b"new");
+
+        fs.create_alias(root, "home", "/index.html", 0).unwrap();
+        fs.create_alias(root, "old-path", "/docs/old-path", 0)
+            .unwrap();
+        fs.create_alias(root, "docs/old-path", "docs/new-path.html", 0)
+            .unwrap();
+
+        assert_eq!(fs.read_alias(root, "home"), Ok("/index.html".to_string()));
+        assert_eq!(
+            fs.resolve_alias(root, "/home"),
+            Ok("index.html".to_string())
+        );
+        assert_eq!(
+            fs.resolve_alias(root, "/old-path"),
+            Ok("docs/new-path.html".to_string())
+        );
+        assert_eq!(fs.resolve_alias(root, "docs"), Ok("docs".to_string()));
+        assert_eq!(
+            fs.read_alias(root, "index.html"),
+            Err(Error::InvalidFileType)
+        );
+
+        // aliases are not opened as files
+        assert_eq!(
+            fs.open_or_create(root, "home", FdStat::default(), OpenFlags::empty(), 0),
+            Err(Error::InvalidFileType)
+        );
+
+        fs.create_alias(root, "dangling", "missing", 0).unwrap();
+        assert_eq!(fs.resolve_alias(root, "dangling"), Err(Error::NotFound));
+
+        fs.create_alias(root, "loop1", "loop2", 0).unwrap();
+        fs.create_alias(root, "loop2", "/loop1", 0).unwrap();
+        assert_eq!(
+            fs.resolve_alias(root, "loop1"),
+            Err(Error::TooManySymbolicLinks)
+        );
+
+        fs.remove_file(root, "home").unwrap();
+        assert_eq!(fs.resolve_alias(root, "home"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn convert_times_with_mount_options() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let mut options = fs.options();
+        options.times = TimeConfig {
+            resolution: TimeResolution::Seconds,
+            epoch: Duration::from_secs(1_000),
+        };
+        fs.set_options(options);
+
+        let times = fs.options().times;
+        let now = UNIX_EPOCH + Duration::from_secs(1_500);
+
+        let ctime = times.time_from_system(now);
+        assert_eq!(ctime, 500);
+
+        let fd = fs
+            .create_file(root, "test.txt", FdStat::default(), ctime)
+            .unwrap();
+        let stat = fs.metadata(fd).unwrap();
+
+        assert_eq!(times.to_system_time(stat.times.created), Ok(now));
+        assert_eq!(
+            times.to_wasi_timestamp(stat.times.created),
+            1_500_000_000_000
+        );
+        assert_eq!(times.time_from_wasi(1_500_000_000_000), 500);
+
+        // the times before the epoch are clamped
+        assert_eq!(times.time_from_system(UNIX_EPOCH), 0);
+
+        // the default is nanoseconds since the Unix epoch
+        let times = TimeConfig::default();
+        assert_eq!(times.to_wasi_timestamp(42), 42);
+        assert_eq!(
+            times.to_system_time(1_000_000_000),
+            Ok(UNIX_EPOCH + Duration::from_secs(1))
+        );
+
+        // the times out of the range of a duration do not overflow
+        let times = TimeConfig {
+            resolution: TimeResolution::Seconds,
+            epoch: Duration::from_secs(1),
+        };
+        assert_eq!(times.to_system_time(u64::MAX), Err(Error::InvalidMetadata));
+        assert_eq!(times.to_wasi_timestamp(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn list_open_fds() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let dir = fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+        let stat = FdStat {
+            flags: FdFlags::APPEND,
+            ..FdStat::default()
+        };
+        let file = fs.create_file(dir, "file.txt", stat, 0).unwrap();
+
+        let fds: Vec<_> = fs.open_fds().collect();
+        assert_eq!(
+            fds,
+            vec![
+                (
+                    root,
+                    FileType::Directory,
+                    fs.storage.root_node(),
+                    FdFlags::empty()
+                ),
+                (
+                    dir,
+                    FileType::Directory,
+                    fs.get_node(dir).unwrap(),
+                    FdFlags::empty()
+                ),
+                (
+                    file,
+                    FileType::RegularFile,
+                    fs.get_node(file).unwrap(),
+                    FdFlags::APPEND
+                ),
+            ]
+        );
+
+        fs.close(dir).unwrap();
+        let fds: Vec<Fd> = fs.open_fds().map(|(fd, ..)| fd).collect();
+        assert_eq!(fds, vec![root, file]);
+    }
+
+    #[test]
+    fn close_all_except_kept_fds() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let fd1 = fs
+            .create_file(root, "file1.txt", FdStat::default(), 0)
+            .unwrap();
+        let fd2 = fs
+            .create_file(root, "file2.txt", FdStat::default(), 0)
+            .unwrap();
+        let fd3 = fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+
+        assert_eq!(fs.close_all_except(&[fd2]), 2);
+
+        let fds: Vec<Fd> = fs.open_fds().map(|(fd, ..)| fd).collect();
+        assert_eq!(fds, vec![root, fd2]);
+        assert_eq!(fs.close(fd1), Err(Error::NotFound));
+        assert_eq!(fs.close(fd3), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn fd_scope_closes_leaked_fds() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let kept = fs
+            .create_file(root, "kept.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.set_persistent(kept, true).unwrap();
+
+        {
+            let mut scope = fs.fd_scope();
+            let leaked = scope
+                .create_file(root, "leaked.txt", FdStat::default(), 0)
+                .unwrap();
+            scope.write(leaked, b"data").unwrap();
+            assert_eq!(scope.open_fds().count(), 3);
+        }
+
+        let fds: Vec<Fd> = fs.open_fds().map(|(fd, ..)| fd).collect();
+        assert_eq!(fds, vec![root, kept]);
+
+        // a descriptor is no longer persistent once closed
+        fs.close(kept).unwrap();
+        let fd = fs
+            .create_file(root, "other.txt", FdStat::default(), 0)
+            .unwrap();
+        assert_eq!(fd, kept);
+        drop(fs.fd_scope());
+        assert_eq!(fs.open_fds().count(), 1);
+
+        assert_eq!(fs.set_persistent(fd, true), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn open_handles_are_closed_on_drop() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+        fs.close_all_except(&[]);
+
+        {
+            let mut dir = fs.open_dir(root, "dir").unwrap();
+            let mut file = dir
+                .open_file("file.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+            file.write(b"hello").unwrap();
+            file.seek(0, super::Whence::SET).unwrap();
+
+            let mut buf = [0; 5];
+            assert_eq!(file.read(&mut buf), Ok(5));
+            assert_eq!(&buf, b"hello");
+            assert_eq!(file.metadata().unwrap().size, 5);
+        }
+        assert_eq!(fs.open_fds().count(), 1);
+
+        let dir = fs.open_dir(root, "dir").unwrap();
+        assert_eq!(dir.list().unwrap().len(), 1);
+        let fd = dir.into_fd();
+        assert_eq!(fs.open_fds().count(), 2);
+        fs.close(fd).unwrap();
+
+        assert!(fs
+            .open_file(root, "dir", FdStat::default(), OpenFlags::empty(), 0)
+            .is_err());
+        assert!(fs.open_dir(root, "dir/file.txt").is_err());
+        assert_eq!(fs.open_fds().count(), 1);
+    }
+
+    #[test]
+    fn build_fd_stat_with_rights() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let stat = FdStat::builder()
+            .flags(FdFlags::APPEND)
+            .rights(Rights::READ_ONLY)
+            .inheriting(Rights::ALL_FILE)
+            .build();
+
+        let fd = fs.create_file(root, "test.txt", stat, 0).unwrap();
+        let stored = fs.get_stat(fd).unwrap();
+
+        assert_eq!(stored.flags, FdFlags::APPEND);
+        assert!(stored.rights_base.contains(Rights::FD_READ));
+        assert!(!stored.rights_base.contains(Rights::FD_WRITE));
+        assert!(Rights::ALL_FILE.contains(Rights::READ_ONLY));
+        assert_eq!(stored.rights_inheriting, Rights::ALL_FILE);
+
+        // the WASI numeric rights are kept as is
+        assert_eq!(Rights::from_bits_retain(1 << 6), Rights::FD_WRITE);
+        assert_eq!(Rights::FD_READ.bits(), 2);
+    }
+
+    #[test]
+    fn move_entries_in_directory_order() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        for name in ["a", "b", "c", "d"] {
+            let fd = fs.create_file(root, name, FdStat::default(), 0).unwrap();
+            fs.close(fd).unwrap();
+        }
+
+        let names = |fs: &FileSystem| -> Vec<String> {
+            fs.list_dir(root)
+                .unwrap()
+                .iter()
+                .map(|e| String::from_utf8_lossy(&e.name.bytes[..e.name.length as usize]).into())
+                .collect()
+        };
+
+        fs.move_entry(root, "d", EntryPosition::First).unwrap();
+        assert_eq!(names(&fs), ["d", "a", "b", "c"]);
+
+        fs.move_entry(root, "a", EntryPosition::After("c")).unwrap();
+        assert_eq!(names(&fs), ["d", "b", "c", "a"]);
+
+        fs.move_entry(root, "a", EntryPosition::Before("b"))
+            .unwrap();
+        assert_eq!(names(&fs), ["d", "a", "b", "c"]);
+
+        fs.move_entry(root, "d", EntryPosition::Last).unwrap();
+        fs.move_entry(root, "b", EntryPosition::Before("b"))
+            .unwrap();
+        assert_eq!(names(&fs), ["a", "b", "c", "d"]);
+
+        // the new entries do not overwrite the moved ones
+        fs.move_entry(root, "d", EntryPosition::First).unwrap();
+        let fd = fs.create_file(root, "e", FdStat::default(), 0).unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(names(&fs), ["d", "a", "b", "c", "e"]);
+        assert_eq!(fs.metadata(root).unwrap().size, 5);
+
+        fs.remove_file(root, "d").unwrap();
+        assert_eq!(names(&fs), ["a", "b", "c", "e"]);
+
+        assert_eq!(
+            fs.move_entry(root, "x", EntryPosition::First),
+            Err(Error::NotFound)
+        );
+        assert_eq!(
+            fs.move_entry(root, "a", EntryPosition::After("x")),
+            Err(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn file_copied_onto_itself_is_kept() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "a.txt", &[7; FILE_CHUNK_SIZE + 10]);
+
+        let src = fs
+            .open_or_create(root, "a.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        let dst = fs
+            .open_or_create(root, "a.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        assert_eq!(fs.copy_file(src, dst), Ok(FILE_CHUNK_SIZE as u64 + 10));
+        fs.close(src).unwrap();
+        fs.close(dst).unwrap();
+
+        assert_eq!(
+            read_test_file(&mut fs, "a.txt"),
+            vec![7; FILE_CHUNK_SIZE + 10]
+        );
+    }
+
+    #[test]
+    fn update_metadata_validates_changes() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let fd = fs
+            .create_file(root, "test.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, &[1; 100]).unwrap();
+
+        fs.update_metadata(fd, |metadata| {
+            metadata.times.created = 1;
+            metadata.times.modified = 2;
+            metadata.size = 50;
+        })
+        .unwrap();
+
+        let stat = fs.metadata(fd).unwrap();
+        assert_eq!(stat.times.created, 1);
+        assert_eq!(stat.times.modified, 2);
+        assert_eq!(stat.size, 50);
+
+        assert_eq!(
+            fs.update_metadata(fd, |metadata| metadata.file_type = FileType::Directory),
+            Err(Error::InvalidMetadata)
+        );
+        assert_eq!(
+            fs.update_metadata(fd, |metadata| metadata.size = u64::MAX),
+            Err(Error::FileTooLarge)
+        );
+        assert_eq!(
+            fs.update_metadata(root, |metadata| metadata.size += 1),
+            Err(Error::InvalidMetadata)
+        );
+        assert_eq!(
+            fs.update_metadata(fd, |metadata| metadata.link_count = 0),
+            Err(Error::InvalidMetadata)
+        );
+        assert_eq!(fs.metadata(fd).unwrap().size, 50);
+
+        // the bytes past a shorter size read as zeros when the file grows again
+        fs.update_metadata(fd, |metadata| metadata.size = 10)
+            .unwrap();
+        fs.update_metadata(fd, |metadata| metadata.size = 100)
+            .unwrap();
+        fs.close(fd).unwrap();
+        let mut expected = vec![1; 10];
+        expected.resize(100, 0);
+        assert_eq!(read_test_file(&mut fs, "test.txt"), expected);
+        assert_eq!(fs.list_dir(root).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn expired_files_are_removed() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        for (path, expiry) in [
+            ("session/a", Some(100)),
+            ("session/b", Some(200)),
+            ("uploads/c", Some(100)),
+            ("keep", None),
+        ] {
+            let fd = fs
+                .open_or_create(root, path, FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+            fs.set_expiry(fd, expiry).unwrap();
+            assert_eq!(fs.expiry(fd), Ok(expiry));
+            if path != "uploads/c" {
+                fs.close(fd).unwrap();
+            }
+        }
+
+        assert_eq!(fs.expire_now(50), Ok(0));
+
+        // the opened file stays until the next sweep
+        assert_eq!(fs.expire_now(150), Ok(1));
+        assert!(fs.open_metadata(root, "session/a").is_err());
+        assert!(fs.open_metadata(root, "session/b").is_ok());
+        assert!(fs.open_metadata(root, "uploads/c").is_ok());
+
+        // the policy keeps the files it does not allow to remove
+        struct KeepUploads;
+
+        impl Policy for KeepUploads {
+            fn before_remove(&mut self, _: Node, path: &str) -> Result<(), Error> {
+                if path.starts_with("uploads") {
+                    return Err(Error::AccessDenied);
+                }
+                Ok(())
+            }
+        }
+
+        fs.close_all_except(&[]);
+        fs.set_policy(Box::new(KeepUploads));
+        assert_eq!(fs.expire_now(1000), Ok(1));
+        assert!(fs.open_metadata(root, "session/b").is_err());
+        assert!(fs.open_metadata(root, "uploads/c").is_ok());
+        assert!(fs.open_metadata(root, "keep").is_ok());
+
+        assert_eq!(fs.set_expiry(root, Some(1)), Err(Error::InvalidFileType));
+    }
+
+    #[test]
+    fn mock_clock_drives_the_expiry() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        let clock = MockClock::new(1_000);
+
+        let fd = fs
+            .create_file(root, "session", FdStat::default(), clock.now())
+            .unwrap();
+        fs.set_expiry(fd, Some(clock.after(60))).unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(
+            fs.open_metadata(root, "session").unwrap().times.created,
+            1_000
+        );
+
+        assert_eq!(clock.advance_and_expire(&mut fs, 59), Ok(0));
+        assert_eq!(clock.advance_and_expire(&mut fs, 1), Ok(1));
+        assert_eq!(clock.now(), 1_060);
+
+        // a duration is counted in the unit of the stored times
+        let times = TimeConfig {
+            resolution: TimeResolution::Seconds,
+            ..Default::default()
+        };
+        assert_eq!(
+            clock.advance_by(std::time::Duration::from_secs(40), times),
+            1_100
+        );
+    }
+
+    #[test]
+    fn cache_directory_evicts_least_recently_used_files() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let cache = fs.create_dir(root, "cache", FdStat::default(), 0).unwrap();
+        fs.set_cache_policy(cache, 300, EvictionPolicy::Lru)
+            .unwrap();
+
+        for name in ["cache/a", "cache/b", "cache/c"] {
+            write_test_file(&mut fs, name, &[1; 100]);
+        }
+
+        // reading "a" makes "b" the least recently used file
+        read_test_file(&mut fs, "cache/a");
+        write_test_file(&mut fs, "cache/nested/d", &[2; 100]);
+
+        assert!(fs.open_metadata(root, "cache/a").is_ok());
+        assert!(fs.open_metadata(root, "cache/b").is_err());
+        assert!(fs.open_metadata(root, "cache/c").is_ok());
+        assert!(fs.open_metadata(root, "cache/nested/d").is_ok());
+
+        // the files outside of the cache directory are not evicted
+        write_test_file(&mut fs, "other", &[3; 1000]);
+        assert!(fs.open_metadata(root, "cache/c").is_ok());
+
+        fs.clear_cache_policy(cache).unwrap();
+        write_test_file(&mut fs, "cache/e", &[4; 100]);
+        assert!(fs.open_metadata(root, "cache/c").is_ok());
+    }
+
+    #[test]
+    fn cache_directory_tracks_existing_files_and_checks_the_removals() {
+        struct KeepPinned;
+
+        impl Policy for KeepPinned {
+            fn before_remove(&mut self, _: Node, path: &str) -> Result<(), Error> {
+                if path.starts_with("pinned") {
+                    return Err(Error::AccessDenied);
+                }
+                Ok(())
+            }
+        }
+
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let cache = fs.create_dir(root, "cache", FdStat::default(), 0).unwrap();
+        write_test_file(&mut fs, "cache/pinned", &[1; 100]);
+        write_test_file(&mut fs, "cache/a", &[1; 100]);
+        fs.set_policy(Box::new(KeepPinned));
+        fs.set_cache_policy(cache, 250, EvictionPolicy::Lru)
+            .unwrap();
+
+        // the files written before the policy was set are evicted, the policy keeps the pinned one
+        assert_eq!(fs.apply_file(cache, "b", &[2; 100], 0), Ok(()));
+        assert!(fs.open_metadata(root, "cache/pinned").is_ok());
+        assert!(fs.open_metadata(root, "cache/a").is_err());
+        assert!(fs.open_metadata(root, "cache/b").is_ok());
+
+        // the files removed by the user are not counted any more
+        fs.remove_file(cache, "b").unwrap();
+        write_test_file(&mut fs, "cache/c", &[3; 100]);
+        assert!(fs.open_metadata(root, "cache/c").is_ok());
+
+        // a write over the limit succeeds when nothing can be evicted
+        assert_eq!(fs.apply_file(cache, "d", &[4; 100], 0), Ok(()));
+        assert!(fs.open_metadata(root, "cache/pinned").is_ok());
+        assert!(fs.open_metadata(root, "cache/c").is_err());
+        assert_eq!(fs.apply_file(cache, "d", &[4; 300], 0), Ok(()));
+        assert_eq!(read_test_file(&mut fs, "cache/d"), vec![4; 300]);
+    }
+
+    #[test]
+    fn poll_directory_changes() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let dir = fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+        write_test_file(&mut fs, "dir/a", b"a");
+        fs.enable_changelog(dir, 3).unwrap();
+
+        let changes = fs.changes_since(dir, 0).unwrap();
+        assert!(changes.changes.is_empty());
+        assert!(changes.complete);
+
+        write_test_file(&mut fs, "dir/b", b"b");
+        write_test_file(&mut fs, "dir/a", b"changed");
+
+        let changes = fs.changes_since(dir, 0).unwrap();
+        let summary: Vec<_> = changes
+            .changes
+            .iter()
+            .map(|c| (c.name.as_str(), c.kind))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("b", ChangeKind::Created), ("a", ChangeKind::Modified)]
+        );
+        let generation = changes.generation;
+
+        fs.remove_file(dir, "a").unwrap();
+        let changes = fs.changes_since(dir, generation).unwrap();
+        assert_eq!(changes.changes.len(), 1);
+        assert_eq!(changes.changes[0].kind, ChangeKind::Removed);
+        assert!(changes.complete);
+
+        // the oldest changes are dropped beyond the capacity
+        write_test_file(&mut fs, "dir/c", b"c");
+        let changes = fs.changes_since(dir, 0).unwrap();
+        assert_eq!(changes.changes.len(), 3);
+        assert!(!changes.complete);
+        assert!(fs.changes_since(dir, generation).unwrap().complete);
+
+        fs.disable_changelog(dir).unwrap();
+        assert_eq!(fs.changes_since(dir, 0), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn changelog_reports_rewrites_of_the_same_size() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let dir = fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+        write_test_file(&mut fs, "dir/a", b"old");
+        fs.enable_changelog(dir, 10).unwrap();
+
+        let fd = fs
+            .open_or_create(root, "dir/a", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        fs.write(fd, b"new").unwrap();
+        fs.seek(fd, 0, super::Whence::SET).unwrap();
+        fs.write(fd, b"two").unwrap();
+
+        // the writes before a query are recorded once
+        let changes = fs.changes_since(dir, 0).unwrap();
+        assert_eq!(changes.changes.len(), 1);
+        assert_eq!(changes.changes[0].name, "a");
+        assert_eq!(changes.changes[0].kind, ChangeKind::Modified);
+
+        fs.seek(fd, 0, super::Whence::SET).unwrap();
+        fs.write(fd, b"new").unwrap();
+        let changes = fs.changes_since(dir, changes.generation).unwrap();
+        assert_eq!(changes.changes.len(), 1);
+        assert_eq!(changes.changes[0].kind, ChangeKind::Modified);
+        fs.close(fd).unwrap();
+
+        // the entries replaced by a rename are reported as removed and created again
+        write_test_file(&mut fs, "dir/b", b"b");
+        fs.rename_with_flags(dir, "b", dir, "a", super::RenameFlags::empty())
+            .unwrap();
+        let changes = fs.changes_since(dir, changes.generation).unwrap();
+        let summary: Vec<_> = changes
+            .changes
+            .iter()
+            .map(|c| (c.name.as_str(), c.kind))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("b", ChangeKind::Created),
+                ("a", ChangeKind::Removed),
+                ("a", ChangeKind::Created),
+                ("b", ChangeKind::Removed),
+            ]
+        );
+    }
+
+    #[cfg(feature = "write-stats")]
+    #[test]
+    fn hot_files_are_ordered_by_chunks_written() {
+        use super::WriteStats;
+
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let cold = fs.create_file(root, "cold", FdStat::default(), 0).unwrap();
+        let hot = fs.create_file(root, "hot", FdStat::default(), 0).unwrap();
+
+        fs.write(cold, &[1; 10]).unwrap();
+        for _ in 0..3 {
+            fs.write(hot, &[2; 5000]).unwrap();
+        }
+
+        let hot_node = fs.get_node(hot).unwrap();
+        let files = fs.hot_files(1);
+        assert_eq!(
+            files,
+            vec![(
+                hot_node,
+                WriteStats {
+                    writes: 3,
+                    chunks_written: 6,
+                    bytes_written: 15000,
+                }
+            )]
+        );
+        assert_eq!(fs.hot_files(10).len(), 2);
+
+        fs.reset_write_stats();
+        assert!(fs.hot_files(10).is_empty());
+    }
+
+    #[test]
+    fn directory_entry_limit() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let mut options = fs.options();
+        options.max_dir_entries = Some(2);
+        fs.set_options(options);
+
+        fs.create_file(root, "a.txt", FdStat::default(), 0).unwrap();
+        let dir = fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+
+        assert_eq!(
+            fs.create_file(root, "b.txt", FdStat::default(), 0),
+            Err(Error::DirectoryFull)
+        );
+        assert_eq!(
+            fs.create_dir(root, "new/sub", FdStat::default(), 0),
+            Err(Error::DirectoryFull)
+        );
+        assert_eq!(
+            fs.create_hard_link(root, "a.txt", root, "c.txt"),
+            Err(Error::DirectoryFull)
+        );
+        // existing entries are still reported as such
+        assert_eq!(
+            fs.create_file(root, "a.txt", FdStat::default(), 0),
+            Err(Error::FileAlreadyExists)
+        );
+
+        // renaming within a full directory is allowed
+        fs.rename(root, "a.txt", root, "b.txt").unwrap();
+
+        // the subdirectory has its own limit
+        let fd = fs.create_file(dir, "1.txt", FdStat::default(), 0).unwrap();
+        fs.create_file(root, "dir/2.txt", FdStat::default(), 0)
+            .unwrap();
+        assert_eq!(
+            fs.rename(root, "b.txt", dir, "3.txt"),
+            Err(Error::DirectoryFull)
+        );
+
+        fs.close(fd).unwrap();
+        fs.remove_file(dir, "1.txt").unwrap();
+        fs.rename(root, "b.txt", dir, "3.txt").unwrap();
+        fs.create_file(root, "c.txt", FdStat::default(), 0).unwrap();
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        let options = MountOptions {
+            name_normalizer: NameNormalizer::NfcLowercase,
+            ..Default::default()
+        };
+        let mut fs =
+            FileSystem::new_with_options(Box::new(TransientStorage::new()), options).unwrap();
+        let root = fs.root_fd();
+
+        // "Café" with a combining accent
+        let fd = fs
+            .create_file(root, "Dir/Cafe\u{301}.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, b"coffee").unwrap();
+        fs.close(fd).unwrap();
+
+        let meta = fs.open_metadata(root, "dir/caf\u{e9}.TXT").unwrap();
+        assert_eq!(meta.size, 6);
+
+        assert_eq!(
+            fs.create_file(root, "DIR/CAFE\u{301}.TXT", FdStat::default(), 0),
+            Err(Error::FileAlreadyExists)
+        );
+
+        let dir = fs
+            .open_or_create(root, "dir", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        let names: Vec<String> = fs
+            .list_dir(dir)
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                String::from_utf8(entry.name.bytes[..entry.name.length as usize].to_vec()).unwrap()
+            })
+            .collect();
+        assert_eq!(names, vec!["caf\u{e9}.txt".to_string()]);
+
+        let fd = fs
+            .rename(root, "DIR/CAF\u{c9}.txt", root, "Other.txt")
+            .unwrap();
+        fs.close(fd).unwrap();
+        fs.remove_file(root, "OTHER.TXT").unwrap();
+    }
+
+    #[test]
+    fn name_normalizer_is_stored() {
+        let memory = DefaultMemoryImpl::default();
+
+        let options = MountOptions {
+            name_normalizer: NameNormalizer::Lowercase,
+            ..Default::default()
+        };
+        let storage = StableStorage::new(memory.clone());
+        let mut fs = FileSystem::new_with_options(Box::new(storage), options).unwrap();
+        let root = fs.root_fd();
+        fs.create_dir(root, "Assets", FdStat::default(), 0).unwrap();
+
+        // a later mount uses the stored normalizer
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        assert_eq!(fs.options().name_normalizer, NameNormalizer::Lowercase);
+        let root = fs.root_fd();
+        assert!(fs.open_metadata(root, "ASSETS").is_ok());
+
+        // it cannot be changed later
+        fs.set_options(MountOptions::default());
+        assert_eq!(fs.options().name_normalizer, NameNormalizer::Lowercase);
+
+        let options = MountOptions {
+            name_normalizer: NameNormalizer::Nfc,
+            ..Default::default()
+        };
+        let storage = StableStorage::new(memory.clone());
+        assert!(matches!(
+            FileSystem::new_with_options(Box::new(storage), options),
+            Err(Error::InvalidMountOptions)
+        ));
+
+        // a normalizer is not stored with the names already stored exactly
+        let memory = DefaultMemoryImpl::default();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        write_test_file(&mut fs, "README", b"readme");
+        drop(fs);
+        let options = MountOptions {
+            name_normalizer: NameNormalizer::Lowercase,
+            ..Default::default()
+        };
+        let storage = StableStorage::new(memory.clone());
+        assert!(matches!(
+            FileSystem::new_with_options(Box::new(storage), options),
+            Err(Error::InvalidMountOptions)
+        ));
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory))).unwrap();
+        assert_eq!(fs.options().name_normalizer, NameNormalizer::Exact);
+        assert_eq!(read_test_file(&mut fs, "README"), b"readme");
+    }
+
+    #[test]
+    fn custom_name_normalizer() {
+        let options = MountOptions {
+            name_normalizer: NameNormalizer::Custom,
+            ..Default::default()
+        };
+        assert!(matches!(
+            FileSystem::new_with_options(Box::new(TransientStorage::new()), options),
+            Err(Error::InvalidMountOptions)
+        ));
+
+        let options = MountOptions {
+            name_normalizer: NameNormalizer::Custom,
+            custom_normalizer: Some(|name| name.replace(' ', "_")),
+            ..Default::default()
+        };
+        let mut fs =
+            FileSystem::new_with_options(Box::new(TransientStorage::new()), options).unwrap();
+        let root = fs.root_fd();
+
+        fs.create_dir(root, "my files", FdStat::default(), 0)
+            .unwrap();
+        assert!(fs.open_metadata(root, "my_files").is_ok());
+    }
+
+    #[test]
+    fn sorted_listing_follows_the_collation() {
+        use std::cmp::Ordering;
+
+        let names = |fs: &FileSystem| {
+            let entries = fs.list_dir_sorted(fs.root_fd()).unwrap();
+            entries
+                .iter()
+                .map(|entry| {
+                    String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize])
+                        .into_owned()
+                })
+                .collect::<Vec<_>>()
+        };
+        let memory = DefaultMemoryImpl::default();
+
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        for name in ["b", "A", "a", "C"] {
+            write_test_file(&mut fs, name, b"x");
+        }
+        assert_eq!(names(&fs), ["A", "C", "a", "b"]);
+
+        // the first mount asking for a collation stores it
+        let options = MountOptions {
+            collation: Collation::AsciiCaseInsensitive,
+            ..Default::default()
+        };
+        let storage = StableStorage::new(memory.clone());
+        let fs = FileSystem::new_with_options(Box::new(storage), options).unwrap();
+        assert_eq!(names(&fs), ["A", "a", "b", "C"]);
+
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        assert_eq!(fs.options().collation, Collation::AsciiCaseInsensitive);
+        fs.set_options(MountOptions::default());
+        assert_eq!(names(&fs), ["A", "a", "b", "C"]);
+
+        // a different collation cannot be mounted later
+        let storage = StableStorage::new(memory.clone());
+        assert!(matches!(
+            FileSystem::builder()
+                .storage(Box::new(storage))
+                .custom_collation(|a, b| b.cmp(a))
+                .build(),
+            Err(Error::InvalidMountOptions)
+        ));
+
+        // a custom collation needs its comparator
+        let options = MountOptions {
+            collation: Collation::Custom,
+            ..Default::default()
+        };
+        assert!(matches!(
+            FileSystem::new_with_options(Box::new(TransientStorage::new()), options),
+            Err(Error::InvalidMountOptions)
+        ));
+
+        let reverse: fn(&str, &str) -> Ordering = |a, b| b.cmp(a);
+        let options = MountOptions {
+            collation: Collation::Custom,
+            custom_collation: Some(reverse),
+            ..Default::default()
+        };
+        let mut fs =
+            FileSystem::new_with_options(Box::new(TransientStorage::new()), options).unwrap();
+        for name in ["b", "A", "c"] {
+            write_test_file(&mut fs, name, b"x");
+        }
+        assert_eq!(names(&fs), ["c", "b", "A"]);
+    }
+
+    #[test]
+    fn open_with_nofollow_any() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+        write_test_file(&mut fs, "dir/file.txt", b"data");
+        fs.create_alias(root, "link", "dir", 0).unwrap();
+        fs.create_alias(root, "dir/file_link", "dir/file.txt", 0)
+            .unwrap();
+
+        for path in ["link", "link/file.txt", "dir/file_link"] {
+            assert_eq!(
+                fs.open_or_create(root, path, FdStat::default(), OpenFlags::NOFOLLOW_ANY, 0),
+                Err(Error::TooManySymbolicLinks)
+            );
+        }
+        // no file is created behind the link
+        assert_eq!(
+            fs.open_or_create(
+                root,
+                "link/new.txt",
+                FdStat::default(),
+                OpenFlags::NOFOLLOW_ANY | OpenFlags::CREATE,
+                0
+            ),
+            Err(Error::TooManySymbolicLinks)
+        );
+
+        let fd = fs
+            .open_or_create(
+                root,
+                "dir/file.txt",
+                FdStat::default(),
+                OpenFlags::NOFOLLOW_ANY,
+                0,
+            )
+            .unwrap();
+        fs.close(fd).unwrap();
+        let fd = fs
+            .open_or_create(
+                root,
+                "dir/new.txt",
+                FdStat::default(),
+                OpenFlags::NOFOLLOW_ANY | OpenFlags::CREATE,
+                0,
+            )
+            .unwrap();
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn sandbox_audit_and_enforcement() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        write_test_file(&mut fs, "secret/key.txt", b"key");
+        write_test_file(&mut fs, "jail/file.txt", b"data");
+        fs.create_alias(root, "jail/out", "secret/key.txt", 0)
+            .unwrap();
+        fs.create_alias(root, "jail/in", "jail/file.txt", 0)
+            .unwrap();
+        fs.create_alias(root, "jail/dir", "secret", 0).unwrap();
+
+        let jail = fs
+            .open_or_create(root, "jail", FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+        fs.set_sandbox_boundary(jail).unwrap();
+
+        // nothing is recorded without the sandbox mode
+        assert!(fs.open_metadata(root, "../jail").is_err());
+        assert!(fs.take_path_audit().is_empty());
+
+        let mut options = fs.options();
+        options.sandbox = SandboxMode::Audit;
+        fs.set_options(options);
+
+        assert_eq!(
+            fs.open_metadata(root, "jail/../secret"),
+            Err(Error::InvalidFileName)
+        );
+        assert!(fs.open_metadata(root, "/jail").is_err());
+        assert_eq!(
+            fs.resolve_alias(root, "jail/out"),
+            Ok("secret/key.txt".to_string())
+        );
+        assert_eq!(fs.resolve_alias(root, "jail/dir"), Ok("secret".to_string()));
+        assert_eq!(
+            fs.resolve_alias(root, "jail/in"),
+            Ok("jail/file.txt".to_string())
+        );
+
+        let violations: Vec<PathViolation> = fs
+            .take_path_audit()
+            .into_iter()
+            .map(|record| {
+                assert!(!record.rejected);
+                record.violation
+            })
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                PathViolation::ParentReference,
+                PathViolation::AbsolutePath,
+                PathViolation::AliasEscape,
+                PathViolation::AliasEscape,
+            ]
+        );
+
+        options.sandbox = SandboxMode::Enforce;
+        fs.set_options(options);
+
+        assert_eq!(
+            fs.resolve_alias(root, "jail/out"),
+            Err(Error::SandboxViolation)
+        );
+        assert_eq!(
+            fs.resolve_alias(root, "jail/in"),
+            Ok("jail/file.txt".to_string())
+        );
+
+        let audit = fs.take_path_audit();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].path, "jail/out");
+        assert!(audit[0].rejected);
+    }
+
+    #[test]
+    fn sandbox_checks_paths_without_aliases() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        write_test_file(&mut fs, "secret/key.txt", b"key");
+        write_test_file(&mut fs, "jail/cell/file.txt", b"data");
+
+        let jail = fs
+            .open_or_create(root, "jail", FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+        let cell = fs
+            .open_or_create(
+                root,
+                "jail/cell",
+                FdStat::default(),
+                OpenFlags::DIRECTORY,
+                0,
+            )
+            .unwrap();
+        fs.set_sandbox_boundary(jail).unwrap();
+
+        let mut options = fs.options();
+        options.sandbox = SandboxMode::Enforce;
+        fs.set_options(options);
+
+        // the paths resolved inside the boundary or from its parents are allowed
+        assert_eq!(fs.open_metadata(cell, "file.txt").unwrap().size, 4);
+        assert_eq!(fs.open_metadata(root, "secret/key.txt").unwrap().size, 3);
+        assert!(fs.take_path_audit().is_empty());
+
+        // ".." and absolute paths do not leave a directory of the boundary
+        assert_eq!(
+            fs.open_metadata(jail, "../secret/key.txt").map(|_| ()),
+            Err(Error::SandboxViolation)
+        );
+        assert_eq!(
+            fs.open_metadata(cell, "/secret/key.txt").map(|_| ()),
+            Err(Error::SandboxViolation)
+        );
+
+        // a directory moved out of the boundary while opened is outside of it
+        fs.rename(root, "jail/cell", root, "secret/cell").unwrap();
+        assert_eq!(
+            fs.open_metadata(cell, "file.txt").map(|_| ()),
+            Err(Error::SandboxViolation)
+        );
+        assert_eq!(
+            fs.create_file(cell, "new.txt", FdStat::default(), 0)
+                .map(|_| ()),
+            Err(Error::SandboxViolation)
+        );
+        assert_eq!(
+            fs.open_metadata(root, "secret/cell/new.txt").map(|_| ()),
+            Err(Error::NotFound)
+        );
+
+        let violations: Vec<(PathViolation, bool)> = fs
+            .take_path_audit()
+            .into_iter()
+            .map(|record| (record.violation, record.rejected))
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                (PathViolation::ParentReference, true),
+                (PathViolation::AbsolutePath, true),
+                (PathViolation::BoundaryEscape, true),
+                (PathViolation::BoundaryEscape, true),
+            ]
+        );
+
+        // the audit mode records the escape and resolves the path
+        options.sandbox = SandboxMode::Audit;
+        fs.set_options(options);
+        assert_eq!(fs.open_metadata(cell, "file.txt").unwrap().size, 4);
+        let audit = fs.take_path_audit();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].violation, PathViolation::BoundaryEscape);
+        assert!(!audit[0].rejected);
+    }
+
+    #[test]
+    fn proc_files_show_current_state() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        write_test_file(&mut fs, "assets/index.html", b"<html></html>");
+        fs.mount_proc(root, "proc", 0).unwrap();
+
+        let version = String::from_utf8(read_test_file(&mut fs, "proc/version")).unwrap();
+        assert!(version.starts_with(&format!("stable-fs {}", env!("CARGO_PKG_VERSION"))));
+
+        let stats = String::from_utf8(read_test_file(&mut fs, "proc/stats")).unwrap();
+        assert!(stats.contains("files 1\n"));
+        assert!(stats.contains("directories 2\n"));
+        assert!(stats.contains("reusable_bytes 0\n"));
+
+        let mounts = String::from_utf8(read_test_file(&mut fs, "proc/mounts")).unwrap();
+        assert!(mounts.contains("/proc proc\n"));
+
+        // the contents are generated on each open
+        let file_fd = fs
+            .open_or_create(
+                root,
+                "assets/index.html",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+        let fds = String::from_utf8(read_test_file(&mut fs, "proc/fds")).unwrap();
+        let node = fs.get_test_file(file_fd).node;
+        assert!(fds.contains(&format!("{file_fd} file {node} ")));
+
+        // the introspection files are read-only
+        let fd = fs
+            .open_or_create(
+                root,
+                "proc/stats",
+                FdStat::default(),
+                OpenFlags::TRUNCATE,
+                0,
+            )
+            .unwrap();
+        assert_eq!(fs.write(fd, b"changed"), Err(Error::InvalidFileDescriptor));
+        fs.close(fd).unwrap();
+        let stats_after = String::from_utf8(read_test_file(&mut fs, "proc/stats")).unwrap();
+        assert!(stats_after.starts_with("files 1\n"));
+    }
+
+    #[test]
+    fn proc_files_are_not_stored() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        fs.mount_proc(root, "proc", 0).unwrap();
+        let proc_dir = fs
+            .open_or_create(root, "proc", FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+        assert!(fs.list_dir(proc_dir).unwrap().is_empty());
+        assert_eq!(fs.open_metadata(root, "proc/stats"), Err(Error::NotFound));
+
+        // the contents are generated without changing the tree, also in the upgrade mode
+        let hash = fs.merkle_hash(proc_dir).unwrap();
+        fs.enter_upgrade_mode().unwrap();
+        let fd = fs
+            .open_or_create(
+                root,
+                "proc/version",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+        let size = fs.metadata(fd).unwrap().size;
+        assert_eq!(fs.seek(fd, 8, super::Whence::SET), Ok(8));
+        let mut buf = vec![0; size as usize];
+        assert_eq!(fs.read(fd, &mut buf), Ok(size - 8));
+        assert_eq!(fs.tell(fd), Ok(size));
+        assert_eq!(fs.set_size(fd, 0), Err(Error::InvalidFileDescriptor));
+        fs.close(fd).unwrap();
+        assert_eq!(
+            fs.read_path(root, "proc/version", 0, 9).unwrap(),
+            b"stable-fs"
+        );
+        fs.exit_upgrade_mode();
+        assert_eq!(fs.merkle_hash(proc_dir), Ok(hash));
+        assert_eq!(
+            fs.write_path(root, "proc/stats", 0, b"x", true, 0),
+            Err(Error::InvalidFileDescriptor)
+        );
+        fs.close(proc_dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_removals() {
+        let mut fs = fs_with_tree(&[
+            ("logs/a.log", b"12345"),
+            ("logs/old/b.log", b"123"),
+            ("tmp.txt", b"1234567"),
+        ]);
+        let root = fs.root_fd();
+
+        assert_eq!(
+            fs.remove_dry_run(root, "logs", RemoveOptions::dir()),
+            Err(Error::DirectoryNotEmpty)
+        );
+
+        let options = RemoveOptions {
+            must_be_dir: Some(true),
+            must_be_empty: false,
+            recursive: true,
+            follow_symlinks: false,
+            zeroing: None,
+        };
+        let report = fs.remove_dry_run(root, "logs", options).unwrap();
+        assert_eq!(
+            report.paths,
+            vec!["logs", "logs/a.log", "logs/old", "logs/old/b.log"]
+        );
+        assert_eq!((report.files, report.directories, report.bytes), (2, 2, 8));
+
+        // nothing was removed
+        assert_eq!(read_test_file(&mut fs, "logs/old/b.log"), b"123");
+
+        let fd = fs
+            .open_or_create(root, "tmp.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        fs.set_expiry(fd, Some(10)).unwrap();
+        let log = fs
+            .open_or_create(root, "logs/a.log", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        fs.set_expiry(log, Some(20)).unwrap();
+        fs.close(log).unwrap();
+
+        let report = fs.expire_now_dry_run(30).unwrap();
+        // the opened file is kept
+        assert_eq!(report.paths, vec!["logs/a.log"]);
+        assert_eq!(report.bytes, 5);
+
+        fs.close(fd).unwrap();
+        let report = fs.expire_now_dry_run(30).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.bytes, 12);
+        assert_eq!(fs.expire_now(30), Ok(2));
+    }
+
+    #[test]
+    fn publish_checks_the_hash() {
+        use sha2::{Digest, Sha256};
+
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        write_test_file(&mut fs, "assets/app.js", b"old");
+
+        let data = b"console.log(1)";
+        let hash: [u8; 32] = Sha256::digest(data).into();
+
+        assert_eq!(
+            fs.publish(root, "assets/app.js", data, &[0; 32], 0),
+            Err(Error::IntegrityCheckFailed)
+        );
+        assert_eq!(read_test_file(&mut fs, "assets/app.js"), b"old");
+
+        fs.publish(root, "assets/app.js", data, &hash, 0).unwrap();
+        assert_eq!(read_test_file(&mut fs, "assets/app.js"), data);
+
+        fs.publish(root, "assets/new.js", data, &hash, 0).unwrap();
+        assert_eq!(read_test_file(&mut fs, "assets/new.js"), data);
+
+        // no temporary files are left
+        let dir = fs
+            .open_or_create(root, "assets", FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+        assert_eq!(fs.list_dir(dir).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn publish_leaves_other_files_and_failures_untouched() {
+        use sha2::{Digest, Sha256};
+
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "app.js", b"old");
+        write_test_file(&mut fs, "app.js.publishing", b"user data");
+
+        let data = b"console.log(2)";
+        let hash: [u8; 32] = Sha256::digest(data).into();
+        fs.publish(root, "app.js", data, &hash, 0).unwrap();
+        assert_eq!(read_test_file(&mut fs, "app.js"), data);
+        assert_eq!(read_test_file(&mut fs, "app.js.publishing"), b"user data");
+        assert_eq!(fs.list_dir(root).unwrap().len(), 2);
+
+        // the storage fails while the new contents are written
+        let budget = Rc::new(Cell::new(None));
+        let mut fs = FileSystem::new(Box::new(FaultyStorage::new(budget.clone()))).unwrap();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "app.js", b"old");
+        budget.set(Some(0));
+        assert_eq!(
+            fs.publish(root, "app.js", data, &hash, 0),
+            Err(Error::StorageFull)
+        );
+        budget.set(None);
+        assert_eq!(read_test_file(&mut fs, "app.js"), b"old");
+        assert_eq!(fs.list_dir(root).unwrap().len(), 1);
+        assert_eq!(fs.open_fds().count(), 1);
+    }
+
+    #[test]
+    fn concat_parts() {
+        for mut fs in [test_fs(), test_fs_transient()] {
+            let root = fs.root_fd();
+
+            // the first two parts are moved, the third one starts inside a chunk and is copied
+            let parts: Vec<Vec<u8>> = vec![
+                (0..FILE_CHUNK_SIZE * 2).map(|i| i as u8).collect(),
+                vec![7; 100],
+                (0..FILE_CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect(),
+            ];
+            for (i, part) in parts.iter().enumerate() {
+                write_test_file(&mut fs, &format!("upload/part{i}"), part);
+            }
+
+            write_test_file(&mut fs, "upload/other", b"x");
+            let fd = fs
+                .open_or_create(
+                    root,
+                    "upload/other",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )
+                .unwrap();
+            assert_eq!(
+                fs.concat(root, "upload/full", &["upload/part0", "upload/other"], 0),
+                Err(Error::CannotRemoveOpenedNode)
+            );
+            assert_eq!(
+                fs.concat(root, "upload/part0", &["upload/part0"], 0),
+                Err(Error::InvalidFileName)
+            );
+            fs.close(fd).unwrap();
+
+            let size = fs
+                .concat(
+                    root,
+                    "upload/full",
+                    &["upload/part0", "upload/part1", "upload/part2"],
+                    0,
+                )
+                .unwrap();
+
+            assert_eq!(
+                size,
+                parts.iter().map(|part| part.len() as u64).sum::<u64>()
+            );
+            assert_eq!(read_test_file(&mut fs, "upload/full"), parts.concat());
+            assert_eq!(fs.open_metadata(root, "upload/part0"), Err(Error::NotFound));
+
+            let dir = fs
+                .open_or_create(root, "upload", FdStat::default(), OpenFlags::DIRECTORY, 0)
+                .unwrap();
+            assert_eq!(fs.list_dir(dir).unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn split_into_parts() {
+        for mut fs in [test_fs(), test_fs_transient()] {
+            let root = fs.root_fd();
+            let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 5 + 10)
+                .map(|i| (i % 251) as u8)
+                .collect();
+
+            // the chunk-sized parts are moved
+            write_test_file(&mut fs, "blob", &data);
+            let parts = fs
+                .split(root, "blob", FILE_CHUNK_SIZE as u64 * 2, 0)
+                .unwrap();
+            assert_eq!(parts, vec!["blob.000", "blob.001", "blob.002"]);
+            assert_eq!(
+                read_test_file(&mut fs, "blob.002").len(),
+                FILE_CHUNK_SIZE + 10
+            );
+            assert_eq!(fs.open_metadata(root, "blob"), Err(Error::NotFound));
+
+            let parts: Vec<&str> = parts.iter().map(|part| part.as_str()).collect();
+            fs.concat(root, "blob", &parts, 0).unwrap();
+            assert_eq!(read_test_file(&mut fs, "blob"), data);
+
+            // the parts inside the chunks are copied
+            let parts = fs.split(root, "blob", 1000, 0).unwrap();
+            assert_eq!(parts.len(), data.len().div_ceil(1000));
+            let joined: Vec<u8> = parts
+                .iter()
+                .flat_map(|part| read_test_file(&mut fs, part))
+                .collect();
+            assert_eq!(joined, data);
+
+            // the existing parts are not replaced
+            write_test_file(&mut fs, "small", b"abc");
+            assert_eq!(
+                fs.split(root, "small", 0, 0),
+                Err(Error::InvalidBufferLength)
+            );
+            write_test_file(&mut fs, "small.001", b"x");
+            assert_eq!(fs.split(root, "small", 2, 0), Err(Error::FileAlreadyExists));
+            assert_eq!(read_test_file(&mut fs, "small"), b"abc");
+            assert_eq!(fs.open_metadata(root, "small.000"), Err(Error::NotFound));
+        }
+    }
+
+    #[test]
+    fn swap_files() {
+        let mut fs = fs_with_tree(&[
+            ("site/index.html", b"blue"),
+            ("staging/index.html", b"green"),
+        ]);
+        let root = fs.root_fd();
+
+        // a reader opened before the swap keeps the old version
+        let reader = fs
+            .open_or_create(
+                root,
+                "site/index.html",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+
+        fs.swap(root, "site/index.html", root, "staging/index.html")
+            .unwrap();
+        assert_eq!(read_test_file(&mut fs, "site/index.html"), b"green");
+        assert_eq!(read_test_file(&mut fs, "staging/index.html"), b"blue");
+
+        let mut buf = [0; 4];
+        fs.read(reader, &mut buf).unwrap();
+        assert_eq!(&buf, b"blue");
+        fs.close(reader).unwrap();
+
+        assert_eq!(
+            fs.swap(root, "site/index.html", root, "staging"),
+            Err(Error::IsDirectory)
+        );
+        assert_eq!(
+            fs.swap(root, "site/index.html", root, "missing.html"),
+            Err(Error::NotFound)
+        );
+        assert_eq!(read_test_file(&mut fs, "site/index.html"), b"green");
+    }
+
+    #[test]
+    fn frozen_file_versions() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        write_test_file(&mut fs, "video.bin", b"version 1");
+        let fd = fs
+            .open_or_create(root, "video.bin", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        let first = fs.freeze_node(fd).unwrap();
+        let also_first = fs.freeze_node(fd).unwrap();
+        fs.close(fd).unwrap();
+        // the frozen file takes no descriptor
+        assert_eq!(fs.open_fds().count(), 1);
+
+        // the file is rewritten between the reads of the snapshot
+        let mut buf = [0; 4];
+        assert_eq!(fs.read_snapshot(first, 0, &mut buf), Ok(4));
+        write_test_file(&mut fs, "video.bin", b"v2");
+        assert_eq!(fs.read_snapshot(first, 4, &mut buf), Ok(4));
+        assert_eq!(&buf, b"ion ");
+        assert_eq!(fs.snapshot_size(first), Ok(9));
+        assert_eq!(read_test_file(&mut fs, "video.bin"), b"v2");
+
+        // the snapshots share the copy
+        fs.release_snapshot(first).unwrap();
+        assert_eq!(fs.read_snapshot(also_first, 8, &mut buf), Ok(1));
+        assert_eq!(buf[0], b'1');
+
+        // a frozen file counts as opened
+        assert_eq!(
+            fs.remove_file(root, "video.bin"),
+            Err(Error::CannotRemoveOpenedNode)
+        );
+        fs.release_snapshot(also_first).unwrap();
+        assert_eq!(fs.release_snapshot(also_first), Err(Error::NotFound));
+        fs.remove_file(root, "video.bin").unwrap();
+        assert_eq!(fs.open_fds().count(), 1);
+    }
+
+    #[test]
+    fn frozen_versions_copy_only_the_changed_chunks() {
+        use super::Whence;
+        use crate::storage::types::{FileSize, FILE_CHUNK_SIZE};
+
+        let memory = DefaultMemoryImpl::default();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root = fs.root_fd();
+
+        let data: Vec<u8> = (0..3 * FILE_CHUNK_SIZE).map(|i| (i / 1000) as u8).collect();
+        write_test_file(&mut fs, "big.bin", &data);
+        let fd = fs
+            .open_or_create(root, "big.bin", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        let token = fs.freeze_node(fd).unwrap();
+
+        // a write into the middle chunk copies only that chunk
+        fs.seek(fd, FILE_CHUNK_SIZE as i64 + 10, Whence::SET)
+            .unwrap();
+        fs.write(fd, b"changed").unwrap();
+        fs.write(fd, b"again").unwrap();
+        let copy = fs.frozen.values().next().unwrap().copy.unwrap();
+        assert_eq!(fs.storage.filechunk_indices(copy, 3), vec![1]);
+
+        // the truncated chunks are copied before they are dropped
+        fs.set_size(fd, 10).unwrap();
+        assert_eq!(fs.storage.filechunk_indices(copy, 3), vec![0, 1, 2]);
+
+        let mut buf = vec![0; data.len() + 5];
+        assert_eq!(
+            fs.read_snapshot(token, 0, &mut buf),
+            Ok(data.len() as FileSize)
+        );
+        assert_eq!(&buf[..data.len()], &data[..]);
+        let mut buf = [0; 4];
+        fs.read_snapshot(token, FILE_CHUNK_SIZE as FileSize - 2, &mut buf)
+            .unwrap();
+        assert_eq!(&buf, &data[FILE_CHUNK_SIZE - 2..FILE_CHUNK_SIZE + 2]);
+
+        // the copy is not kept over a reload without its token
+        drop(fs);
+        let fs = FileSystem::new(Box::new(StableStorage::new(memory))).unwrap();
+        assert_eq!(fs.metadata_from_node(copy), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn policy_rejects_operations() {
+        use std::{cell::Cell, rc::Rc};
+
+        struct Quota {
+            written: Rc<Cell<u64>>,
+            max_bytes: u64,
+        }
+
+        impl Policy for Quota {
+            fn before_create(
+                &mut self,
+                _parent: Node,
+                path: &str,
+                _file_type: FileType,
+            ) -> Result<(), Error> {
+                if path.ends_with(".exe") {
+                    return Err(Error::InvalidFileName);
+                }
+                Ok(())
+            }
+
+            fn before_write(&mut self, _node: Node, _offset: u64, size: u64) -> Result<(), Error> {
+                if self.written.get() + size > self.max_bytes {
+                    return Err(Error::FileTooLarge);
+                }
+                self.written.set(self.written.get() + size);
+                Ok(())
+            }
+
+            fn before_remove(&mut self, _parent: Node, path: &str) -> Result<(), Error> {
+                if path == "keep" {
+                    return Err(Error::CannotRemoveOpenedNode);
+                }
+                Ok(())
+            }
+        }
+
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "old.exe", b"1");
+        let written = Rc::new(Cell::new(0));
+        fs.set_policy(Box::new(Quota {
+            written: written.clone(),
+            max_bytes: 10,
+        }));
+
+        write_test_file(&mut fs, "a.txt", b"12345678");
+        assert_eq!(
+            fs.apply_file(root, "b.txt", b"123", 0),
+            Err(Error::FileTooLarge)
+        );
+        assert_eq!(written.get(), 8);
+
+        assert_eq!(
+            fs.create_file(root, "setup.exe", FdStat::default(), 0),
+            Err(Error::InvalidFileName)
+        );
+        let fd = fs.rename(root, "a.txt", root, "keep").unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(
+            fs.rename(root, "keep", root, "a.exe"),
+            Err(Error::InvalidFileName)
+        );
+        assert_eq!(
+            fs.remove_file(root, "keep"),
+            Err(Error::CannotRemoveOpenedNode)
+        );
+
+        // an exchange creates and removes both names
+        write_test_file(&mut fs, "c", b"2");
+        assert_eq!(
+            fs.swap(root, "old.exe", root, "c"),
+            Err(Error::InvalidFileName)
+        );
+        assert_eq!(
+            fs.swap(root, "c", root, "keep"),
+            Err(Error::CannotRemoveOpenedNode)
+        );
+        assert_eq!(
+            fs.rename_with_flags(root, "keep", root, "c", super::RenameFlags::EXCHANGE),
+            Err(Error::CannotRemoveOpenedNode)
+        );
+        assert_eq!(read_test_file(&mut fs, "keep"), b"12345678");
+
+        assert!(fs.take_policy().is_some());
+        fs.remove_file(root, "keep").unwrap();
+    }
+
+    #[test]
+    fn spans_are_recorded() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let mut options = fs.options();
+        options.sandbox = SandboxMode::Audit;
+        fs.set_options(options);
+
+        {
+            let mut request = fs.span(7);
+            assert!(request.open_metadata(root, "/a").is_err());
+
+            let step = request.span(8);
+            assert!(step.open_metadata(root, "../a").is_err());
+            assert_eq!(step.current_span(), Some(8));
+        }
+        assert_eq!(fs.current_span(), None);
+        assert!(fs.open_metadata(root, "/b").is_err());
+
+        let spans: Vec<Option<u64>> = fs
+            .take_path_audit()
+            .into_iter()
+            .map(|record| record.span)
+            .collect();
+        assert_eq!(spans, vec![Some(7), Some(8), None]);
+    }
+
+    fn faulty_fs(write_failure: WriteFailure) -> (FileSystem, Rc<Cell<Option<usize>>>) {
+        let budget = Rc::new(Cell::new(None));
+        let options = MountOptions {
+            write_failure,
+            ..Default::default()
+        };
+        let storage = FaultyStorage::new(budget.clone());
+        let fs = FileSystem::new_with_options(Box::new(storage), options).unwrap();
+        (fs, budget)
+    }
+
+    #[test]
+    fn failed_writes_keep_the_written_part() {
+        let (mut fs, budget) = faulty_fs(WriteFailure::KeepPartial);
+        let fd = fs
+            .create_file(fs.root_fd(), "f", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, &[1; FILE_CHUNK_SIZE * 4]).unwrap();
+
+        // two chunks are overwritten, the quota is hit on the second appended chunk
+        budget.set(Some(1));
+        fs.seek(fd, FILE_CHUNK_SIZE as i64 * 2, super::Whence::SET)
+            .unwrap();
+        assert_eq!(
+            fs.write(fd, &[2; FILE_CHUNK_SIZE * 4]),
+            Ok(FILE_CHUNK_SIZE as u64 * 3)
+        );
+        let end = FILE_CHUNK_SIZE as u64 * 5;
+        assert_eq!(fs.tell(fd), Ok(end));
+        assert_eq!(fs.metadata(fd).unwrap().size, end);
+
+        let mut buf = vec![0; FILE_CHUNK_SIZE * 5];
+        fs.read_vec_with_offset(
+            fd,
+            &[DstBuf {
+                buf: buf.as_mut_ptr(),
+                len: buf.len(),
+            }],
+            0,
+        )
+        .unwrap();
+        assert!(buf[..FILE_CHUNK_SIZE * 2].iter().all(|b| *b == 1));
+        assert!(buf[FILE_CHUNK_SIZE * 2..].iter().all(|b| *b == 2));
+
+        // nothing is written
+        let data = [3; 10];
+        let src = [SrcBuf {
+            buf: data.as_ptr(),
+            len: data.len(),
+        }];
+        assert_eq!(
+            fs.write_vec_with_offset(fd, &src, end),
+            Err(Error::StorageFull)
+        );
+        assert_eq!(fs.metadata(fd).unwrap().size, end);
+    }
+
+    #[test]
+    fn failed_concat_changes_nothing() {
+        let (mut fs, budget) = faulty_fs(WriteFailure::KeepPartial);
+        let root = fs.root_fd();
+
+        // the first two parts are moved, the third one is copied and needs a new chunk
+        let parts: Vec<Vec<u8>> = vec![
+            vec![1; FILE_CHUNK_SIZE * 2],
+            vec![2; 100],
+            vec![3; FILE_CHUNK_SIZE + 10],
+        ];
+        for (i, part) in parts.iter().enumerate() {
+            write_test_file(&mut fs, &format!("part{i}"), part);
+        }
+        write_test_file(&mut fs, "full", b"old");
+
+        budget.set(Some(0));
+        assert_eq!(
+            fs.concat(root, "full", &["part0", "part1", "part2"], 0),
+            Err(Error::StorageFull)
+        );
+        budget.set(None);
+
+        assert_eq!(read_test_file(&mut fs, "full"), b"old");
+        for (i, part) in parts.iter().enumerate() {
+            assert_eq!(&read_test_file(&mut fs, &format!("part{i}")), part);
+        }
+
+        // the bytes copied after the end of a moved part are zeroed
+        let node = fs.open_metadata(root, "part1").unwrap().node;
+        let mut chunk = vec![1; FILE_CHUNK_SIZE];
+        fs.storage.read_filechunk(node, 0, 0, &mut chunk).unwrap();
+        assert!(chunk[100..].iter().all(|b| *b == 0));
+
+        // the unnamed file is removed
+        assert!(fs.storage.orphans().is_empty());
+        assert_eq!(fs.list_dir(root).unwrap().len(), 4);
+        assert_eq!(fs.open_fds().count(), 1);
+    }
+
+    #[test]
+    fn failed_split_keeps_the_source() {
+        let (mut fs, budget) = faulty_fs(WriteFailure::KeepPartial);
+        let root = fs.root_fd();
+
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        write_test_file(&mut fs, "blob", &data);
+
+        // the parts inside the chunks are copied and need new chunks
+        budget.set(Some(0));
+        assert_eq!(fs.split(root, "blob", 1000, 0), Err(Error::StorageFull));
+        budget.set(None);
+
+        assert_eq!(read_test_file(&mut fs, "blob"), data);
+        assert_eq!(fs.list_dir(root).unwrap().len(), 1);
+        assert_eq!(fs.open_fds().count(), 1);
+
+        // an empty file is split into one empty part
+        write_test_file(&mut fs, "empty", b"");
+        assert_eq!(
+            fs.split(root, "empty", 10, 0),
+            Ok(vec!["empty.000".to_string()])
+        );
+        assert_eq!(read_test_file(&mut fs, "empty.000"), b"");
+        assert_eq!(fs.open_metadata(root, "empty"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn failed_writes_are_rolled_back() {
+        let (mut fs, budget) = faulty_fs(WriteFailure::Rollback);
+        let fd = fs
+            .create_file(fs.root_fd(), "f", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, &[1; FILE_CHUNK_SIZE * 4]).unwrap();
+        let node = fs.metadata(fd).unwrap().node;
+
+        // the second buffer appends a chunk and fails on the next one
+        budget.set(Some(1));
+        let first = [2; FILE_CHUNK_SIZE * 2];
+        let second = [3; FILE_CHUNK_SIZE * 3];
+        let src = [
+            SrcBuf {
+                buf: first.as_ptr(),
+                len: first.len(),
+            },
+            SrcBuf {
+                buf: second.as_ptr(),
+                len: second.len(),
+            },
+        ];
+        assert_eq!(
+            fs.write_vec_with_offset(fd, &src, FILE_CHUNK_SIZE as u64 * 2),
+            Err(Error::StorageFull)
+        );
+
+        budget.set(None);
+        assert_eq!(fs.metadata(fd).unwrap().size, FILE_CHUNK_SIZE as u64 * 4);
+        assert_eq!(fs.storage.filechunk_indices(node, 8), vec![0, 1, 2, 3]);
+
+        let mut buf = vec![0; FILE_CHUNK_SIZE * 4];
+        fs.read_vec_with_offset(
+            fd,
+            &[DstBuf {
+                buf: buf.as_mut_ptr(),
+                len: buf.len(),
+            }],
+            0,
+        )
+        .unwrap();
+        assert!(buf.iter().all(|b| *b == 1));
+
+        // the cursor is not moved by a rolled back write
+        budget.set(Some(0));
+        assert_eq!(fs.write(fd, &[4; 10]), Err(Error::StorageFull));
+        assert_eq!(fs.tell(fd), Ok(FILE_CHUNK_SIZE as u64 * 4));
+    }
+
+    #[test]
+    fn fd_provenance_is_recorded() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+
+        // the location of the call is recorded
+        let line = line!() + 2;
+        let fd = fs
+            .create_file(root_fd, "f.txt", FdStat::default(), 0)
+            .unwrap();
+
+        let provenance = fs.fd_provenance(fd).unwrap();
+        assert_eq!(provenance.path.as_deref(), Some("f.txt"));
+        assert_eq!(provenance.location.file(), file!());
+        assert_eq!(provenance.location.line(), line);
+
+        assert_eq!(
+            format!("{fd} {fd:?}"),
+            format!("{} Fd({})", fd.raw(), fd.raw())
+        );
+        assert_eq!(Fd::from(u32::from(fd)), fd);
+
+        fs.close(fd).unwrap();
+        assert_eq!(fs.fd_provenance(fd), None);
+    }
+
+    #[test]
+    fn fd_views_and_dir_cursor() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+
+        for name in ["a", "b", "c"] {
+            let fd = fs.create_file(root_fd, name, FdStat::default(), 0).unwrap();
+            fs.close(fd).unwrap();
+        }
+        let fd = fs
+            .open_or_create(root_fd, "b", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        fs.write(fd, &[1, 2, 3]).unwrap();
+
+        let view = fs.fd_view(fd).unwrap();
+        assert_eq!(
+            (view.fd, view.file_type, view.cursor),
+            (fd, FileType::RegularFile, 3)
+        );
+        assert_eq!(view.node, fs.metadata(fd).unwrap().node);
+        assert_eq!(
+            fs.fd_views().map(|view| view.fd).collect::<Vec<_>>(),
+            vec![root_fd, fd]
+        );
+        assert_eq!(fs.fd_view(Fd::new(100)).map(|_| ()), Err(Error::NotFound));
+
+        // the directory entries are listed in portions
+        let names = |entries: Vec<DirEntry>| -> Vec<Vec<u8>> {
+            entries
+                .iter()
+                .map(|entry| entry.name.bytes[..entry.name.length as usize].to_vec())
+                .collect()
+        };
+        assert_eq!(names(fs.list_dir_next(root_fd, 2).unwrap()), [b"a", b"b"]);
+        assert_eq!(fs.fd_view(root_fd).unwrap().cursor, 2);
+        assert_eq!(names(fs.list_dir_next(root_fd, 2).unwrap()), [b"c"]);
+        assert!(fs.list_dir_next(root_fd, 2).unwrap().is_empty());
+
+        // changing the directory keeps the position
+        fs.close(fd).unwrap();
+        fs.remove(root_fd, "a", RemoveOptions::file()).unwrap();
+        assert_eq!(fs.fd_view(root_fd).unwrap().cursor, 3);
+
+        fs.rewind_dir(root_fd).unwrap();
+        assert_eq!(names(fs.list_dir_next(root_fd, 5).unwrap()), [b"b", b"c"]);
+        assert_eq!(fs.list_dir_next(fd, 1).map(|_| ()), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn storage_adapters_compose() {
+        use crate::storage::{
+            cached::CachedStorage, encrypted::EncryptedStorage, metrics::MetricsStorage,
+            quota::QuotaStorage,
+        };
+
+        let storage = EncryptedStorage::new(Box::new(TransientStorage::new()), [1; 32]);
+        let storage = QuotaStorage::new(Box::new(storage), FILE_CHUNK_SIZE as u64 * 3);
+        let storage = MetricsStorage::new(Box::new(storage));
+        let storage = CachedStorage::new(Box::new(storage), 4);
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+
+        let fd = fs
+            .create_file(fs.root_fd(), "f", FdStat::default(), 0)
+            .unwrap();
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 2).map(|i| i as u8).collect();
+        fs.write(fd, &data).unwrap();
+
+        let mut buf = vec![0; data.len()];
+        fs.seek(fd, 0, super::Whence::SET).unwrap();
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // the quota of the inner layer is reported through the stack
+        assert_eq!(fs.write(fd, &data), Ok(FILE_CHUNK_SIZE as u64));
+        assert_eq!(fs.write(fd, &data), Err(Error::StorageFull));
+    }
+
+    #[test]
+    fn write_amplification_is_reported() {
+        let mut fs = test_fs();
+        let fd = fs
+            .create_file(fs.root_fd(), "f", FdStat::default(), 0)
+            .unwrap();
+        fs.reset_write_amplification();
+        assert_eq!(fs.write_amplification().ratio(), None);
+
+        // a small write rewrites a whole chunk and the file metadata
+        fs.write(fd, &[1; 10]).unwrap();
+        let stats = fs.write_amplification();
+        assert_eq!(stats.logical_bytes, 10);
+        assert_eq!(stats.physical.chunk_bytes, FILE_CHUNK_SIZE as u64);
+        assert!(stats.physical.metadata_bytes > 0);
+        assert!(stats.ratio().unwrap() > FILE_CHUNK_SIZE as f64 / 10.0);
+
+        // a write of whole chunks has almost no overhead
+        fs.reset_write_amplification();
+        let data = [2; FILE_CHUNK_SIZE * 4];
+        let src = [SrcBuf {
+            buf: data.as_ptr(),
+            len: data.len(),
+        }];
+        fs.write_vec_with_offset(fd, &src, 0).unwrap();
+        let stats = fs.write_amplification();
+        assert_eq!(stats.physical.chunk_bytes, stats.logical_bytes);
+        assert!(stats.ratio().unwrap() < 1.1);
+
+        // the storages in heap memory report no physical writes
+        let mut fs = test_fs_transient();
+        let fd = fs
+            .create_file(fs.root_fd(), "f", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, &[1; 10]).unwrap();
+        assert_eq!(fs.write_amplification().physical, PhysicalWrites::default());
+    }
+
+    #[test]
+    fn unnamed_files_are_linked_into_place() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+
+        let fd = fs.create_unnamed(root_fd, FdStat::default(), 12).unwrap();
+        fs.write(fd, b"staged").unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().link_count, 0);
+        assert!(fs.list_dir(root_fd).unwrap().is_empty());
+
+        fs.link_fd(fd, root_fd, "dir/data.txt").unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().link_count, 1);
+        assert_eq!(
+            fs.link_fd(fd, root_fd, "dir/data.txt"),
+            Err(Error::FileAlreadyExists)
+        );
+        fs.close(fd).unwrap();
+
+        let stat = fs.open_metadata(root_fd, "dir/data.txt").unwrap();
+        assert_eq!((stat.size, stat.times.created), (6, 12));
+
+        // an unnamed file is removed on close
+        let fd = fs.create_unnamed(root_fd, FdStat::default(), 0).unwrap();
+        fs.write(fd, &[1; FILE_CHUNK_SIZE * 2]).unwrap();
+        let node = fs.metadata(fd).unwrap().node;
+        fs.close(fd).unwrap();
+        assert_eq!(
+            fs.metadata_from_node(node).map(|_| ()),
+            Err(Error::NotFound)
+        );
+        assert!(fs.storage.filechunk_indices(node, 4).is_empty());
+
+        let dir = fs
+            .open_or_create(root_fd, "dir", FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+        assert_eq!(fs.link_fd(dir, root_fd, "x"), Err(Error::InvalidFileType));
+        assert_eq!(
+            fs.create_unnamed(Fd::new(100), FdStat::default(), 0)
+                .map(|_| ()),
+            Err(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn rename_flags() {
+        use super::RenameFlags;
+
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        for (name, data) in [("a", b"a"), ("b", b"b"), ("dir/c", b"c")] {
+            fs.apply_file(root, name, data, 0).unwrap();
+        }
+        let read = |fs: &mut FileSystem, path: &str| -> Vec<u8> {
+            let fd = fs
+                .open_or_create(root, path, FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap();
+            let mut buf = vec![0; 10];
+            let len = fs.read(fd, &mut buf).unwrap() as usize;
+            fs.close(fd).unwrap();
+            buf.truncate(len);
+            buf
+        };
+
+        // the legacy mode keeps the destination
+        assert_eq!(
+            fs.rename(root, "a", root, "b").map(|_| ()),
+            Err(Error::FileAlreadyExists)
+        );
+        assert_eq!(
+            fs.rename_with_flags(root, "a", root, "b", RenameFlags::NOREPLACE)
+                .map(|_| ()),
+            Err(Error::FileAlreadyExists)
+        );
+        assert_eq!(
+            fs.rename_with_flags(root, "a", root, "b", RenameFlags::all())
+                .map(|_| ()),
+            Err(Error::InvalidRenameFlags)
+        );
+
+        // the entries are exchanged, the folders included
+        let fd = fs
+            .rename_with_flags(root, "a", root, "dir/c", RenameFlags::EXCHANGE)
+            .unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(read(&mut fs, "a"), b"c");
+        assert_eq!(read(&mut fs, "dir/c"), b"a");
+        let fd = fs.create_dir(root, "other", FdStat::default(), 0).unwrap();
+        fs.close(fd).unwrap();
+        let fd = fs
+            .rename_with_flags(root, "dir", root, "other", RenameFlags::EXCHANGE)
+            .unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(read(&mut fs, "other/c"), b"a");
+        assert_eq!(
+            fs.rename_with_flags(root, "other", root, "other/c", RenameFlags::EXCHANGE)
+                .map(|_| ()),
+            Err(Error::CannotMoveDirectoryIntoItself)
+        );
+        assert_eq!(
+            fs.rename_with_flags(root, "a", root, "missing", RenameFlags::EXCHANGE)
+                .map(|_| ()),
+            Err(Error::NotFound)
+        );
+
+        // without flags the destination is replaced
+        let fd = fs
+            .rename_with_flags(root, "a", root, "b", RenameFlags::empty())
+            .unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(read(&mut fs, "b"), b"c");
+        assert_eq!(
+            fs.open_metadata(root, "a").map(|_| ()),
+            Err(Error::NotFound)
+        );
+        assert_eq!(
+            fs.rename_with_flags(root, "b", root, "dir", RenameFlags::empty())
+                .map(|_| ()),
+            Err(Error::IsDirectory)
+        );
+        assert_eq!(
+            fs.rename_with_flags(root, "dir", root, "b", RenameFlags::empty())
+                .map(|_| ()),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(
+            fs.rename_with_flags(root, "dir", root, "other", RenameFlags::empty())
+                .map(|_| ()),
+            Err(Error::DirectoryNotEmpty)
+        );
+        let fd = fs
+            .rename_with_flags(root, "other", root, "dir", RenameFlags::empty())
+            .unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(read(&mut fs, "dir/c"), b"a");
+    }
+
+    #[test]
+    fn paths_follow_the_component_rules() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        fs.apply_file(root, "dir//f.txt", b"data", 0).unwrap();
+        assert_eq!(fs.open_metadata(root, "dir/f.txt").unwrap().size, 4);
+        assert_eq!(fs.open_metadata(root, "dir///f.txt").unwrap().size, 4);
+
+        for path in ["", "/dir/f.txt"] {
+            assert_eq!(
+                fs.open_metadata(root, path).map(|_| ()),
+                Err(Error::InvalidFileName)
+            );
+        }
+        for path in ["dir/./f.txt", "dir/../dir/f.txt", "./dir/f.txt"] {
+            assert_eq!(fs.open_metadata(root, path).unwrap().size, 4);
+        }
+        // a ".." cannot lead above the descriptor the path is resolved from
+        for path in ["../dir/f.txt", "dir/../../dir/f.txt"] {
+            assert_eq!(
+                fs.open_metadata(root, path).map(|_| ()),
+                Err(Error::PermissionDenied)
+            );
+        }
+        // the elements before a ".." must exist, nothing is created for them
+        assert_eq!(
+            fs.create_file(root, "new/../x", FdStat::default(), 0)
+                .map(|_| ()),
+            Err(Error::NotFound)
+        );
+        assert_eq!(
+            fs.open_metadata(root, "new").map(|_| ()),
+            Err(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn dot_elements_are_resolved() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        let dir = fs.create_dir(root, "a/b", FdStat::default(), 0).unwrap();
+
+        // the paths are resolved from the descriptor, ".." leads back to the parent of an element
+        let fd = fs
+            .create_file(root, "a/./b/../c/d.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, b"nested").unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(read_test_file(&mut fs, "a/c/d.txt"), b"nested");
+        assert_eq!(fs.open_metadata(root, "a/b/../c/./d.txt").unwrap().size, 6);
+
+        // a ".." cannot lead above the descriptor, nothing is created outside of it
+        assert_eq!(
+            fs.create_file(dir, "./../c/e.txt", FdStat::default(), 0)
+                .map(|_| ()),
+            Err(Error::PermissionDenied)
+        );
+        assert_eq!(
+            fs.open_metadata(dir, "../../a/c/d.txt").map(|_| ()),
+            Err(Error::PermissionDenied)
+        );
+        assert_eq!(
+            fs.open_metadata(root, "a/c/e.txt").map(|_| ()),
+            Err(Error::NotFound)
+        );
+
+        // a path ending with a dot element names an existing directory, it cannot be removed
+        assert_eq!(
+            fs.open_metadata(root, "a/b/..").unwrap().file_type,
+            FileType::Directory
+        );
+        assert_eq!(
+            fs.create_file(root, "a/..", FdStat::default(), 0)
+                .map(|_| ()),
+            Err(Error::FileAlreadyExists)
+        );
+        assert_eq!(fs.remove_dir(dir, "."), Err(Error::InvalidFileName));
+        assert_eq!(
+            fs.open_metadata(root, "a/c/d.txt/..").map(|_| ()),
+            Err(Error::NotADirectory)
+        );
+
+        fs.remove_file(root, "a/b/../c/d.txt").unwrap();
+        assert_eq!(
+            fs.open_metadata(root, "a/c/d.txt").map(|_| ()),
+            Err(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn trailing_slashes_require_directories() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        fs.apply_file(root, "file", b"data", 0).unwrap();
+
+        let fd = fs.create_dir(root, "dir/", FdStat::default(), 0).unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(
+            fs.open_metadata(root, "dir").unwrap().file_type,
+            FileType::Directory
+        );
+
+        let open = |fs: &mut FileSystem, path: &str, flags: OpenFlags| {
+            fs.open_or_create(root, path, FdStat::default(), flags, 0)
+                .and_then(|fd| fs.close(fd))
+        };
+        let cases = [
+            ("dir/", OpenFlags::empty(), Ok(())),
+            ("dir/", OpenFlags::DIRECTORY, Ok(())),
+            ("file/", OpenFlags::empty(), Err(Error::NotADirectory)),
+            ("file/", OpenFlags::CREATE, Err(Error::NotADirectory)),
+            ("new/", OpenFlags::CREATE, Err(Error::NotADirectory)),
+            ("new/", OpenFlags::empty(), Err(Error::NotFound)),
+        ];
+        for (path, flags, expected) in cases {
+            let bits = flags.bits();
+            assert_eq!(open(&mut fs, path, flags), expected, "{path} {bits}");
+        }
+        assert_eq!(
+            fs.create_file(root, "new/", FdStat::default(), 0)
+                .map(|_| ()),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(
+            fs.open_metadata(root, "new").map(|_| ()),
+            Err(Error::NotFound)
+        );
+        assert_eq!(
+            fs.open_metadata(root, "file/").map(|_| ()),
+            Err(Error::NotADirectory)
+        );
+
+        // renaming and linking to a path with a trailing slash needs a directory
+        let rename = |fs: &mut FileSystem, from: &str, to: &str| {
+            fs.rename(root, from, root, to).and_then(|fd| fs.close(fd))
+        };
+        assert_eq!(rename(&mut fs, "file", "moved/"), Err(Error::NotADirectory));
+        assert_eq!(rename(&mut fs, "file/", "moved"), Err(Error::NotADirectory));
+        assert_eq!(rename(&mut fs, "dir/", "moved/"), Ok(()));
+        assert_eq!(
+            fs.create_hard_link(root, "file", root, "link/").map(|_| ()),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(
+            fs.create_alias(root, "alias/", "file", 0),
+            Err(Error::NotADirectory)
+        );
+
+        assert_eq!(fs.remove_file(root, "file/"), Err(Error::NotADirectory));
+        fs.remove_dir(root, "moved/").unwrap();
+        fs.remove_file(root, "file").unwrap();
+    }
+
+    #[test]
+    fn path_depth_and_alias_hops_are_limited() {
+        let options = MountOptions {
+            max_path_depth: Some(3),
+            max_alias_hops: Some(1),
+            ..Default::default()
+        };
+        let mut fs =
+            FileSystem::new_with_options(Box::new(TransientStorage::new()), options).unwrap();
+        let root = fs.root_fd();
+
+        fs.create_file(root, "a/b/c.txt", FdStat::default(), 0)
+            .unwrap();
+        assert_eq!(
+            fs.create_file(root, "a/b/c/d.txt", FdStat::default(), 0)
+                .map(|_| ()),
+            Err(Error::PathTooDeep)
+        );
+        assert_eq!(fs.remove_dir(root, "a/././b"), Err(Error::PathTooDeep));
+        assert_eq!(
+            fs.create_alias(root, "deep", "a/b/c/d", 0),
+            Err(Error::PathTooDeep)
+        );
+
+        // one hop is followed, a chain of two is not
+        fs.create_alias(root, "first", "a/b/c.txt", 0).unwrap();
+        fs.create_alias(root, "second", "first", 0).unwrap();
+        assert_eq!(fs.resolve_alias(root, "first"), Ok("a/b/c.txt".to_string()));
+        assert_eq!(
+            fs.resolve_alias(root, "second"),
+            Err(Error::TooManySymbolicLinks)
+        );
+    }
+
+    #[test]
+    fn layout_info_reports_the_limits() {
+        let fs = test_fs();
+        let layout = fs.layout_info();
+        assert_eq!(layout.chunk_size, FILE_CHUNK_SIZE);
+        assert_eq!(layout.max_file_name, 255);
+        assert_eq!(layout.max_file_size, crate::storage::types::MAX_FILE_SIZE);
+        assert_eq!(layout.storage_version, fs.get_storage_version());
+        assert_eq!(layout.max_path_depth, None);
+        assert_eq!(layout.max_alias_hops, 16);
+
+        let options = MountOptions {
+            max_dir_entries: Some(10),
+            max_alias_hops: Some(2),
+            ..Default::default()
+        };
+        let fs = FileSystem::new_with_options(Box::new(TransientStorage::new()), options).unwrap();
+        assert_eq!(fs.layout_info().max_dir_entries, Some(10));
+        assert_eq!(fs.layout_info().max_alias_hops, 2);
+    }
+
+    #[test]
+    fn removed_files_leave_reusable_space() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "a.bin", &[1; FILE_CHUNK_SIZE * 2]);
+        assert_eq!(fs.reusable_space(), ReusableSpace::default());
+
+        fs.remove_file(root, "a.bin").unwrap();
+        let reusable = fs.reusable_space();
+        assert_eq!(reusable.chunks, 2);
+        assert_eq!(reusable.metadata_records, 1);
+        assert_eq!(reusable.direntry_records, 1);
+
+        write_test_file(&mut fs, "b.bin", &[2; 10]);
+        assert_eq!(
+            fs.reusable_space(),
+            ReusableSpace {
+                chunks: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn budgeted_operations_return_partial_results() {
+        let options = MountOptions {
+            instruction_budget: Some(1),
+            ..Default::default()
+        };
+        let mut fs =
+            FileSystem::new_with_options(Box::new(TransientStorage::new()), options).unwrap();
+        let root = fs.root_fd();
+        let fd = fs
+            .create_file(root, "big.bin", FdStat::default(), 0)
+            .unwrap();
+
+        // one slice is written per call, the next call continues with the rest
+        let data: Vec<u8> = (0..BUDGET_SLICE_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let mut done = 0;
+        let mut calls = 0;
+        loop {
+            calls += 1;
+            match fs.write_budgeted(fd, &data[done..]).unwrap() {
+                Budgeted::Complete(size) => {
+                    done += size as usize;
+                    break;
+                }
+                Budgeted::Partial { continuation } => done += continuation.done as usize,
+            }
+        }
+        assert_eq!((done, calls), (data.len(), 3));
+
+        fs.seek(fd, 0, super::Whence::SET).unwrap();
+        let mut buf = vec![0; data.len() + 100];
+        assert_eq!(
+            fs.read_budgeted(fd, &mut buf),
+            Ok(Budgeted::Partial {
+                continuation: Continuation {
+                    done: BUDGET_SLICE_SIZE as u64
+                }
+            })
+        );
+        let rest = &mut buf[BUDGET_SLICE_SIZE..];
+        assert!(matches!(
+            fs.read_budgeted(fd, rest),
+            Ok(Budgeted::Partial { .. })
+        ));
+        fs.close(fd).unwrap();
+
+        // the expired files are removed one per call
+        for name in ["a", "b"] {
+            let fd = fs.create_file(root, name, FdStat::default(), 0).unwrap();
+            fs.set_expiry(fd, Some(10)).unwrap();
+            fs.close(fd).unwrap();
+        }
+        assert_eq!(
+            fs.expire_now_budgeted(10),
+            Ok(Budgeted::Partial {
+                continuation: Continuation { done: 1 }
+            })
+        );
+        assert_eq!(fs.expire_now_budgeted(10), Ok(Budgeted::Complete(1)));
+
+        // without a budget everything is done at once
+        fs.set_options(MountOptions::default());
+        let fd = fs
+            .open_or_create(root, "big.bin", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        let mut buf = vec![0; data.len()];
+        assert_eq!(
+            fs.read_budgeted(fd, &mut buf),
+            Ok(Budgeted::Complete(data.len() as u64))
+        );
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn read_and_write_by_path() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        assert_eq!(
+            fs.write_path(root, "docs/a.txt", 0, b"hello", false, 0),
+            Err(Error::NotFound)
+        );
+        assert_eq!(fs.write_path(root, "a.txt", 0, b"hello", true, 0), Ok(5));
+        assert_eq!(fs.write_path(root, "a.txt", 3, b"p me", true, 0), Ok(4));
+        assert_eq!(fs.read_path(root, "a.txt", 0, 100), Ok(b"help me".to_vec()));
+        assert_eq!(fs.read_path(root, "a.txt", 5, 1), Ok(b"m".to_vec()));
+        assert_eq!(fs.read_path(root, "a.txt", 100, 1), Ok(Vec::new()));
+
+        // a write past the end leaves a hole read as zeros
+        fs.write_path(root, "a.txt", 9, b"!", false, 0).unwrap();
+        assert_eq!(fs.read_path(root, "a.txt", 7, 3), Ok(vec![0, 0, b'!']));
+        // no descriptors are left open
+        assert_eq!(fs.open_fds().count(), 1);
+
+        fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+        assert_eq!(fs.read_path(root, "dir", 0, 1), Err(Error::IsDirectory));
+        assert_eq!(
+            fs.write_path(root, "dir", 0, b"x", true, 0),
+            Err(Error::IsDirectory)
+        );
+        assert_eq!(fs.read_path(root, "missing", 0, 1), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn list_dir_with_projection() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "a.txt", b"aaa");
+        write_test_file(&mut fs, "b.txt", b"bb");
+        fs.create_dir(root, "c", FdStat::default(), 0).unwrap();
+
+        let names = fs
+            .list_dir_with(root, Projection::NamesOnly, Page::all())
+            .unwrap();
+        let names: Vec<_> = names.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec!["a.txt", "b.txt", "c"]);
+
+        let page = Page { start: 1, limit: 1 };
+        let listed = fs
+            .list_dir_with(root, Projection::WithMetadata, page)
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name, "b.txt");
+        assert_eq!(listed[0].stat.map(|stat| stat.size), Some(2));
+        assert_eq!(listed[0].hash, None);
+
+        let listed = fs
+            .list_dir_with(root, Projection::WithHashes, page)
+            .unwrap();
+        let fd = fs
+            .open_or_create(root, "b.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        assert_eq!(listed[0].hash, Some(fs.merkle_hash(fd).unwrap()));
+        assert_eq!(listed[0].stat, None);
+
+        let page = Page {
+            start: 5,
+            limit: 10,
+        };
+        assert_eq!(
+            fs.list_dir_with(root, Projection::NamesOnly, page),
+            Ok(Vec::new())
+        );
+    }
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn file_handles_outlive_descriptors() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        let fd = fs.create_file(root, "a.txt", FdStat::default(), 0).unwrap();
+        fs.write(fd, b"hello").unwrap();
+        let handle = fs.file_handle(fd).unwrap();
+        fs.close(fd).unwrap();
 
-    use crate::{
-        error::Error,
-        fs::{DstBuf, FdFlags, SrcBuf},
-        runtime::{
-            structure_helpers::find_node,
-            types::{FdStat, OpenFlags},
-        },
-        storage::types::FileType,
-        test_utils::{test_fs, test_fs_transient},
-    };
+        // the handle is stored as bytes and still leads to the renamed file
+        let handle = FileHandle::from_bytes(handle.to_bytes());
+        let renamed = fs.rename(root, "a.txt", root, "b.txt").unwrap();
+        fs.close(renamed).unwrap();
+        let fd = fs
+            .open_handle(handle, FdStat::default(), OpenFlags::empty())
+            .unwrap();
+        let mut buf = [0; 5];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        fs.close(fd).unwrap();
 
-    use super::{Fd, FileSystem};
+        fs.remove_file(root, "b.txt").unwrap();
+        assert_eq!(
+            fs.open_handle(handle, FdStat::default(), OpenFlags::empty()),
+            Err(Error::StaleFileHandle)
+        );
+        let stale = FileHandle {
+            generation: 1,
+            ..fs.file_handle(root).unwrap()
+        };
+        assert_eq!(
+            fs.open_handle(stale, FdStat::default(), OpenFlags::empty()),
+            Err(Error::StaleFileHandle)
+        );
+    }
 
     #[test]
-    fn get_root_info() {
-        let fs = test_fs();
+    fn handles_of_recreated_files_are_stale() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        let fd = fs.create_file(root, "a.txt", FdStat::default(), 0).unwrap();
+        let old = fs.file_handle(fd).unwrap();
+        fs.close(fd).unwrap();
+        fs.remove_file(root, "a.txt").unwrap();
 
-        let fd = fs.root_fd();
-        let path = fs.root_path();
+        let fd = fs.create_file(root, "a.txt", FdStat::default(), 0).unwrap();
+        let new = fs.file_handle(fd).unwrap();
+        assert!(new.generation > old.generation);
+        assert_eq!(fs.metadata(fd).unwrap().generation, new.generation);
 
-        assert!(fd == 3);
-        assert!(path == "/");
+        // a node number given out again keeps the old handles stale
+        let mut metadata = fs.raw_metadata(fd).unwrap();
+        metadata.node = old.node;
+        fs.storage.put_metadata(old.node, metadata);
+        assert_eq!(
+            fs.open_handle(old, FdStat::default(), OpenFlags::empty()),
+            Err(Error::StaleFileHandle)
+        );
+        let reused = FileHandle {
+            node: old.node,
+            generation: new.generation,
+        };
+        let reopened = fs
+            .open_handle(reused, FdStat::default(), OpenFlags::empty())
+            .unwrap();
+        fs.close(reopened).unwrap();
+        fs.close(fd).unwrap();
     }
 
     #[test]
-    fn create_file() {
+    fn watched_paths_report_changes() {
         let mut fs = test_fs();
+        let root = fs.root_fd();
+        let watch = fs.watch("cfg/app.toml").unwrap();
+        assert_eq!(fs.poll_watches().unwrap(), vec![]);
+
+        let kinds = |events: Vec<WatchEvent>| {
+            events
+                .into_iter()
+                .map(|event| {
+                    assert_eq!(event.watch, watch);
+                    assert_eq!(event.path, "cfg/app.toml");
+                    event.kind
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let dir = fs.create_dir(root, "cfg", FdStat::default(), 0).unwrap();
+        let fd = fs
+            .create_file(dir, "app.toml", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, b"a = 1").unwrap();
+        assert_eq!(kinds(fs.poll_watches().unwrap()), vec![ChangeKind::Created]);
 
-        let file = fs
-            .create_file(fs.root_fd(), "test.txt", Default::default(), 0)
+        // a write of the same size is noticed too
+        fs.seek(fd, 0, super::Whence::SET).unwrap();
+        fs.write(fd, b"a = 2").unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(
+            kinds(fs.poll_watches().unwrap()),
+            vec![ChangeKind::Modified]
+        );
+        assert_eq!(fs.poll_watches().unwrap(), vec![]);
+
+        let fd = fs
+            .create_file(dir, "new.toml", FdStat::default(), 0)
+            .unwrap();
+        fs.close(fd).unwrap();
+        let fd = fs
+            .rename_with_flags(
+                dir,
+                "new.toml",
+                dir,
+                "app.toml",
+                super::RenameFlags::empty(),
+            )
             .unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(
+            kinds(fs.poll_watches().unwrap()),
+            vec![ChangeKind::Removed, ChangeKind::Created]
+        );
 
-        assert!(file > fs.root_fd());
+        fs.remove_file(dir, "app.toml").unwrap();
+        assert_eq!(kinds(fs.poll_watches().unwrap()), vec![ChangeKind::Removed]);
+
+        fs.unwatch(watch).unwrap();
+        assert_eq!(fs.unwatch(watch), Err(Error::NotFound));
+        write_test_file(&mut fs, "cfg/app.toml", b"b = 1");
+        assert_eq!(fs.poll_watches().unwrap(), vec![]);
     }
 
     #[test]
-    fn create_dir() {
+    fn mounted_snapshots_are_read_only() {
         let mut fs = test_fs();
-
-        let dir = fs
-            .create_dir(fs.root_fd(), "test", FdStat::default(), 0)
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "site/index.html", b"v1");
+        write_test_file(&mut fs, "site/css/main.css", b"body {}");
+        let site = fs
+            .open_or_create(root, "site", FdStat::default(), OpenFlags::DIRECTORY, 0)
             .unwrap();
 
-        let fd = fs
-            .create_file(dir, "file.txt", FdStat::default(), 0)
-            .unwrap();
-        fs.write(fd, "Hello, world!".as_bytes()).unwrap();
+        fs.mount_snapshot(site, "v1", 0).unwrap();
+        assert_eq!(
+            fs.mount_snapshot(site, "v1", 0),
+            Err(Error::FileAlreadyExists)
+        );
+        assert_eq!(
+            fs.mount_snapshot(site, "a/b", 0),
+            Err(Error::InvalidFileName)
+        );
+        assert_eq!(fs.snapshot_mounts(), vec!["v1".to_string()]);
 
-        let dir = fs
+        write_test_file(&mut fs, "site/index.html", b"v2");
+        assert_eq!(read_test_file(&mut fs, ".snapshots/v1/index.html"), b"v1");
+        assert_eq!(
+            read_test_file(&mut fs, ".snapshots/v1/css/main.css"),
+            b"body {}"
+        );
+
+        assert_eq!(
+            fs.write_path(root, ".snapshots/v1/index.html", 0, b"v3", false, 0),
+            Err(Error::ReadOnlyFileSystem)
+        );
+        assert!(matches!(
+            fs.create_file(root, ".snapshots/v1/css/new.css", FdStat::default(), 0),
+            Err(Error::ReadOnlyFileSystem)
+        ));
+        assert_eq!(
+            fs.remove_file(root, ".snapshots/v1/index.html"),
+            Err(Error::ReadOnlyFileSystem)
+        );
+        assert_eq!(
+            fs.apply_remove(root, ".snapshots"),
+            Err(Error::ReadOnlyFileSystem)
+        );
+        assert!(matches!(
+            fs.rename(root, ".snapshots/v1/css", root, "css"),
+            Err(Error::ReadOnlyFileSystem)
+        ));
+
+        let fd = fs
             .open_or_create(
-                fs.root_fd(),
-                "test",
+                root,
+                ".snapshots/v1/index.html",
                 FdStat::default(),
                 OpenFlags::empty(),
                 0,
             )
             .unwrap();
+        assert_eq!(fs.write(fd, b"v3"), Err(Error::ReadOnlyFileSystem));
+        assert_eq!(fs.set_modified_time(fd, 5), Err(Error::ReadOnlyFileSystem));
+        fs.close(fd).unwrap();
 
-        let fd = fs
-            .open_or_create(dir, "file.txt", FdStat::default(), OpenFlags::empty(), 0)
-            .unwrap();
-
-        let mut buf = [0; 13];
-        fs.read(fd, &mut buf).unwrap();
-        assert_eq!(&buf, "Hello, world!".as_bytes());
+        fs.unmount_snapshot("v1").unwrap();
+        assert_eq!(fs.unmount_snapshot("v1"), Err(Error::NotFound));
+        assert!(matches!(
+            fs.read_path(root, ".snapshots/v1/index.html", 0, 2),
+            Err(Error::NotFound)
+        ));
+        assert_eq!(read_test_file(&mut fs, "site/index.html"), b"v2");
     }
 
     #[test]
-    fn create_file_creates_a_few_files() {
-        let mut fs = test_fs();
+    fn prewarm_requests_the_file_chunks() {
+        use crate::storage::{cached::CachedStorage, transient::TransientStorage};
+
+        let storage = CachedStorage::new(Box::new(TransientStorage::new()), 16);
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "assets/app.js", &[1; FILE_CHUNK_SIZE + 1]);
+        write_test_file(&mut fs, "index.html", b"<html>");
+        write_test_file(&mut fs, "empty.txt", b"");
+
+        let paths = [
+            "assets/app.js",
+            "index.html",
+            "empty.txt",
+            "assets",
+            "missing.css",
+        ];
+        assert_eq!(fs.prewarm(root, &paths), Ok(3));
+        assert_eq!(read_test_file(&mut fs, "index.html"), b"<html>");
+    }
 
-        let dir = fs.root_fd();
+    #[test]
+    fn tree_is_verified_against_metadata_manifest() {
+        use super::{DeviationKind, MetadataManifest};
 
-        fs.create_file(dir, "test1.txt", FdStat::default(), 0)
-            .unwrap();
-        fs.create_file(dir, "test2.txt", FdStat::default(), 0)
-            .unwrap();
-        fs.create_file(dir, "test3.txt", FdStat::default(), 0)
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "site/index.html", b"<html>");
+        write_test_file(&mut fs, "site/app.js", b"main()");
+        write_test_file(&mut fs, "README", b"readme");
+
+        let manifest = fs.export_metadata_manifest(root).unwrap();
+        assert_eq!(manifest.entries["site/app.js"].size, 6);
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&manifest, &mut bytes).unwrap();
+        let manifest: MetadataManifest = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(fs.verify_against_manifest(root, &manifest), Ok(vec![]));
+
+        // the same size with other contents, a removed and an added file
+        write_test_file(&mut fs, "site/app.js", b"evil()");
+        fs.remove_file(root, "README").unwrap();
+        write_test_file(&mut fs, "site/extra.js", b"");
+        let fd = fs
+            .open_or_create(
+                root,
+                "site/index.html",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
             .unwrap();
+        fs.set_modified_time(fd, 42).unwrap();
+        fs.close(fd).unwrap();
 
-        let meta = fs.metadata(fs.root_fd()).unwrap();
+        let deviations: Vec<_> = fs
+            .verify_against_manifest(root, &manifest)
+            .unwrap()
+            .into_iter()
+            .map(|deviation| (deviation.path, deviation.kind))
+            .collect();
+        assert_eq!(
+            deviations,
+            vec![
+                ("README".to_string(), DeviationKind::Missing),
+                ("site/app.js".to_string(), DeviationKind::Hash),
+                ("site/index.html".to_string(), DeviationKind::Times),
+                ("site/extra.js".to_string(), DeviationKind::Unexpected),
+            ]
+        );
+    }
 
-        let entry_index = meta.first_dir_entry.unwrap();
+    #[test]
+    fn create_file_collision_policy() {
+        use super::CollisionPolicy;
 
-        let entry1 = fs.get_direntry(fs.root_fd(), entry_index).unwrap();
-        let entry2 = fs.get_direntry(fs.root_fd(), entry_index + 1).unwrap();
-        let entry3 = fs.get_direntry(fs.root_fd(), entry_index + 2).unwrap();
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "a.txt", b"hello");
+        let create = |fs: &mut FileSystem, path, policy| {
+            fs.create_file_with(root, path, FdStat::default(), policy, 0)
+        };
+
+        assert!(matches!(
+            create(&mut fs, "a.txt", CollisionPolicy::Error),
+            Err(Error::FileAlreadyExists)
+        ));
+
+        let fd = create(&mut fs, "a.txt", CollisionPolicy::OpenExisting).unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().size, 5);
+        fs.close(fd).unwrap();
 
-        assert_eq!(entry1.prev_entry, None);
-        assert_eq!(entry1.next_entry, Some(entry_index + 1));
+        let fd = create(&mut fs, "a.txt", CollisionPolicy::Truncate).unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().size, 0);
+        fs.close(fd).unwrap();
 
-        assert_eq!(entry2.prev_entry, Some(entry_index));
-        assert_eq!(entry2.next_entry, Some(entry_index + 2));
+        // a missing file is created with any policy
+        let fd = create(&mut fs, "b.txt", CollisionPolicy::OpenExisting).unwrap();
+        fs.close(fd).unwrap();
 
-        assert_eq!(entry3.prev_entry, Some(entry_index + 1));
-        assert_eq!(entry3.next_entry, None);
+        let dir = fs.create_dir(root, "dir", FdStat::default(), 0).unwrap();
+        fs.close(dir).unwrap();
+        assert!(matches!(
+            create(&mut fs, "dir", CollisionPolicy::Truncate),
+            Err(Error::FileAlreadyExists)
+        ));
     }
 
     #[test]
-    fn close_file_fd_reused() {
+    fn dir_handles_take_relative_paths() {
         let mut fs = test_fs();
+        let root = fs.root_dir();
+        assert_eq!(root.fd(), fs.root_fd());
 
-        let dir = fs.root_fd();
-
-        fs.create_file(dir, "test1.txt", FdStat::default(), 0)
+        let site = root
+            .create_dir(&mut fs, "site", FdStat::default(), 0)
             .unwrap();
-        let fd2 = fs
-            .create_file(dir, "test2.txt", FdStat::default(), 0)
+        let fd = site
+            .create_file(&mut fs, "index.html", FdStat::default(), 0)
             .unwrap();
-        fs.create_file(dir, "test3.txt", FdStat::default(), 0)
+        assert_eq!(fs.dir_handle(fd), Err(Error::InvalidFileType));
+        fs.write(fd, b"<html>").unwrap();
+        fs.close(fd).unwrap();
+
+        let css = site.open_dir(&mut fs, "css").map(|_| ());
+        assert_eq!(css, Err(Error::NotFound));
+        let fd = site
+            .open(
+                &mut fs,
+                "index.html",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
             .unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().size, 6);
+        fs.close(fd).unwrap();
 
-        fs.close(fd2).unwrap();
+        let names: Vec<u8> = site
+            .read_dir(&fs)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name.bytes[0])
+            .collect();
+        assert_eq!(names, vec![b'i']);
 
-        let fd4 = fs
-            .create_file(dir, "test4.txt", FdStat::default(), 0)
+        site.remove(&mut fs, "index.html", RemoveOptions::file())
             .unwrap();
+        assert_eq!(site.read_dir(&fs).unwrap().len(), 0);
 
-        assert_eq!(fd2, fd4);
+        let again = root.open_dir(&mut fs, "site").unwrap();
+        assert_eq!(fs.dir_handle(again.fd()), Ok(again));
+        again.close(&mut fs).unwrap();
+        site.close(&mut fs).unwrap();
+        assert_eq!(fs.dir_handle(site.fd()), Err(Error::NotFound));
     }
 
     #[test]
-    fn fd_renumber() {
-        let mut fs = test_fs();
+    fn builder_validates_and_applies_options() {
+        struct NoExe;
 
-        let dir = fs.root_fd();
+        impl Policy for NoExe {
+            fn before_create(&mut self, _: Node, path: &str, _: FileType) -> Result<(), Error> {
+                if path.ends_with(".exe") {
+                    return Err(Error::InvalidFileName);
+                }
+                Ok(())
+            }
+        }
 
-        let fd1 = fs
-            .create_file(dir, "test1.txt", FdStat::default(), 0)
+        let mut fs = FileSystem::builder()
+            .storage(Box::new(TransientStorage::new()))
+            .chunk_size(FILE_CHUNK_SIZE)
+            .cache_chunks(8)
+            .name_normalizer(NameNormalizer::Lowercase)
+            .max_dir_entries(2)
+            .policy(Box::new(NoExe))
+            .build()
             .unwrap();
-        let fd2 = fs
-            .create_file(dir, "test2.txt", FdStat::default(), 0)
+        assert_eq!(fs.options().max_dir_entries, Some(2));
+        let root = fs.root_fd();
+
+        write_test_file(&mut fs, "README", b"readme");
+        assert_eq!(read_test_file(&mut fs, "readme"), b"readme");
+        assert!(matches!(
+            fs.create_file(root, "run.exe", FdStat::default(), 0),
+            Err(Error::InvalidFileName)
+        ));
+        write_test_file(&mut fs, "b", b"");
+        assert!(matches!(
+            fs.create_file(root, "c", FdStat::default(), 0),
+            Err(Error::DirectoryFull)
+        ));
+
+        let invalid = [
+            FileSystem::builder(),
+            FileSystem::builder()
+                .storage(Box::new(TransientStorage::new()))
+                .chunk_size(FILE_CHUNK_SIZE / 2),
+            FileSystem::builder()
+                .storage(Box::new(TransientStorage::new()))
+                .cache_chunks(0),
+            FileSystem::builder()
+                .storage(Box::new(TransientStorage::new()))
+                .max_path_depth(0),
+        ];
+        for builder in invalid {
+            assert!(matches!(builder.build(), Err(Error::InvalidMountOptions)));
+        }
+    }
+
+    #[test]
+    fn builder_options_are_stored_with_the_file_system() {
+        let memory = DefaultMemoryImpl::default();
+        let fs = FileSystem::builder()
+            .storage(Box::new(StableStorage::new(memory.clone())))
+            .name_normalizer(NameNormalizer::Lowercase)
+            .max_dir_entries(2)
+            .max_path_depth(4)
+            .clock(|| 1_000)
+            .build()
             .unwrap();
+        assert_eq!(fs.now(), Some(1_000));
+        drop(fs);
+
+        // the stored normalizer and limits are used by a mount without them
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let options = fs.options();
+        assert_eq!(options.name_normalizer, NameNormalizer::Lowercase);
+        assert_eq!(options.max_dir_entries, Some(2));
+        assert_eq!(options.max_path_depth, Some(4));
+        assert_eq!(fs.now(), None);
+        assert_eq!(fs.expire(), Err(Error::InvalidMountOptions));
+        drop(fs);
+
+        // other limits are rejected
+        for builder in [
+            FileSystem::builder().max_dir_entries(3),
+            FileSystem::builder().max_path_depth(2),
+            FileSystem::builder().name_normalizer(NameNormalizer::Nfc),
+        ] {
+            let storage = Box::new(StableStorage::new(memory.clone()));
+            assert!(matches!(
+                builder.storage(storage).build(),
+                Err(Error::InvalidMountOptions)
+            ));
+        }
 
-        let entry1 = fs.get_node(fd1);
+        // the limits changed while mounted are stored too
+        let mut fs = FileSystem::builder()
+            .storage(Box::new(StableStorage::new(memory.clone())))
+            .max_dir_entries(2)
+            .clock(|| 20)
+            .build()
+            .unwrap();
+        let fd = fs
+            .create_file(fs.root_fd(), "session", FdStat::default(), 0)
+            .unwrap();
+        fs.set_expiry(fd, Some(10)).unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(fs.expire(), Ok(1));
+
+        let mut options = fs.options();
+        options.max_dir_entries = Some(100);
+        fs.set_options(options);
+        drop(fs);
+        let fs = FileSystem::new(Box::new(StableStorage::new(memory))).unwrap();
+        assert_eq!(fs.options().max_dir_entries, Some(100));
+    }
 
-        fs.renumber(fd1, fd2).unwrap();
+    #[test]
+    fn transient_files_are_lost_on_upgrade() {
+        use super::StorageClass;
+        use crate::storage::tiered::TieredStorage;
+
+        let memory = DefaultMemoryImpl::default();
+        let storage = TieredStorage::new(Box::new(StableStorage::new(memory.clone())));
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        let root = fs.root_fd();
+
+        let tmp = fs.create_dir(root, "tmp", FdStat::default(), 0).unwrap();
+        fs.set_storage_class(tmp, StorageClass::Transient).unwrap();
+        fs.close(tmp).unwrap();
+        write_test_file(&mut fs, "tmp/scratch.bin", b"scratch");
+        write_test_file(&mut fs, "data.txt", b"kept");
+        write_test_file(&mut fs, "cache.txt", b"cached");
+
+        let open = |fs: &mut FileSystem, path| {
+            fs.open_or_create(root, path, FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap()
+        };
+        let fd = open(&mut fs, "tmp/scratch.bin");
+        assert_eq!(fs.storage_class(fd), Ok(StorageClass::Transient));
+        fs.close(fd).unwrap();
+        let fd = open(&mut fs, "cache.txt");
+        fs.set_storage_class(fd, StorageClass::Transient).unwrap();
+        assert_eq!(
+            fs.update_metadata(fd, |metadata| metadata.storage_class = StorageClass::Stable),
+            Err(Error::InvalidMetadata)
+        );
+        fs.close(fd).unwrap();
+        assert_eq!(read_test_file(&mut fs, "cache.txt"), b"cached");
 
-        assert!(fs.get_node(fd1).is_err());
-        assert_eq!(fs.get_node(fd2), entry1);
+        // a later mount keeps the tree and the stable contents
+        let storage = TieredStorage::new(Box::new(StableStorage::new(memory)));
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        assert_eq!(read_test_file(&mut fs, "data.txt"), b"kept");
+        assert_eq!(read_test_file(&mut fs, "tmp/scratch.bin"), b"");
+        assert_eq!(read_test_file(&mut fs, "cache.txt"), b"");
+
+        write_test_file(&mut fs, "tmp/scratch.bin", b"again");
+        assert_eq!(read_test_file(&mut fs, "tmp/scratch.bin"), b"again");
     }
 
     #[test]
-    fn seek_and_write() {
+    fn publish_batches_are_certified_at_the_end() {
         let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "index.html", b"v1");
+        let before = fs.certified_hash().unwrap();
+        assert_eq!(before, fs.merkle_hash(root).unwrap());
+
+        fs.begin_publish().unwrap();
+        write_test_file(&mut fs, "index.html", b"v2");
+        fs.begin_publish().unwrap();
+        write_test_file(&mut fs, "app.js", b"main()");
+        assert_eq!(fs.end_publish(), Ok(before));
+        assert_eq!(fs.certified_hash(), Ok(before));
+        assert_ne!(fs.merkle_hash(root).unwrap(), before);
+
+        let after = fs.end_publish().unwrap();
+        assert_eq!(after, fs.merkle_hash(root).unwrap());
+        assert_eq!(fs.certified_hash(), Ok(after));
+
+        // without a batch each write is certified
+        write_test_file(&mut fs, "app.js", b"main(1)");
+        assert_ne!(fs.certified_hash().unwrap(), after);
+    }
 
-        let dir = fs.root_fd();
+    #[test]
+    fn reader_sees_the_tree_without_changing_it() {
+        let mut fs = test_fs();
+        write_test_file(&mut fs, "assets/index.html", b"<html/>");
+        let root = fs.root_fd();
+        fs.create_alias(root, "home", "/assets/index.html", 0)
+            .unwrap();
+        let hash = fs.merkle_hash(root).unwrap();
+
+        let reader = fs.reader();
+        assert!(reader.exists("assets/index.html"));
+        assert!(!reader.exists("assets/missing.html"));
+        assert_eq!(reader.metadata("assets/index.html").unwrap().size, 7);
+        assert_eq!(reader.read("assets/index.html", 1, 4).unwrap(), b"html");
+        assert_eq!(reader.read("assets/index.html", 5, 100).unwrap(), b"/>");
+        assert_eq!(reader.read("assets", 0, 1), Err(Error::IsDirectory));
+        assert_eq!(
+            reader.read_alias("home"),
+            Ok("/assets/index.html".to_string())
+        );
+        assert_eq!(reader.http_metadata("home"), Ok(None));
+
+        let names: Vec<u8> = reader
+            .list_dir("")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name.bytes[0])
+            .collect();
+        assert_eq!(names, vec![b'a', b'h']);
+        assert_eq!(
+            reader.list_dir("home").map(|_| ()),
+            Err(Error::InvalidFileType)
+        );
+
+        assert_eq!(fs.merkle_hash(root).unwrap(), hash);
+    }
 
+    #[test]
+    fn upgrade_mode_rejects_changes() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "data.txt", b"saved");
         let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .open_or_create(root, "data.txt", FdStat::default(), OpenFlags::empty(), 0)
             .unwrap();
 
-        fs.seek(fd, 24, super::Whence::SET).unwrap();
+        fs.begin_publish().unwrap();
+        let hash = fs.enter_upgrade_mode().unwrap();
+        assert!(fs.is_upgrading());
+        assert_eq!(fs.certified_hash(), Ok(hash));
 
-        fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(fs.write(fd, b"lost"), Err(Error::UpgradeInProgress));
+        assert_eq!(
+            fs.create_file(root, "new.txt", FdStat::default(), 0),
+            Err(Error::UpgradeInProgress)
+        );
+        assert_eq!(
+            fs.remove_file(root, "data.txt"),
+            Err(Error::UpgradeInProgress)
+        );
+        assert_eq!(fs.set_modified_time(fd, 10), Err(Error::UpgradeInProgress));
+        assert_eq!(
+            fs.create_unnamed(root, FdStat::default(), 0).map(|_| ()),
+            Err(Error::UpgradeInProgress)
+        );
+        assert_eq!(fs.reader().read("data.txt", 0, 10).unwrap(), b"saved");
 
-        let meta = fs.metadata(fd).unwrap();
+        fs.exit_upgrade_mode();
+        fs.write(fd, b"S").unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(read_test_file(&mut fs, "data.txt"), b"Saved");
+    }
 
-        assert_eq!(meta.size, 29);
+    #[test]
+    fn remove_dir_all_releases_the_subtree() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "logs/a.log", &[1; 5000]);
+        write_test_file(&mut fs, "logs/old/b.log", b"123");
+        write_test_file(&mut fs, "keep.txt", b"kept");
+        let node = find_node(fs.storage.root_node(), "logs/a.log", fs.storage.as_ref()).unwrap();
 
-        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
-        let mut buf = [42u8; 29];
-        let rr = fs.read(fd, &mut buf).unwrap();
-        assert_eq!(rr, 29);
+        assert_eq!(fs.remove_dir(root, "logs"), Err(Error::DirectoryNotEmpty));
         assert_eq!(
-            buf,
-            [
-                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
-                4, 5
-            ]
+            fs.remove_dir_all(root, "keep.txt"),
+            Err(Error::ExpectedToRemoveDirectory)
+        );
+
+        fs.remove_dir_all(root, "logs").unwrap();
+        assert_eq!(
+            fs.open_metadata(root, "logs").map(|_| ()),
+            Err(Error::NotFound)
         );
+        assert_eq!(fs.storage.filechunk_indices(node, 2), Vec::<u32>::new());
+        assert!(fs.storage.get_metadata(node).is_err());
+        assert_eq!(read_test_file(&mut fs, "keep.txt"), b"kept");
+
+        fs.create_dir(root, "empty", FdStat::default(), 0).unwrap();
+        fs.close_all_except(&[root]);
+        fs.remove_dir(root, "empty").unwrap();
+    }
+
+    #[test]
+    fn removal_can_defer_zeroing() {
+        use super::ChunkZeroing;
+
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "a.txt", b"secret");
+        write_test_file(&mut fs, "b.txt", b"public");
+
+        fs.remove(
+            root,
+            "a.txt",
+            RemoveOptions {
+                zeroing: Some(ChunkZeroing::Deferred),
+                ..RemoveOptions::file()
+            },
+        )
+        .unwrap();
+        fs.remove_file(root, "b.txt").unwrap();
+
+        // only the chunk of the deferred removal is waiting for zeroing
+        assert_eq!(fs.zero_freed_chunks(10), 1);
+        assert_eq!(fs.zero_freed_chunks(10), 0);
+    }
+
+    #[test]
+    fn create_dir_all_opens_existing_directories() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "a/file.txt", b"data");
 
+        let fd = fs
+            .create_dir_all(root, "a/b/c", FdStat::default(), 0)
+            .unwrap();
+        let fd = fs
+            .create_file(fd, "leaf.txt", FdStat::default(), 0)
+            .unwrap();
         fs.close(fd).unwrap();
 
+        // the second call opens the directories created by the first one
         let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .create_dir_all(root, "a/b/c", FdStat::default(), 0)
             .unwrap();
+        assert_eq!(fs.open_metadata(fd, "leaf.txt").unwrap().size, 0);
+        let fd = fs.create_dir_all(root, "a", FdStat::default(), 0).unwrap();
+        assert_eq!(fs.open_metadata(fd, "file.txt").unwrap().size, 4);
 
-        let mut buf = [42u8; 29];
-        let rr = fs.read(fd, &mut buf).unwrap();
-        assert_eq!(rr, 29);
         assert_eq!(
-            buf,
-            [
-                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
-                4, 5
-            ]
+            fs.create_dir_all(root, "a/file.txt", FdStat::default(), 0)
+                .map(|_| ()),
+            Err(Error::FileAlreadyExists)
+        );
+        assert_eq!(
+            fs.create_dir_all(root, "a/file.txt/d", FdStat::default(), 0)
+                .map(|_| ()),
+            Err(Error::InvalidFileType)
+        );
+    }
+
+    #[test]
+    fn set_size_shrinks_and_grows_files() {
+        use crate::storage::types::{FileSize, MAX_FILE_SIZE};
+
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 3)
+            .map(|i| (i % 251) as u8 + 1)
+            .collect();
+        write_test_file(&mut fs, "f", &data);
+        let fd = fs
+            .open_or_create(root, "f", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+
+        // the chunks past the new end are removed
+        let size = FILE_CHUNK_SIZE as FileSize + 10;
+        fs.set_size(fd, size).unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().size, size);
+        assert_eq!(read_test_file(&mut fs, "f"), data[..size as usize]);
+        assert_eq!(
+            fs.storage
+                .filechunk_indices(fs.metadata(fd).unwrap().node, 3),
+            vec![0, 1]
+        );
+
+        // the grown file reads as zeros past the old end, the removed bytes do not come back
+        let grown = FILE_CHUNK_SIZE as FileSize * 4;
+        fs.set_size(fd, grown).unwrap();
+        let contents = read_test_file(&mut fs, "f");
+        assert_eq!(contents.len() as FileSize, grown);
+        assert_eq!(contents[..size as usize], data[..size as usize]);
+        assert!(contents[size as usize..].iter().all(|b| *b == 0));
+        assert_eq!(
+            fs.storage
+                .filechunk_indices(fs.metadata(fd).unwrap().node, 4),
+            vec![0, 1]
         );
 
-        fs.close(fd).unwrap();
+        assert_eq!(fs.set_size(fd, MAX_FILE_SIZE + 1), Err(Error::FileTooLarge));
+        assert_eq!(fs.set_size(root, 0), Err(Error::InvalidFileType));
     }
 
     #[test]
-    fn read_and_write_vec() {
-        let mut fs = test_fs();
-
-        let dir = fs.root_fd();
+    fn fixture_trees_are_built() {
+        let mut fs = fs_with_tree(&[("a/b.txt", b"text"), ("a/empty/", b""), ("c", b"")]);
+        let root = fs.root_fd();
 
-        let write_content1 = "This is a sample file content.";
-        let write_content2 = "1234567890";
+        assert_eq!(read_test_file(&mut fs, "a/b.txt"), b"text");
+        let empty = fs.open_metadata(root, "a/empty").unwrap();
+        assert_eq!((empty.file_type, empty.size), (FileType::Directory, 0));
+        assert_eq!(
+            fs.open_metadata(root, "c").unwrap().file_type,
+            FileType::RegularFile
+        );
+    }
 
-        let write_content = [
-            SrcBuf {
-                buf: write_content1.as_ptr(),
-                len: write_content1.len(),
-            },
-            SrcBuf {
-                buf: write_content2.as_ptr(),
-                len: write_content2.len(),
-            },
-        ];
+    #[test]
+    fn allocated_ranges_are_written_without_new_chunks() {
+        use super::{Advice, Whence};
 
+        let budget = Rc::new(Cell::new(None));
+        let mut fs = FileSystem::new(Box::new(FaultyStorage::new(budget.clone()))).unwrap();
         let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .create_file(fs.root_fd(), "f", FdStat::default(), 0)
             .unwrap();
 
-        fs.write_vec(fd, write_content.as_ref()).unwrap();
-
-        let meta = fs.metadata(fd).unwrap();
-        assert_eq!(meta.size, 40);
-
-        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
-
-        let mut read_content1 = String::from("......................");
-        let mut read_content2 = String::from("......................");
-
-        let read_content = [
-            DstBuf {
-                buf: read_content1.as_mut_ptr(),
-                len: read_content1.len(),
-            },
-            DstBuf {
-                buf: read_content2.as_mut_ptr(),
-                len: read_content2.len(),
-            },
-        ];
+        fs.allocate(fd, 10, FILE_CHUNK_SIZE as u64 * 2).unwrap();
+        assert_eq!(
+            fs.metadata(fd).unwrap().size,
+            10 + FILE_CHUNK_SIZE as u64 * 2
+        );
 
-        fs.read_vec(fd, &read_content).unwrap();
+        // the writes into the range do not need new chunks
+        budget.set(Some(0));
+        let data = vec![7; FILE_CHUNK_SIZE * 2];
+        fs.seek(fd, 10, Whence::SET).unwrap();
+        assert_eq!(fs.write(fd, &data), Ok(data.len() as u64));
+        // the write past the range stops at the end of its last chunk
+        assert_eq!(
+            fs.write(fd, &[1; FILE_CHUNK_SIZE]),
+            Ok(FILE_CHUNK_SIZE as u64 - 10)
+        );
 
-        assert_eq!("This is a sample file ", read_content1);
-        assert_eq!("content.1234567890....", read_content2);
+        // a failed allocation keeps nothing
+        let size = fs.metadata(fd).unwrap().size;
+        assert_eq!(
+            fs.allocate(fd, size, FILE_CHUNK_SIZE as u64 * 4),
+            Err(Error::StorageFull)
+        );
+        assert_eq!(fs.metadata(fd).unwrap().size, size);
 
-        fs.close(fd).unwrap();
+        fs.advise(fd, 0, size, Advice::WillNeed).unwrap();
+        fs.advise(fd, 0, size, Advice::Sequential).unwrap();
+        assert_eq!(
+            fs.advise(fs.root_fd(), 0, 1, Advice::DontNeed),
+            Err(Error::InvalidFileType)
+        );
     }
 
     #[test]
-    fn read_and_write_vec_with_offset() {
+    fn append_writes_go_to_the_end_of_file() {
+        use super::Whence;
+
         let mut fs = test_fs();
+        let root = fs.root_fd();
+        let writer = fs.create_file(root, "log", FdStat::default(), 0).unwrap();
+        let stat = FdStat {
+            flags: FdFlags::APPEND,
+            ..FdStat::default()
+        };
+        let appender = fs
+            .open_or_create(root, "log", stat, OpenFlags::empty(), 0)
+            .unwrap();
 
-        let dir = fs.root_fd();
+        // the other descriptor extends the file after the appender was opened
+        fs.write(writer, b"first ").unwrap();
+        fs.write(appender, b"second ").unwrap();
+        assert_eq!(fs.tell(appender), Ok(13));
+        fs.seek(writer, 0, Whence::END).unwrap();
+        fs.write(writer, b"third ").unwrap();
+
+        let data = b"fourth";
+        let src = [SrcBuf {
+            buf: data.as_ptr(),
+            len: data.len(),
+        }];
+        fs.seek(appender, 0, Whence::SET).unwrap();
+        fs.write_vec(appender, &src).unwrap();
+        assert_eq!(read_test_file(&mut fs, "log"), b"first second third fourth");
+    }
 
-        let write_content1 = "This is a sample file content.";
-        let write_content2 = "1234567890";
+    #[test]
+    fn access_mode_is_enforced() {
+        use super::AccessMode;
 
-        let write_content = [
-            SrcBuf {
-                buf: write_content1.as_ptr(),
-                len: write_content1.len(),
-            },
-            SrcBuf {
-                buf: write_content2.as_ptr(),
-                len: write_content2.len(),
-            },
-        ];
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        write_test_file(&mut fs, "asset.css", b"body {}");
 
-        let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+        let read_only = FdStat::builder().access(AccessMode::Read).build();
+        let reader = fs
+            .open_or_create(root, "asset.css", read_only, OpenFlags::empty(), 0)
             .unwrap();
+        assert_eq!(fs.write(reader, b"p {}"), Err(Error::AccessDenied));
+        assert_eq!(fs.set_size(reader, 0), Err(Error::AccessDenied));
+        let mut buf = [0; 7];
+        assert_eq!(fs.read(reader, &mut buf), Ok(7));
+        assert_eq!(&buf, b"body {}");
+        assert!(!fs
+            .get_stat(reader)
+            .unwrap()
+            .rights_base
+            .contains(Rights::FD_WRITE));
+
+        // the access cannot be widened later
+        assert_eq!(
+            fs.set_stat(reader, FdStat::default()),
+            Err(Error::AccessDenied)
+        );
+        assert_eq!(
+            fs.open_or_create(root, "asset.css", read_only, OpenFlags::TRUNCATE, 0),
+            Err(Error::AccessDenied)
+        );
 
-        fs.write_vec_with_offset(fd, write_content.as_ref(), 2)
+        let write_only = FdStat::builder().access(AccessMode::Write).build();
+        let writer = fs
+            .open_or_create(root, "asset.css", write_only, OpenFlags::empty(), 0)
             .unwrap();
+        assert_eq!(fs.read(writer, &mut buf), Err(Error::AccessDenied));
+        assert_eq!(fs.write(writer, b"p {}"), Ok(4));
+        assert_eq!(read_test_file(&mut fs, "asset.css"), b"p {} {}");
 
-        let meta = fs.metadata(fd).unwrap();
-        assert_eq!(meta.size, 42);
-
-        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+        let fd = fs
+            .open_or_create(root, "asset.css", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        fs.set_stat(fd, read_only).unwrap();
+        assert_eq!(fs.write(fd, b"x"), Err(Error::AccessDenied));
+    }
 
-        let mut read_content1 = String::from("......................");
-        let mut read_content2 = String::from("......................");
+    #[test]
+    fn read_dir_resumes_after_removed_entries() {
+        let mut fs = fs_with_tree(&[("a", b"1"), ("b", b"2"), ("c", b"3"), ("d/", b"")]);
+        let root = fs.root_fd();
+
+        let entries: Vec<_> = fs.read_dir(root).unwrap().map(Result::unwrap).collect();
+        let names: Vec<_> = entries.iter().map(|(name, _, _)| name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c", "d"]);
+        assert_eq!(entries[3].2, FileType::Directory);
+        assert_eq!(entries[0].1, fs.open_metadata(root, "a").unwrap().node);
+
+        let mut iter = fs.read_dir(root).unwrap();
+        iter.next().unwrap().unwrap();
+        let cookie = iter.cookie();
+        iter.next().unwrap().unwrap();
+        let removed = iter.cookie();
+
+        // resuming after an entry still in the directory
+        let names: Vec<_> = fs
+            .readdir_from(root, cookie)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(names, ["b", "c", "d"]);
+
+        // resuming after a removed entry
+        fs.remove_file(root, "b").unwrap();
+        fs.remove_file(root, "c").unwrap();
+        let names: Vec<_> = fs
+            .readdir_from(root, removed)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(names, ["d"]);
 
-        let read_content = [
-            DstBuf {
-                buf: read_content1.as_mut_ptr(),
-                len: read_content1.len(),
-            },
-            DstBuf {
-                buf: read_content2.as_mut_ptr(),
-                len: read_content2.len(),
-            },
-        ];
+        let dir = fs
+            .open_or_create(root, "d", FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+        assert_eq!(fs.read_dir(dir).unwrap().count(), 0);
+    }
 
-        fs.read_vec_with_offset(fd, &read_content, 1).unwrap();
+    #[test]
+    fn region_files_have_a_fixed_size() {
+        use super::Whence;
+        use crate::storage::types::{FileSize, FILE_CHUNK_SIZE};
 
-        assert_eq!("\0This is a sample file", read_content1);
-        assert_eq!(" content.1234567890...", read_content2);
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+        let size = 2 * FILE_CHUNK_SIZE as FileSize + 100;
+        let fd = fs
+            .create_region(root, "ring.bin", FdStat::default(), size, 0)
+            .unwrap();
+        assert_eq!(fs.region_size(fd), Ok(Some(size)));
+        assert_eq!(fs.metadata(fd).unwrap().size, size);
+
+        // the writes stay within the region
+        let data = vec![7; 200];
+        fs.seek(fd, (size - 100) as i64, Whence::SET).unwrap();
+        assert_eq!(fs.write(fd, &data), Err(Error::FileTooLarge));
+        fs.seek(fd, (size - 200) as i64, Whence::SET).unwrap();
+        assert_eq!(fs.write(fd, &data), Ok(200));
+        assert_eq!(fs.metadata(fd).unwrap().size, size);
+
+        let mut buf = vec![0; 200];
+        fs.seek(fd, (size - 200) as i64, Whence::SET).unwrap();
+        assert_eq!(fs.read(fd, &mut buf), Ok(200));
+        assert_eq!(buf, data);
+
+        assert_eq!(fs.set_size(fd, 10), Err(Error::InvalidFileType));
+        assert_eq!(fs.allocate(fd, 0, size + 1), Err(Error::FileTooLarge));
+        assert_eq!(
+            fs.open_or_create(root, "ring.bin", FdStat::default(), OpenFlags::TRUNCATE, 0),
+            Err(Error::InvalidFileType)
+        );
 
-        fs.close(fd).unwrap();
+        let plain = fs.create_file(root, "plain", FdStat::default(), 0).unwrap();
+        assert_eq!(fs.region_size(plain), Ok(None));
     }
 
     #[test]
@@ -876,6 +8345,282 @@ mod tests {
         fs.remove_file(dir, "test.txt").unwrap();
     }
 
+    #[test]
+    fn remove_with_options_checks_entry_type() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let fd = fs.create_dir(dir, "test", FdStat::default(), 0).unwrap();
+        fs.close(fd).unwrap();
+        let fd = fs
+            .create_file(dir, "test.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.close(fd).unwrap();
+
+        let err = fs.remove(dir, "test", RemoveOptions::file()).unwrap_err();
+        assert_eq!(err, Error::ExpectedToRemoveFile);
+
+        let err = fs
+            .remove(dir, "test.txt", RemoveOptions::dir())
+            .unwrap_err();
+        assert_eq!(err, Error::ExpectedToRemoveDirectory);
+
+        fs.remove(dir, "test", RemoveOptions::default()).unwrap();
+        fs.remove(dir, "test.txt", RemoveOptions::default())
+            .unwrap();
+
+        let meta = fs.metadata(dir).unwrap();
+        assert_eq!(meta.size, 0);
+    }
+
+    #[test]
+    fn remove_recursive() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let fd = create_test_file(&mut fs, dir, "dir1/dir2/file1.txt");
+        fs.close(fd).unwrap();
+        let fd = create_test_file(&mut fs, dir, "dir1/file2.txt");
+        fs.close(fd).unwrap();
+
+        let err = fs.remove(dir, "dir1", RemoveOptions::dir()).unwrap_err();
+        assert_eq!(err, Error::DirectoryNotEmpty);
+
+        let options = RemoveOptions {
+            must_be_dir: Some(true),
+            must_be_empty: false,
+            recursive: true,
+            follow_symlinks: false,
+            zeroing: None,
+        };
+
+        let file_node = find_node(
+            fs.storage.root_node(),
+            "dir1/dir2/file1.txt",
+            fs.storage.as_ref(),
+        )
+        .unwrap();
+
+        fs.remove(dir, "dir1", options).unwrap();
+
+        let meta = fs.metadata(dir).unwrap();
+        assert_eq!(meta.size, 0);
+        assert_eq!(
+            fs.metadata_from_node(file_node).unwrap_err(),
+            Error::NotFound
+        );
+
+        let err = fs
+            .open_or_create(dir, "dir1", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap_err();
+        assert_eq!(err, Error::NotFound);
+    }
+
+    #[test]
+    fn remove_recursive_fails_on_opened_node() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let fd = create_test_file(&mut fs, dir, "dir1/dir2/file1.txt");
+
+        let options = RemoveOptions {
+            must_be_dir: None,
+            must_be_empty: false,
+            recursive: true,
+            follow_symlinks: false,
+            zeroing: None,
+        };
+
+        let err = fs.remove(dir, "dir1", options).unwrap_err();
+        assert_eq!(err, Error::CannotRemoveOpenedNode);
+
+        // nothing was removed
+        fs.metadata(fd).unwrap();
+
+        fs.close(fd).unwrap();
+        fs.remove(dir, "dir1", options).unwrap();
+    }
+
+    #[test]
+    fn failed_recursive_remove_keeps_the_subtree() {
+        let mut fs = fs_with_tree(&[("dir1/dir2/file1.txt", b"a"), ("dir1/file2.txt", b"b")]);
+        let root = fs.root_fd();
+
+        let options = RemoveOptions {
+            must_be_dir: Some(false),
+            ..RemoveOptions::dir_all()
+        };
+        assert_eq!(
+            fs.remove(root, "dir1", options),
+            Err(Error::ExpectedToRemoveFile)
+        );
+        assert!(fs.open_metadata(root, "dir1/dir2/file1.txt").is_ok());
+        assert!(fs.open_metadata(root, "dir1/file2.txt").is_ok());
+
+        // the directory itself is opened
+        let dir = fs
+            .open_or_create(root, "dir1", FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+        assert_eq!(
+            fs.remove(root, "dir1", RemoveOptions::dir_all()),
+            Err(Error::CannotRemoveOpenedNode)
+        );
+        assert_eq!(fs.metadata(dir).unwrap().size, 2);
+        assert!(fs.open_metadata(root, "dir1/dir2/file1.txt").is_ok());
+
+        fs.close(dir).unwrap();
+        fs.remove(root, "dir1", RemoveOptions::dir_all()).unwrap();
+    }
+
+    #[test]
+    fn remove_with_conflicting_options_fails() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let fd = fs.create_dir(dir, "test", FdStat::default(), 0).unwrap();
+        fs.close(fd).unwrap();
+
+        let options = RemoveOptions {
+            must_be_dir: Some(true),
+            must_be_empty: true,
+            recursive: true,
+            follow_symlinks: false,
+            zeroing: None,
+        };
+
+        let err = fs.remove(dir, "test", options).unwrap_err();
+        assert_eq!(err, Error::InvalidRemoveOptions);
+    }
+
+    #[test]
+    fn remove_follows_aliases_only_when_asked() {
+        let mut fs = fs_with_tree(&[
+            ("docs/a.txt", b"a"),
+            ("docs/b.txt", b"b"),
+            ("logs/c.log", b"c"),
+        ]);
+        let root = fs.root_fd();
+        fs.create_alias(root, "a", "docs/a.txt", 0).unwrap();
+        fs.create_alias(root, "b", "docs/b.txt", 0).unwrap();
+        fs.create_alias(root, "logs-link", "logs", 0).unwrap();
+
+        let follow = RemoveOptions {
+            follow_symlinks: true,
+            ..RemoveOptions::file()
+        };
+
+        // by default the alias itself is removed, its target is kept
+        fs.remove(root, "a", RemoveOptions::file()).unwrap();
+        assert!(!fs.reader().exists("a"));
+        assert!(fs.reader().exists("docs/a.txt"));
+
+        // a followed alias is kept, the file it leads to is removed
+        fs.remove(root, "b", follow).unwrap();
+        assert!(fs.reader().exists("b"));
+        assert!(!fs.reader().exists("docs/b.txt"));
+        assert_eq!(fs.remove(root, "b", follow), Err(Error::NotFound));
+
+        // the type guards apply to the target
+        assert_eq!(
+            fs.remove(root, "logs-link", follow),
+            Err(Error::ExpectedToRemoveFile)
+        );
+        let follow_dir = RemoveOptions {
+            follow_symlinks: true,
+            ..RemoveOptions::dir_all()
+        };
+        let report = fs.remove_dry_run(root, "logs-link", follow_dir).unwrap();
+        assert_eq!(report.paths, vec!["logs", "logs/c.log"]);
+        fs.remove(root, "logs-link", follow_dir).unwrap();
+        assert!(!fs.reader().exists("logs"));
+        assert!(fs.reader().exists("logs-link"));
+
+        // without following, the alias to a directory is removed as a file
+        fs.remove(root, "logs-link", RemoveOptions::file()).unwrap();
+        assert!(!fs.reader().exists("logs-link"));
+    }
+
+    #[test]
+    fn list_dir_with_dot_entries() {
+        let mut fs = test_fs();
+
+        let root_fd = fs.root_fd();
+
+        let dir_fd = fs
+            .create_dir(root_fd, "dir1/dir2", FdStat::default(), 0)
+            .unwrap();
+        fs.create_file(dir_fd, "test.txt", FdStat::default(), 0)
+            .unwrap();
+
+        let names = |entries: &Vec<crate::storage::types::DirEntry>| {
+            entries
+                .iter()
+                .map(|e| String::from_utf8(e.name.bytes[0..e.name.length as usize].to_vec()))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        let entries = fs.list_dir(dir_fd).unwrap();
+        assert_eq!(names(&entries), vec!["test.txt"]);
+
+        let mut options = fs.options();
+        options.dot_entries = true;
+        fs.set_options(options);
+
+        let dir_node = fs.metadata(dir_fd).unwrap().node;
+        let parent_node = find_node(fs.storage.root_node(), "dir1", fs.storage.as_ref()).unwrap();
+
+        let entries = fs.list_dir(dir_fd).unwrap();
+        assert_eq!(names(&entries), vec![".", "..", "test.txt"]);
+        assert_eq!(entries[0].node, dir_node);
+        assert_eq!(entries[1].node, parent_node);
+
+        // the parent of the root folder is the root folder itself
+        let root_node = fs.storage.root_node();
+        let entries = fs.list_dir(root_fd).unwrap();
+        assert_eq!(names(&entries), vec![".", "..", "dir1"]);
+        assert_eq!(entries[0].node, root_node);
+        assert_eq!(entries[1].node, root_node);
+    }
+
+    #[test]
+    fn dir_generation_changes_with_entries() {
+        let mut fs = test_fs();
+
+        let root_fd = fs.root_fd();
+        let dir_fd = fs
+            .create_dir(root_fd, "dir1", FdStat::default(), 0)
+            .unwrap();
+
+        let gen0 = fs.dir_generation(dir_fd).unwrap();
+
+        let fd = fs
+            .create_file(dir_fd, "test.txt", FdStat::default(), 0)
+            .unwrap();
+        let gen1 = fs.dir_generation(dir_fd).unwrap();
+        assert!(gen1 > gen0);
+
+        // writing into a file does not change the directory entries
+        fs.write(fd, &[1, 2, 3]).unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(fs.dir_generation(dir_fd).unwrap(), gen1);
+
+        let fd = fs.rename(dir_fd, "test.txt", dir_fd, "test2.txt").unwrap();
+        let gen2 = fs.dir_generation(dir_fd).unwrap();
+        assert!(gen2 > gen1);
+
+        assert_eq!(fs.dir_generation(fd), Err(Error::InvalidFileType));
+        fs.close(fd).unwrap();
+
+        fs.remove_file(dir_fd, "test2.txt").unwrap();
+        let gen3 = fs.dir_generation(dir_fd).unwrap();
+        assert!(gen3 > gen2);
+    }
+
     #[test]
     fn renumber_when_the_alternative_file_exists() {
         let mut fs = test_fs();
@@ -924,7 +8669,7 @@ mod tests {
 
         assert!(pos1 == 5);
 
-        let fd2 = 100;
+        let fd2 = Fd::new(100);
 
         fs.renumber(fd1, fd2).unwrap();
 
@@ -963,6 +8708,33 @@ mod tests {
         assert_eq!(metadata.file_type, FileType::RegularFile);
     }
 
+    #[test]
+    fn metadata_returns_file_stat() {
+        let mut fs = test_fs();
+        let dir = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(dir, "file1.txt", FdStat::default(), OpenFlags::CREATE, 111)
+            .unwrap();
+        fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+
+        fs.create_hard_link(dir, "file1.txt", dir, "file2.txt")
+            .unwrap();
+
+        let stat = fs.metadata(fd).unwrap();
+        let metadata = fs.raw_metadata(fd).unwrap();
+
+        assert_eq!(stat.node, metadata.node);
+        assert_eq!(stat.generation, 0);
+        assert_eq!(stat.file_type, FileType::RegularFile);
+        assert_eq!(stat.link_count, 2);
+        assert_eq!(stat.size, 5);
+        assert_eq!(stat.times, metadata.times);
+
+        assert_eq!(fs.open_metadata(dir, "file2.txt"), Ok(stat));
+        assert_eq!(fs.metadata_from_node(stat.node), Ok(stat));
+    }
+
     #[test]
     fn set_stat_get_stat() {
         let mut fs = test_fs();
@@ -974,21 +8746,52 @@ mod tests {
 
         fs.write(fd1, &[1, 2, 3, 4, 5]).unwrap();
 
-        let (file_type, mut stat) = fs.get_stat(fd1).unwrap();
+        let stat = fs.get_stat(fd1).unwrap();
 
-        assert_eq!(file_type, FileType::RegularFile);
+        assert_eq!(stat.file_type, FileType::RegularFile);
 
         assert_eq!(stat.flags, FdFlags::empty());
 
-        stat.flags = FdFlags::APPEND;
-
-        fs.set_stat(fd1, stat).unwrap();
+        fs.set_stat(fd1, FdStat::builder().flags(FdFlags::APPEND).build())
+            .unwrap();
 
-        let (_, stat2) = fs.get_stat(fd1).unwrap();
+        let stat2 = fs.get_stat(fd1).unwrap();
 
         assert_eq!(stat2.flags, FdFlags::APPEND);
     }
 
+    #[test]
+    fn get_stat_reports_wasi_fdstat() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        // the descriptors without rights are not restricted
+        let stat = fs.get_stat(root).unwrap();
+        assert_eq!(stat.rights_base, Rights::ALL_DIR);
+        assert_eq!(stat.rights_inheriting, Rights::ALL_FILE | Rights::ALL_DIR);
+        assert_eq!(stat.wasi_file_type(), 3);
+
+        let fd = fs
+            .create_file(
+                root,
+                "f",
+                FdStat::builder()
+                    .flags(FdFlags::APPEND | FdFlags::SYNC)
+                    .rights(Rights::READ_ONLY)
+                    .build(),
+                0,
+            )
+            .unwrap();
+        let bytes = fs.get_stat(fd).unwrap().to_bytes();
+        assert_eq!(bytes[0], 4);
+        assert_eq!(u16::from_le_bytes([bytes[2], bytes[3]]), 1 | 16);
+        assert_eq!(
+            u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            Rights::READ_ONLY.bits()
+        );
+        assert_eq!(u64::from_le_bytes(bytes[16..].try_into().unwrap()), 0);
+    }
+
     fn create_test_file_with_content(
         fs: &mut FileSystem,
         parent: Fd,
@@ -1029,12 +8832,10 @@ mod tests {
         let mut fs = test_fs();
         let dir = fs.root_fd();
 
-        let root_fd = 3i32;
-
         let file_name1 = String::from("file.txt");
         let file_name2 = String::from("file_link.txt");
 
-        let file_fd = create_test_file(&mut fs, root_fd as Fd, &file_name1);
+        let file_fd = create_test_file(&mut fs, dir, &file_name1);
 
         let root_node = fs.storage.as_ref().root_node();
         let node1 = find_node(root_node, &file_name1, fs.storage.as_ref()).unwrap();
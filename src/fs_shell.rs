@@ -0,0 +1,262 @@
+// A small command interpreter for inspecting and changing the file system from a debug endpoint.
+// The commands are separated by ';' or new lines, the paths are resolved from the root folder.
+
+use std::fmt::Write;
+
+use crate::{
+    error::Error,
+    fs::{Fd, FdStat, FileSystem, OpenFlags},
+    storage::types::{FileSize, FileType, FILE_CHUNK_SIZE},
+};
+
+// The most bytes of a file printed by `cat`, the rest is cut off.
+const MAX_CAT_SIZE: usize = 1024 * 1024;
+
+const HELP: &str = "commands:
+  ls [-l] [path]        list a directory
+  cat path              print a file, up to 1 MiB
+  stat path             print the node stats
+  mkdir path            create a directory with the missing parents
+  write path text...    replace the file contents with the text
+  rm [-r] path          remove a file, or a directory with its contents
+  mv from to            rename a file or a directory
+  echo text...          print the text
+  help                  print this help
+";
+
+// Execute the commands of a script and return their output, the errors are reported in the output.
+// The created nodes get the creation time 0.
+pub fn exec(fs: &mut FileSystem, script: &str) -> String {
+    let mut output = String::new();
+
+    for command in script.split([';', '\n']) {
+        let args: Vec<&str> = command.split_whitespace().collect();
+
+        let Some((&name, args)) = args.split_first() else {
+            continue;
+        };
+
+        if let Err(err) = exec_command(fs, name, args, &mut output) {
+            let _ = writeln!(output, "{name}: {err:?}");
+        }
+    }
+
+    output
+}
+
+fn exec_command(
+    fs: &mut FileSystem,
+    name: &str,
+    args: &[&str],
+    output: &mut String,
+) -> Result<(), Error> {
+    match (name, args) {
+        ("ls", ["-l"]) => ls(fs, "", true, output),
+        ("ls", ["-l", path]) => ls(fs, path, true, output),
+        ("ls", []) => ls(fs, "", false, output),
+        ("ls", [path]) => ls(fs, path, false, output),
+        ("cat", [path]) => cat(fs, path, output),
+        ("stat", [path]) => stat(fs, path, output),
+        ("mkdir", [path]) => mkdir(fs, path),
+        ("write", [path, text @ ..]) => {
+            let root = fs.root_fd();
+            fs.apply_file(root, relative(path), text.join(" ").as_bytes(), 0)
+        }
+        ("rm", [path]) => {
+            let root = fs.root_fd();
+            fs.remove_file(root, relative(path))
+        }
+        ("rm", ["-r", path]) => {
+            let root = fs.root_fd();
+            fs.apply_remove(root, relative(path))
+        }
+        ("mv", [from, to]) => {
+            let root = fs.root_fd();
+            let fd = fs.rename(root, relative(from), root, relative(to))?;
+            fs.close(fd)
+        }
+        ("echo", text) => {
+            let _ = writeln!(output, "{}", text.join(" "));
+            Ok(())
+        }
+        ("help", []) => {
+            output.push_str(HELP);
+            Ok(())
+        }
+        _ => {
+            let _ = writeln!(output, "{name}: unknown command or arguments, try help");
+            Ok(())
+        }
+    }
+}
+
+// The shell paths are absolute, the file system paths are relative to the root folder.
+fn relative(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+fn ls(fs: &mut FileSystem, path: &str, long: bool, output: &mut String) -> Result<(), Error> {
+    let root = fs.root_fd();
+    let path = relative(path);
+
+    let fd = if path.is_empty() {
+        root
+    } else {
+        fs.open_or_create(root, path, FdStat::default(), OpenFlags::DIRECTORY, 0)?
+    };
+
+    let result = fs.list_dir(fd);
+
+    if fd != root {
+        fs.close(fd)?;
+    }
+
+    for entry in result? {
+        let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+
+        if long {
+            let stat = fs.metadata_from_node(entry.node)?;
+            let _ = writeln!(
+                output,
+                "{} {:>10} {}",
+                type_char(stat.file_type),
+                stat.size,
+                name
+            );
+        } else {
+            let _ = writeln!(output, "{name}");
+        }
+    }
+
+    Ok(())
+}
+
+fn cat(fs: &mut FileSystem, path: &str, output: &mut String) -> Result<(), Error> {
+    let root = fs.root_fd();
+    let fd = fs.open_or_create(
+        root,
+        relative(path),
+        FdStat::default(),
+        OpenFlags::empty(),
+        0,
+    )?;
+
+    let result = read_head(fs, fd);
+    fs.close(fd)?;
+    let (content, size) = result?;
+
+    let text = String::from_utf8_lossy(&content);
+    output.push_str(&text);
+    if !text.is_empty() && !text.ends_with('\n') {
+        output.push('\n');
+    }
+    if size > content.len() as FileSize {
+        let _ = writeln!(output, "cat: {} of {size} bytes printed", content.len());
+    }
+
+    Ok(())
+}
+
+// Read at most `MAX_CAT_SIZE` bytes of a file one chunk at a time, returns them with the file size.
+fn read_head(fs: &mut FileSystem, fd: Fd) -> Result<(Vec<u8>, FileSize), Error> {
+    let size = fs.metadata(fd)?.size;
+    let mut content = Vec::new();
+    let mut buf = vec![0; FILE_CHUNK_SIZE];
+
+    while content.len() < MAX_CAT_SIZE {
+        let len = buf.len().min(MAX_CAT_SIZE - content.len());
+        let read = fs.read(fd, &mut buf[..len])? as usize;
+        if read == 0 {
+            break;
+        }
+        content.extend_from_slice(&buf[..read]);
+    }
+
+    Ok((content, size))
+}
+
+fn stat(fs: &mut FileSystem, path: &str, output: &mut String) -> Result<(), Error> {
+    let root = fs.root_fd();
+    let path = relative(path);
+
+    let stat = if path.is_empty() {
+        fs.metadata(root)?
+    } else {
+        fs.open_metadata(root, path)?
+    };
+
+    let _ = writeln!(
+        output,
+        "type {}\nnode {}\nsize {}\nlinks {}\nmodified {}",
+        type_char(stat.file_type),
+        stat.node,
+        stat.size,
+        stat.link_count,
+        stat.times.modified
+    );
+
+    Ok(())
+}
+
+fn mkdir(fs: &mut FileSystem, path: &str) -> Result<(), Error> {
+    let root = fs.root_fd();
+    let fd = fs.create_dir(root, relative(path), FdStat::default(), 0)?;
+    fs.close(fd)
+}
+
+fn type_char(file_type: FileType) -> char {
+    match file_type {
+        FileType::Directory => 'd',
+        FileType::RegularFile => '-',
+        FileType::SymbolicLink => 'l',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::test_fs;
+
+    use super::{exec, MAX_CAT_SIZE};
+
+    #[test]
+    fn run_a_script() {
+        let mut fs = test_fs();
+
+        let output = exec(
+            &mut fs,
+            "mkdir /assets; write /assets/cfg.json {\"a\": 1}\nls -l /assets; cat /assets/cfg.json",
+        );
+        assert_eq!(output, "-          8 cfg.json\n{\"a\": 1}\n");
+
+        let output = exec(
+            &mut fs,
+            "mv /assets/cfg.json /cfg.json; ls; rm /cfg.json; ls /assets",
+        );
+        assert_eq!(output, "assets\ncfg.json\n");
+
+        let output = exec(&mut fs, "cat /missing.txt; ls /a /b; echo done");
+        assert_eq!(
+            output,
+            "cat: NotFound\nls: unknown command or arguments, try help\ndone\n"
+        );
+
+        let output = exec(&mut fs, "write /a/b.txt x; rm -r /a; ls");
+        assert_eq!(output, "assets\n");
+    }
+
+    #[test]
+    fn cat_prints_the_head_of_a_large_file() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        fs.apply_file(root, "large.txt", &vec![b'x'; MAX_CAT_SIZE + 10], 0)
+            .unwrap();
+        let output = exec(&mut fs, "cat /large.txt");
+        let note = format!(
+            "cat: {MAX_CAT_SIZE} of {} bytes printed\n",
+            MAX_CAT_SIZE + 10
+        );
+        assert_eq!(output.len(), MAX_CAT_SIZE + 1 + note.len());
+        assert!(output.ends_with(&format!("xxx\n{note}")));
+    }
+}
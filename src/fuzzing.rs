@@ -0,0 +1,472 @@
+// The driver of the fuzz targets in the `fuzz` directory.
+// The input bytes are decoded into a sequence of file system operations with arbitrary paths, names,
+// flags, offsets and buffer shapes, the operations are applied to a file system over the transient
+// storage and checked against a model of the file contents. Any broken invariant panics.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    error::Error,
+    fs::{DstBuf, Fd, FdStat, FileSystem, OpenFlags, RemoveOptions, SrcBuf, Whence},
+    storage::{
+        transient::TransientStorage,
+        types::{FileSize, FileType, Node, MAX_FILE_NAME, MAX_FILE_SIZE},
+    },
+};
+
+// The maximum number of operations decoded from one input.
+const MAX_OPS: usize = 256;
+// The small offsets are kept below this size so that the model stays small.
+const MAX_MODEL_OFFSET: u64 = 64 * 1024;
+const MAX_BUFFERS: usize = 4;
+const MAX_BUFFER_LEN: usize = 600;
+
+// A reader of the fuzzer input, an exhausted input reads as zeros.
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Input<'_> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn byte(&mut self) -> u8 {
+        let byte = self.data.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        byte
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.byte(), self.byte()])
+    }
+
+    fn u64(&mut self) -> u64 {
+        (0..8).fold(0, |value, _| (value << 8) | self.byte() as u64)
+    }
+
+    // A name from a small set, so that the operations hit the same files, or a hostile one.
+    fn name(&mut self) -> String {
+        match self.byte() % 12 {
+            0..=3 => "a".to_string(),
+            4 | 5 => "b".to_string(),
+            6 => "c.txt".to_string(),
+            7 => ".".to_string(),
+            8 => "..".to_string(),
+            9 => String::new(),
+            10 => "x".repeat(MAX_FILE_NAME + self.byte() as usize % 2),
+            _ => {
+                let len = self.byte() as usize % 8;
+                let bytes: Vec<u8> = (0..len).map(|_| self.byte()).collect();
+                String::from_utf8_lossy(&bytes).into_owned()
+            }
+        }
+    }
+
+    fn path(&mut self) -> String {
+        let components = 1 + self.byte() as usize % 3;
+        let mut path = (0..components)
+            .map(|_| self.name())
+            .collect::<Vec<_>>()
+            .join("/");
+        match self.byte() % 8 {
+            0 => path.insert(0, '/'),
+            1 => path.push('/'),
+            _ => {}
+        }
+        path
+    }
+
+    // A small offset checked by the model, or one at or above the maximum file size.
+    fn offset(&mut self) -> FileSize {
+        match self.byte() % 8 {
+            0 => MAX_FILE_SIZE + self.u16() as u64,
+            1 => u64::MAX - self.u16() as u64,
+            2 => self.u64() | MAX_FILE_SIZE,
+            _ => self.u16() as u64 % MAX_MODEL_OFFSET,
+        }
+    }
+
+    // The lengths of the buffers of an iovec.
+    fn shape(&mut self) -> Vec<usize> {
+        let count = self.byte() as usize % (MAX_BUFFERS + 1);
+        (0..count)
+            .map(|_| self.u16() as usize % MAX_BUFFER_LEN)
+            .collect()
+    }
+}
+
+enum Op {
+    Open {
+        path: String,
+        flags: OpenFlags,
+    },
+    CreateDir {
+        path: String,
+    },
+    Write {
+        fd: usize,
+        shape: Vec<usize>,
+        offset: Option<FileSize>,
+    },
+    Read {
+        fd: usize,
+        shape: Vec<usize>,
+        offset: Option<FileSize>,
+    },
+    Seek {
+        fd: usize,
+        delta: i64,
+        whence: Whence,
+    },
+    Close {
+        fd: usize,
+    },
+    Remove {
+        path: String,
+        dir: bool,
+    },
+    Rename {
+        old_path: String,
+        new_path: String,
+    },
+}
+
+impl Op {
+    fn decode(input: &mut Input) -> Self {
+        let offset = |input: &mut Input| match input.byte() % 2 {
+            0 => None,
+            _ => Some(input.offset()),
+        };
+
+        match input.byte() % 8 {
+            0 => Op::Open {
+                path: input.path(),
+                flags: OpenFlags::from_bits_truncate(input.u16()),
+            },
+            1 => Op::CreateDir { path: input.path() },
+            2 => Op::Write {
+                fd: input.byte() as usize,
+                shape: input.shape(),
+                offset: offset(input),
+            },
+            3 => Op::Read {
+                fd: input.byte() as usize,
+                shape: input.shape(),
+                offset: offset(input),
+            },
+            4 => Op::Seek {
+                fd: input.byte() as usize,
+                delta: match input.byte() % 2 {
+                    0 => input.u16() as i16 as i64,
+                    _ => input.u64() as i64,
+                },
+                whence: match input.byte() % 3 {
+                    0 => Whence::SET,
+                    1 => Whence::CUR,
+                    _ => Whence::END,
+                },
+            },
+            5 => Op::Close {
+                fd: input.byte() as usize,
+            },
+            6 => Op::Remove {
+                path: input.path(),
+                dir: input.byte() % 2 == 1,
+            },
+            _ => Op::Rename {
+                old_path: input.path(),
+                new_path: input.path(),
+            },
+        }
+    }
+}
+
+// The file system under test with the expected contents of its regular files.
+struct Model {
+    fs: FileSystem,
+    files: BTreeMap<Node, Vec<u8>>,
+    // the descriptors opened by the operations
+    fds: Vec<Fd>,
+    seed: u8,
+}
+
+impl Model {
+    fn new() -> Self {
+        let fs =
+            FileSystem::new(Box::new(TransientStorage::new())).expect("the file system is created");
+        Self {
+            fs,
+            files: BTreeMap::new(),
+            fds: Vec::new(),
+            seed: 0,
+        }
+    }
+
+    // Pick one of the opened descriptors, an index past them picks an invalid descriptor.
+    fn fd(&self, index: usize) -> Fd {
+        match self.fds.len() {
+            len if index % (len + 1) == len => Fd::new(u32::MAX - index as u32),
+            len => self.fds[index % len],
+        }
+    }
+
+    // The node of a regular file opened under the descriptor.
+    fn file_node(&self, fd: Fd) -> Option<Node> {
+        self.fs
+            .open_fds()
+            .find(|(open_fd, file_type, _, _)| {
+                *open_fd == fd && *file_type == FileType::RegularFile
+            })
+            .map(|(_, _, node, _)| node)
+    }
+
+    fn data(&mut self, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                self.seed = self.seed.wrapping_add(1);
+                self.seed
+            })
+            .collect()
+    }
+
+    fn apply(&mut self, op: Op) {
+        let root = self.fs.root_fd();
+
+        match op {
+            Op::Open { path, flags } => {
+                let truncate = flags.contains(OpenFlags::TRUNCATE);
+                if let Ok(fd) = self
+                    .fs
+                    .open_or_create(root, &path, FdStat::default(), flags, 0)
+                {
+                    self.opened(fd, truncate);
+                }
+            }
+            Op::CreateDir { path } => {
+                let created = self.fs.create_dir(root, &path, FdStat::default(), 0);
+                if let Ok(fd) = created {
+                    self.fds.push(fd);
+                }
+            }
+            Op::Write { fd, shape, offset } => self.write(self.fd(fd), shape, offset),
+            Op::Read { fd, shape, offset } => self.read(self.fd(fd), shape, offset),
+            Op::Seek { fd, delta, whence } => {
+                let fd = self.fd(fd);
+                let before = self.fs.tell(fd);
+                if self.fs.seek(fd, delta, whence).is_err() {
+                    assert_eq!(self.fs.tell(fd), before, "a failed seek moves the cursor");
+                }
+            }
+            Op::Close { fd } => {
+                let fd = self.fd(fd);
+                let result = self.fs.close(fd);
+                assert_eq!(
+                    result.is_ok(),
+                    self.fds.contains(&fd),
+                    "closing the descriptor {fd}"
+                );
+                self.fds.retain(|open| *open != fd);
+            }
+            Op::Remove { path, dir } => {
+                let options = match dir {
+                    true => RemoveOptions::dir(),
+                    false => RemoveOptions::file(),
+                };
+                let node = self.fs.open_metadata(root, &path).map(|stat| stat.node);
+                if self.fs.remove(root, &path, options).is_ok() {
+                    // the node numbers of the removed files can be reused
+                    if let Ok(node) = node {
+                        self.files.remove(&node);
+                    }
+                    assert_eq!(
+                        self.fs.open_metadata(root, &path).map(|_| ()),
+                        Err(Error::NotFound),
+                        "the removed path {path:?} is found"
+                    );
+                }
+            }
+            Op::Rename { old_path, new_path } => {
+                if let Ok(fd) = self.fs.rename(root, &old_path, root, &new_path) {
+                    self.fs.close(fd).expect("the renamed file is closed");
+                }
+            }
+        }
+
+        self.check_sizes();
+    }
+
+    fn opened(&mut self, fd: Fd, truncate: bool) {
+        assert!(!self.fds.contains(&fd), "the descriptor {fd} is reused");
+        self.fds.push(fd);
+
+        if let Some(node) = self.file_node(fd) {
+            let size = self
+                .fs
+                .metadata(fd)
+                .expect("the opened file has metadata")
+                .size;
+            let contents = self.files.entry(node).or_default();
+            if truncate {
+                contents.clear();
+            }
+            assert_eq!(
+                size,
+                contents.len() as u64,
+                "the size of the opened node {node}"
+            );
+        }
+    }
+
+    fn write(&mut self, fd: Fd, shape: Vec<usize>, offset: Option<FileSize>) {
+        let buffers: Vec<Vec<u8>> = shape.iter().map(|len| self.data(*len)).collect();
+        let src: Vec<SrcBuf> = buffers
+            .iter()
+            .map(|buf| SrcBuf {
+                buf: buf.as_ptr(),
+                len: buf.len(),
+            })
+            .collect();
+
+        let start = match offset {
+            Some(offset) => Ok(offset),
+            None => self.fs.tell(fd),
+        };
+        let result = match offset {
+            Some(offset) => self.fs.write_vec_with_offset(fd, &src, offset),
+            None => self.fs.write_vec(fd, &src),
+        };
+
+        let Some(node) = self.file_node(fd) else {
+            assert!(result.is_err(), "written into the non-file descriptor {fd}");
+            return;
+        };
+        let contents = self
+            .files
+            .get_mut(&node)
+            .expect("the opened file is modeled");
+        let start = start.expect("the opened file has a cursor");
+        let data = buffers.concat();
+
+        match result {
+            Ok(size) => {
+                assert_eq!(size, data.len() as u64, "the written size");
+                if !data.is_empty() {
+                    let start = start as usize;
+                    if contents.len() < start + data.len() {
+                        contents.resize(start + data.len(), 0);
+                    }
+                    contents[start..start + data.len()].copy_from_slice(&data);
+                }
+            }
+            Err(err) => {
+                assert_eq!(err, Error::FileTooLarge, "writing at {start}");
+                assert!(start >= MAX_MODEL_OFFSET, "failed writing at {start}");
+            }
+        }
+    }
+
+    fn read(&mut self, fd: Fd, shape: Vec<usize>, offset: Option<FileSize>) {
+        let mut buffers: Vec<Vec<u8>> = shape.iter().map(|len| vec![0; *len]).collect();
+        let dst: Vec<DstBuf> = buffers
+            .iter_mut()
+            .map(|buf| DstBuf {
+                buf: buf.as_mut_ptr(),
+                len: buf.len(),
+            })
+            .collect();
+
+        let start = match offset {
+            Some(offset) => Ok(offset),
+            None => self.fs.tell(fd),
+        };
+        let result = match offset {
+            Some(offset) => self.fs.read_vec_with_offset(fd, &dst, offset),
+            None => self.fs.read_vec(fd, &dst),
+        };
+
+        let Some(node) = self.file_node(fd) else {
+            assert!(result.is_err(), "read from the non-file descriptor {fd}");
+            return;
+        };
+        let contents = &self.files[&node];
+        let start = start.expect("the opened file has a cursor");
+        let size = result.expect("the opened file is read");
+
+        let expected = contents.get(start as usize..).unwrap_or_default();
+        let expected = &expected[..expected.len().min(buffers.iter().map(Vec::len).sum())];
+        assert_eq!(size, expected.len() as u64, "the read size at {start}");
+        assert_eq!(
+            &buffers.concat()[..expected.len()],
+            expected,
+            "the contents read at {start}"
+        );
+    }
+
+    // The sizes of all opened files match the model.
+    fn check_sizes(&self) {
+        for (fd, file_type, node, _) in self.fs.open_fds() {
+            if file_type == FileType::RegularFile {
+                let size = self
+                    .fs
+                    .metadata(fd)
+                    .expect("the opened file has metadata")
+                    .size;
+                assert_eq!(
+                    size,
+                    self.files[&node].len() as u64,
+                    "the size of node {node}"
+                );
+            }
+        }
+    }
+
+    fn close_all(&mut self) {
+        for fd in std::mem::take(&mut self.fds) {
+            self.fs.close(fd).expect("the opened descriptor is closed");
+        }
+        assert_eq!(self.fs.open_fds().count(), 1, "only the root is left open");
+    }
+}
+
+// Decode the input into operations and apply them, panics if an invariant is broken.
+pub fn run_ops(data: &[u8]) {
+    let mut input = Input { data, pos: 0 };
+    let mut model = Model::new();
+
+    for _ in 0..MAX_OPS {
+        if input.is_empty() {
+            break;
+        }
+        let op = Op::decode(&mut input);
+        model.apply(op);
+    }
+
+    model.close_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::FuzzRng;
+
+    use super::run_ops;
+
+    #[test]
+    fn random_operations_keep_the_invariants() {
+        let mut rng = FuzzRng::new(471);
+
+        for _ in 0..300 {
+            let len = rng.below(2048) as usize;
+            run_ops(&rng.bytes(len));
+        }
+    }
+
+    #[test]
+    fn empty_and_short_inputs() {
+        run_ops(&[]);
+        for byte in 0..=255 {
+            run_ops(&[byte]);
+        }
+    }
+}
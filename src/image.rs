@@ -0,0 +1,218 @@
+// Comparison of two disk images of the file system on the host, e.g. the stable memory of a canister
+// downloaded before and after an upgrade or a restore. The images are copied, they are never changed.
+
+use std::collections::BTreeSet;
+
+use ic_stable_structures::VectorMemory;
+
+use crate::{
+    error::Error,
+    fs::FileSystem,
+    runtime::{budget::BUDGET_SLICE_SIZE, structure_helpers::find_node},
+    storage::{
+        stable::StableStorage,
+        types::{FileSize, FileType},
+    },
+};
+
+// The type and the size of a path in an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathSummary {
+    pub file_type: FileType,
+    pub size: FileSize,
+}
+
+// A path present in both images with different contents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangedPath {
+    pub path: String,
+    pub before: PathSummary,
+    pub after: PathSummary,
+    // The bytes that differ within the length of the shorter version, 0 if the types differ.
+    pub changed_bytes: FileSize,
+}
+
+// The paths added, removed and changed between two images, sorted by path.
+// The directories are only reported when they are added or removed, their changes are the changes of their entries.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageDiff {
+    pub added: Vec<(String, PathSummary)>,
+    pub removed: Vec<(String, PathSummary)>,
+    pub changed: Vec<ChangedPath>,
+}
+
+impl ImageDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// Mount a copy of a disk image made by the stable storage.
+pub fn open_image(image: &[u8]) -> Result<FileSystem, Error> {
+    let memory = VectorMemory::default();
+    memory.borrow_mut().extend_from_slice(image);
+    FileSystem::new(Box::new(StableStorage::try_new(memory)?))
+}
+
+// Find the paths the image `b` added, removed and changed compared to the image `a`.
+pub fn diff_images(a: &[u8], b: &[u8]) -> Result<ImageDiff, Error> {
+    let mut fs_a = open_image(a)?;
+    let mut fs_b = open_image(b)?;
+    let manifest_a = fs_a.manifest(fs_a.root_fd())?;
+    let manifest_b = fs_b.manifest(fs_b.root_fd())?;
+
+    let paths: BTreeSet<&String> = manifest_a
+        .entries
+        .keys()
+        .chain(manifest_b.entries.keys())
+        .collect();
+
+    let mut diff = ImageDiff::default();
+    for path in paths {
+        match (manifest_a.entries.get(path), manifest_b.entries.get(path)) {
+            (Some(_), None) => diff.removed.push((path.clone(), summary(&fs_a, path)?)),
+            (None, Some(_)) => diff.added.push((path.clone(), summary(&fs_b, path)?)),
+            (Some(entry_a), Some(entry_b)) if entry_a != entry_b => {
+                let before = summary(&fs_a, path)?;
+                let after = summary(&fs_b, path)?;
+                if before.file_type == FileType::Directory && after.file_type == FileType::Directory
+                {
+                    continue;
+                }
+
+                let changed_bytes = if before.file_type == after.file_type {
+                    changed_bytes(&mut fs_a, &mut fs_b, path, before.size.min(after.size))?
+                } else {
+                    0
+                };
+                diff.changed.push(ChangedPath {
+                    path: path.clone(),
+                    before,
+                    after,
+                    changed_bytes,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(diff)
+}
+
+fn summary(fs: &FileSystem, path: &str) -> Result<PathSummary, Error> {
+    let node = find_node(fs.storage.root_node(), path, fs.storage.as_ref())?;
+    let metadata = fs.storage.get_metadata(node)?;
+    Ok(PathSummary {
+        file_type: metadata.file_type,
+        size: metadata.size,
+    })
+}
+
+// Count the differing bytes of the first `len` bytes of the files, the aliases compare their targets.
+fn changed_bytes(
+    fs_a: &mut FileSystem,
+    fs_b: &mut FileSystem,
+    path: &str,
+    len: FileSize,
+) -> Result<FileSize, Error> {
+    let root_a = fs_a.root_fd();
+    let root_b = fs_b.root_fd();
+
+    if summary(fs_a, path)?.file_type == FileType::SymbolicLink {
+        let target_a = fs_a.read_alias(root_a, path)?;
+        let target_b = fs_b.read_alias(root_b, path)?;
+        return Ok(count_changed(target_a.as_bytes(), target_b.as_bytes()));
+    }
+
+    let mut changed = 0;
+    let mut offset = 0;
+    while offset < len {
+        let slice = (len - offset).min(BUDGET_SLICE_SIZE as FileSize);
+        let data_a = fs_a.read_path(root_a, path, offset, slice)?;
+        let data_b = fs_b.read_path(root_b, path, offset, slice)?;
+        changed += count_changed(&data_a, &data_b);
+        offset += slice;
+    }
+
+    Ok(changed)
+}
+
+fn count_changed(a: &[u8], b: &[u8]) -> FileSize {
+    a.iter().zip(b).filter(|(a, b)| a != b).count() as FileSize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{FdStat, OpenFlags};
+
+    fn image(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let memory = VectorMemory::default();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root = fs.root_fd();
+        for (path, content) in files {
+            fs.apply_file(root, path, content, 0).unwrap();
+        }
+        let image = memory.borrow().clone();
+        image
+    }
+
+    #[test]
+    fn images_are_compared_by_path() {
+        let a = image(&[
+            ("index.html", b"<html>v1</html>"),
+            ("assets/old.css", b"body {}"),
+            ("config", b"a = 1"),
+        ]);
+        let b = image(&[
+            ("index.html", b"<html>v2</html>!"),
+            ("assets/new.css", b"p {}"),
+            ("config", b"a = 1"),
+            ("data/log.txt", b"started"),
+        ]);
+
+        let diff = diff_images(&a, &b).unwrap();
+        let file = |size| PathSummary {
+            file_type: FileType::RegularFile,
+            size,
+        };
+        let dir = PathSummary {
+            file_type: FileType::Directory,
+            size: 1,
+        };
+
+        assert_eq!(
+            diff.added,
+            vec![
+                ("assets/new.css".to_string(), file(4)),
+                ("data".to_string(), dir),
+                ("data/log.txt".to_string(), file(7)),
+            ]
+        );
+        assert_eq!(diff.removed, vec![("assets/old.css".to_string(), file(7))]);
+        assert_eq!(
+            diff.changed,
+            vec![ChangedPath {
+                path: "index.html".to_string(),
+                before: file(15),
+                after: file(16),
+                changed_bytes: 1,
+            }]
+        );
+
+        assert!(diff_images(&a, &a).unwrap().is_empty());
+
+        // an image can also be mounted for inspection
+        let mut fs = open_image(&a).unwrap();
+        let fd = fs
+            .open_or_create(
+                fs.root_fd(),
+                "config",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().size, 5);
+    }
+}
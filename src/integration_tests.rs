@@ -29,7 +29,6 @@ fn setup() -> (PocketIc, Principal) {
 
 #[test]
 fn test_hello() {
-
     setup_test_projects();
 
     let (pic, backend_canister) = setup();
@@ -72,7 +71,7 @@ fn test_writing_10mb() {
     setup_test_projects();
 
     let (pic, backend_canister) = setup();
-    
+
     pic.tick();
 
     let args = candid::encode_args(("test.txt", 1u64)).unwrap();
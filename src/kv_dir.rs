@@ -0,0 +1,264 @@
+// A key-value store keeping each value in a file of a directory.
+// The files are spread over 256 subdirectories chosen by the first byte of the key hash, the file names
+// are the hex encoded keys.
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Error,
+    fs::{FdStat, FileSystem, OpenFlags, RemoveOptions},
+    storage::types::{FileSize, FileType, MAX_FILE_NAME},
+};
+
+// The longest key that fits into a file name.
+pub const MAX_KEY_SIZE: usize = MAX_FILE_NAME / 2;
+
+pub struct KvDir {
+    dir: String,
+    len: u64,
+    total_bytes: FileSize,
+}
+
+impl KvDir {
+    // Open the store in the directory `dir` of the root folder, the directory is created if missing.
+    pub fn open(fs: &mut FileSystem, dir: &str, ctime: u64) -> Result<Self, Error> {
+        let root = fs.root_fd();
+
+        let dir_fd = match fs.open_or_create(root, dir, FdStat::default(), OpenFlags::DIRECTORY, 0)
+        {
+            Err(Error::NotFound) => fs.create_dir(root, dir, FdStat::default(), ctime)?,
+            result => result?,
+        };
+        fs.close(dir_fd)?;
+
+        let mut kv = Self {
+            dir: dir.to_string(),
+            len: 0,
+            total_bytes: 0,
+        };
+
+        for bucket in kv.buckets(fs)? {
+            for (_, size) in kv.bucket_entries(fs, &bucket)? {
+                kv.len += 1;
+                kv.total_bytes += size;
+            }
+        }
+
+        Ok(kv)
+    }
+
+    // The number of the stored keys.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The total size of the stored values.
+    pub fn total_bytes(&self) -> FileSize {
+        self.total_bytes
+    }
+
+    // Get the value of a key.
+    pub fn get(&self, fs: &mut FileSystem, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let root = fs.root_fd();
+        let fd = fs.open_or_create(
+            root,
+            &self.key_path(key)?,
+            FdStat::default(),
+            OpenFlags::empty(),
+            0,
+        )?;
+
+        let value = fs.metadata(fd).and_then(|stat| {
+            let mut buf = vec![0; stat.size as usize];
+            fs.read(fd, &mut buf).map(|_| buf)
+        });
+        fs.close(fd)?;
+
+        value
+    }
+
+    // Store the value of a key, replacing the previous one.
+    pub fn put(
+        &mut self,
+        fs: &mut FileSystem,
+        key: &[u8],
+        value: &[u8],
+        ctime: u64,
+    ) -> Result<(), Error> {
+        let path = self.key_path(key)?;
+        let root = fs.root_fd();
+
+        let old_size = match fs.open_metadata(root, &path) {
+            Ok(stat) => Some(stat.size),
+            Err(Error::NotFound) => None,
+            Err(err) => return Err(err),
+        };
+
+        fs.apply_file(root, &path, value, ctime)?;
+
+        match old_size {
+            Some(old_size) => self.total_bytes -= old_size,
+            None => self.len += 1,
+        }
+        self.total_bytes += value.len() as FileSize;
+
+        Ok(())
+    }
+
+    // Remove a key with its value.
+    pub fn delete(&mut self, fs: &mut FileSystem, key: &[u8]) -> Result<(), Error> {
+        let path = self.key_path(key)?;
+        let root = fs.root_fd();
+
+        let size = fs.open_metadata(root, &path)?.size;
+        fs.remove(root, &path, RemoveOptions::file())?;
+
+        self.len -= 1;
+        self.total_bytes -= size;
+
+        Ok(())
+    }
+
+    // List all the stored keys in the ascending order.
+    pub fn list(&self, fs: &mut FileSystem) -> Result<Vec<Vec<u8>>, Error> {
+        let mut keys = Vec::new();
+
+        for bucket in self.buckets(fs)? {
+            for (name, _) in self.bucket_entries(fs, &bucket)? {
+                if let Some(key) = decode_hex(&name) {
+                    keys.push(key);
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn key_path(&self, key: &[u8]) -> Result<String, Error> {
+        if key.is_empty() {
+            return Err(Error::InvalidFileName);
+        }
+        if key.len() > MAX_KEY_SIZE {
+            return Err(Error::NameTooLong);
+        }
+
+        let hash = Sha256::digest(key);
+        Ok(format!("{}/{:02x}/{}", self.dir, hash[0], encode_hex(key)))
+    }
+
+    // Get the names of the fan-out subdirectories.
+    fn buckets(&self, fs: &mut FileSystem) -> Result<Vec<String>, Error> {
+        Ok(self
+            .dir_entries(fs, &self.dir)?
+            .into_iter()
+            .filter(|(_, file_type, _)| *file_type == FileType::Directory)
+            .map(|(name, _, _)| name)
+            .collect())
+    }
+
+    // Get the names and the sizes of the value files of a fan-out subdirectory.
+    fn bucket_entries(
+        &self,
+        fs: &mut FileSystem,
+        bucket: &str,
+    ) -> Result<Vec<(String, FileSize)>, Error> {
+        Ok(self
+            .dir_entries(fs, &format!("{}/{bucket}", self.dir))?
+            .into_iter()
+            .filter(|(_, file_type, _)| *file_type == FileType::RegularFile)
+            .map(|(name, _, size)| (name, size))
+            .collect())
+    }
+
+    fn dir_entries(
+        &self,
+        fs: &mut FileSystem,
+        path: &str,
+    ) -> Result<Vec<(String, FileType, FileSize)>, Error> {
+        let root = fs.root_fd();
+        let fd = fs.open_or_create(root, path, FdStat::default(), OpenFlags::DIRECTORY, 0)?;
+        let entries = fs.list_dir(fd);
+        fs.close(fd)?;
+
+        let mut result = Vec::new();
+        for entry in entries? {
+            let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+            if name == "." || name == ".." {
+                continue;
+            }
+            let stat = fs.metadata_from_node(entry.node)?;
+            result.push((name.into_owned(), stat.file_type, stat.size));
+        }
+
+        Ok(result)
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::Error, test_utils::test_fs};
+
+    use super::{KvDir, MAX_KEY_SIZE};
+
+    #[test]
+    fn put_get_delete() {
+        let mut fs = test_fs();
+        let mut kv = KvDir::open(&mut fs, "blobs", 0).unwrap();
+
+        kv.put(&mut fs, b"user/1", b"alice", 0).unwrap();
+        kv.put(&mut fs, b"user/2", b"bob", 0).unwrap();
+        kv.put(&mut fs, b"user/1", b"alice2", 0).unwrap();
+
+        assert_eq!(kv.get(&mut fs, b"user/1").unwrap(), b"alice2");
+        assert_eq!(kv.get(&mut fs, b"user/3"), Err(Error::NotFound));
+        assert_eq!((kv.len(), kv.total_bytes()), (2, 9));
+
+        assert_eq!(
+            kv.list(&mut fs).unwrap(),
+            vec![b"user/1".to_vec(), b"user/2".to_vec()]
+        );
+
+        kv.delete(&mut fs, b"user/2").unwrap();
+        assert_eq!(kv.delete(&mut fs, b"user/2"), Err(Error::NotFound));
+        assert_eq!((kv.len(), kv.total_bytes()), (1, 6));
+
+        // the accounting is restored when opened again
+        let kv = KvDir::open(&mut fs, "blobs", 0).unwrap();
+        assert_eq!((kv.len(), kv.total_bytes()), (1, 6));
+        assert_eq!(kv.list(&mut fs).unwrap(), vec![b"user/1".to_vec()]);
+    }
+
+    #[test]
+    fn invalid_keys() {
+        let mut fs = test_fs();
+        let mut kv = KvDir::open(&mut fs, "blobs", 0).unwrap();
+
+        assert_eq!(kv.put(&mut fs, b"", b"x", 0), Err(Error::InvalidFileName));
+        assert_eq!(
+            kv.put(&mut fs, &[1; MAX_KEY_SIZE + 1], b"x", 0),
+            Err(Error::NameTooLong)
+        );
+        kv.put(&mut fs, &[1; MAX_KEY_SIZE], b"x", 0).unwrap();
+        assert_eq!(kv.list(&mut fs).unwrap(), vec![vec![1; MAX_KEY_SIZE]]);
+    }
+}
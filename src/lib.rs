@@ -1,10 +1,23 @@
+pub mod config;
 pub mod error;
 pub mod fs;
+#[cfg(feature = "fs-shell")]
+pub mod fs_shell;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzzing;
+pub mod image;
+pub mod kv_dir;
+pub mod log_file;
+pub mod path;
+pub mod ring_file;
 mod runtime;
 pub mod storage;
 
-#[cfg(test)]
-mod test_utils;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 
 #[cfg(test)]
 mod integration_tests;
+
+#[cfg(test)]
+mod conformance_tests;
@@ -0,0 +1,394 @@
+// An append-only log of records stored in a directory of segment files.
+// Each record is stored as its length (u32, little endian) followed by its bytes. A record is addressed
+// by its offset in the log, the segment files are named after the offset of their first record.
+
+use crate::{
+    error::Error,
+    fs::{Fd, FdStat, FileSystem, OpenFlags, RemoveOptions, Whence},
+    storage::types::FileSize,
+};
+
+const SEGMENT_SUFFIX: &str = ".log";
+const LENGTH_SIZE: FileSize = 4;
+
+// The segment size and the retention of a log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogOptions {
+    // A new segment is started once appending a record would make the segment larger.
+    pub segment_size: FileSize,
+    // The maximum number of segments kept, the oldest segments are removed first.
+    pub max_segments: Option<usize>,
+    // The maximum number of bytes kept in all the segments, the active segment is always kept.
+    pub max_bytes: Option<FileSize>,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            segment_size: 1024 * 1024,
+            max_segments: None,
+            max_bytes: None,
+        }
+    }
+}
+
+pub struct LogFile {
+    dir: String,
+    options: LogOptions,
+    // the offsets of the first records of the segments, in the ascending order
+    segments: Vec<FileSize>,
+    // the offset of the next appended record
+    end: FileSize,
+}
+
+impl LogFile {
+    // Open the log stored in the directory `dir` of the root folder, the directory is created if missing.
+    pub fn open(
+        fs: &mut FileSystem,
+        dir: &str,
+        options: LogOptions,
+        ctime: u64,
+    ) -> Result<Self, Error> {
+        let root = fs.root_fd();
+
+        let dir_fd = match fs.open_or_create(root, dir, FdStat::default(), OpenFlags::DIRECTORY, 0)
+        {
+            Err(Error::NotFound) => fs.create_dir(root, dir, FdStat::default(), ctime)?,
+            result => result?,
+        };
+
+        let entries = fs.list_dir(dir_fd);
+        fs.close(dir_fd)?;
+
+        let mut segments: Vec<FileSize> = entries?
+            .iter()
+            .filter_map(|entry| {
+                let name =
+                    std::str::from_utf8(&entry.name.bytes[..entry.name.length as usize]).ok()?;
+                name.strip_suffix(SEGMENT_SUFFIX)?.parse().ok()
+            })
+            .collect();
+        segments.sort();
+
+        let mut log = Self {
+            dir: dir.to_string(),
+            options,
+            segments,
+            end: 0,
+        };
+
+        if let Some(&last) = log.segments.last() {
+            let stat = fs.open_metadata(root, &log.segment_path(last))?;
+            log.end = last + stat.size;
+        }
+
+        Ok(log)
+    }
+
+    // The offset of the next appended record.
+    pub fn end(&self) -> FileSize {
+        self.end
+    }
+
+    // The offset of the oldest record kept.
+    pub fn start(&self) -> FileSize {
+        self.segments.first().copied().unwrap_or(self.end)
+    }
+
+    // The offsets of the first records of the segments.
+    pub fn segments(&self) -> &[FileSize] {
+        &self.segments
+    }
+
+    // Append a record to the log, returns its offset.
+    pub fn append(
+        &mut self,
+        fs: &mut FileSystem,
+        record: &[u8],
+        ctime: u64,
+    ) -> Result<FileSize, Error> {
+        let length = u32::try_from(record.len()).map_err(|_| Error::FileTooLarge)?;
+        let record_size = LENGTH_SIZE + record.len() as FileSize;
+
+        let rotate = match self.segments.last() {
+            None => true,
+            Some(&last) => {
+                self.end > last && self.end - last + record_size > self.options.segment_size
+            }
+        };
+
+        let root = fs.root_fd();
+        let offset = self.end;
+
+        if rotate {
+            let fd = fs.create_file(root, &self.segment_path(offset), FdStat::default(), ctime)?;
+            fs.close(fd)?;
+            self.segments.push(offset);
+        }
+
+        let segment = *self.segments.last().unwrap();
+        let fd = fs.open_or_create(
+            root,
+            &self.segment_path(segment),
+            FdStat::default(),
+            OpenFlags::empty(),
+            0,
+        )?;
+
+        let mut buf = Vec::with_capacity(record_size as usize);
+        buf.extend_from_slice(&length.to_le_bytes());
+        buf.extend_from_slice(record);
+
+        let result = fs
+            .seek(fd, (offset - segment) as i64, Whence::SET)
+            .and_then(|_| fs.write(fd, &buf));
+        fs.close(fd)?;
+        result?;
+
+        self.end += record_size;
+
+        if rotate {
+            self.apply_retention(fs)?;
+        }
+
+        Ok(offset)
+    }
+
+    // Iterate the records starting with the one at `offset`, the offset must be returned by `append`.
+    // Iterating from an offset of a removed segment starts with the oldest record kept.
+    pub fn iter_from<'a>(&self, fs: &'a mut FileSystem, offset: FileSize) -> LogIter<'a> {
+        LogIter {
+            fs,
+            dir: self.dir.clone(),
+            segments: self.segments.clone(),
+            end: self.end,
+            offset: offset.max(self.start()),
+            current: None,
+        }
+    }
+
+    // Remove the oldest segments exceeding the retention limits.
+    fn apply_retention(&mut self, fs: &mut FileSystem) -> Result<(), Error> {
+        let root = fs.root_fd();
+
+        while self.segments.len() > 1 {
+            let too_many = self
+                .options
+                .max_segments
+                .is_some_and(|max| self.segments.len() > max);
+            let too_large = self
+                .options
+                .max_bytes
+                .is_some_and(|max| self.end - self.segments[0] > max);
+
+            if !too_many && !too_large {
+                break;
+            }
+
+            let path = self.segment_path(self.segments[0]);
+            fs.remove(root, &path, RemoveOptions::file())?;
+            self.segments.remove(0);
+        }
+
+        Ok(())
+    }
+
+    fn segment_path(&self, offset: FileSize) -> String {
+        segment_path(&self.dir, offset)
+    }
+}
+
+fn segment_path(dir: &str, offset: FileSize) -> String {
+    format!("{dir}/{offset:020}{SEGMENT_SUFFIX}")
+}
+
+// An iterator over the records of a log returning their offsets and contents.
+pub struct LogIter<'a> {
+    fs: &'a mut FileSystem,
+    dir: String,
+    segments: Vec<FileSize>,
+    end: FileSize,
+    offset: FileSize,
+    // the opened segment and the offset of its first record
+    current: Option<(Fd, FileSize)>,
+}
+
+impl LogIter<'_> {
+    // Read the record at the current offset, returns `None` when the rest of the segment is empty.
+    fn read_record(&mut self) -> Result<Option<(FileSize, Vec<u8>)>, Error> {
+        let segment = match self
+            .segments
+            .iter()
+            .rev()
+            .find(|start| **start <= self.offset)
+        {
+            Some(segment) => *segment,
+            None => return Err(Error::NotFound),
+        };
+
+        let fd = match self.current {
+            Some((fd, start)) if start == segment => fd,
+            _ => {
+                self.close_segment()?;
+                let root = self.fs.root_fd();
+                let path = segment_path(&self.dir, segment);
+                let fd = self.fs.open_or_create(
+                    root,
+                    &path,
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )?;
+                self.current = Some((fd, segment));
+                fd
+            }
+        };
+
+        let position = self.offset - segment;
+        let size = self.fs.metadata(fd)?.size;
+
+        if position >= size {
+            // continue with the next segment
+            self.offset = self
+                .segments
+                .iter()
+                .find(|start| **start > segment)
+                .copied()
+                .unwrap_or(self.end);
+            return Ok(None);
+        }
+
+        let length = self
+            .fs
+            .read_ranges(fd, &[(position, LENGTH_SIZE)])?
+            .remove(0);
+        let length = u32::from_le_bytes(length.try_into().map_err(|_| Error::DataCorrupted)?);
+
+        let record = self
+            .fs
+            .read_ranges(fd, &[(position + LENGTH_SIZE, length as FileSize)])?
+            .remove(0);
+
+        if record.len() != length as usize {
+            return Err(Error::DataCorrupted);
+        }
+
+        let offset = self.offset;
+        self.offset += LENGTH_SIZE + length as FileSize;
+
+        Ok(Some((offset, record)))
+    }
+
+    fn close_segment(&mut self) -> Result<(), Error> {
+        if let Some((fd, _)) = self.current.take() {
+            self.fs.close(fd)?;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for LogIter<'_> {
+    type Item = Result<(FileSize, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.offset < self.end {
+            match self.read_record() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => {}
+                Err(err) => {
+                    self.offset = self.end;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for LogIter<'_> {
+    fn drop(&mut self) {
+        let _ = self.close_segment();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fs::FileSystem, test_utils::test_fs};
+
+    use super::{LogFile, LogOptions};
+
+    fn records(log: &LogFile, fs: &mut FileSystem, offset: u64) -> Vec<(u64, Vec<u8>)> {
+        log.iter_from(fs, offset)
+            .map(|record| record.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn append_and_iterate() {
+        let mut fs = test_fs();
+        let options = LogOptions {
+            segment_size: 16,
+            ..Default::default()
+        };
+        let mut log = LogFile::open(&mut fs, "events", options, 0).unwrap();
+
+        let first = log.append(&mut fs, b"first", 0).unwrap();
+        let second = log.append(&mut fs, b"second", 0).unwrap();
+        let third = log.append(&mut fs, b"3", 0).unwrap();
+
+        assert_eq!((first, second, third), (0, 9, 19));
+        // the second record did not fit into the first segment
+        assert_eq!(log.segments(), [0, 9]);
+
+        assert_eq!(
+            records(&log, &mut fs, 0),
+            vec![
+                (0, b"first".to_vec()),
+                (9, b"second".to_vec()),
+                (19, b"3".to_vec())
+            ]
+        );
+        assert_eq!(records(&log, &mut fs, second).len(), 2);
+        assert!(records(&log, &mut fs, log.end()).is_empty());
+
+        // the log is read back from the segment files
+        let log = LogFile::open(&mut fs, "events", options, 0).unwrap();
+        assert_eq!(log.end(), 24);
+        assert_eq!(records(&log, &mut fs, third), vec![(19, b"3".to_vec())]);
+
+        // the iterator closes the opened segments
+        assert_eq!(fs.open_fds().count(), 1);
+    }
+
+    #[test]
+    fn old_segments_are_removed() {
+        let mut fs = test_fs();
+        let options = LogOptions {
+            segment_size: 10,
+            max_segments: Some(2),
+            max_bytes: None,
+        };
+        let mut log = LogFile::open(&mut fs, "audit/log", options, 0).unwrap();
+
+        for i in 0..5u8 {
+            log.append(&mut fs, &[i; 6], 0).unwrap();
+        }
+
+        assert_eq!(log.segments(), [30, 40]);
+        let kept: Vec<u8> = records(&log, &mut fs, 0)
+            .into_iter()
+            .map(|(_, record)| record[0])
+            .collect();
+        assert_eq!(kept, vec![3, 4]);
+
+        let options = LogOptions {
+            segment_size: 10,
+            max_segments: None,
+            max_bytes: Some(15),
+        };
+        let mut log = LogFile::open(&mut fs, "audit/log", options, 0).unwrap();
+        log.append(&mut fs, &[5; 6], 0).unwrap();
+        assert_eq!(log.segments(), [50]);
+    }
+}
@@ -0,0 +1,144 @@
+// Parsing of the paths given to the file system, the resolver uses the same rules.
+// The elements of a path are separated by '/', the repeated separators are collapsed. A leading separator
+// makes the path absolute and a trailing one is reported separately, neither of them yields an element.
+
+use std::borrow::Cow;
+
+// An element of a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component<'a> {
+    // "."
+    CurDir,
+    // ".."
+    ParentDir,
+    Normal(&'a str),
+}
+
+impl<'a> Component<'a> {
+    pub fn as_str(&self) -> &'a str {
+        match self {
+            Component::CurDir => ".",
+            Component::ParentDir => "..",
+            Component::Normal(name) => name,
+        }
+    }
+}
+
+// Iterator over the elements of a path.
+#[derive(Clone, Debug)]
+pub struct Components<'a> {
+    rest: &'a str,
+    absolute: bool,
+    trailing_slash: bool,
+}
+
+impl<'a> Components<'a> {
+    // Check if the path starts with a separator.
+    pub fn is_absolute(&self) -> bool {
+        self.absolute
+    }
+
+    // Check if the path ends with a separator after its last element, e.g. "dir/".
+    pub fn has_trailing_slash(&self) -> bool {
+        self.trailing_slash
+    }
+
+    // Get the part of the path not iterated yet.
+    pub fn as_str(&self) -> &'a str {
+        self.rest.trim_start_matches('/')
+    }
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Component<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_start_matches('/');
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+
+        let (name, rest) = rest.split_once('/').unwrap_or((rest, ""));
+        self.rest = rest;
+
+        Some(match name {
+            "." => Component::CurDir,
+            ".." => Component::ParentDir,
+            name => Component::Normal(name),
+        })
+    }
+}
+
+// Iterate over the elements of a path.
+pub fn components(path: &str) -> Components<'_> {
+    Components {
+        rest: path,
+        absolute: path.starts_with('/'),
+        trailing_slash: path.ends_with('/') && !path.trim_matches('/').is_empty(),
+    }
+}
+
+// Collapse the repeated separators of a path, the leading and the trailing separators are kept.
+pub fn normalize(path: &str) -> Cow<'_, str> {
+    if !path.contains("//") {
+        return Cow::Borrowed(path);
+    }
+
+    let parts = components(path);
+    let mut result = String::with_capacity(path.len());
+    if parts.is_absolute() {
+        result.push('/');
+    }
+    let trailing_slash = parts.has_trailing_slash();
+    for (position, part) in parts.enumerate() {
+        if position > 0 {
+            result.push('/');
+        }
+        result.push_str(part.as_str());
+    }
+    if trailing_slash {
+        result.push('/');
+    }
+
+    Cow::Owned(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_components() {
+        use Component::*;
+
+        let parts = components("a//./b/../c.txt");
+        assert!(!parts.is_absolute());
+        assert!(!parts.has_trailing_slash());
+        assert_eq!(
+            parts.collect::<Vec<_>>(),
+            vec![Normal("a"), CurDir, Normal("b"), ParentDir, Normal("c.txt")]
+        );
+
+        let parts = components("//dir/sub//");
+        assert!(parts.is_absolute());
+        assert!(parts.has_trailing_slash());
+        assert_eq!(
+            parts.collect::<Vec<_>>(),
+            vec![Normal("dir"), Normal("sub")]
+        );
+
+        let mut parts = components("a/b/c");
+        parts.next();
+        assert_eq!(parts.as_str(), "b/c");
+
+        for path in ["", "/", "///"] {
+            assert_eq!(components(path).next(), None);
+            assert!(!components(path).has_trailing_slash());
+        }
+
+        assert_eq!(normalize("a//b///c/"), "a/b/c/");
+        assert_eq!(normalize("//a"), "/a");
+        assert!(matches!(normalize("a/b"), Cow::Borrowed("a/b")));
+    }
+}
@@ -0,0 +1,353 @@
+// A bounded history of records stored in a region file, the oldest records are overwritten once it is full.
+// The file starts with a header: the number of the records pushed (u64), the record size (u32) and the capacity
+// (u32), all little endian. It is followed by `capacity + 1` slots of the records, each slot is the record length
+// (u32, little endian) followed by `record_size` bytes. The record with the sequence number `n` is in the slot
+// `n % (capacity + 1)`, the slot after the newest record is never read and takes the next record.
+
+use crate::{
+    error::Error,
+    fs::{Fd, FdStat, FileSystem, OpenFlags, Whence},
+    storage::types::FileSize,
+};
+
+const HEADER_SIZE: FileSize = 16;
+const LENGTH_SIZE: FileSize = 4;
+
+// The layout of a ring file, only used when the file is created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RingOptions {
+    // The maximum size of a record.
+    pub record_size: u32,
+    // The number of the records kept.
+    pub capacity: u32,
+}
+
+impl Default for RingOptions {
+    fn default() -> Self {
+        Self {
+            record_size: 256,
+            capacity: 1024,
+        }
+    }
+}
+
+impl RingOptions {
+    fn slot_size(&self) -> FileSize {
+        LENGTH_SIZE + self.record_size as FileSize
+    }
+
+    fn slot_count(&self) -> FileSize {
+        self.capacity as FileSize + 1
+    }
+
+    fn file_size(&self) -> FileSize {
+        HEADER_SIZE + self.slot_count() * self.slot_size()
+    }
+}
+
+pub struct RingFile {
+    path: String,
+    options: RingOptions,
+    // the number of the records pushed, the sequence number of the next record
+    pushed: u64,
+}
+
+impl RingFile {
+    // Open the ring stored in the file `path` of the root folder, the file is created with the layout of
+    // `options` if missing. An existing file keeps the layout it was created with.
+    pub fn open(
+        fs: &mut FileSystem,
+        path: &str,
+        options: RingOptions,
+        ctime: u64,
+    ) -> Result<Self, Error> {
+        let root = fs.root_fd();
+
+        let fd = match fs.open_or_create(root, path, FdStat::default(), OpenFlags::empty(), 0) {
+            Err(Error::NotFound) => {
+                if options.record_size == 0 || options.capacity == 0 {
+                    return Err(Error::InvalidBufferLength);
+                }
+                let fd =
+                    fs.create_region(root, path, FdStat::default(), options.file_size(), ctime)?;
+                let ring = Self {
+                    path: path.to_string(),
+                    options,
+                    pushed: 0,
+                };
+                let result = ring.write_header(fs, fd);
+                fs.close(fd)?;
+                result?;
+                return Ok(ring);
+            }
+            result => result?,
+        };
+
+        let result = read_header(fs, fd);
+        fs.close(fd)?;
+        let (pushed, options) = result?;
+
+        Ok(Self {
+            path: path.to_string(),
+            options,
+            pushed,
+        })
+    }
+
+    // The layout of the ring.
+    pub fn options(&self) -> RingOptions {
+        self.options
+    }
+
+    // The number of the records pushed, including the overwritten ones.
+    pub fn pushed(&self) -> u64 {
+        self.pushed
+    }
+
+    // The number of the records kept.
+    pub fn len(&self) -> usize {
+        self.pushed.min(self.options.capacity as u64) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pushed == 0
+    }
+
+    // Push a record into the ring overwriting the oldest one if it is full, returns its sequence number.
+    pub fn push(&mut self, fs: &mut FileSystem, record: &[u8]) -> Result<u64, Error> {
+        if record.len() > self.options.record_size as usize {
+            return Err(Error::FileTooLarge);
+        }
+
+        let sequence = self.pushed;
+        let mut buf = Vec::with_capacity(LENGTH_SIZE as usize + record.len());
+        buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        buf.extend_from_slice(record);
+
+        let fd = self.open_file(fs)?;
+        // the record goes into the spare slot and is counted by the header written after it in one write,
+        // a failed push leaves the ring as it was
+        let result = fs
+            .seek(fd, self.slot_offset(sequence) as i64, Whence::SET)
+            .and_then(|_| fs.write(fd, &buf))
+            .and_then(|written| {
+                if written != buf.len() as FileSize {
+                    return Err(Error::StorageFull);
+                }
+                self.pushed += 1;
+                self.write_header(fs, fd).inspect_err(|_| self.pushed -= 1)
+            });
+        fs.close(fd)?;
+        result?;
+
+        Ok(sequence)
+    }
+
+    // Iterate the `n` most recent records from the oldest to the newest, with their sequence numbers.
+    pub fn iter_recent<'a>(&self, fs: &'a mut FileSystem, n: usize) -> RingIter<'a> {
+        let count = n.min(self.len()) as u64;
+        RingIter {
+            fs,
+            path: self.path.clone(),
+            options: self.options,
+            next: self.pushed - count,
+            end: self.pushed,
+            fd: None,
+        }
+    }
+
+    fn open_file(&self, fs: &mut FileSystem) -> Result<Fd, Error> {
+        let root = fs.root_fd();
+        fs.open_or_create(root, &self.path, FdStat::default(), OpenFlags::empty(), 0)
+    }
+
+    fn slot_offset(&self, sequence: u64) -> FileSize {
+        slot_offset(&self.options, sequence)
+    }
+
+    fn write_header(&self, fs: &mut FileSystem, fd: Fd) -> Result<(), Error> {
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(&self.pushed.to_le_bytes());
+        header.extend_from_slice(&self.options.record_size.to_le_bytes());
+        header.extend_from_slice(&self.options.capacity.to_le_bytes());
+
+        fs.seek(fd, 0, Whence::SET)?;
+        if fs.write(fd, &header)? != HEADER_SIZE {
+            return Err(Error::StorageFull);
+        }
+        Ok(())
+    }
+}
+
+fn slot_offset(options: &RingOptions, sequence: u64) -> FileSize {
+    HEADER_SIZE + (sequence % options.slot_count()) * options.slot_size()
+}
+
+fn read_header(fs: &mut FileSystem, fd: Fd) -> Result<(u64, RingOptions), Error> {
+    let header = fs.read_ranges(fd, &[(0, HEADER_SIZE)])?.remove(0);
+    if header.len() != HEADER_SIZE as usize {
+        return Err(Error::DataCorrupted);
+    }
+
+    let pushed = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let options = RingOptions {
+        record_size: u32::from_le_bytes(header[8..12].try_into().unwrap()),
+        capacity: u32::from_le_bytes(header[12..16].try_into().unwrap()),
+    };
+    if options.capacity == 0 || fs.metadata(fd)?.size < options.file_size() {
+        return Err(Error::DataCorrupted);
+    }
+
+    Ok((pushed, options))
+}
+
+// An iterator over the records of a ring returning their sequence numbers and contents.
+pub struct RingIter<'a> {
+    fs: &'a mut FileSystem,
+    path: String,
+    options: RingOptions,
+    next: u64,
+    end: u64,
+    fd: Option<Fd>,
+}
+
+impl RingIter<'_> {
+    fn read_record(&mut self) -> Result<(u64, Vec<u8>), Error> {
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => {
+                let root = self.fs.root_fd();
+                let fd = self.fs.open_or_create(
+                    root,
+                    &self.path,
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )?;
+                self.fd = Some(fd);
+                fd
+            }
+        };
+
+        let offset = slot_offset(&self.options, self.next);
+        let length = self.fs.read_ranges(fd, &[(offset, LENGTH_SIZE)])?.remove(0);
+        let length = u32::from_le_bytes(length.try_into().map_err(|_| Error::DataCorrupted)?);
+        if length > self.options.record_size {
+            return Err(Error::DataCorrupted);
+        }
+
+        let record = self
+            .fs
+            .read_ranges(fd, &[(offset + LENGTH_SIZE, length as FileSize)])?
+            .remove(0);
+
+        let sequence = self.next;
+        self.next += 1;
+        Ok((sequence, record))
+    }
+}
+
+impl Iterator for RingIter<'_> {
+    type Item = Result<(u64, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+
+        let result = self.read_record();
+        if result.is_err() {
+            self.next = self.end;
+        }
+        Some(result)
+    }
+}
+
+impl Drop for RingIter<'_> {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd.take() {
+            let _ = self.fs.close(fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::{
+        error::Error,
+        fs::FileSystem,
+        test_utils::{test_fs, FaultyStorage},
+    };
+
+    use super::{RingFile, RingOptions};
+
+    fn recent(ring: &RingFile, fs: &mut FileSystem, n: usize) -> Vec<(u64, Vec<u8>)> {
+        ring.iter_recent(fs, n)
+            .map(|record| record.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn push_wraps_around() {
+        let mut fs = test_fs();
+        let options = RingOptions {
+            record_size: 8,
+            capacity: 3,
+        };
+        let mut ring = RingFile::open(&mut fs, "history.ring", options, 0).unwrap();
+        assert!(ring.is_empty());
+
+        for i in 0..5u8 {
+            assert_eq!(
+                ring.push(&mut fs, &vec![i; i as usize + 1]).unwrap(),
+                i as u64
+            );
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(
+            recent(&ring, &mut fs, 10),
+            vec![(2, vec![2; 3]), (3, vec![3; 4]), (4, vec![4; 5])]
+        );
+        assert_eq!(recent(&ring, &mut fs, 1), vec![(4, vec![4; 5])]);
+        assert_eq!(ring.push(&mut fs, &[0; 9]), Err(Error::FileTooLarge));
+
+        // the ring does not grow and is read back with its stored layout
+        let size = fs.open_metadata(fs.root_fd(), "history.ring").unwrap().size;
+        assert_eq!(size, 16 + 4 * 12);
+        let ring = RingFile::open(&mut fs, "history.ring", RingOptions::default(), 0).unwrap();
+        assert_eq!(ring.options(), options);
+        assert_eq!(ring.pushed(), 5);
+        assert_eq!(recent(&ring, &mut fs, 2).len(), 2);
+
+        // the iterator closes the opened file
+        assert_eq!(fs.open_fds().count(), 1);
+    }
+
+    #[test]
+    fn failed_push_keeps_the_ring() {
+        let budget = Rc::new(Cell::new(None));
+        let storage = FaultyStorage::counting_overwrites(budget.clone());
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        let options = RingOptions {
+            record_size: 8,
+            capacity: 2,
+        };
+        let mut ring = RingFile::open(&mut fs, "history.ring", options, 0).unwrap();
+        for i in 0..3u8 {
+            ring.push(&mut fs, &[i; 4]).unwrap();
+        }
+
+        // the record is written, the header is not
+        budget.set(Some(1));
+        assert_eq!(ring.push(&mut fs, &[9; 4]), Err(Error::StorageFull));
+        budget.set(None);
+
+        assert_eq!(ring.pushed(), 3);
+        let expected = vec![(1, vec![1; 4]), (2, vec![2; 4])];
+        assert_eq!(recent(&ring, &mut fs, 10), expected);
+        let ring = RingFile::open(&mut fs, "history.ring", options, 0).unwrap();
+        assert_eq!(recent(&ring, &mut fs, 10), expected);
+    }
+}
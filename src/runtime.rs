@@ -1,5 +1,14 @@
+pub mod budget;
+pub mod changelog;
 pub mod dir;
 pub mod fd;
 pub mod file;
+pub mod merkle;
+pub mod policy;
+pub mod proc;
 pub mod structure_helpers;
+pub mod sync;
 pub mod types;
+pub mod watch;
+#[cfg(feature = "write-stats")]
+pub mod write_stats;
@@ -0,0 +1,76 @@
+use crate::storage::types::FILE_CHUNK_SIZE;
+
+// The part of a buffer read or written between two budget checks.
+pub const BUDGET_SLICE_SIZE: usize = 16 * FILE_CHUNK_SIZE;
+
+// The estimated instructions of the steps, used where no instruction counter is available.
+const INSTRUCTIONS_PER_CHUNK: u64 = 50_000;
+const INSTRUCTIONS_PER_BYTE: u64 = 30;
+const INSTRUCTIONS_PER_REMOVAL: u64 = 200_000;
+
+// Get the instructions executed by the current message, the counter exists only in a canister.
+fn instruction_counter() -> Option<u64> {
+    #[cfg(target_arch = "wasm32")]
+    {
+        Some(ic_cdk::api::performance_counter(0))
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        None
+    }
+}
+
+// Get the estimated instructions of reading or writing `len` bytes.
+pub fn io_cost(len: usize) -> u64 {
+    let chunks = len.div_ceil(FILE_CHUNK_SIZE) as u64;
+    chunks * INSTRUCTIONS_PER_CHUNK + len as u64 * INSTRUCTIONS_PER_BYTE
+}
+
+// Get the estimated instructions of removing a file.
+pub fn removal_cost() -> u64 {
+    INSTRUCTIONS_PER_REMOVAL
+}
+
+// Stops an operation between its steps before it runs out of the instructions it may use.
+// The first step always runs, so that every call makes progress.
+pub struct BudgetGuard {
+    max_instructions: Option<u64>,
+    start: Option<u64>,
+    estimated: u64,
+    steps: u64,
+}
+
+impl BudgetGuard {
+    pub fn new(max_instructions: Option<u64>) -> Self {
+        Self {
+            max_instructions,
+            start: instruction_counter(),
+            estimated: 0,
+            steps: 0,
+        }
+    }
+
+    // Get the instructions used since the guard was created, measured if possible and estimated otherwise.
+    fn used(&self) -> u64 {
+        match (self.start, instruction_counter()) {
+            (Some(start), Some(now)) => now.saturating_sub(start),
+            _ => self.estimated,
+        }
+    }
+
+    // Check if a step with the estimated cost fits into the remaining budget.
+    pub fn allows(&self, cost: u64) -> bool {
+        match self.max_instructions {
+            Some(max_instructions) => {
+                self.steps == 0 || self.used().saturating_add(cost) <= max_instructions
+            }
+            None => true,
+        }
+    }
+
+    // Account for a finished step.
+    pub fn charge(&mut self, cost: u64) {
+        self.estimated = self.estimated.saturating_add(cost);
+        self.steps += 1;
+    }
+}
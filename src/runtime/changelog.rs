@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    error::Error,
+    runtime::types::{ChangeKind, DirChange, DirChanges},
+    storage::{types::Node, Storage},
+};
+
+// A bounded journal of the changes of the directory entries.
+// The changes are recorded by the file system when they are made, each change gets its own generation.
+// Repeated writes into an entry are recorded once until the change is returned by a query.
+pub struct DirChangelog {
+    capacity: usize,
+    generation: u64,
+    // the changes with generations up to this one were dropped from the journal
+    truncated_generation: u64,
+    // the highest generation returned by a query
+    queried_generation: u64,
+    // the nodes of the entries, as last recorded
+    entries: BTreeMap<String, Node>,
+    changes: VecDeque<DirChange>,
+}
+
+impl DirChangelog {
+    // Start tracking the changes of a directory, at most `capacity` changes are kept.
+    pub fn new(dir_node: Node, capacity: usize, storage: &dyn Storage) -> Result<Self, Error> {
+        Ok(Self {
+            capacity,
+            generation: 0,
+            truncated_generation: 0,
+            queried_generation: 0,
+            entries: read_entries(dir_node, storage)?,
+            changes: VecDeque::new(),
+        })
+    }
+
+    // Record the change of the entry `name` after it was created, removed or replaced by another node.
+    pub fn entry_changed(&mut self, name: &str, node: Option<Node>) {
+        let old = match node {
+            Some(node) => self.entries.insert(name.to_string(), node),
+            None => self.entries.remove(name),
+        };
+
+        if old == node {
+            return;
+        }
+        if old.is_some() {
+            self.push(name, ChangeKind::Removed);
+        }
+        if node.is_some() {
+            self.push(name, ChangeKind::Created);
+        }
+    }
+
+    // Record the writes into the entries referencing the `node`.
+    pub fn node_written(&mut self, node: Node) {
+        let names: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry_node)| **entry_node == node)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in names {
+            let last = self.changes.back();
+            let pending = last.is_some_and(|change| {
+                change.generation > self.queried_generation
+                    && change.name == name
+                    && change.kind != ChangeKind::Removed
+            });
+            if !pending {
+                self.push(&name, ChangeKind::Modified);
+            }
+        }
+    }
+
+    fn push(&mut self, name: &str, kind: ChangeKind) {
+        self.generation += 1;
+        self.changes.push_back(DirChange {
+            generation: self.generation,
+            name: name.to_string(),
+            kind,
+        });
+
+        while self.changes.len() > self.capacity {
+            if let Some(dropped) = self.changes.pop_front() {
+                self.truncated_generation = dropped.generation;
+            }
+        }
+    }
+
+    // Get the changes made after the `generation`.
+    pub fn changes_since(&mut self, generation: u64) -> DirChanges {
+        self.queried_generation = self.generation;
+
+        DirChanges {
+            generation: self.generation,
+            changes: self
+                .changes
+                .iter()
+                .filter(|change| change.generation > generation)
+                .cloned()
+                .collect(),
+            complete: generation >= self.truncated_generation,
+        }
+    }
+}
+
+fn read_entries(dir_node: Node, storage: &dyn Storage) -> Result<BTreeMap<String, Node>, Error> {
+    let mut result = BTreeMap::new();
+    let mut next_index = storage.get_metadata(dir_node)?.first_dir_entry;
+
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(dir_node, index)?;
+        let name = &entry.name.bytes[..entry.name.length as usize];
+        result.insert(String::from_utf8_lossy(name).into_owned(), entry.node);
+        next_index = entry.next_entry;
+    }
+
+    Ok(result)
+}
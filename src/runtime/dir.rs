@@ -4,14 +4,19 @@ use crate::{
     error::Error,
     runtime::file::File,
     storage::{
-        types::{DirEntry, DirEntryIndex, FileType, Node, FILE_CHUNK_SIZE},
+        types::{
+            DirEntry, DirEntryIndex, FileName, FileSize, FileType, Metadata, Node, FILE_CHUNK_SIZE,
+        },
         Storage,
     },
 };
 
 use super::{
-    structure_helpers::{create_path, find_node, rm_dir_entry},
-    types::FdStat,
+    structure_helpers::{
+        check_subtree_not_opened, collect_removal, create_path, find_node, get_parent_node,
+        rm_dir_contents, rm_dir_entry, rm_node_data,
+    },
+    types::{FdStat, RemovalReport, RemoveOptions},
 };
 
 #[derive(Clone, Debug)]
@@ -26,10 +31,8 @@ impl Dir {
         let file_type = storage.get_metadata(node)?.file_type;
         match file_type {
             FileType::Directory => {}
-            FileType::RegularFile => {
-                unreachable!("Unexpected file type, expected directory.");
-            }
-            FileType::SymbolicLink => unimplemented!("Symbolic links are not supported"),
+            FileType::RegularFile => return Err(Error::NotADirectory),
+            FileType::SymbolicLink => return Err(Error::InvalidFileType),
         };
         Ok(Self { node, stat })
     }
@@ -55,26 +58,127 @@ impl Dir {
         Self::new(node, stat, storage)
     }
 
-    // Remove directory entry from the current directory.
-    pub fn remove_dir(
+    // Create an alias entry pointing to the `target` path, the target does not need to exist.
+    pub fn create_alias(
         &self,
         path: &str,
+        target: &str,
+        storage: &mut dyn Storage,
+        ctime: u64,
+    ) -> Result<Node, Error> {
+        if target.is_empty() {
+            return Err(Error::InvalidFileName);
+        }
+        if target.len() > FILE_CHUNK_SIZE {
+            return Err(Error::NameTooLong);
+        }
+
+        let found = find_node(self.node, path, storage);
+        match found {
+            Err(Error::NotFound) => {}
+            Ok(_) => return Err(Error::FileAlreadyExists),
+            Err(err) => return Err(err),
+        }
+
+        let (node, _leaf_name) = create_path(
+            self.node,
+            path,
+            Some(FileType::SymbolicLink),
+            ctime,
+            storage,
+        )?;
+
+        // the target is kept as the contents of the alias node
+        storage.write_filechunk(node, 0, 0, target.as_bytes())?;
+        let mut metadata = storage.get_metadata(node)?;
+        metadata.size = target.len() as FileSize;
+        storage.put_metadata(node, metadata);
+
+        Ok(node)
+    }
+
+    // Remove a file or a directory entry from the current directory.
+    // If `allow_opened` is set, the opened nodes can be unlinked, their data is kept until they are closed.
+    pub fn remove(
+        &self,
+        path: &str,
+        options: RemoveOptions,
         node_refcount: &BTreeMap<Node, usize>,
+        allow_opened: bool,
         storage: &mut dyn Storage,
     ) -> Result<(), Error> {
-        let (node, metadata) = rm_dir_entry(self.node, path, Some(true), node_refcount, storage)?;
+        if options.recursive && options.must_be_empty {
+            return Err(Error::InvalidRemoveOptions);
+        }
+
+        let no_refcount = BTreeMap::new();
+        let checked_refcount = if allow_opened {
+            &no_refcount
+        } else {
+            node_refcount
+        };
+
+        // everything is checked before the contents are removed, a failed removal changes nothing
+        if options.recursive {
+            let node = find_node(self.node, path, storage)?;
+            let metadata = storage.get_metadata(node)?;
+            check_removable(node, &metadata, options, checked_refcount, storage)?;
+
+            if metadata.file_type == FileType::Directory && metadata.size > 0 {
+                rm_dir_contents(node, node_refcount, storage)?;
+            }
+        }
+
+        let (node, metadata) = rm_dir_entry(
+            self.node,
+            path,
+            options.must_be_dir,
+            checked_refcount,
+            storage,
+        )?;
 
         if metadata.link_count == 0 {
-            let chunk_cnt = (metadata.size + FILE_CHUNK_SIZE as u64 - 1) / FILE_CHUNK_SIZE as u64;
-            for index in 0..chunk_cnt {
-                storage.rm_filechunk(node, index as u32);
+            // the data of an opened node is removed when it is closed, or on the next mount
+            if node_refcount.contains_key(&node) {
+                storage.add_orphan(node);
+            } else {
+                rm_node_data(node, &metadata, storage);
             }
-            storage.rm_metadata(node);
         }
 
         Ok(())
     }
 
+    // Check the removal of `path` like `remove` does and report the nodes it would remove, nothing is changed.
+    pub fn remove_dry_run(
+        &self,
+        path: &str,
+        options: RemoveOptions,
+        node_refcount: &BTreeMap<Node, usize>,
+        allow_opened: bool,
+        storage: &dyn Storage,
+    ) -> Result<RemovalReport, Error> {
+        if options.recursive && options.must_be_empty {
+            return Err(Error::InvalidRemoveOptions);
+        }
+
+        let node = find_node(self.node, path, storage)?;
+        let metadata = storage.get_metadata(node)?;
+
+        let no_refcount = BTreeMap::new();
+        let checked_refcount = if allow_opened {
+            &no_refcount
+        } else {
+            node_refcount
+        };
+        check_removable(node, &metadata, options, checked_refcount, storage)?;
+
+        let mut report = RemovalReport::default();
+        collect_removal(node, path, storage, &mut report)?;
+
+        Ok(report)
+    }
+
     // Create file entry in the current directory.
     pub fn create_file(
         &self,
@@ -96,24 +200,38 @@ impl Dir {
         File::new(node, stat, storage)
     }
 
-    // Remove file entry from the current directory.
-    pub fn remove_file(
+    // List the directory entries in their stored order, optionally starting with "." and "..".
+    pub fn list_entries(
         &self,
-        path: &str,
-        node_refcount: &BTreeMap<Node, usize>,
-        storage: &mut dyn Storage,
-    ) -> Result<(), Error> {
-        let (node, metadata) = rm_dir_entry(self.node, path, Some(false), node_refcount, storage)?;
+        dot_entries: bool,
+        storage: &dyn Storage,
+    ) -> Result<Vec<DirEntry>, Error> {
+        let mut result = vec![];
+
+        if dot_entries {
+            result.push(DirEntry {
+                name: FileName::new(".".as_bytes())?,
+                node: self.node,
+                next_entry: None,
+                prev_entry: None,
+            });
+            result.push(DirEntry {
+                name: FileName::new("..".as_bytes())?,
+                node: get_parent_node(self.node, storage)?,
+                next_entry: None,
+                prev_entry: None,
+            });
+        }
 
-        if metadata.link_count == 0 {
-            let chunk_cnt = (metadata.size + FILE_CHUNK_SIZE as u64 - 1) / FILE_CHUNK_SIZE as u64;
-            for index in 0..chunk_cnt {
-                storage.rm_filechunk(node, index as u32);
-            }
-            storage.rm_metadata(node);
+        let mut next_index = storage.get_metadata(self.node)?.first_dir_entry;
+
+        while let Some(index) = next_index {
+            let entry = storage.get_direntry(self.node, index)?;
+            next_index = entry.next_entry;
+            result.push(entry);
         }
 
-        Ok(())
+        Ok(result)
     }
 
     // Get directory entry by index.
@@ -126,10 +244,57 @@ impl Dir {
     }
 }
 
+// Check that the node can be removed with the options, the opened nodes of `node_refcount` cannot be removed.
+fn check_removable(
+    node: Node,
+    metadata: &Metadata,
+    options: RemoveOptions,
+    node_refcount: &BTreeMap<Node, usize>,
+    storage: &dyn Storage,
+) -> Result<(), Error> {
+    match metadata.file_type {
+        FileType::Directory => {
+            if options.must_be_dir == Some(false) {
+                return Err(Error::ExpectedToRemoveFile);
+            }
+            if !options.recursive && metadata.link_count == 1 && metadata.size > 0 {
+                return Err(Error::DirectoryNotEmpty);
+            }
+        }
+        FileType::RegularFile | FileType::SymbolicLink => {
+            if options.must_be_dir == Some(true) {
+                return Err(Error::ExpectedToRemoveDirectory);
+            }
+        }
+    }
+
+    if node_refcount
+        .get(&node)
+        .is_some_and(|refcount| *refcount > 0)
+        && metadata.link_count == 1
+    {
+        return Err(Error::CannotRemoveOpenedNode);
+    }
+
+    if options.recursive && metadata.file_type == FileType::Directory {
+        check_subtree_not_opened(node, node_refcount, storage)?;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
-    use crate::{error::Error, fs::OpenFlags, runtime::types::FdStat, test_utils::test_fs};
+    use crate::{
+        error::Error,
+        fs::OpenFlags,
+        runtime::{
+            structure_helpers::{find_node, get_parent_node},
+            types::FdStat,
+        },
+        test_utils::test_fs,
+    };
 
     #[test]
     fn remove_middle_file() {
@@ -155,7 +320,7 @@ mod tests {
 
         fs.remove_file(fs.root_fd(), "test2.txt").unwrap();
 
-        let meta = fs.metadata(dir).unwrap();
+        let meta = fs.raw_metadata(dir).unwrap();
         assert_eq!(meta.size, 2);
 
         let entry1_index = meta.first_dir_entry.unwrap();
@@ -208,7 +373,7 @@ mod tests {
 
         fs.remove_file(fs.root_fd(), "test2.txt").unwrap();
 
-        let meta = fs.metadata(fs.root_fd()).unwrap();
+        let meta = fs.raw_metadata(fs.root_fd()).unwrap();
         assert_eq!(meta.size, 0);
 
         assert_eq!(meta.first_dir_entry, None);
@@ -258,6 +423,34 @@ mod tests {
         assert_eq!(120, metadata.times.created);
     }
 
+    #[test]
+    fn hard_link_keeps_contents_until_last_link_is_removed() {
+        let mut fs = test_fs();
+        let root = fs.root_fd();
+
+        let fd = fs.create_file(root, "a.txt", FdStat::default(), 0).unwrap();
+        fs.write(fd, &[7; 5000]).unwrap();
+        let link = fs.create_hard_link(root, "a.txt", root, "b.txt").unwrap();
+        let node = fs.metadata(fd).unwrap().node;
+        assert_eq!(fs.metadata(link).unwrap().link_count, 2);
+        fs.close(fd).unwrap();
+        fs.close(link).unwrap();
+
+        fs.remove_file(root, "a.txt").unwrap();
+        let fd = fs
+            .open_or_create(root, "b.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().link_count, 1);
+        let mut buf = [0; 5000];
+        assert_eq!(fs.read(fd, &mut buf), Ok(5000));
+        assert_eq!(buf, [7; 5000]);
+        fs.close(fd).unwrap();
+
+        fs.remove_file(root, "b.txt").unwrap();
+        assert_eq!(fs.storage.filechunk_indices(node, 2), Vec::<u32>::new());
+        assert!(fs.storage.get_metadata(node).is_err());
+    }
+
     #[test]
     fn create_directory_hard_link_fails() {
         let mut fs = test_fs();
@@ -559,4 +752,100 @@ mod tests {
 
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn rename_a_folder_into_itself_fails() {
+        let mut fs = test_fs();
+
+        let root_fd = fs.root_fd();
+
+        let dir1_fd = fs
+            .create_dir(root_fd, "dir1/dir2", FdStat::default(), 120)
+            .unwrap();
+
+        let res = fs.rename(root_fd, "dir1", root_fd, "dir1/dir3");
+        assert_eq!(res, Err(Error::CannotMoveDirectoryIntoItself));
+
+        let res = fs.rename(root_fd, "dir1", root_fd, "dir1/dir2/dir3");
+        assert_eq!(res, Err(Error::CannotMoveDirectoryIntoItself));
+
+        // the destination folder is given by a descriptor pointing inside the moved folder
+        let res = fs.rename(root_fd, "dir1", dir1_fd, "dir3");
+        assert_eq!(res, Err(Error::CannotMoveDirectoryIntoItself));
+
+        // no intermediate folders were created by the failed attempts
+        let res = fs.open_or_create(
+            root_fd,
+            "dir1/dir2/dir3",
+            FdStat::default(),
+            OpenFlags::empty(),
+            0,
+        );
+        assert_eq!(res, Err(Error::NotFound));
+
+        let meta = fs.metadata(root_fd).unwrap();
+        assert_eq!(meta.size, 1);
+    }
+
+    #[test]
+    fn rename_sequence_keeps_parent_links() {
+        let mut fs = test_fs();
+
+        let root_fd = fs.root_fd();
+
+        fs.create_dir(root_fd, "a/b/c", FdStat::default(), 0)
+            .unwrap();
+        fs.create_dir(root_fd, "d", FdStat::default(), 0).unwrap();
+
+        let root = fs.storage.root_node();
+
+        fs.rename(root_fd, "a/b", root_fd, "d/b").unwrap();
+        fs.rename(root_fd, "a", root_fd, "d/b/c/a").unwrap();
+
+        let d = find_node(root, "d", fs.storage.as_ref()).unwrap();
+        let b = find_node(root, "d/b", fs.storage.as_ref()).unwrap();
+        let c = find_node(root, "d/b/c", fs.storage.as_ref()).unwrap();
+        let a = find_node(root, "d/b/c/a", fs.storage.as_ref()).unwrap();
+
+        assert_eq!(get_parent_node(d, fs.storage.as_ref()), Ok(root));
+        assert_eq!(get_parent_node(b, fs.storage.as_ref()), Ok(d));
+        assert_eq!(get_parent_node(c, fs.storage.as_ref()), Ok(b));
+        assert_eq!(get_parent_node(a, fs.storage.as_ref()), Ok(c));
+
+        // moving the top folder under its former child must fail now
+        let res = fs.rename(root_fd, "d", root_fd, "d/b/c/a/d");
+        assert_eq!(res, Err(Error::CannotMoveDirectoryIntoItself));
+
+        // moving the nested folder back up is allowed
+        fs.rename(root_fd, "d/b/c/a", root_fd, "a").unwrap();
+        assert_eq!(get_parent_node(a, fs.storage.as_ref()), Ok(root));
+
+        let meta = fs.metadata_from_node(c).unwrap();
+        assert_eq!(meta.size, 0);
+        assert_eq!(meta.link_count, 1);
+    }
+
+    #[test]
+    fn parent_is_found_for_folders_without_parent_link() {
+        let mut fs = test_fs();
+
+        let root_fd = fs.root_fd();
+
+        let dir_fd = fs
+            .create_dir(root_fd, "dir1/dir2", FdStat::default(), 0)
+            .unwrap();
+
+        let root = fs.storage.root_node();
+        let dir1 = find_node(root, "dir1", fs.storage.as_ref()).unwrap();
+
+        // simulate metadata stored by an older version of the file system
+        let mut meta = fs.raw_metadata(dir_fd).unwrap();
+        meta.parent = None;
+        fs.set_metadata(dir_fd, meta.clone()).unwrap();
+
+        assert_eq!(get_parent_node(meta.node, fs.storage.as_ref()), Ok(dir1));
+
+        let res = fs.rename(root_fd, "dir1", root_fd, "dir1/dir2/dir3");
+        assert_eq!(res, Err(Error::CannotMoveDirectoryIntoItself));
+    }
 }
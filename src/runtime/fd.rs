@@ -1,18 +1,206 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fmt, panic::Location};
 
 use crate::{
     error::Error,
-    runtime::{dir::Dir, file::File},
-    storage::types::Node,
+    runtime::{
+        dir::Dir,
+        file::File,
+        proc::ProcContent,
+        types::{FdStat, ReadPattern},
+    },
+    storage::types::{FileSize, FileType, Node},
 };
 
-const RESERVED_FD_COUNT: Fd = 3;
+const RESERVED_FD_COUNT: u32 = 3;
 
-pub type Fd = u32;
+// A file descriptor, the number is converted explicitly to keep the arbitrary integers from being used as descriptors.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fd(u32);
 
+impl Fd {
+    pub const fn new(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    // The number of the descriptor.
+    pub const fn raw(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<u32> for Fd {
+    fn from(raw: u32) -> Self {
+        Self(raw)
+    }
+}
+
+impl From<Fd> for u32 {
+    fn from(fd: Fd) -> Self {
+        fd.0
+    }
+}
+
+impl fmt::Display for Fd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for Fd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Fd({})", self.0)
+    }
+}
+
+// Where a file descriptor was opened, recorded in the debug builds only.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FdProvenance {
+    // The caller of the file system function opening the descriptor.
+    pub location: &'static Location<'static>,
+    // The path the descriptor was opened with, if opened by path.
+    pub path: Option<String>,
+}
+
+// The state of an open descriptor.
 pub enum FdEntry {
-    File(File),
-    Dir(Dir),
+    File {
+        node: Node,
+        cursor: FileSize,
+        stat: FdStat,
+        read_pattern: ReadPattern,
+    },
+    Dir {
+        node: Node,
+        // the number of the entries already listed by `FileSystem::list_dir_next`
+        cursor: u64,
+        stat: FdStat,
+    },
+    // An introspection file, it is not stored and refers to the introspection directory as its node.
+    Proc {
+        dir: Node,
+        content: ProcContent,
+        stat: FdStat,
+    },
+}
+
+impl FdEntry {
+    pub fn node(&self) -> Node {
+        match self {
+            FdEntry::File { node, .. } | FdEntry::Dir { node, .. } => *node,
+            FdEntry::Proc { dir, .. } => *dir,
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self {
+            FdEntry::File { .. } | FdEntry::Proc { .. } => FileType::RegularFile,
+            FdEntry::Dir { .. } => FileType::Directory,
+        }
+    }
+
+    pub fn stat(&self) -> FdStat {
+        match self {
+            FdEntry::File { stat, .. } | FdEntry::Dir { stat, .. } | FdEntry::Proc { stat, .. } => {
+                *stat
+            }
+        }
+    }
+
+    pub fn set_stat(&mut self, new_stat: FdStat) {
+        match self {
+            FdEntry::File { stat, .. } | FdEntry::Dir { stat, .. } | FdEntry::Proc { stat, .. } => {
+                *stat = new_stat
+            }
+        }
+    }
+
+    // The file cursor, or the number of the listed entries of a directory.
+    pub fn cursor(&self) -> u64 {
+        match self {
+            FdEntry::File { cursor, .. } | FdEntry::Dir { cursor, .. } => *cursor,
+            FdEntry::Proc { content, .. } => content.cursor,
+        }
+    }
+
+    // Get the generated contents of an introspection file descriptor.
+    pub fn proc_content(&mut self) -> Option<&mut ProcContent> {
+        match self {
+            FdEntry::Proc { content, .. } => Some(content),
+            FdEntry::File { .. } | FdEntry::Dir { .. } => None,
+        }
+    }
+
+    // Get the file handle of a file descriptor.
+    pub fn file(&self) -> Option<File> {
+        match self {
+            FdEntry::File {
+                node,
+                cursor,
+                stat,
+                read_pattern,
+            } => Some(File {
+                node: *node,
+                cursor: *cursor,
+                stat: *stat,
+                read_pattern: *read_pattern,
+            }),
+            FdEntry::Dir { .. } | FdEntry::Proc { .. } => None,
+        }
+    }
+
+    // Get the directory handle of a directory descriptor.
+    pub fn dir(&self) -> Option<Dir> {
+        match self {
+            FdEntry::Dir { node, stat, .. } => Some(Dir {
+                node: *node,
+                stat: *stat,
+            }),
+            FdEntry::File { .. } | FdEntry::Proc { .. } => None,
+        }
+    }
+
+    pub fn view(&self, fd: Fd) -> FdView {
+        FdView {
+            fd,
+            node: self.node(),
+            file_type: self.file_type(),
+            stat: self.stat(),
+            cursor: self.cursor(),
+        }
+    }
+}
+
+impl From<File> for FdEntry {
+    fn from(file: File) -> Self {
+        FdEntry::File {
+            node: file.node,
+            cursor: file.cursor,
+            stat: file.stat,
+            read_pattern: file.read_pattern,
+        }
+    }
+}
+
+// A directory entered from its handle starts listing from the first entry.
+impl From<Dir> for FdEntry {
+    fn from(dir: Dir) -> Self {
+        FdEntry::Dir {
+            node: dir.node,
+            cursor: 0,
+            stat: dir.stat,
+        }
+    }
+}
+
+// A read-only view of an open descriptor.
+#[derive(Clone, Copy, Debug)]
+pub struct FdView {
+    pub fd: Fd,
+    pub node: Node,
+    pub file_type: FileType,
+    pub stat: FdStat,
+    // The file cursor, or the number of the entries listed by `FileSystem::list_dir_next`.
+    pub cursor: u64,
 }
 
 //
@@ -22,9 +210,12 @@ pub struct FdTable {
     // backward links to see how many file descriptors are currently pointing to any particular node.
     node_refcount: BTreeMap<Node, usize>,
     // the next generated descriptor's ID (if there is nothing to reuse).
-    next_fd: Fd,
+    next_fd: u32,
     // freed file descriptors ready to reuse.
     free_fds: Vec<Fd>,
+    // where the open descriptors were opened.
+    #[cfg(debug_assertions)]
+    provenance: BTreeMap<Fd, FdProvenance>,
 }
 
 impl FdTable {
@@ -35,6 +226,8 @@ impl FdTable {
             node_refcount: BTreeMap::default(),
             next_fd: RESERVED_FD_COUNT,
             free_fds: vec![],
+            #[cfg(debug_assertions)]
+            provenance: BTreeMap::default(),
         }
     }
 
@@ -50,10 +243,10 @@ impl FdTable {
 
     // Update a file descriptor entry, it returns the old entry if existed.
     pub fn insert(&mut self, fd: Fd, entry: FdEntry) -> Option<FdEntry> {
-        self.inc_node_refcount(&entry);
+        self.inc_node_refcount(entry.node());
         let prev_entry = self.table.insert(fd, entry);
         if let Some(prev_entry) = prev_entry.as_ref() {
-            self.dec_node_refcount(prev_entry);
+            self.dec_node_refcount(prev_entry.node());
         }
         prev_entry
     }
@@ -63,44 +256,124 @@ impl FdTable {
         self.table.get(&fd)
     }
 
-    // Open a new file descriptor.
-    pub fn open(&mut self, entry: FdEntry) -> Fd {
+    // Get an FdEntry for changing the state of a descriptor, the node of the entry must be kept.
+    pub fn get_mut(&mut self, fd: Fd) -> Option<&mut FdEntry> {
+        self.table.get_mut(&fd)
+    }
+
+    // Iterate over the open file descriptors in the ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (Fd, &FdEntry)> {
+        self.table.iter().map(|(fd, entry)| (*fd, entry))
+    }
+
+    // Open a new file descriptor, the debug builds record the caller as its provenance.
+    #[track_caller]
+    pub fn open(&mut self, entry: FdEntry) -> Result<Fd, Error> {
         let fd = match self.free_fds.pop() {
             Some(fd) => fd,
-            None => {
-                let fd = self.next_fd;
-                self.next_fd += 1;
-                fd
-            }
+            None => self.next_number()?,
         };
         let prev = self.insert(fd, entry);
         assert!(prev.is_none());
-        fd
+
+        #[cfg(debug_assertions)]
+        self.provenance.insert(
+            fd,
+            FdProvenance {
+                location: Location::caller(),
+                path: None,
+            },
+        );
+
+        Ok(fd)
     }
 
+    // Take the next number never given out, the numbers taken by `renumber` are skipped.
+    // The number `u32::MAX` is not given out.
+    fn next_number(&mut self) -> Result<Fd, Error> {
+        while self.next_fd < u32::MAX {
+            let fd = Fd(self.next_fd);
+            self.next_fd += 1;
+            if !self.table.contains_key(&fd) {
+                return Ok(fd);
+            }
+        }
+
+        Err(Error::TooManyOpenFiles)
+    }
+
+    // Get where a descriptor was opened, always `None` in the release builds.
+    #[cfg(debug_assertions)]
+    pub fn provenance(&self, fd: Fd) -> Option<&FdProvenance> {
+        self.provenance.get(&fd)
+    }
+
+    #[cfg(not(debug_assertions))]
+    pub fn provenance(&self, _fd: Fd) -> Option<&FdProvenance> {
+        None
+    }
+
+    // Record the path a descriptor was opened with.
+    #[cfg(debug_assertions)]
+    pub fn set_path(&mut self, fd: Fd, path: &str) {
+        if let Some(provenance) = self.provenance.get_mut(&fd) {
+            provenance.path = Some(path.to_string());
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    pub fn set_path(&mut self, _fd: Fd, _path: &str) {}
+
     // Reassign a file descriptor to a new number, the source descriptor is closed in the process.
-    // If the destination descriptor is busy, it is closed in the process.
+    // If the destination descriptor is busy, it is closed in the process. The destination number may be
+    // any number, also one that was never given out; the numbers below it are still given out.
     pub fn renumber(&mut self, src: Fd, dst: Fd) -> Result<(), Error> {
+        if !self.table.contains_key(&src) {
+            return Err(Error::NotFound);
+        }
+        if src == dst {
+            return Ok(());
+        }
+        if dst.0 == u32::MAX {
+            return Err(Error::InvalidFileDescriptor);
+        }
+
+        #[cfg(debug_assertions)]
+        let provenance = self.provenance.get(&src).cloned();
         let old_entry = self.close(src).ok_or(Error::NotFound)?;
 
         // quietly close the destination file descriptor
-        if let Some(_old_dst_entry) = self.close(dst) {
-            let removed = self.free_fds.pop().unwrap();
-            assert_eq!(removed, dst);
-        }
+        self.close(dst);
+        self.reserve(dst);
 
         self.insert(dst, old_entry);
 
+        #[cfg(debug_assertions)]
+        if let Some(provenance) = provenance {
+            self.provenance.insert(dst, provenance);
+        }
+
         Ok(())
     }
 
+    // Take a descriptor number out of the free numbers, so that it is not given out while it is used.
+    // A number not given out yet is skipped by `next_number` while it is used.
+    fn reserve(&mut self, fd: Fd) {
+        self.free_fds.retain(|free| *free != fd);
+    }
+
     // Close file descriptor.
     pub fn close(&mut self, fd: Fd) -> Option<FdEntry> {
         let entry = self.table.remove(&fd);
 
         if let Some(entry) = entry {
-            self.free_fds.push(fd);
-            self.dec_node_refcount(&entry);
+            #[cfg(debug_assertions)]
+            self.provenance.remove(&fd);
+            // a number not given out yet is taken by `next_number` later
+            if fd.0 < self.next_fd {
+                self.free_fds.push(fd);
+            }
+            self.dec_node_refcount(entry.node());
 
             Some(entry)
         } else {
@@ -108,21 +381,22 @@ impl FdTable {
         }
     }
 
-    fn inc_node_refcount(&mut self, entry: &FdEntry) {
-        let node = match entry {
-            FdEntry::File(file) => file.node,
-            FdEntry::Dir(dir) => dir.node,
-        };
+    // Count a reference to a node held without a descriptor, e.g. by a frozen file version.
+    // The pinned node counts as opened until it is unpinned.
+    pub fn pin(&mut self, node: Node) {
+        self.inc_node_refcount(node);
+    }
+
+    pub fn unpin(&mut self, node: Node) {
+        self.dec_node_refcount(node);
+    }
+
+    fn inc_node_refcount(&mut self, node: Node) {
         let refcount = self.node_refcount.entry(node).or_default();
         *refcount += 1;
     }
 
-    fn dec_node_refcount(&mut self, entry: &FdEntry) {
-        let node = match entry {
-            FdEntry::File(file) => file.node,
-            FdEntry::Dir(dir) => dir.node,
-        };
-
+    fn dec_node_refcount(&mut self, node: Node) {
         let refcount = self.node_refcount.remove(&node);
         if let Some(mut refcount) = refcount {
             refcount -= 1;
@@ -132,3 +406,41 @@ impl FdTable {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Error,
+        runtime::{dir::Dir, types::FdStat},
+    };
+
+    use super::{Fd, FdTable};
+
+    fn dir_entry() -> super::FdEntry {
+        Dir {
+            node: 1,
+            stat: FdStat::default(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn numbers_near_the_limit() {
+        let mut table = FdTable::new();
+        let fd = table.open(dir_entry()).unwrap();
+
+        // the numbers below a renumbered descriptor are still given out
+        table.renumber(fd, Fd::new(u32::MAX - 1)).unwrap();
+        let next = table.open(dir_entry()).unwrap();
+        assert!(next.raw() < 10);
+
+        table.next_fd = u32::MAX - 2;
+        assert_eq!(table.open(dir_entry()), Ok(Fd::new(u32::MAX - 2)));
+        // the renumbered descriptor is skipped
+        assert_eq!(table.open(dir_entry()), Err(Error::TooManyOpenFiles));
+
+        table.close(Fd::new(u32::MAX - 1)).unwrap();
+        assert_eq!(table.open(dir_entry()), Ok(Fd::new(u32::MAX - 1)));
+        assert_eq!(table.open(dir_entry()), Err(Error::TooManyOpenFiles));
+    }
+}
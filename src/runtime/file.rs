@@ -1,17 +1,28 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+};
+
 use crate::{
     error::Error,
-    runtime::types::{FdFlags, FdStat, Whence},
+    runtime::types::{FdFlags, FdStat, ReadPattern, SparseExtent, SparseFile, Whence},
     storage::{
-        types::{FileChunkIndex, FileSize, FileType, Node, FILE_CHUNK_SIZE},
+        types::{
+            FileChunkIndex, FileSize, FileType, Metadata, Node, FILE_CHUNK_SIZE, MAX_FILE_SIZE,
+        },
         Storage,
     },
 };
 
+// The number of chunks read ahead when the file is read sequentially.
+pub const READAHEAD_CHUNK_COUNT: FileChunkIndex = 4;
+
 #[derive(Clone, Debug)]
 pub struct File {
     pub node: Node,
     pub cursor: FileSize,
     pub stat: FdStat,
+    pub read_pattern: ReadPattern,
 }
 
 impl File {
@@ -21,17 +32,20 @@ impl File {
         let file_type = metadata.file_type;
         match file_type {
             FileType::RegularFile => {}
-            FileType::Directory => {
-                unreachable!("Unexpected file type, expected a regular file.");
-            }
-            FileType::SymbolicLink => unimplemented!("Symbolic links are not implemented yet"),
+            FileType::Directory => return Err(Error::IsDirectory),
+            FileType::SymbolicLink => return Err(Error::InvalidFileType),
         };
         let cursor = if stat.flags.contains(FdFlags::APPEND) {
             metadata.size
         } else {
             0
         };
-        Ok(Self { node, cursor, stat })
+        Ok(Self {
+            node,
+            cursor,
+            stat,
+            read_pattern: ReadPattern::default(),
+        })
     }
 
     // Seek a position in a file for reading or writing.
@@ -43,31 +57,12 @@ impl File {
     ) -> Result<FileSize, Error> {
         let size = storage.get_metadata(self.node)?.size;
         let position = match whence {
-            Whence::SET => {
-                if delta < 0 {
-                    return Err(Error::InvalidOffset);
-                }
-                delta as FileSize
-            }
-            Whence::CUR => {
-                let back = if delta < 0 {
-                    (-delta).try_into().map_err(|_| Error::InvalidOffset)?
-                } else {
-                    0
-                };
-                let fwd = if delta >= 0 { delta as FileSize } else { 0 };
-                if back > self.cursor {
-                    return Err(Error::InvalidOffset);
-                }
-                self.cursor + fwd - back
-            }
-            Whence::END => {
-                let back: FileSize = (-delta).try_into().map_err(|_| Error::InvalidOffset)?;
-                if back > size {
-                    return Err(Error::InvalidOffset);
-                }
-                size - back
-            }
+            Whence::SET => FileSize::try_from(delta).map_err(|_| Error::InvalidOffset)?,
+            Whence::CUR => self
+                .cursor
+                .checked_add_signed(delta)
+                .ok_or(Error::InvalidOffset)?,
+            Whence::END => size.checked_add_signed(delta).ok_or(Error::InvalidOffset)?,
         };
         self.cursor = position;
         Ok(self.cursor)
@@ -86,10 +81,33 @@ impl File {
     ) -> Result<FileSize, Error> {
         let read_size = self.read_with_offset(self.cursor, buf, storage)?;
         self.cursor += read_size;
+
+        if self.read_pattern == ReadPattern::Sequential && read_size > 0 {
+            self.read_ahead(storage)?;
+        }
+
         Ok(read_size)
     }
 
+    // Prefetch the chunks starting at the cursor position that are still within the file.
+    fn read_ahead(&self, storage: &dyn Storage) -> Result<(), Error> {
+        let file_size = storage.get_metadata(self.node)?.size;
+
+        if self.cursor >= file_size {
+            return Ok(());
+        }
+
+        let first = offset_to_file_chunk_index(self.cursor);
+        let last = offset_to_file_chunk_index(file_size - 1);
+        let count = (last - first + 1).min(READAHEAD_CHUNK_COUNT as FileSize);
+
+        storage.prefetch_filechunks(self.node, first as FileChunkIndex, count as FileChunkIndex);
+
+        Ok(())
+    }
+
     // Write file at the current file cursor, the cursor position will be updated after reading.
+    #[cfg(test)]
     pub fn write_with_cursor(
         &mut self,
         buf: &[u8],
@@ -101,6 +119,7 @@ impl File {
     }
 
     // Read file at the current file cursor, the cursor position will NOT be updated after reading.
+    // Reading at or past the end of file returns 0, the regions that were never written are read as zeros.
     pub fn read_with_offset(
         &self,
         offset: FileSize,
@@ -112,57 +131,593 @@ impl File {
         }
 
         let file_size = storage.get_metadata(self.node)?.size;
-        let end = (offset + buf.len() as FileSize).min(file_size);
+        self.read_with_size(offset, buf, file_size, storage)
+    }
+
+    // Read the file at the given offset like `read_with_offset`, the file size is known to the caller.
+    pub fn read_with_size(
+        &self,
+        offset: FileSize,
+        buf: &mut [u8],
+        file_size: FileSize,
+        storage: &dyn Storage,
+    ) -> Result<FileSize, Error> {
+        let end = offset.saturating_add(buf.len() as FileSize).min(file_size);
         let chunk_infos = get_chunk_infos(offset, end);
 
         let mut read_size = 0;
 
         for chunk in chunk_infos.into_iter() {
-            storage.read_filechunk(
-                self.node,
-                chunk.index,
-                chunk.offset,
-                &mut buf[read_size..read_size + chunk.len as usize],
-            )?;
+            let dst = &mut buf[read_size..read_size + chunk.len as usize];
+            match storage.read_filechunk(self.node, chunk.index, chunk.offset, dst) {
+                Ok(()) => {}
+                Err(Error::NotFound) => dst.fill(0),
+                Err(err) => return Err(err),
+            }
             read_size += chunk.len as usize;
         }
         Ok(read_size as FileSize)
     }
 
     // Write file at the current file cursor, the cursor position will NOT be updated after reading.
+    // A zero-length write is a no-op, it does not extend the file even if the offset is past the end of file.
+    // If a chunk write fails, the chunks written before are kept and counted into the file size.
     pub fn write_with_offset(
         &self,
         offset: FileSize,
         buf: &[u8],
         storage: &mut dyn Storage,
     ) -> Result<FileSize, Error> {
-        let mut metadata = storage.get_metadata(self.node)?;
-        let end = offset + buf.len() as FileSize;
+        let (written_size, result) = self.write_partial(offset, buf, None, None, storage);
+        result.map(|_| written_size)
+    }
+
+    // Write file at the given offset, returns the size written before a chunk write failed with the failure.
+    // The file size is extended to the end of the written part, the chunks are saved into `undo` before
+    // they are overwritten. The file metadata is read from the storage unless the caller passes it.
+    pub fn write_partial(
+        &self,
+        offset: FileSize,
+        buf: &[u8],
+        metadata: Option<Metadata>,
+        mut undo: Option<&mut WriteUndo>,
+        storage: &mut dyn Storage,
+    ) -> (FileSize, Result<(), Error>) {
+        if buf.is_empty() {
+            return (0, Ok(()));
+        }
+
+        let metadata = match metadata {
+            Some(metadata) => Ok(metadata),
+            None => storage.get_metadata(self.node),
+        };
+        let mut metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(err) => return (0, Err(err)),
+        };
+        // a region is not extended by the writes
+        let max_size = if metadata.region {
+            metadata.size
+        } else {
+            MAX_FILE_SIZE
+        };
+        let end = match offset
+            .checked_add(buf.len() as FileSize)
+            .filter(|end| *end <= max_size)
+        {
+            Some(end) => end,
+            None => return (0, Err(Error::FileTooLarge)),
+        };
+
         let chunk_infos = get_chunk_infos(offset, end);
         let mut written_size = 0;
+        let mut result = Ok(());
         for chunk in chunk_infos.into_iter() {
-            storage.write_filechunk(
-                self.node,
-                chunk.index,
-                chunk.offset,
-                &buf[written_size..written_size + chunk.len as usize],
-            );
+            if let Some(undo) = undo.as_deref_mut() {
+                result = undo.save(chunk.index, storage);
+            }
+            result = result.and_then(|_| {
+                storage.write_filechunk(
+                    self.node,
+                    chunk.index,
+                    chunk.offset,
+                    &buf[written_size..written_size + chunk.len as usize],
+                )
+            });
+            if result.is_err() {
+                break;
+            }
             written_size += chunk.len as usize;
         }
-        if end > metadata.size {
-            metadata.size = end;
+
+        let written_end = offset + written_size as FileSize;
+        if written_end > metadata.size {
+            metadata.size = written_end;
             storage.put_metadata(self.node, metadata)
         }
-        Ok(written_size as FileSize)
+        (written_size as FileSize, result)
+    }
+
+    // Read several `(offset, len)` ranges of the file, each range is cut at the end of file.
+    // The chunks are fetched in order and only once, even if several ranges overlap them.
+    pub fn read_ranges(
+        &self,
+        ranges: &[(FileSize, FileSize)],
+        storage: &mut dyn Storage,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let file_size = storage.get_metadata(self.node)?.size;
+
+        let range_chunks: Vec<Vec<ChunkHandle>> = ranges
+            .iter()
+            .map(|(offset, len)| {
+                let end = offset.saturating_add(*len).min(file_size);
+                if *offset >= end {
+                    Vec::new()
+                } else {
+                    get_chunk_infos(*offset, end)
+                }
+            })
+            .collect();
+
+        let indices: BTreeSet<FileChunkIndex> = range_chunks
+            .iter()
+            .flatten()
+            .map(|chunk| chunk.index)
+            .collect();
+
+        let mut chunks = BTreeMap::new();
+        for index in indices {
+            let mut buf = vec![0; FILE_CHUNK_SIZE];
+            match storage.read_filechunk(self.node, index, 0, &mut buf) {
+                Ok(()) => {}
+                // the chunk was never written and is read as zeros
+                Err(Error::NotFound) => {}
+                Err(err) => return Err(err),
+            }
+            chunks.insert(index, buf);
+        }
+
+        let result = range_chunks
+            .iter()
+            .map(|handles| {
+                let mut data = Vec::new();
+                for handle in handles {
+                    let start = handle.offset as usize;
+                    data.extend_from_slice(
+                        &chunks[&handle.index][start..start + handle.len as usize],
+                    );
+                }
+                data
+            })
+            .collect();
+
+        Ok(result)
+    }
+
+    // Read a whole chunk of the file without copying it when possible, the last chunk is cut at the end of file.
+    // Reading a chunk past the end of file returns an empty slice.
+    pub fn read_chunk<'a>(
+        &self,
+        index: FileChunkIndex,
+        storage: &'a mut dyn Storage,
+    ) -> Result<Cow<'a, [u8]>, Error> {
+        let file_size = storage.get_metadata(self.node)?.size;
+        let start = file_chunk_index_to_offset(index as FileSize);
+
+        if start >= file_size {
+            return Ok(Cow::Borrowed(&[]));
+        }
+
+        let len = (file_size - start).min(FILE_CHUNK_SIZE as FileSize) as usize;
+
+        match storage.get_filechunk(self.node, index) {
+            Ok(Cow::Borrowed(chunk)) => Ok(Cow::Borrowed(&chunk[0..len])),
+            Ok(Cow::Owned(mut chunk)) => {
+                chunk.truncate(len);
+                Ok(Cow::Owned(chunk))
+            }
+            Err(Error::NotFound) => Ok(Cow::Owned(vec![0; len])),
+            Err(err) => Err(err),
+        }
     }
 
-    // Truncate file to 0 size.
+    // Truncate file to 0 size, the file chunks are removed.
     pub fn truncate(&self, storage: &mut dyn Storage) -> Result<(), Error> {
         let mut metadata = storage.get_metadata(self.node)?;
+        if metadata.region {
+            return Err(Error::InvalidFileType);
+        }
+        self.rm_chunks(metadata.size, storage);
         metadata.size = 0;
         storage.put_metadata(self.node, metadata);
         Ok(())
     }
+
+    // Change the file size like `ftruncate`, the chunks past the new end are removed. The bytes added by growing
+    // the file are not written, they read as zeros.
+    pub fn set_size(&self, size: FileSize, storage: &mut dyn Storage) -> Result<(), Error> {
+        if size > MAX_FILE_SIZE {
+            return Err(Error::FileTooLarge);
+        }
+
+        let mut metadata = storage.get_metadata(self.node)?;
+        if size == metadata.size {
+            return Ok(());
+        }
+        if metadata.region {
+            return Err(Error::InvalidFileType);
+        }
+
+        let kept_chunks = size.div_ceil(FILE_CHUNK_SIZE as FileSize);
+        let chunk_count = metadata.size.div_ceil(FILE_CHUNK_SIZE as FileSize);
+        for index in storage.filechunk_indices(self.node, chunk_count) {
+            if index as FileSize >= kept_chunks {
+                storage.rm_filechunk(self.node, index);
+            }
+        }
+
+        // the bytes of the last kept chunk past the shorter size become readable, they are cleared
+        let end = size.min(metadata.size);
+        let tail = (end % FILE_CHUNK_SIZE as FileSize) as usize;
+        let index = (end / FILE_CHUNK_SIZE as FileSize) as FileChunkIndex;
+        if tail > 0 && storage.read_filechunk(self.node, index, 0, &mut []).is_ok() {
+            let zeros = vec![0; FILE_CHUNK_SIZE - tail];
+            storage.write_filechunk(self.node, index, tail as FileSize, &zeros)?;
+        }
+
+        metadata.size = size;
+        storage.put_metadata(self.node, metadata);
+        Ok(())
+    }
+
+    // Store the missing chunks of the range `offset..offset + len` so that writing into it does not need
+    // new memory, the file is extended to the end of the range. The chunks stored by a failed call are removed.
+    pub fn allocate(
+        &self,
+        offset: FileSize,
+        len: FileSize,
+        storage: &mut dyn Storage,
+    ) -> Result<(), Error> {
+        // the chunks of a region are all stored, it cannot be extended
+        let max_size = match storage.get_metadata(self.node)? {
+            metadata if metadata.region => metadata.size,
+            _ => MAX_FILE_SIZE,
+        };
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= max_size)
+            .ok_or(Error::FileTooLarge)?;
+        if len == 0 {
+            return Ok(());
+        }
+
+        let first = offset_to_file_chunk_index(offset) as FileChunkIndex;
+        let last = offset_to_file_chunk_index(end - 1) as FileChunkIndex;
+        let mut stored = Vec::new();
+        for index in first..=last {
+            if storage.read_filechunk(self.node, index, 0, &mut []).is_ok() {
+                continue;
+            }
+            if let Err(err) = storage.write_filechunk(self.node, index, 0, &[]) {
+                for index in stored {
+                    storage.rm_filechunk(self.node, index);
+                }
+                return Err(err);
+            }
+            stored.push(index);
+        }
+
+        let mut metadata = storage.get_metadata(self.node)?;
+        if end > metadata.size {
+            metadata.size = end;
+            storage.put_metadata(self.node, metadata);
+        }
+        Ok(())
+    }
+
+    // Remove all the written chunks of a file of the given size.
+    fn rm_chunks(&self, size: FileSize, storage: &mut dyn Storage) {
+        let chunk_count = size.div_ceil(FILE_CHUNK_SIZE as FileSize);
+        for index in storage.filechunk_indices(self.node, chunk_count) {
+            storage.rm_filechunk(self.node, index);
+        }
+    }
+
+    // Get the indices of the chunks holding data, the chunks that were never written or contain only zeros are skipped.
+    pub fn data_chunks(&self, storage: &dyn Storage) -> Result<Vec<FileChunkIndex>, Error> {
+        let size = storage.get_metadata(self.node)?.size;
+        let chunk_count = size.div_ceil(FILE_CHUNK_SIZE as FileSize);
+
+        let mut result = Vec::new();
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+
+        for index in storage.filechunk_indices(self.node, chunk_count) {
+            storage.read_filechunk(self.node, index, 0, &mut buf)?;
+            if buf.iter().any(|b| *b != 0) {
+                result.push(index);
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Export the file contents keeping only its data regions.
+    pub fn export_sparse(&self, storage: &dyn Storage) -> Result<SparseFile, Error> {
+        let size = storage.get_metadata(self.node)?.size;
+        let mut extents: Vec<SparseExtent> = Vec::new();
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+
+        for index in self.data_chunks(storage)? {
+            let offset = file_chunk_index_to_offset(index as FileSize);
+            let len = (size - offset).min(FILE_CHUNK_SIZE as FileSize) as usize;
+            storage.read_filechunk(self.node, index, 0, &mut buf[..len])?;
+
+            // merge the adjacent chunks into one extent
+            match extents.last_mut() {
+                Some(last) if last.offset + last.data.len() as FileSize == offset => {
+                    last.data.extend_from_slice(&buf[..len])
+                }
+                _ => extents.push(SparseExtent {
+                    offset,
+                    data: buf[..len].to_vec(),
+                }),
+            }
+        }
+
+        Ok(SparseFile { size, extents })
+    }
+
+    // Replace the file contents with the sparse file contents, the holes are not written.
+    pub fn import_sparse(
+        &self,
+        sparse: &SparseFile,
+        storage: &mut dyn Storage,
+    ) -> Result<(), Error> {
+        if sparse.size > MAX_FILE_SIZE {
+            return Err(Error::FileTooLarge);
+        }
+
+        self.truncate(storage)?;
+
+        for extent in sparse.extents.iter() {
+            let end = extent.offset.saturating_add(extent.data.len() as FileSize);
+            if end > sparse.size {
+                return Err(Error::InvalidOffset);
+            }
+            self.write_with_offset(extent.offset, &extent.data, storage)?;
+        }
+
+        let mut metadata = storage.get_metadata(self.node)?;
+        metadata.size = sparse.size;
+        storage.put_metadata(self.node, metadata);
+
+        Ok(())
+    }
+
+    // Fill the empty file with the contents of the `sources` one after another, the sources are left as they were
+    // except for the chunks moved. The chunks of a source starting at a chunk boundary are moved and recorded
+    // in `moved` as `(source node, source index, file index)`, the other sources are copied.
+    pub fn concat(
+        &self,
+        sources: &[File],
+        moved: &mut Vec<(Node, FileChunkIndex, FileChunkIndex)>,
+        storage: &mut dyn Storage,
+    ) -> Result<FileSize, Error> {
+        let mut total: FileSize = 0;
+        for src in sources {
+            total += storage.get_metadata(src.node)?.size;
+            if total > MAX_FILE_SIZE {
+                return Err(Error::FileTooLarge);
+            }
+        }
+
+        let mut size: FileSize = 0;
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+
+        for src in sources {
+            let src_size = storage.get_metadata(src.node)?.size;
+
+            if size.is_multiple_of(FILE_CHUNK_SIZE as FileSize) {
+                let first_index = (size / FILE_CHUNK_SIZE as FileSize) as FileChunkIndex;
+                let chunk_count = src_size.div_ceil(FILE_CHUNK_SIZE as FileSize);
+
+                for index in storage.filechunk_indices(src.node, chunk_count) {
+                    storage.move_filechunk(src.node, index, self.node, first_index + index)?;
+                    moved.push((src.node, index, first_index + index));
+                }
+
+                let mut metadata = storage.get_metadata(self.node)?;
+                metadata.size = size + src_size;
+                storage.put_metadata(self.node, metadata);
+            } else {
+                let mut offset = 0;
+                while offset < src_size {
+                    let len = (src_size - offset).min(FILE_CHUNK_SIZE as FileSize) as usize;
+                    src.read_with_offset(offset, &mut buf[..len], storage)?;
+                    self.write_with_offset(size + offset, &buf[..len], storage)?;
+                    offset += len as FileSize;
+                }
+            }
+
+            size += src_size;
+        }
+
+        Ok(size)
+    }
+
+    // Give the chunks `moved` by `concat` back to their sources, the bytes written after the end of a source
+    // into its last chunk are zeroed.
+    pub fn restore_chunks(
+        &self,
+        moved: &[(Node, FileChunkIndex, FileChunkIndex)],
+        storage: &mut dyn Storage,
+    ) {
+        for (src, index, file_index) in moved.iter().rev() {
+            if storage
+                .move_filechunk(self.node, *file_index, *src, *index)
+                .is_err()
+            {
+                continue;
+            }
+
+            let Ok(metadata) = storage.get_metadata(*src) else {
+                continue;
+            };
+            let start = *index as FileSize * FILE_CHUNK_SIZE as FileSize;
+            if metadata.size > start && metadata.size - start < FILE_CHUNK_SIZE as FileSize {
+                let tail = vec![0; FILE_CHUNK_SIZE - (metadata.size - start) as usize];
+                let _ = storage.write_filechunk(*src, *index, metadata.size - start, &tail);
+            }
+        }
+    }
+
+    // Move the file contents into the `parts` of `part_size` bytes each, the last part gets the rest
+    // and the file is left empty. The chunks of a part starting and ending at a chunk boundary are moved
+    // and recorded in `moved` as `(part node, part index, file index)`, the other parts are copied.
+    pub fn split(
+        &self,
+        parts: &[File],
+        part_size: FileSize,
+        moved: &mut Vec<(Node, FileChunkIndex, FileChunkIndex)>,
+        storage: &mut dyn Storage,
+    ) -> Result<(), Error> {
+        let size = storage.get_metadata(self.node)?.size;
+        let chunk_size = FILE_CHUNK_SIZE as FileSize;
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+
+        for (i, part) in parts.iter().enumerate() {
+            let offset = i as FileSize * part_size;
+            let end = if i + 1 == parts.len() {
+                size
+            } else {
+                (offset + part_size).min(size)
+            };
+
+            part.truncate(storage)?;
+
+            if offset.is_multiple_of(chunk_size) && (end.is_multiple_of(chunk_size) || end == size)
+            {
+                let first_index = (offset / chunk_size) as FileChunkIndex;
+                let chunk_count = end.div_ceil(chunk_size);
+
+                for index in storage.filechunk_indices(self.node, chunk_count) {
+                    if index >= first_index {
+                        storage.move_filechunk(self.node, index, part.node, index - first_index)?;
+                        moved.push((part.node, index - first_index, index));
+                    }
+                }
+
+                let mut metadata = storage.get_metadata(part.node)?;
+                metadata.size = end - offset;
+                storage.put_metadata(part.node, metadata);
+            } else {
+                let mut position = offset;
+                while position < end {
+                    let len = (end - position).min(chunk_size) as usize;
+                    self.read_with_offset(position, &mut buf[..len], storage)?;
+                    part.write_with_offset(position - offset, &buf[..len], storage)?;
+                    position += len as FileSize;
+                }
+            }
+        }
+
+        self.truncate(storage)
+    }
+
+    // Take back the chunks `moved` into the parts by a failed `split`, the file size was not changed.
+    pub fn restore_split(
+        &self,
+        moved: &[(Node, FileChunkIndex, FileChunkIndex)],
+        storage: &mut dyn Storage,
+    ) {
+        for (part, part_index, index) in moved.iter().rev() {
+            let _ = storage.move_filechunk(*part, *part_index, self.node, *index);
+        }
+    }
+
+    // Replace the file contents with the contents of `src`, only the data chunks are copied.
+    // A file copied onto itself is left as it is.
+    pub fn copy_from(&self, src: &File, storage: &mut dyn Storage) -> Result<FileSize, Error> {
+        let mut size = storage.get_metadata(src.node)?.size;
+        if src.node == self.node {
+            return Ok(size);
+        }
+        let chunks = src.data_chunks(storage)?;
+
+        self.truncate(storage)?;
+
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+        let mut result = Ok(size);
+        for index in chunks {
+            storage.read_filechunk(src.node, index, 0, &mut buf)?;
+            if let Err(err) = storage.write_filechunk(self.node, index, 0, &buf) {
+                // the chunks before the failed one are copied
+                result = Err(err);
+                size = size.min(index as FileSize * FILE_CHUNK_SIZE as FileSize);
+                break;
+            }
+        }
+
+        let mut metadata = storage.get_metadata(self.node)?;
+        metadata.size = size;
+        storage.put_metadata(self.node, metadata);
+
+        result
+    }
+}
+
+// The previous contents of the chunks overwritten by a file write, used to roll back a failed write.
+pub struct WriteUndo {
+    node: Node,
+    size: FileSize,
+    // the saved chunks, `None` for the chunks that were not stored
+    chunks: Vec<(FileChunkIndex, Option<Vec<u8>>)>,
+}
+
+impl WriteUndo {
+    pub fn new(node: Node, storage: &dyn Storage) -> Result<Self, Error> {
+        Ok(Self {
+            node,
+            size: storage.get_metadata(node)?.size,
+            chunks: Vec::new(),
+        })
+    }
+
+    // Save a chunk before it is overwritten, a chunk is saved only once.
+    fn save(&mut self, index: FileChunkIndex, storage: &mut dyn Storage) -> Result<(), Error> {
+        if self.chunks.iter().any(|(saved, _)| *saved == index) {
+            return Ok(());
+        }
+
+        let chunk = match storage.get_filechunk(self.node, index) {
+            Ok(chunk) => Some(chunk.into_owned()),
+            Err(Error::NotFound) => None,
+            Err(err) => return Err(err),
+        };
+        self.chunks.push((index, chunk));
+        Ok(())
+    }
+
+    // Restore the saved chunks and the file size, the added chunks are removed first to free their space.
+    pub fn restore(self, storage: &mut dyn Storage) -> Result<(), Error> {
+        let mut metadata = storage.get_metadata(self.node)?;
+        metadata.size = self.size;
+        storage.put_metadata(self.node, metadata);
+
+        let (saved, added): (Vec<_>, Vec<_>) = self
+            .chunks
+            .into_iter()
+            .partition(|(_, chunk)| chunk.is_some());
+        for (index, _) in added {
+            storage.rm_filechunk(self.node, index);
+        }
+        for (index, chunk) in saved {
+            if let Some(chunk) = chunk {
+                storage.write_filechunk(self.node, index, 0, &chunk)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -172,14 +727,16 @@ struct ChunkHandle {
     len: FileSize,
 }
 
-fn offset_to_file_chunk_index(offset: FileSize) -> FileChunkIndex {
-    (offset / FILE_CHUNK_SIZE as FileSize) as FileChunkIndex
+fn offset_to_file_chunk_index(offset: FileSize) -> FileSize {
+    offset / FILE_CHUNK_SIZE as FileSize
 }
 
-fn file_chunk_index_to_offset(index: FileChunkIndex) -> FileSize {
-    index as FileSize * FILE_CHUNK_SIZE as FileSize
+fn file_chunk_index_to_offset(index: FileSize) -> FileSize {
+    index * FILE_CHUNK_SIZE as FileSize
 }
 
+// The chunk indices are computed in FileSize to not wrap around at the MAX_FILE_SIZE boundary,
+// all chunks returned lie below MAX_FILE_SIZE and fit into FileChunkIndex.
 fn get_chunk_infos(start: FileSize, end: FileSize) -> Vec<ChunkHandle> {
     let mut result = vec![];
     let start_index = offset_to_file_chunk_index(start);
@@ -191,7 +748,7 @@ fn get_chunk_infos(start: FileSize, end: FileSize) -> Vec<ChunkHandle> {
         let end_in_chunk = (start_of_chunk + FILE_CHUNK_SIZE as FileSize).min(end) - start_of_chunk;
         if start_in_chunk < end_in_chunk {
             result.push(ChunkHandle {
-                index,
+                index: index as FileChunkIndex,
                 offset: start_in_chunk,
                 len: end_in_chunk - start_in_chunk,
             });
@@ -202,7 +759,7 @@ fn get_chunk_infos(start: FileSize, end: FileSize) -> Vec<ChunkHandle> {
 
 #[cfg(test)]
 mod tests {
-    use crate::test_utils::test_fs;
+    use crate::test_utils::{test_fs, test_fs_transient};
 
     use super::*;
 
@@ -342,4 +899,336 @@ mod tests {
             assert_eq!(buf, expected);
         }
     }
+
+    #[test]
+    fn zero_length_write_does_not_extend_file() {
+        let mut fs = test_fs();
+        let fd = fs
+            .create_file(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let file = fs.get_test_file(fd);
+        let storage = fs.get_test_storage();
+
+        file.write_with_offset(0, &[1; 10], storage).unwrap();
+
+        assert_eq!(file.write_with_offset(100, &[], storage), Ok(0));
+        assert_eq!(storage.get_metadata(file.node).unwrap().size, 10);
+    }
+
+    #[test]
+    fn read_at_and_past_eof() {
+        let mut fs = test_fs();
+        let fd = fs
+            .create_file(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let file = fs.get_test_file(fd);
+        let storage = fs.get_test_storage();
+
+        file.write_with_offset(0, &[1; 10], storage).unwrap();
+
+        let mut buf = [42; 10];
+        assert_eq!(file.read_with_offset(10, &mut buf, storage), Ok(0));
+        assert_eq!(file.read_with_offset(10_000, &mut buf, storage), Ok(0));
+        assert_eq!(buf, [42; 10]);
+
+        assert_eq!(file.read_with_offset(5, &mut buf, storage), Ok(5));
+        assert_eq!(buf[0..5], [1; 5]);
+    }
+
+    #[test]
+    fn read_unwritten_chunks_as_zeros() {
+        let mut fs = test_fs();
+        let fd = fs
+            .create_file(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let file = fs.get_test_file(fd);
+        let storage = fs.get_test_storage();
+
+        let offset = 3 * FILE_CHUNK_SIZE as FileSize;
+        file.write_with_offset(offset, &[1; 10], storage).unwrap();
+
+        let mut buf = vec![42; FILE_CHUNK_SIZE * 3 + 10];
+        assert_eq!(
+            file.read_with_offset(0, &mut buf, storage),
+            Ok(buf.len() as FileSize)
+        );
+        assert!(buf[0..FILE_CHUNK_SIZE * 3].iter().all(|b| *b == 0));
+        assert_eq!(buf[FILE_CHUNK_SIZE * 3..], [1; 10]);
+    }
+
+    #[test]
+    fn get_chunk_infos_at_max_file_size() {
+        let chunks = get_chunk_infos(MAX_FILE_SIZE - 10, MAX_FILE_SIZE);
+        assert_eq!(
+            chunks,
+            vec![ChunkHandle {
+                index: FileChunkIndex::MAX,
+                offset: FILE_CHUNK_SIZE as FileSize - 10,
+                len: 10,
+            }]
+        );
+    }
+
+    #[test]
+    fn write_and_read_across_4gib() {
+        let mut fs = test_fs_transient();
+        let fd = fs
+            .create_file(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let file = fs.get_test_file(fd);
+        let storage = fs.get_test_storage();
+
+        let offset: FileSize = (1 << 32) - 3;
+        let data = [1, 2, 3, 4, 5, 6];
+        assert_eq!(file.write_with_offset(offset, &data, storage), Ok(6));
+        assert_eq!(storage.get_metadata(file.node).unwrap().size, offset + 6);
+
+        let mut buf = [0; 8];
+        assert_eq!(file.read_with_offset(offset - 1, &mut buf, storage), Ok(7));
+        assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 0]);
+    }
+
+    #[test]
+    fn write_past_max_file_size_fails() {
+        let mut fs = test_fs_transient();
+        let fd = fs
+            .create_file(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let file = fs.get_test_file(fd);
+        let storage = fs.get_test_storage();
+
+        assert_eq!(
+            file.write_with_offset(MAX_FILE_SIZE - 1, &[1, 2], storage),
+            Err(Error::FileTooLarge)
+        );
+        assert_eq!(
+            file.write_with_offset(FileSize::MAX, &[1], storage),
+            Err(Error::FileTooLarge)
+        );
+        assert_eq!(
+            file.write_with_offset(MAX_FILE_SIZE - 1, &[1], storage),
+            Ok(1)
+        );
+        assert_eq!(storage.get_metadata(file.node).unwrap().size, MAX_FILE_SIZE);
+    }
+
+    #[test]
+    fn seek_with_large_offsets() {
+        let mut fs = test_fs_transient();
+        let fd = fs
+            .create_file(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let mut file = fs.get_test_file(fd);
+        let storage = fs.get_test_storage();
+
+        file.write_with_offset(0, &[0; 100], storage).unwrap();
+
+        let five_gib: FileSize = 5 << 30;
+        assert_eq!(
+            file.seek(five_gib as i64, Whence::SET, storage),
+            Ok(five_gib)
+        );
+        assert_eq!(
+            file.seek(five_gib as i64, Whence::CUR, storage),
+            Ok(2 * five_gib)
+        );
+        assert_eq!(file.seek(10, Whence::END, storage), Ok(110));
+
+        assert_eq!(
+            file.seek(i64::MIN, Whence::END, storage),
+            Err(Error::InvalidOffset)
+        );
+        assert_eq!(
+            file.seek(i64::MIN, Whence::CUR, storage),
+            Err(Error::InvalidOffset)
+        );
+
+        file.seek(i64::MAX, Whence::SET, storage).unwrap();
+        assert_eq!(
+            file.seek(i64::MAX, Whence::CUR, storage),
+            Ok(i64::MAX as FileSize * 2)
+        );
+        assert_eq!(
+            file.seek(2, Whence::CUR, storage),
+            Err(Error::InvalidOffset)
+        );
+        assert_eq!(file.tell(), i64::MAX as FileSize * 2);
+    }
+
+    #[test]
+    fn sequential_read_prefetches_next_chunks() {
+        use crate::storage::{cached::CachedStorage, transient::TransientStorage};
+
+        let mut storage = CachedStorage::new(Box::new(TransientStorage::new()), 64);
+        let root_node = storage.root_node();
+        let (node, _) = crate::runtime::structure_helpers::create_path(
+            root_node,
+            "test",
+            Some(FileType::RegularFile),
+            0,
+            &mut storage,
+        )
+        .unwrap();
+
+        let mut file = File::new(node, FdStat::default(), &storage).unwrap();
+        file.write_with_offset(0, &vec![1; FILE_CHUNK_SIZE * 8], &mut storage)
+            .unwrap();
+
+        for index in 0..8 {
+            storage.rm_filechunk(node, index);
+            storage
+                .write_filechunk(node, index, 0, &[index as u8])
+                .unwrap();
+        }
+        assert_eq!(storage.stats().cached_chunks, 0);
+
+        file.read_pattern = ReadPattern::Sequential;
+        let mut buf = [0; 10];
+        file.read_with_cursor(&mut buf, &mut storage).unwrap();
+
+        for index in 0..READAHEAD_CHUNK_COUNT {
+            assert!(storage.is_cached(node, index));
+        }
+        assert!(!storage.is_cached(node, READAHEAD_CHUNK_COUNT));
+
+        // random reads do not prefetch anything
+        file.read_pattern = ReadPattern::Random;
+        file.seek(6 * FILE_CHUNK_SIZE as i64, Whence::SET, &storage)
+            .unwrap();
+        file.read_with_cursor(&mut buf, &mut storage).unwrap();
+        assert!(!storage.is_cached(node, 7));
+    }
+
+    #[test]
+    fn read_chunk_cuts_at_end_of_file() {
+        let mut fs = test_fs_transient();
+        let fd = fs
+            .create_file(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let file = fs.get_test_file(fd);
+        let storage = fs.get_test_storage();
+
+        let size = 2 * FILE_CHUNK_SIZE + 10;
+        file.write_with_offset(
+            FILE_CHUNK_SIZE as FileSize,
+            &vec![3; size - FILE_CHUNK_SIZE],
+            storage,
+        )
+        .unwrap();
+
+        // the first chunk was never written
+        let chunk = file.read_chunk(0, storage).unwrap();
+        assert_eq!(chunk.len(), FILE_CHUNK_SIZE);
+        assert!(chunk.iter().all(|b| *b == 0));
+
+        let chunk = file.read_chunk(1, storage).unwrap();
+        assert!(matches!(chunk, Cow::Borrowed(_)));
+        assert_eq!(chunk.len(), FILE_CHUNK_SIZE);
+
+        let chunk = file.read_chunk(2, storage).unwrap();
+        assert_eq!(chunk.as_ref(), &[3; 10]);
+
+        let chunk = file.read_chunk(3, storage).unwrap();
+        assert!(chunk.is_empty());
+    }
+
+    #[test]
+    fn read_overlapping_ranges() {
+        let mut fs = test_fs_transient();
+        let fd = fs
+            .create_file(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let file = fs.get_test_file(fd);
+        let storage = fs.get_test_storage();
+
+        let data: Vec<u8> = (0..3 * FILE_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        file.write_with_offset(0, &data, storage).unwrap();
+
+        let size = data.len() as FileSize;
+        let ranges = [
+            (5000, 10),
+            (10, 5000),
+            (4090, 20),
+            (size - 5, 100),
+            (size + 10, 10),
+            (0, 0),
+        ];
+        let result = file.read_ranges(&ranges, storage).unwrap();
+
+        assert_eq!(result.len(), ranges.len());
+        assert_eq!(result[0], data[5000..5010]);
+        assert_eq!(result[1], data[10..5010]);
+        assert_eq!(result[2], data[4090..4110]);
+        assert_eq!(result[3], data[data.len() - 5..]);
+        assert!(result[4].is_empty());
+        assert!(result[5].is_empty());
+    }
+
+    #[test]
+    fn sparse_file_round_trip() {
+        let mut fs = test_fs_transient();
+        let root = fs.root_fd();
+        let src_fd = fs.create_file(root, "src", FdStat::default(), 0).unwrap();
+        let dst_fd = fs.create_file(root, "dst", FdStat::default(), 0).unwrap();
+        let imported_fd = fs
+            .create_file(root, "imported", FdStat::default(), 0)
+            .unwrap();
+
+        let src = fs.get_test_file(src_fd);
+        let dst = fs.get_test_file(dst_fd);
+        let imported = fs.get_test_file(imported_fd);
+        let storage = fs.get_test_storage();
+
+        let size: FileSize = 1 << 30;
+        file_write(&src, 0, &[1; 100], storage);
+        file_write(&src, size / 2, &[2; 5000], storage);
+        file_write(&src, size - 10, &[3; 10], storage);
+        // a written chunk of zeros is a hole too
+        file_write(&src, 3 * FILE_CHUNK_SIZE as FileSize, &[0; 10], storage);
+
+        assert_eq!(src.data_chunks(storage).unwrap().len(), 4);
+
+        // the destination had some data before
+        file_write(&dst, 5 * FILE_CHUNK_SIZE as FileSize, &[9; 10], storage);
+
+        assert_eq!(dst.copy_from(&src, storage), Ok(size));
+        assert_eq!(storage.filechunk_indices(dst.node, u64::MAX).len(), 4);
+
+        // copying onto the same file keeps its contents
+        assert_eq!(dst.copy_from(&dst, storage), Ok(size));
+        assert_eq!(storage.filechunk_indices(dst.node, u64::MAX).len(), 4);
+
+        let sparse = src.export_sparse(storage).unwrap();
+        assert_eq!(sparse.size, size);
+        assert_eq!(sparse.extents.len(), 3);
+        let data_size: usize = sparse.extents.iter().map(|e| e.data.len()).sum();
+        assert_eq!(data_size, 4 * FILE_CHUNK_SIZE);
+
+        imported.import_sparse(&sparse, storage).unwrap();
+        assert_eq!(storage.filechunk_indices(imported.node, u64::MAX).len(), 4);
+
+        let ranges = [
+            (0, 100),
+            (5 * FILE_CHUNK_SIZE as FileSize, 10),
+            (size / 2, 5000),
+            (size - 10, 10),
+        ];
+        let expected = src.read_ranges(&ranges, storage).unwrap();
+        assert_eq!(expected[1], [0; 10]);
+        assert_eq!(dst.read_ranges(&ranges, storage).unwrap(), expected);
+        assert_eq!(imported.read_ranges(&ranges, storage).unwrap(), expected);
+        assert_eq!(storage.get_metadata(imported.node).unwrap().size, size);
+    }
+
+    fn file_write(file: &File, offset: FileSize, data: &[u8], storage: &mut dyn Storage) {
+        file.write_with_offset(offset, data, storage).unwrap();
+    }
 }
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Error,
+    runtime::types::MerkleHash,
+    storage::{
+        types::{FileChunkIndex, FileSize, FileType, Metadata, Node, FILE_CHUNK_SIZE},
+        Storage,
+    },
+};
+
+// Domain separation prefixes of the hashed nodes.
+const FILE_PREFIX: u8 = 0;
+const DIR_PREFIX: u8 = 1;
+
+// Compute the Merkle hash of a node.
+// A file hash covers its size and the hashes of its chunks, a directory hash covers the names and hashes
+// of its entries sorted by name, so the result does not depend on the order the entries were created in.
+// The hashes of file contents are kept in `cache`, the caller is responsible for removing the changed files from it.
+pub fn node_hash(
+    node: Node,
+    storage: &dyn Storage,
+    cache: &mut BTreeMap<Node, MerkleHash>,
+) -> Result<MerkleHash, Error> {
+    let metadata = storage.get_metadata(node)?;
+
+    match metadata.file_type {
+        FileType::Directory => dir_hash(&metadata, storage, cache),
+        FileType::RegularFile | FileType::SymbolicLink => {
+            if let Some(hash) = cache.get(&node) {
+                return Ok(*hash);
+            }
+            let hash = file_hash(&metadata, storage)?;
+            cache.insert(node, hash);
+            Ok(hash)
+        }
+    }
+}
+
+fn file_hash(metadata: &Metadata, storage: &dyn Storage) -> Result<MerkleHash, Error> {
+    let mut hasher = Sha256::new();
+    hasher.update([FILE_PREFIX]);
+    hasher.update(metadata.size.to_le_bytes());
+
+    let mut buf = vec![0; FILE_CHUNK_SIZE];
+    let chunk_count = metadata.size.div_ceil(FILE_CHUNK_SIZE as FileSize);
+
+    for index in 0..chunk_count {
+        let start = index * FILE_CHUNK_SIZE as FileSize;
+        let len = (metadata.size - start).min(FILE_CHUNK_SIZE as FileSize) as usize;
+
+        match storage.read_filechunk(metadata.node, index as FileChunkIndex, 0, &mut buf[..len]) {
+            Ok(()) => {}
+            // the chunk was never written
+            Err(Error::NotFound) => buf[..len].fill(0),
+            Err(err) => return Err(err),
+        }
+
+        hasher.update(Sha256::digest(&buf[..len]));
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+// Get the names and nodes of the directory entries sorted by name.
+pub fn sorted_entries(
+    metadata: &Metadata,
+    storage: &dyn Storage,
+) -> Result<Vec<(Vec<u8>, Node)>, Error> {
+    let mut entries = Vec::new();
+    let mut next_index = metadata.first_dir_entry;
+
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(metadata.node, index)?;
+        let name = entry.name.bytes[..entry.name.length as usize].to_vec();
+        entries.push((name, entry.node));
+        next_index = entry.next_entry;
+    }
+
+    entries.sort();
+
+    Ok(entries)
+}
+
+fn dir_hash(
+    metadata: &Metadata,
+    storage: &dyn Storage,
+    cache: &mut BTreeMap<Node, MerkleHash>,
+) -> Result<MerkleHash, Error> {
+    let entries = sorted_entries(metadata, storage)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update([DIR_PREFIX]);
+
+    for (name, node) in entries {
+        hasher.update([name.len() as u8]);
+        hasher.update(&name);
+        hasher.update(node_hash(node, storage, cache)?);
+    }
+
+    Ok(hasher.finalize().into())
+}
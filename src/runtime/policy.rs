@@ -0,0 +1,34 @@
+use crate::{
+    error::Error,
+    storage::types::{FileSize, FileType, Node},
+};
+
+// The rules checked by the file system before it changes the tree, e.g. quotas or file name rules.
+// An error returned by a callback rejects the operation and is returned to the caller unchanged.
+// The paths are normalized and relative to the `parent` directory node.
+pub trait Policy {
+    // Called before a file, a directory, an alias or a link is created, also for the new name of a renamed node.
+    fn before_create(
+        &mut self,
+        _parent: Node,
+        _path: &str,
+        _file_type: FileType,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Called before `size` bytes are written into the file `node` at `offset`.
+    fn before_write(
+        &mut self,
+        _node: Node,
+        _offset: FileSize,
+        _size: FileSize,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Called before a file or a directory is removed.
+    fn before_remove(&mut self, _parent: Node, _path: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
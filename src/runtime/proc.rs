@@ -0,0 +1,179 @@
+use std::fmt::Write;
+
+use crate::{
+    error::Error,
+    runtime::{
+        fd::Fd,
+        types::{FdFlags, MountOptions, Whence},
+    },
+    storage::{
+        types::{FileSize, FileType, Node, ReusableSpace},
+        Storage,
+    },
+};
+
+// The synthetic files of the introspection directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcFile {
+    Version,
+    Stats,
+    Fds,
+    Mounts,
+}
+
+impl ProcFile {
+    pub const ALL: [ProcFile; 4] = [
+        ProcFile::Version,
+        ProcFile::Stats,
+        ProcFile::Fds,
+        ProcFile::Mounts,
+    ];
+
+    // The file name in the introspection directory.
+    pub fn name(self) -> &'static str {
+        match self {
+            ProcFile::Version => "version",
+            ProcFile::Stats => "stats",
+            ProcFile::Fds => "fds",
+            ProcFile::Mounts => "mounts",
+        }
+    }
+}
+
+// The contents of an introspection file opened by a descriptor, generated when the file is opened.
+// Nothing is stored, the contents are dropped with the descriptor.
+pub struct ProcContent {
+    pub content: Vec<u8>,
+    pub cursor: FileSize,
+}
+
+impl ProcContent {
+    pub fn new(content: String) -> Self {
+        Self {
+            content: content.into_bytes(),
+            cursor: 0,
+        }
+    }
+
+    pub fn size(&self) -> FileSize {
+        self.content.len() as FileSize
+    }
+
+    // Read the contents at the cursor, the cursor is moved past the read bytes.
+    pub fn read(&mut self, buf: &mut [u8]) -> FileSize {
+        let start = usize::try_from(self.cursor)
+            .unwrap_or(usize::MAX)
+            .min(self.content.len());
+        let len = buf.len().min(self.content.len() - start);
+        buf[..len].copy_from_slice(&self.content[start..start + len]);
+        self.cursor += len as FileSize;
+        len as FileSize
+    }
+
+    pub fn seek(&mut self, delta: i64, whence: Whence) -> Result<FileSize, Error> {
+        self.cursor = match whence {
+            Whence::SET => FileSize::try_from(delta).map_err(|_| Error::InvalidOffset)?,
+            Whence::CUR => self
+                .cursor
+                .checked_add_signed(delta)
+                .ok_or(Error::InvalidOffset)?,
+            Whence::END => self
+                .size()
+                .checked_add_signed(delta)
+                .ok_or(Error::InvalidOffset)?,
+        };
+        Ok(self.cursor)
+    }
+}
+
+// The number of nodes and the bytes stored in a subtree.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    pub files: u64,
+    pub directories: u64,
+    pub aliases: u64,
+    pub bytes: FileSize,
+}
+
+// Add the nodes of the subtree to the stats, the directory itself is not counted.
+pub fn collect_tree_stats(
+    dir_node: Node,
+    storage: &dyn Storage,
+    stats: &mut TreeStats,
+) -> Result<(), Error> {
+    let mut next_index = storage.get_metadata(dir_node)?.first_dir_entry;
+
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(dir_node, index)?;
+        let metadata = storage.get_metadata(entry.node)?;
+
+        match metadata.file_type {
+            FileType::Directory => {
+                stats.directories += 1;
+                collect_tree_stats(entry.node, storage, stats)?;
+            }
+            FileType::RegularFile => {
+                stats.files += 1;
+                stats.bytes += metadata.size;
+            }
+            FileType::SymbolicLink => stats.aliases += 1,
+        }
+
+        next_index = entry.next_entry;
+    }
+
+    Ok(())
+}
+
+pub fn version_text(storage_version: u32) -> String {
+    format!(
+        "{} {}\nstorage_version {}\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        storage_version
+    )
+}
+
+pub fn stats_text(stats: &TreeStats, open_fds: usize, reusable: &ReusableSpace) -> String {
+    format!(
+        "files {}\ndirectories {}\naliases {}\nbytes {}\nopen_fds {}\nreusable_bytes {}\n",
+        stats.files,
+        stats.directories,
+        stats.aliases,
+        stats.bytes,
+        open_fds,
+        reusable.chunk_bytes()
+    )
+}
+
+// One line per descriptor: the descriptor, the node type, the node and the descriptor flags.
+pub fn fds_text(fds: impl Iterator<Item = (Fd, FileType, Node, FdFlags)>) -> String {
+    let mut text = String::new();
+
+    for (fd, file_type, node, flags) in fds {
+        let file_type = match file_type {
+            FileType::Directory => "directory",
+            FileType::RegularFile => "file",
+            FileType::SymbolicLink => "alias",
+        };
+        let _ = writeln!(text, "{fd} {file_type} {node} {:#x}", flags.bits());
+    }
+
+    text
+}
+
+pub fn mounts_text(proc_path: &str, options: &MountOptions, snapshots: &[String]) -> String {
+    let mut text = format!(
+        "/ stable-fs dot_entries={} strict_posix={} max_dir_entries={:?} name_normalizer={:?} sandbox={:?}\n{} proc\n",
+        options.dot_entries,
+        options.strict_posix,
+        options.max_dir_entries,
+        options.name_normalizer,
+        options.sandbox,
+        proc_path
+    );
+    for name in snapshots {
+        text.push_str(&format!("/.snapshots/{name} snapshot ro\n"));
+    }
+    text
+}
@@ -1,7 +1,12 @@
 use crate::{
     error::Error,
+    path::{components, Component},
+    runtime::types::{EntryPosition, RemovalReport},
     storage::{
-        types::{DirEntry, DirEntryIndex, FileName, FileType, Metadata, Node, Times},
+        types::{
+            Collation, DirEntry, DirEntryIndex, FileName, FileSize, FileType, FsLimits, Metadata,
+            NameNormalizer, Node, Times, FILE_CHUNK_SIZE,
+        },
         Storage,
     },
 };
@@ -15,12 +20,74 @@ struct EntryFindResult {
     next_entry: Option<DirEntryIndex>,
 }
 
+// Get the element names of a path to resolve, the empty and absolute paths and the unresolved "." and ".." elements
+// are rejected. A trailing separator is allowed, the path must lead to a directory then.
+fn path_names(path: &str) -> Result<impl Iterator<Item = &str>, Error> {
+    let parts = components(path);
+    if parts.is_absolute()
+        || parts.clone().next().is_none()
+        || parts
+            .clone()
+            .any(|part| !matches!(part, Component::Normal(_)))
+    {
+        return Err(Error::InvalidFileName);
+    }
+    Ok(parts.map(|part| part.as_str()))
+}
+
+// Resolve a path up to its last "." or ".." element, returns the directory reached and the rest of the path
+// holding only plain names. A ".." element may not lead above the starting directory.
+fn resolve_dot_elements<'a>(
+    parent_dir_node: Node,
+    path: &'a str,
+    storage: &dyn Storage,
+) -> Result<(Node, &'a str), Error> {
+    let mut parts = components(path);
+    let last_dot = parts
+        .clone()
+        .enumerate()
+        .filter(|(_, part)| !matches!(part, Component::Normal(_)))
+        .map(|(position, _)| position)
+        .last();
+    let Some(last_dot) = last_dot.filter(|_| !parts.is_absolute()) else {
+        return Ok((parent_dir_node, path));
+    };
+
+    let mut cur_node = parent_dir_node;
+    let mut depth = 0usize;
+    for part in parts.by_ref().take(last_dot + 1) {
+        if storage.get_metadata(cur_node)?.file_type != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        cur_node = match part {
+            Component::CurDir => cur_node,
+            Component::ParentDir => {
+                depth = depth.checked_sub(1).ok_or(Error::PermissionDenied)?;
+                get_parent_node(cur_node, storage)?
+            }
+            Component::Normal(name) => {
+                depth += 1;
+                let index = find_entry_index(cur_node, name.as_bytes(), storage)?;
+                storage.get_direntry(cur_node, index)?.node
+            }
+        };
+    }
+
+    if storage.get_metadata(cur_node)?.file_type != FileType::Directory {
+        return Err(Error::NotADirectory);
+    }
+
+    Ok((cur_node, parts.as_str()))
+}
+
 fn find_node_with_index(
     parent_dir_node: Node,
     path: &str,
     storage: &dyn Storage,
 ) -> Result<EntryFindResult, Error> {
-    let parts = path.split('/');
+    let (parent_dir_node, rest) = resolve_dot_elements(parent_dir_node, path, storage)?;
+    let parts = path_names(rest)?;
 
     let mut parent_dir_node = parent_dir_node;
     let mut cur_node = parent_dir_node;
@@ -29,10 +96,6 @@ fn find_node_with_index(
     let mut next_entry_index = None;
 
     for part in parts {
-        if part.is_empty() || part == "." || part == ".." {
-            return Err(Error::InvalidFileName);
-        }
-
         parent_dir_node = cur_node;
         cur_entry_index = find_entry_index(parent_dir_node, part.as_bytes(), storage)?;
         let entry = storage.get_direntry(cur_node, cur_entry_index)?;
@@ -42,6 +105,12 @@ fn find_node_with_index(
         next_entry_index = entry.next_entry;
     }
 
+    if components(path).has_trailing_slash()
+        && storage.get_metadata(cur_node)?.file_type != FileType::Directory
+    {
+        return Err(Error::NotADirectory);
+    }
+
     Ok(EntryFindResult {
         node: cur_node,
         parent_dir: parent_dir_node,
@@ -52,12 +121,265 @@ fn find_node_with_index(
 }
 
 // Find directory entry node by its name, paths containing separator '/' are allowed and processed.
+// A path ending with "." or ".." leads to the directory it names.
 pub fn find_node(parent_dir_node: Node, path: &str, storage: &dyn Storage) -> Result<Node, Error> {
+    let (dir_node, rest) = resolve_dot_elements(parent_dir_node, path, storage)?;
+    if rest.is_empty() && !path.is_empty() {
+        return Ok(dir_node);
+    }
+
     let find_result = find_node_with_index(parent_dir_node, path, storage)?;
 
     Ok(find_result.node)
 }
 
+// The default number of aliases followed while resolving a path before giving up.
+pub const MAX_ALIAS_HOPS: usize = 16;
+
+// Get the target path of an alias node.
+pub fn read_alias_target(node: Node, storage: &dyn Storage) -> Result<String, Error> {
+    let metadata = storage.get_metadata(node)?;
+
+    if metadata.file_type != FileType::SymbolicLink {
+        return Err(Error::InvalidFileType);
+    }
+    // the target is stored in one chunk
+    if metadata.size > FILE_CHUNK_SIZE as FileSize {
+        return Err(Error::DataCorrupted);
+    }
+
+    let mut buf = vec![0; metadata.size as usize];
+    storage.read_filechunk(node, 0, 0, &mut buf)?;
+
+    String::from_utf8(buf).map_err(|_| Error::InvalidFileName)
+}
+
+// Follow the aliases starting at `path` until a file or a directory is found, returns the final path.
+// The alias targets are resolved from `parent_dir_node`, a leading '/' is ignored.
+pub fn resolve_alias(
+    parent_dir_node: Node,
+    path: &str,
+    max_hops: usize,
+    storage: &dyn Storage,
+) -> Result<String, Error> {
+    let mut path = path.trim_start_matches('/').to_string();
+
+    for _ in 0..=max_hops {
+        if path.is_empty() {
+            return Ok(path);
+        }
+
+        let node = find_node(parent_dir_node, &path, storage)?;
+
+        match storage.get_metadata(node)?.file_type {
+            FileType::SymbolicLink => {
+                path = read_alias_target(node, storage)?
+                    .trim_start_matches('/')
+                    .to_string();
+            }
+            _ => return Ok(path),
+        }
+    }
+
+    Err(Error::TooManySymbolicLinks)
+}
+
+// Fail with `TooManySymbolicLinks` if any of the existing elements of the path is a symbolic link.
+pub fn check_no_symlinks(
+    parent_dir_node: Node,
+    path: &str,
+    storage: &dyn Storage,
+) -> Result<(), Error> {
+    let (mut cur_node, path) = match resolve_dot_elements(parent_dir_node, path, storage) {
+        Ok(resolved) => resolved,
+        Err(Error::NotFound) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    for part in components(path) {
+        let index = match find_entry_index(cur_node, part.as_str().as_bytes(), storage) {
+            Ok(index) => index,
+            // the rest of the path does not exist
+            Err(Error::NotFound) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        cur_node = storage.get_direntry(cur_node, index)?.node;
+
+        match storage.get_metadata(cur_node)?.file_type {
+            FileType::SymbolicLink => return Err(Error::TooManySymbolicLinks),
+            FileType::Directory => {}
+            FileType::RegularFile => return Ok(()),
+        }
+    }
+
+    Ok(())
+}
+
+// Follow the aliases from `path` like `resolve_alias`, return the first alias inside the `boundary` directory
+// with a target outside of it.
+pub fn find_escaping_alias(
+    parent_dir_node: Node,
+    path: &str,
+    boundary: Node,
+    max_hops: usize,
+    storage: &dyn Storage,
+) -> Result<Option<String>, Error> {
+    let mut path = path.trim_start_matches('/').to_string();
+
+    for _ in 0..=max_hops {
+        if path.is_empty() {
+            return Ok(None);
+        }
+
+        let node = find_node(parent_dir_node, &path, storage)?;
+        if storage.get_metadata(node)?.file_type != FileType::SymbolicLink {
+            return Ok(None);
+        }
+
+        let target = read_alias_target(node, storage)?
+            .trim_start_matches('/')
+            .to_string();
+
+        let alias_dir = find_existing_dir(parent_dir_node, &path, storage)?;
+
+        // a directory target is checked itself, other targets by their directory
+        let target_dir = match find_node(parent_dir_node, &target, storage) {
+            Ok(target_node)
+                if storage.get_metadata(target_node)?.file_type == FileType::Directory =>
+            {
+                target_node
+            }
+            _ if target.is_empty() => parent_dir_node,
+            _ => find_existing_dir(parent_dir_node, &target, storage)?,
+        };
+
+        if is_ancestor_or_self(boundary, alias_dir, storage)?
+            && !is_ancestor_or_self(boundary, target_dir, storage)?
+        {
+            return Ok(Some(path));
+        }
+
+        path = target;
+    }
+
+    Err(Error::TooManySymbolicLinks)
+}
+
+// Check if resolving `path` from `parent_dir_node` reaches a directory outside of the `boundary` directory, the
+// deepest existing directory on the path is compared by its ancestry. Nothing is checked for the paths resolved
+// from the boundary or its parents, the errors of the path itself are left to the call resolving it.
+pub fn leaves_boundary(
+    parent_dir_node: Node,
+    path: &str,
+    boundary: Node,
+    storage: &dyn Storage,
+) -> Result<bool, Error> {
+    if is_ancestor_or_self(parent_dir_node, boundary, storage)? {
+        return Ok(false);
+    }
+
+    let reached = find_existing_dir(parent_dir_node, path, storage).unwrap_or(parent_dir_node);
+
+    Ok(!is_ancestor_or_self(boundary, reached, storage)?)
+}
+
+// Get the parent directory of a directory node, the root directory is its own parent.
+pub fn get_parent_node(dir_node: Node, storage: &dyn Storage) -> Result<Node, Error> {
+    let root_node = storage.root_node();
+
+    if dir_node == root_node {
+        return Ok(root_node);
+    }
+
+    match storage.get_metadata(dir_node)?.parent {
+        Some(parent) => Ok(parent),
+        // the file system was created before the parent links were introduced
+        None => find_parent_in_subtree(root_node, dir_node, storage)?.ok_or(Error::NotFound),
+    }
+}
+
+// Search the directory subtree for a directory containing the entry with the given node.
+fn find_parent_in_subtree(
+    dir_node: Node,
+    node: Node,
+    storage: &dyn Storage,
+) -> Result<Option<Node>, Error> {
+    let mut next_index = storage.get_metadata(dir_node)?.first_dir_entry;
+
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(dir_node, index)?;
+
+        if entry.node == node {
+            return Ok(Some(dir_node));
+        }
+
+        if storage.get_metadata(entry.node)?.file_type == FileType::Directory {
+            if let Some(parent) = find_parent_in_subtree(entry.node, node, storage)? {
+                return Ok(Some(parent));
+            }
+        }
+
+        next_index = entry.next_entry;
+    }
+
+    Ok(None)
+}
+
+// Check if the directory `ancestor` is the directory `dir_node` itself or one of its parents.
+pub fn is_ancestor_or_self(
+    ancestor: Node,
+    dir_node: Node,
+    storage: &dyn Storage,
+) -> Result<bool, Error> {
+    let root_node = storage.root_node();
+    let mut cur_node = dir_node;
+
+    loop {
+        if cur_node == ancestor {
+            return Ok(true);
+        }
+
+        if cur_node == root_node {
+            return Ok(false);
+        }
+
+        cur_node = get_parent_node(cur_node, storage)?;
+    }
+}
+
+// Find the deepest existing directory on the path leading to the last path element.
+pub fn find_existing_dir(
+    parent_dir_node: Node,
+    path: &str,
+    storage: &dyn Storage,
+) -> Result<Node, Error> {
+    let (mut cur_node, path) = resolve_dot_elements(parent_dir_node, path, storage)?;
+    let mut parts = components(path).peekable();
+
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            break;
+        }
+
+        match find_entry_index(cur_node, part.as_str().as_bytes(), storage) {
+            Ok(index) => {
+                let entry = storage.get_direntry(cur_node, index)?;
+
+                if storage.get_metadata(entry.node)?.file_type != FileType::Directory {
+                    break;
+                }
+
+                cur_node = entry.node;
+            }
+            Err(Error::NotFound) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(cur_node)
+}
+
 // Create a hard link to an existing node
 pub fn create_hard_link(
     parent_dir_node: Node,
@@ -80,15 +402,31 @@ pub fn create_hard_link(
     let mut metadata = storage.get_metadata(node)?;
     let ctime = metadata.times.created;
 
-    //
-    let (dir_node, leaf_name) = create_path(parent_dir_node, new_path, None, ctime, storage)?;
-
     // only allow creating a hardlink on a folder if it is a part of renaming and another link will be removed
     if !is_renaming && metadata.file_type == FileType::Directory {
         return Err(Error::InvalidFileType);
     }
 
+    if components(new_path).has_trailing_slash() && metadata.file_type != FileType::Directory {
+        return Err(Error::NotADirectory);
+    }
+
+    // a directory cannot be moved under itself, this would detach it from the tree
+    if metadata.file_type == FileType::Directory {
+        let dst_dir_node = find_existing_dir(parent_dir_node, new_path, storage)?;
+
+        if is_ancestor_or_self(node, dst_dir_node, storage)? {
+            return Err(Error::CannotMoveDirectoryIntoItself);
+        }
+    }
+
+    //
+    let (dir_node, leaf_name) = create_path(parent_dir_node, new_path, None, ctime, storage)?;
+
     metadata.link_count += 1;
+    if metadata.file_type == FileType::Directory {
+        metadata.parent = Some(dir_node);
+    }
     storage.put_metadata(node, metadata);
 
     add_dir_entry(dir_node, node, leaf_name.as_bytes(), storage)?;
@@ -103,17 +441,35 @@ pub fn create_dir_entry(
     storage: &mut dyn Storage,
     ctime: u64,
 ) -> Result<Node, Error> {
-    if entry_type != FileType::Directory && entry_type != FileType::RegularFile {
-        return Err(Error::InvalidFileType);
-    }
+    let node = create_node(Some(parent_dir_node), entry_type, 1, storage, ctime);
+
+    add_dir_entry(parent_dir_node, node, entry_name, storage)?;
+
+    Ok(node)
+}
+
+// Create the metadata of a new empty node, the caller links it into a directory.
+pub fn create_node(
+    parent_dir_node: Option<Node>,
+    entry_type: FileType,
+    link_count: u64,
+    storage: &mut dyn Storage,
+    ctime: u64,
+) -> Node {
+    // the files and the directories created in a transient directory are transient too
+    let storage_class = parent_dir_node
+        .and_then(|parent| storage.get_metadata(parent).ok())
+        .map(|parent| parent.storage_class)
+        .unwrap_or_default();
 
     let node = storage.new_node();
+    let generation = storage.node_generation();
     storage.put_metadata(
         node,
         Metadata {
             node,
             file_type: entry_type,
-            link_count: 1,
+            link_count,
             size: 0,
             times: Times {
                 accessed: ctime,
@@ -122,18 +478,31 @@ pub fn create_dir_entry(
             },
             first_dir_entry: None,
             last_dir_entry: None,
+            next_dir_entry: (entry_type == FileType::Directory).then_some(1),
+            parent: if entry_type == FileType::Directory {
+                parent_dir_node
+            } else {
+                None
+            },
+            generation,
+            dir_generation: 0,
+            http: None,
+            expires_at: None,
+            name_normalizer: NameNormalizer::Exact,
+            storage_class,
+            collation: Collation::Binary,
+            region: false,
+            limits: FsLimits::default(),
         },
     );
 
-    add_dir_entry(parent_dir_node, node, entry_name, storage)?;
-
-    Ok(node)
+    node
 }
 
 // create whole path if it doesn't exist
 // parent_node        parent folder node
 // path               full path
-// leaf_type          file type of the last path elemen (RegularFile or Directory)
+// leaf_type          file type of the last path elemen (RegularFile, Directory or SymbolicLink)
 // storage            file system storage
 // ctime              creation time to be used
 // returns the node of the last created folder part, return error if creation failed
@@ -144,7 +513,20 @@ pub fn create_path<'a>(
     ctime: u64,
     storage: &mut dyn Storage,
 ) -> Result<(Node, &'a str), Error> {
-    let parts = path.split('/');
+    let (parent_node, path) = resolve_dot_elements(parent_node, path, storage)?;
+    let parts = path_names(path)?;
+
+    // a directory removed while opened takes no new entries
+    if storage.get_metadata(parent_node)?.link_count == 0 {
+        return Err(Error::NoSuchFileOrDirectory);
+    }
+
+    // only a folder can be created with a trailing separator
+    if components(path).has_trailing_slash()
+        && leaf_type.is_some_and(|leaf_type| leaf_type != FileType::Directory)
+    {
+        return Err(Error::NotADirectory);
+    }
 
     let mut parent_node = parent_node;
     let mut cur_node = parent_node;
@@ -173,10 +555,6 @@ pub fn create_path<'a>(
         last_name = part;
         parent_node = cur_node;
 
-        if part.is_empty() || part == "." || part == ".." {
-            return Err(Error::InvalidFileName);
-        }
-
         let path_element = part.as_bytes();
 
         if !needs_folder_creation {
@@ -226,15 +604,14 @@ pub fn find_entry_index(
     let mut next_index = storage.get_metadata(dir_entry_node)?.first_dir_entry;
 
     while let Some(index) = next_index {
-        if let Ok(dir_entry) = storage.get_direntry(dir_entry_node, index) {
-            if dir_entry.name.length as usize == path_element.len()
-                && &dir_entry.name.bytes[0..path_element.len()] == path_element
-            {
-                return Ok(index);
-            }
-
-            next_index = dir_entry.next_entry;
+        let dir_entry = storage.get_direntry(dir_entry_node, index)?;
+        if dir_entry.name.length as usize == path_element.len()
+            && &dir_entry.name.bytes[0..path_element.len()] == path_element
+        {
+            return Ok(index);
         }
+
+        next_index = dir_entry.next_entry;
     }
 
     Err(Error::NotFound)
@@ -251,8 +628,19 @@ pub fn add_dir_entry(
 
     let name = FileName::new(entry_name)?;
 
-    // start numbering with 1
-    let new_entry_index: DirEntryIndex = metadata.last_dir_entry.unwrap_or(0) + 1;
+    // start numbering with 1, the directories without the next index look for a free index once: the index after
+    // the last entry can be taken by an entry moved from the end
+    let new_entry_index = match metadata.next_dir_entry {
+        Some(index) => index,
+        None => {
+            let mut index: DirEntryIndex = metadata.last_dir_entry.unwrap_or(0) + 1;
+            while storage.get_direntry(parent_dir_node, index).is_ok() {
+                index += 1;
+            }
+            index
+        }
+    };
+    metadata.next_dir_entry = Some(new_entry_index.checked_add(1).ok_or(Error::DirectoryFull)?);
 
     storage.put_direntry(
         parent_dir_node,
@@ -280,12 +668,255 @@ pub fn add_dir_entry(
         metadata.first_dir_entry = Some(new_entry_index);
     }
     metadata.size += 1;
+    metadata.dir_generation += 1;
 
     storage.put_metadata(parent_dir_node, metadata);
 
     Ok(())
 }
 
+// Collect the directory nodes, the names and the metadata of all the non-directory entries in the subtree.
+pub fn collect_files(
+    dir_node: Node,
+    storage: &dyn Storage,
+    files: &mut Vec<(Node, String, Metadata)>,
+) -> Result<(), Error> {
+    let mut next_index = storage.get_metadata(dir_node)?.first_dir_entry;
+
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(dir_node, index)?;
+        let metadata = storage.get_metadata(entry.node)?;
+
+        if metadata.file_type == FileType::Directory {
+            collect_files(entry.node, storage, files)?;
+        } else {
+            let name = &entry.name.bytes[..entry.name.length as usize];
+            files.push((
+                dir_node,
+                String::from_utf8_lossy(name).into_owned(),
+                metadata,
+            ));
+        }
+
+        next_index = entry.next_entry;
+    }
+
+    Ok(())
+}
+
+// Collect the paths of all the files in the subtree that expire at `now` or earlier.
+// The paths start with `dir_path`, which is empty for the subtree root.
+pub fn collect_expired(
+    dir_node: Node,
+    dir_path: &str,
+    now: u64,
+    storage: &dyn Storage,
+    expired: &mut Vec<String>,
+) -> Result<(), Error> {
+    let mut next_index = storage.get_metadata(dir_node)?.first_dir_entry;
+
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(dir_node, index)?;
+        let metadata = storage.get_metadata(entry.node)?;
+        let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+        let path = join_path(dir_path, &name);
+
+        if metadata.file_type == FileType::Directory {
+            collect_expired(entry.node, &path, now, storage, expired)?;
+        } else if metadata
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+        {
+            expired.push(path);
+        }
+
+        next_index = entry.next_entry;
+    }
+
+    Ok(())
+}
+
+fn join_path(dir_path: &str, name: &str) -> String {
+    if dir_path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir_path}/{name}")
+    }
+}
+
+// Add a node that is going to be removed, with all the contents of a directory, to the removal report.
+pub fn collect_removal(
+    node: Node,
+    path: &str,
+    storage: &dyn Storage,
+    report: &mut RemovalReport,
+) -> Result<(), Error> {
+    let metadata = storage.get_metadata(node)?;
+    report.paths.push(path.to_string());
+
+    if metadata.file_type != FileType::Directory {
+        report.files += 1;
+        // the data of the nodes with other links is kept
+        if metadata.link_count <= 1 {
+            report.bytes += metadata.size;
+        }
+        return Ok(());
+    }
+
+    report.directories += 1;
+
+    let mut next_index = metadata.first_dir_entry;
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(node, index)?;
+        let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+        collect_removal(entry.node, &join_path(path, &name), storage, report)?;
+        next_index = entry.next_entry;
+    }
+
+    Ok(())
+}
+
+// Exchange the nodes of two directory entries in one step, the entry names and positions stay.
+// Returns the nodes now found at `path_a` and `path_b`; directories cannot be exchanged.
+pub fn swap_dir_entries(
+    dir_a: Node,
+    path_a: &str,
+    dir_b: Node,
+    path_b: &str,
+    is_renaming: bool,
+    storage: &mut dyn Storage,
+) -> Result<(Node, Node), Error> {
+    let a = find_node_with_index(dir_a, path_a, storage)?;
+    let b = find_node_with_index(dir_b, path_b, storage)?;
+
+    // only allow exchanging folders as a part of renaming, a folder cannot go under itself
+    for (node, other_parent) in [(a.node, b.parent_dir), (b.node, a.parent_dir)] {
+        if storage.get_metadata(node)?.file_type == FileType::Directory {
+            if !is_renaming {
+                return Err(Error::IsDirectory);
+            }
+            if is_ancestor_or_self(node, other_parent, storage)? {
+                return Err(Error::CannotMoveDirectoryIntoItself);
+            }
+        }
+    }
+
+    // the same node or two hard links of it
+    if a.node == b.node {
+        return Ok((a.node, b.node));
+    }
+
+    for (node, parent) in [(a.node, b.parent_dir), (b.node, a.parent_dir)] {
+        let mut metadata = storage.get_metadata(node)?;
+        if metadata.file_type == FileType::Directory {
+            metadata.parent = Some(parent);
+            storage.put_metadata(node, metadata);
+        }
+    }
+
+    let mut entry_a = storage.get_direntry(a.parent_dir, a.entry_index)?;
+    let mut entry_b = storage.get_direntry(b.parent_dir, b.entry_index)?;
+    entry_a.node = b.node;
+    entry_b.node = a.node;
+    storage.put_direntry(a.parent_dir, a.entry_index, entry_a);
+    storage.put_direntry(b.parent_dir, b.entry_index, entry_b);
+
+    let mut parents = vec![a.parent_dir, b.parent_dir];
+    parents.dedup();
+    for parent in parents {
+        let mut metadata = storage.get_metadata(parent)?;
+        metadata.dir_generation += 1;
+        storage.put_metadata(parent, metadata);
+    }
+
+    Ok((b.node, a.node))
+}
+
+// Move the directory entry `entry_name` to another position in the directory order.
+pub fn move_dir_entry(
+    parent_dir_node: Node,
+    entry_name: &[u8],
+    position: EntryPosition,
+    storage: &mut dyn Storage,
+) -> Result<(), Error> {
+    let index = find_entry_index(parent_dir_node, entry_name, storage)?;
+
+    let anchor = match position {
+        EntryPosition::Before(name) | EntryPosition::After(name) => {
+            let anchor = find_entry_index(parent_dir_node, name.as_bytes(), storage)?;
+            if anchor == index {
+                return Ok(());
+            }
+            Some(anchor)
+        }
+        EntryPosition::First | EntryPosition::Last => None,
+    };
+
+    // detach the entry
+    let mut entry = storage.get_direntry(parent_dir_node, index)?;
+    let mut metadata = storage.get_metadata(parent_dir_node)?;
+
+    match entry.prev_entry {
+        Some(prev) => {
+            let mut prev_entry = storage.get_direntry(parent_dir_node, prev)?;
+            prev_entry.next_entry = entry.next_entry;
+            storage.put_direntry(parent_dir_node, prev, prev_entry);
+        }
+        None => metadata.first_dir_entry = entry.next_entry,
+    }
+
+    match entry.next_entry {
+        Some(next) => {
+            let mut next_entry = storage.get_direntry(parent_dir_node, next)?;
+            next_entry.prev_entry = entry.prev_entry;
+            storage.put_direntry(parent_dir_node, next, next_entry);
+        }
+        None => metadata.last_dir_entry = entry.prev_entry,
+    }
+
+    // find the new neighbours
+    let (prev, next) = match (position, anchor) {
+        (EntryPosition::Before(_), Some(anchor)) => (
+            storage.get_direntry(parent_dir_node, anchor)?.prev_entry,
+            Some(anchor),
+        ),
+        (EntryPosition::After(_), Some(anchor)) => (
+            Some(anchor),
+            storage.get_direntry(parent_dir_node, anchor)?.next_entry,
+        ),
+        (EntryPosition::First, _) => (None, metadata.first_dir_entry),
+        _ => (metadata.last_dir_entry, None),
+    };
+
+    // attach the entry
+    match prev {
+        Some(prev) => {
+            let mut prev_entry = storage.get_direntry(parent_dir_node, prev)?;
+            prev_entry.next_entry = Some(index);
+            storage.put_direntry(parent_dir_node, prev, prev_entry);
+        }
+        None => metadata.first_dir_entry = Some(index),
+    }
+
+    match next {
+        Some(next) => {
+            let mut next_entry = storage.get_direntry(parent_dir_node, next)?;
+            next_entry.prev_entry = Some(index);
+            storage.put_direntry(parent_dir_node, next, next_entry);
+        }
+        None => metadata.last_dir_entry = Some(index),
+    }
+
+    entry.prev_entry = prev;
+    entry.next_entry = next;
+    storage.put_direntry(parent_dir_node, index, entry);
+
+    metadata.dir_generation += 1;
+    storage.put_metadata(parent_dir_node, metadata);
+
+    Ok(())
+}
+
 /// Remove the directory entry from the current directory by entry name.
 ///
 /// path            The name of the entry to delete
@@ -293,7 +924,6 @@ pub fn add_dir_entry(
 /// node_refcount   A map of nodes to check if the file being deleted is opened by multiple file descriptors. Deleting an entry referenced by multiple file descriptors is not allowed and will result in an error.
 /// storage         The reference to the actual storage implementation
 /// is_renaming     true if renaming is in progress, this allows to "delete" a non-empty folder
-
 pub fn rm_dir_entry(
     parent_dir_node: Node,
     path: &str,
@@ -362,6 +992,7 @@ pub fn rm_dir_entry(
 
     // dir entry size is reduced by one
     parent_dir_metadata.size -= 1;
+    parent_dir_metadata.dir_generation += 1;
 
     // update parent metadata
     storage.put_metadata(parent_dir_node, parent_dir_metadata);
@@ -375,6 +1006,89 @@ pub fn rm_dir_entry(
     Ok((removed_dir_entry_node, removed_metadata))
 }
 
+// Remove the chunks and the metadata of a node that is no longer referenced by any directory entry.
+pub fn rm_node_data(node: Node, metadata: &Metadata, storage: &mut dyn Storage) {
+    if metadata.file_type != FileType::Directory {
+        let chunk_cnt = metadata.size.div_ceil(FILE_CHUNK_SIZE as u64);
+        for index in storage.filechunk_indices(node, chunk_cnt) {
+            storage.rm_filechunk(node, index);
+        }
+    }
+    storage.rm_metadata(node);
+    storage.remove_orphan(node);
+}
+
+// Make sure no node in the directory subtree is referenced by an opened file descriptor,
+// the nodes that have other links are allowed to stay opened.
+pub fn check_subtree_not_opened(
+    dir_node: Node,
+    node_refcount: &BTreeMap<Node, usize>,
+    storage: &dyn Storage,
+) -> Result<(), Error> {
+    let mut next_index = storage.get_metadata(dir_node)?.first_dir_entry;
+
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(dir_node, index)?;
+        let metadata = storage.get_metadata(entry.node)?;
+
+        if let Some(refcount) = node_refcount.get(&entry.node) {
+            if *refcount > 0 && metadata.link_count == 1 {
+                return Err(Error::CannotRemoveOpenedNode);
+            }
+        }
+
+        if metadata.file_type == FileType::Directory {
+            check_subtree_not_opened(entry.node, node_refcount, storage)?;
+        }
+
+        next_index = entry.next_entry;
+    }
+
+    Ok(())
+}
+
+// Remove all the entries of a directory, the subdirectories are removed recursively.
+// The data of the opened nodes is kept until they are closed.
+pub fn rm_dir_contents(
+    dir_node: Node,
+    node_refcount: &BTreeMap<Node, usize>,
+    storage: &mut dyn Storage,
+) -> Result<(), Error> {
+    let mut dir_metadata = storage.get_metadata(dir_node)?;
+    let mut next_index = dir_metadata.first_dir_entry;
+
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(dir_node, index)?;
+        next_index = entry.next_entry;
+
+        if storage.get_metadata(entry.node)?.file_type == FileType::Directory {
+            rm_dir_contents(entry.node, node_refcount, storage)?;
+        }
+
+        storage.rm_direntry(dir_node, index);
+
+        let mut metadata = storage.get_metadata(entry.node)?;
+        metadata.link_count -= 1;
+
+        if metadata.link_count == 0 && !node_refcount.contains_key(&entry.node) {
+            rm_node_data(entry.node, &metadata, storage);
+        } else {
+            if metadata.link_count == 0 {
+                storage.add_orphan(entry.node);
+            }
+            storage.put_metadata(entry.node, metadata);
+        }
+    }
+
+    dir_metadata.first_dir_entry = None;
+    dir_metadata.last_dir_entry = None;
+    dir_metadata.size = 0;
+    dir_metadata.dir_generation += 1;
+    storage.put_metadata(dir_node, dir_metadata);
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -382,10 +1096,36 @@ mod tests {
 
     use crate::{
         error::Error,
-        runtime::structure_helpers::{create_path, find_node},
+        runtime::structure_helpers::{add_dir_entry, create_path, find_entry_index, find_node},
         storage::{stable::StableStorage, types::FileType, Storage},
     };
 
+    #[test]
+    fn entry_indices_are_counted_in_the_directory() {
+        let mut storage_box = Box::new(StableStorage::new(DefaultMemoryImpl::default()));
+        let storage = storage_box.as_mut();
+        let root_node = storage.root_node();
+
+        // the root directory has no next index yet, it is found once
+        assert_eq!(
+            storage.get_metadata(root_node).unwrap().next_dir_entry,
+            None
+        );
+        add_dir_entry(root_node, 10, b"a", storage).unwrap();
+        add_dir_entry(root_node, 11, b"b", storage).unwrap();
+        assert_eq!(
+            storage.get_metadata(root_node).unwrap().next_dir_entry,
+            Some(3)
+        );
+
+        let (dir, _) =
+            create_path(root_node, "dir", Some(FileType::Directory), 0, storage).unwrap();
+        assert_eq!(storage.get_metadata(dir).unwrap().next_dir_entry, Some(1));
+        add_dir_entry(dir, 12, b"c", storage).unwrap();
+        assert_eq!(find_entry_index(dir, b"c", storage), Ok(1));
+        assert_eq!(storage.get_metadata(dir).unwrap().next_dir_entry, Some(2));
+    }
+
     #[test]
     fn create_path_with_subfolders() {
         let mut storage_box = Box::new(StableStorage::new(DefaultMemoryImpl::default()));
@@ -572,20 +1312,27 @@ mod tests {
     }
 
     #[test]
-    fn trying_to_create_sym_link_fails() {
+    fn create_sym_link() {
         let mut storage_box = Box::new(StableStorage::new(DefaultMemoryImpl::default()));
         let storage = storage_box.as_mut();
 
         let root_node = storage.root_node();
 
-        let res = create_path(
+        let (node, _) = create_path(
             root_node,
             "test1/sym_link.txt",
             Some(FileType::SymbolicLink),
             43u64,
             storage,
+        )
+        .unwrap();
+
+        let meta = storage.get_metadata(node).unwrap();
+        assert_eq!(meta.file_type, FileType::SymbolicLink);
+        assert_eq!(
+            find_node(root_node, "test1/sym_link.txt", storage),
+            Ok(node)
         );
-        assert_eq!(res, Err(Error::InvalidFileType));
     }
 
     #[test]
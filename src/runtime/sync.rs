@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    error::Error,
+    runtime::{
+        merkle::{node_hash, sorted_entries},
+        types::{
+            DeviationKind, ManifestDeviation, ManifestEntry, MerkleHash, MetadataManifest,
+            MetadataManifestEntry, SyncDiff, SyncManifest,
+        },
+    },
+    storage::{
+        types::{FileType, Node},
+        Storage,
+    },
+};
+
+fn join_path(prefix: &str, name: &[u8]) -> String {
+    let name = String::from_utf8_lossy(name);
+    if prefix.is_empty() {
+        name.into_owned()
+    } else {
+        format!("{prefix}/{name}")
+    }
+}
+
+// Collect the manifest entries of all the nodes inside a given directory.
+pub fn collect_manifest(
+    dir_node: Node,
+    prefix: &str,
+    storage: &dyn Storage,
+    cache: &mut BTreeMap<Node, MerkleHash>,
+    manifest: &mut SyncManifest,
+) -> Result<(), Error> {
+    let metadata = storage.get_metadata(dir_node)?;
+
+    let entries = sorted_entries(&metadata, storage)?;
+    let file_types = entry_types(&entries, storage)?;
+
+    for ((name, node), file_type) in entries.into_iter().zip(file_types) {
+        let path = join_path(prefix, &name);
+        let hash = node_hash(node, storage, cache)?;
+
+        manifest
+            .entries
+            .insert(path.clone(), ManifestEntry { file_type, hash });
+
+        if file_type == FileType::Directory {
+            collect_manifest(node, &path, storage, cache, manifest)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Collect the metadata manifest entries of all the nodes inside a given directory.
+pub fn collect_metadata_manifest(
+    dir_node: Node,
+    prefix: &str,
+    storage: &dyn Storage,
+    cache: &mut BTreeMap<Node, MerkleHash>,
+    manifest: &mut MetadataManifest,
+) -> Result<(), Error> {
+    let metadata = storage.get_metadata(dir_node)?;
+
+    let entries = sorted_entries(&metadata, storage)?;
+    let nodes: Vec<Node> = entries.iter().map(|(_, node)| *node).collect();
+    let entry_metadata = storage.get_metadata_many(&nodes);
+
+    for ((name, node), metadata) in entries.into_iter().zip(entry_metadata) {
+        let metadata = metadata?;
+        let path = join_path(prefix, &name);
+
+        manifest.entries.insert(
+            path.clone(),
+            MetadataManifestEntry {
+                file_type: metadata.file_type,
+                size: metadata.size,
+                hash: node_hash(node, storage, cache)?,
+                times: metadata.times,
+            },
+        );
+
+        if metadata.file_type == FileType::Directory {
+            collect_metadata_manifest(node, &path, storage, cache, manifest)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Compare the metadata manifest of the tree with an expected one. The directory hashes and sizes follow
+// from their entries, so they are not compared.
+pub fn manifest_deviations(
+    expected: &MetadataManifest,
+    actual: &MetadataManifest,
+) -> Vec<ManifestDeviation> {
+    let mut deviations = Vec::new();
+    let mut report = |path: &str,
+                      kind,
+                      expected: Option<&MetadataManifestEntry>,
+                      actual: Option<&MetadataManifestEntry>| {
+        deviations.push(ManifestDeviation {
+            path: path.to_string(),
+            kind,
+            expected: expected.copied(),
+            actual: actual.copied(),
+        })
+    };
+
+    for (path, entry) in expected.entries.iter() {
+        let Some(current) = actual.entries.get(path) else {
+            report(path, DeviationKind::Missing, Some(entry), None);
+            continue;
+        };
+
+        if entry.file_type != current.file_type {
+            report(path, DeviationKind::FileType, Some(entry), Some(current));
+            continue;
+        }
+        if entry.file_type != FileType::Directory {
+            if entry.size != current.size {
+                report(path, DeviationKind::Size, Some(entry), Some(current));
+            }
+            if entry.hash != current.hash {
+                report(path, DeviationKind::Hash, Some(entry), Some(current));
+            }
+        }
+        if entry.times != current.times {
+            report(path, DeviationKind::Times, Some(entry), Some(current));
+        }
+    }
+
+    for (path, current) in actual.entries.iter() {
+        if !expected.entries.contains_key(path) {
+            report(path, DeviationKind::Unexpected, None, Some(current));
+        }
+    }
+
+    deviations
+}
+
+// Find the changes needed to turn the `remote` tree into the local directory `dir_node`.
+// Subtrees with equal hashes on both sides are skipped without visiting their contents.
+pub fn diff_dir(
+    dir_node: Node,
+    prefix: &str,
+    storage: &dyn Storage,
+    cache: &mut BTreeMap<Node, MerkleHash>,
+    remote: &SyncManifest,
+    diff: &mut SyncDiff,
+) -> Result<(), Error> {
+    let metadata = storage.get_metadata(dir_node)?;
+    let entries = sorted_entries(&metadata, storage)?;
+
+    // remote entries of this directory missing locally
+    let local_names: Vec<String> = entries
+        .iter()
+        .map(|(name, _)| join_path(prefix, name))
+        .collect();
+
+    for path in remote.children(prefix) {
+        if local_names
+            .binary_search_by(|p| p.as_str().cmp(path))
+            .is_err()
+        {
+            diff.remove.push(path.to_string());
+        }
+    }
+
+    let file_types = entry_types(&entries, storage)?;
+    for (((_, node), path), file_type) in entries.into_iter().zip(local_names).zip(file_types) {
+        match remote.entries.get(&path) {
+            Some(entry) if entry.file_type == file_type => {
+                if entry.hash == node_hash(node, storage, cache)? {
+                    continue;
+                }
+
+                if file_type == FileType::Directory {
+                    diff_dir(node, &path, storage, cache, remote, diff)?;
+                } else {
+                    diff.transfer.push(path);
+                }
+            }
+            Some(_) => {
+                diff.remove.push(path.clone());
+                add_new(node, file_type, path, storage, diff)?;
+            }
+            None => add_new(node, file_type, path, storage, diff)?,
+        }
+    }
+
+    Ok(())
+}
+
+// Get the types of the entry nodes in one batch.
+fn entry_types(entries: &[(Vec<u8>, Node)], storage: &dyn Storage) -> Result<Vec<FileType>, Error> {
+    let nodes: Vec<Node> = entries.iter().map(|(_, node)| *node).collect();
+    storage
+        .get_metadata_many(&nodes)
+        .into_iter()
+        .map(|metadata| Ok(metadata?.file_type))
+        .collect()
+}
+
+// Add a local node missing in the remote tree together with all its contents.
+fn add_new(
+    node: Node,
+    file_type: FileType,
+    path: String,
+    storage: &dyn Storage,
+    diff: &mut SyncDiff,
+) -> Result<(), Error> {
+    if file_type != FileType::Directory {
+        diff.transfer.push(path);
+        return Ok(());
+    }
+
+    let metadata = storage.get_metadata(node)?;
+    diff.create_dirs.push(path.clone());
+
+    let entries = sorted_entries(&metadata, storage)?;
+    let child_types = entry_types(&entries, storage)?;
+    for ((name, child), child_type) in entries.into_iter().zip(child_types) {
+        add_new(child, child_type, join_path(&path, &name), storage, diff)?;
+    }
+
+    Ok(())
+}
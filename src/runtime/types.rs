@@ -1,20 +1,376 @@
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use bitflags::bitflags;
+use ic_stable_structures::storable::{Bound, Storable};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    error::Error,
+    storage::types::{
+        ChunkZeroing, Collation, FileSize, FileType, Metadata, NameNormalizer, Node,
+        PhysicalWrites, Times,
+    },
+};
+
+// The access of a file descriptor to the file contents, the other access is denied.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AccessMode {
+    Read,
+    Write,
+    #[default]
+    ReadWrite,
+}
+
+impl AccessMode {
+    pub fn can_read(&self) -> bool {
+        *self != AccessMode::Write
+    }
+
+    pub fn can_write(&self) -> bool {
+        *self != AccessMode::Read
+    }
+
+    // Check that this access does not go beyond the `other` access.
+    pub fn within(&self, other: AccessMode) -> bool {
+        (other.can_read() || !self.can_read()) && (other.can_write() || !self.can_write())
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 pub struct FdStat {
     pub flags: FdFlags,
-    pub rights_base: u64,
-    pub rights_inheriting: u64,
+    pub rights_base: Rights,
+    pub rights_inheriting: Rights,
+    pub access: AccessMode,
 }
 
 impl Default for FdStat {
     fn default() -> Self {
         Self {
             flags: FdFlags::empty(),
-            rights_base: 0,
-            rights_inheriting: 0,
+            rights_base: Rights::empty(),
+            rights_inheriting: Rights::empty(),
+            access: AccessMode::ReadWrite,
+        }
+    }
+}
+
+impl FdStat {
+    // Start building the stats of a file descriptor.
+    pub fn builder() -> FdStatBuilder {
+        FdStatBuilder::default()
+    }
+}
+
+// Builder of the file descriptor stats.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FdStatBuilder {
+    stat: FdStat,
+}
+
+impl FdStatBuilder {
+    pub fn flags(mut self, flags: FdFlags) -> Self {
+        self.stat.flags = flags;
+        self
+    }
+
+    // The rights of the file descriptor itself.
+    pub fn rights(mut self, rights: Rights) -> Self {
+        self.stat.rights_base = rights;
+        self
+    }
+
+    // The rights given to the file descriptors opened through this one.
+    pub fn inheriting(mut self, rights: Rights) -> Self {
+        self.stat.rights_inheriting = rights;
+        self
+    }
+
+    // The access to the file contents, reading and writing by default.
+    pub fn access(mut self, access: AccessMode) -> Self {
+        self.stat.access = access;
+        self
+    }
+
+    pub fn build(self) -> FdStat {
+        self.stat
+    }
+}
+
+// The `fdstat` of WASI preview 1 reported for an open descriptor.
+// Descriptors opened without rights are not restricted, they report all the rights of their file type.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WasiFdStat {
+    pub file_type: FileType,
+    pub flags: FdFlags,
+    pub rights_base: Rights,
+    pub rights_inheriting: Rights,
+}
+
+impl WasiFdStat {
+    pub fn new(file_type: FileType, stat: FdStat) -> Self {
+        let all_rights = match file_type {
+            FileType::Directory => Rights::ALL_DIR,
+            FileType::RegularFile | FileType::SymbolicLink => Rights::ALL_FILE,
+        };
+        let (rights_base, rights_inheriting) = if stat.rights_base.is_empty() {
+            (all_rights, Rights::ALL_FILE | Rights::ALL_DIR)
+        } else {
+            (stat.rights_base, stat.rights_inheriting)
+        };
+        let mut rights_base = rights_base;
+        if !stat.access.can_read() {
+            rights_base.remove(Rights::FD_READ);
+        }
+        if !stat.access.can_write() {
+            rights_base.remove(Rights::FD_WRITE);
+        }
+
+        Self {
+            file_type,
+            flags: stat.flags,
+            rights_base,
+            rights_inheriting,
+        }
+    }
+
+    // The WASI `filetype` code of the descriptor.
+    pub fn wasi_file_type(&self) -> u8 {
+        match self.file_type {
+            FileType::Directory => 3,
+            FileType::RegularFile => 4,
+            FileType::SymbolicLink => 7,
+        }
+    }
+
+    // Encode the stats in the memory layout of the WASI `fdstat` structure.
+    pub fn to_bytes(&self) -> [u8; 24] {
+        let mut result = [0; 24];
+        result[0] = self.wasi_file_type();
+        result[2..4].copy_from_slice(&self.flags.bits().to_le_bytes());
+        result[8..16].copy_from_slice(&self.rights_base.bits().to_le_bytes());
+        result[16..24].copy_from_slice(&self.rights_inheriting.bits().to_le_bytes());
+        result
+    }
+}
+
+// SHA-256 based Merkle hash of a file or a directory subtree.
+pub type MerkleHash = [u8; 32];
+
+// A manifest entry describing a node of the synchronized tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub file_type: FileType,
+    pub hash: MerkleHash,
+}
+
+// The hashes of all the nodes in a directory subtree by their path relative to the directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncManifest {
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl SyncManifest {
+    // Iterate over the paths of the direct children of a given directory path, an empty path is the root.
+    pub fn children<'a>(&'a self, dir_path: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let start = if dir_path.is_empty() {
+            String::new()
+        } else {
+            format!("{dir_path}/")
+        };
+        let prefix_len = start.len();
+
+        self.entries
+            .range(start.clone()..)
+            .map(|(path, _)| path.as_str())
+            .take_while(move |path| path.starts_with(&start))
+            .filter(move |path| !path[prefix_len..].contains('/'))
+    }
+}
+
+// The changes to apply to a remote tree to make it equal to the local one, in the order to apply them:
+// remove the paths first, then create the directories and finally transfer the file contents.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncDiff {
+    pub remove: Vec<String>,
+    pub create_dirs: Vec<String>,
+    pub transfer: Vec<String>,
+}
+
+impl SyncDiff {
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.create_dirs.is_empty() && self.transfer.is_empty()
+    }
+}
+
+// The recorded state of a node in a metadata manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataManifestEntry {
+    pub file_type: FileType,
+    pub size: FileSize,
+    pub hash: MerkleHash,
+    pub times: Times,
+}
+
+// The metadata of all the nodes in a directory subtree by their path relative to the directory,
+// exported to verify the tree later without transferring its contents.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataManifest {
+    pub entries: BTreeMap<String, MetadataManifestEntry>,
+}
+
+// The way a path differs from its metadata manifest entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviationKind {
+    // The path is in the manifest but not in the tree.
+    Missing,
+    // The path is in the tree but not in the manifest.
+    Unexpected,
+    FileType,
+    Size,
+    // The contents differ, only reported for files and aliases.
+    Hash,
+    Times,
+}
+
+// A difference between the tree and a metadata manifest, a path can have several.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestDeviation {
+    pub path: String,
+    pub kind: DeviationKind,
+    pub expected: Option<MetadataManifestEntry>,
+    pub actual: Option<MetadataManifestEntry>,
+}
+
+// The public stats of a file or a directory, field by field compatible with the WASI `filestat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStat {
+    /// The node number, also known as inode.
+    pub node: Node,
+    /// The generation of the node number.
+    pub generation: u64,
+    pub file_type: FileType,
+    /// The number of directory entries pointing to the node.
+    pub link_count: u64,
+    /// File size in bytes, the number of entries for directories.
+    pub size: FileSize,
+    pub times: Times,
+}
+
+impl From<&Metadata> for FileStat {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            node: metadata.node,
+            generation: metadata.generation,
+            file_type: metadata.file_type,
+            link_count: metadata.link_count,
+            size: metadata.size,
+            times: metadata.times,
+        }
+    }
+}
+
+// A reference to a file or a directory kept by the canister in its own structures instead of a path or a node.
+// It stays valid across upgrades and renames, and is rejected once the node is removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct FileHandle {
+    pub node: Node,
+    pub generation: u64,
+}
+
+// The handle is stored as the node and the generation in little endian.
+impl Storable for FileHandle {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.node.to_le_bytes());
+        bytes.extend_from_slice(&self.generation.to_le_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut node = [0; 8];
+        let mut generation = [0; 8];
+        node.copy_from_slice(&bytes[..8]);
+        generation.copy_from_slice(&bytes[8..16]);
+        Self {
+            node: Node::from_le_bytes(node),
+            generation: u64::from_le_bytes(generation),
         }
     }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16,
+        is_fixed_size: true,
+    };
+}
+
+// The data `list_dir_with` gets for each listed entry besides its name and node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Projection {
+    #[default]
+    NamesOnly,
+    /// The stats of the entry node.
+    WithMetadata,
+    /// The Merkle hash of the entry node.
+    WithHashes,
+}
+
+// A part of a directory listing: at most `limit` entries after the first `start` ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Page {
+    pub start: usize,
+    pub limit: usize,
+}
+
+impl Page {
+    pub fn all() -> Self {
+        Self {
+            start: 0,
+            limit: usize::MAX,
+        }
+    }
+}
+
+// A directory entry listed by `list_dir_with`, with the data selected by the projection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListedEntry {
+    pub name: String,
+    pub node: Node,
+    pub stat: Option<FileStat>,
+    pub hash: Option<MerkleHash>,
+}
+
+// The expected access pattern of file reads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadPattern {
+    /// The file is read in no particular order.
+    #[default]
+    Random,
+    /// The file is read from start to end, the following chunks are prefetched while reading.
+    Sequential,
+}
+
+// The access pattern hint of `FileSystem::advise`, in the order of the WASI `advice` values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Advice {
+    /// No particular pattern, like `Random`.
+    Normal,
+    /// The range is read from start to end, the following chunks are prefetched while reading.
+    Sequential,
+    /// The range is read in no particular order.
+    Random,
+    /// The range is read soon, its chunks are loaded into the cache.
+    WillNeed,
+    /// The range is not read soon, its chunks are dropped from the cache unless the file is pinned.
+    DontNeed,
+    /// The range is read once, nothing changes.
+    NoReuse,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -25,7 +381,7 @@ pub enum Whence {
 }
 
 bitflags! {
-    #[derive(Copy, Clone, Debug, PartialEq)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub struct FdFlags: u16 {
         const APPEND = 1;
         const DSYNC = 2;
@@ -35,6 +391,83 @@ bitflags! {
     }
 }
 
+bitflags! {
+    // The WASI rights of a file descriptor, bit compatible with the `rights` type of WASI preview 1.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct Rights: u64 {
+        const FD_DATASYNC = 1 << 0;
+        const FD_READ = 1 << 1;
+        const FD_SEEK = 1 << 2;
+        const FD_FDSTAT_SET_FLAGS = 1 << 3;
+        const FD_SYNC = 1 << 4;
+        const FD_TELL = 1 << 5;
+        const FD_WRITE = 1 << 6;
+        const FD_ADVISE = 1 << 7;
+        const FD_ALLOCATE = 1 << 8;
+        const PATH_CREATE_DIRECTORY = 1 << 9;
+        const PATH_CREATE_FILE = 1 << 10;
+        const PATH_LINK_SOURCE = 1 << 11;
+        const PATH_LINK_TARGET = 1 << 12;
+        const PATH_OPEN = 1 << 13;
+        const FD_READDIR = 1 << 14;
+        const PATH_READLINK = 1 << 15;
+        const PATH_RENAME_SOURCE = 1 << 16;
+        const PATH_RENAME_TARGET = 1 << 17;
+        const PATH_FILESTAT_GET = 1 << 18;
+        const PATH_FILESTAT_SET_SIZE = 1 << 19;
+        const PATH_FILESTAT_SET_TIMES = 1 << 20;
+        const FD_FILESTAT_GET = 1 << 21;
+        const FD_FILESTAT_SET_SIZE = 1 << 22;
+        const FD_FILESTAT_SET_TIMES = 1 << 23;
+        const PATH_SYMLINK = 1 << 24;
+        const PATH_REMOVE_DIRECTORY = 1 << 25;
+        const PATH_UNLINK_FILE = 1 << 26;
+        const POLL_FD_READWRITE = 1 << 27;
+        const SOCK_SHUTDOWN = 1 << 28;
+        const SOCK_ACCEPT = 1 << 29;
+
+        /// Reading a file without modifying it.
+        const READ_ONLY = Self::FD_READ.bits()
+            | Self::FD_SEEK.bits()
+            | Self::FD_TELL.bits()
+            | Self::FD_ADVISE.bits()
+            | Self::FD_FILESTAT_GET.bits()
+            | Self::POLL_FD_READWRITE.bits();
+
+        /// All the rights applicable to a regular file.
+        const ALL_FILE = Self::READ_ONLY.bits()
+            | Self::FD_DATASYNC.bits()
+            | Self::FD_FDSTAT_SET_FLAGS.bits()
+            | Self::FD_SYNC.bits()
+            | Self::FD_WRITE.bits()
+            | Self::FD_ALLOCATE.bits()
+            | Self::FD_FILESTAT_SET_SIZE.bits()
+            | Self::FD_FILESTAT_SET_TIMES.bits();
+
+        /// All the rights applicable to a directory.
+        const ALL_DIR = Self::FD_FDSTAT_SET_FLAGS.bits()
+            | Self::FD_SYNC.bits()
+            | Self::FD_ADVISE.bits()
+            | Self::PATH_CREATE_DIRECTORY.bits()
+            | Self::PATH_CREATE_FILE.bits()
+            | Self::PATH_LINK_SOURCE.bits()
+            | Self::PATH_LINK_TARGET.bits()
+            | Self::PATH_OPEN.bits()
+            | Self::FD_READDIR.bits()
+            | Self::PATH_READLINK.bits()
+            | Self::PATH_RENAME_SOURCE.bits()
+            | Self::PATH_RENAME_TARGET.bits()
+            | Self::PATH_FILESTAT_GET.bits()
+            | Self::PATH_FILESTAT_SET_SIZE.bits()
+            | Self::PATH_FILESTAT_SET_TIMES.bits()
+            | Self::FD_FILESTAT_GET.bits()
+            | Self::FD_FILESTAT_SET_TIMES.bits()
+            | Self::PATH_SYMLINK.bits()
+            | Self::PATH_REMOVE_DIRECTORY.bits()
+            | Self::PATH_UNLINK_FILE.bits();
+    }
+}
+
 bitflags! {
     pub struct OpenFlags: u16 {
         /// Create file if it does not exist.
@@ -45,6 +478,427 @@ bitflags! {
         const EXCLUSIVE = 4;
         /// Truncate file to size 0.
         const TRUNCATE = 8;
+        /// Fail if any element of the path is a symbolic link.
+        const NOFOLLOW_ANY = 16;
+    }
+}
+
+// What `create_file_with` does when the name already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    // Fail with `FileAlreadyExists`.
+    #[default]
+    Error,
+    // Open the existing file and truncate it.
+    Truncate,
+    // Open the existing file as it is.
+    OpenExisting,
+}
+
+bitflags! {
+    // The flags of `rename_with_flags`, without flags an existing destination is replaced.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct RenameFlags: u8 {
+        /// Fail if the destination exists, like `RENAME_NOREPLACE`.
+        const NOREPLACE = 1;
+        /// Exchange the source and the existing destination, like `RENAME_EXCHANGE`.
+        const EXCHANGE = 2;
+    }
+}
+
+// The unit of the `u64` times stored in the metadata.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimeResolution {
+    #[default]
+    Nanoseconds,
+    Seconds,
+}
+
+// Interpretation of the stored times: their unit and the moment stored as 0.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TimeConfig {
+    pub resolution: TimeResolution,
+    /// The time stored as 0, relative to the Unix epoch.
+    pub epoch: Duration,
+}
+
+impl TimeConfig {
+    fn duration(self, time: u64) -> Duration {
+        match self.resolution {
+            TimeResolution::Nanoseconds => Duration::from_nanos(time),
+            TimeResolution::Seconds => Duration::from_secs(time),
+        }
+    }
+
+    fn time_of(self, duration: Duration) -> u64 {
+        match self.resolution {
+            TimeResolution::Nanoseconds => u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX),
+            TimeResolution::Seconds => duration.as_secs(),
+        }
+    }
+
+    // Convert a stored time into the system time, a time the system time cannot hold fails with `InvalidMetadata`.
+    pub fn to_system_time(self, time: u64) -> Result<SystemTime, Error> {
+        self.epoch
+            .checked_add(self.duration(time))
+            .and_then(|duration| UNIX_EPOCH.checked_add(duration))
+            .ok_or(Error::InvalidMetadata)
+    }
+
+    // Convert the system time into a stored time, the times before the epoch are stored as 0.
+    pub fn time_from_system(self, time: SystemTime) -> u64 {
+        let duration = time
+            .duration_since(UNIX_EPOCH + self.epoch)
+            .unwrap_or(Duration::ZERO);
+        self.time_of(duration)
+    }
+
+    // Convert a stored time into a WASI timestamp, nanoseconds since the Unix epoch, the later times saturate.
+    pub fn to_wasi_timestamp(self, time: u64) -> u64 {
+        match self.epoch.checked_add(self.duration(time)) {
+            Some(duration) => u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX),
+            None => u64::MAX,
+        }
+    }
+
+    // Convert a WASI timestamp into a stored time.
+    pub fn time_from_wasi(self, timestamp: u64) -> u64 {
+        let duration = Duration::from_nanos(timestamp)
+            .checked_sub(self.epoch)
+            .unwrap_or(Duration::ZERO);
+        self.time_of(duration)
+    }
+}
+
+// The handling of the untrusted path resolutions.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SandboxMode {
+    #[default]
+    Off,
+    // The resolutions are recorded in the path audit.
+    Audit,
+    // The resolutions are recorded and fail with `SandboxViolation`.
+    Enforce,
+}
+
+// The handling of a storage write failing in the middle of a file write, e.g. when a quota is hit.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WriteFailure {
+    // The chunks written before the failure are kept and counted into the file size, the write returns
+    // the written size if it is not zero and the error otherwise.
+    #[default]
+    KeepPartial,
+    // The overwritten chunks and the file size are restored and the write returns the error.
+    Rollback,
+}
+
+// The reason of a path resolution being recorded in the path audit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PathViolation {
+    ParentReference,
+    AbsolutePath,
+    // An alias inside the sandbox boundary leads to a node outside of it.
+    AliasEscape,
+    // A path leads to a directory outside of the sandbox boundary, e.g. it is resolved from a directory moved out
+    // of the boundary while opened. The paths resolved from the parents of the boundary are not checked.
+    BoundaryEscape,
+}
+
+// A token of a file version frozen by `FileSystem::freeze_node`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SnapshotToken(pub u64);
+
+// A path resolution recorded in the sandbox mode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathAudit {
+    // The directory the path was resolved from.
+    pub dir: Node,
+    pub path: String,
+    pub violation: PathViolation,
+    pub rejected: bool,
+    // The span the path was resolved in, see `FileSystem::span`.
+    pub span: Option<u64>,
+}
+
+// Options of a mounted file system.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MountOptions {
+    /// Include the synthetic "." and ".." entries into the directory listings.
+    pub dot_entries: bool,
+    /// Follow POSIX in the corner cases: report `IsDirectory` and `NotADirectory` on file type mismatches,
+    /// allow unlinking the opened files and directories, their data is removed once the last descriptor is closed.
+    pub strict_posix: bool,
+    /// The unit and origin of the stored times.
+    pub times: TimeConfig,
+    /// The maximum number of entries in a directory, adding more entries fails with `DirectoryFull`.
+    /// It is stored with the file system, a later mount requesting another limit fails with `InvalidMountOptions`.
+    pub max_dir_entries: Option<u64>,
+    /// The normalization of the file names, applied to each path element on entry creation and lookup.
+    /// It is stored with the file system by the first mount using it, which fails with `InvalidMountOptions` if the tree
+    /// is not empty. The later mounts use the stored normalizer.
+    pub name_normalizer: NameNormalizer,
+    /// The function used by `NameNormalizer::Custom`, it must return the same result when applied again.
+    pub custom_normalizer: Option<fn(&str) -> String>,
+    /// Record or reject the path resolutions using "..", absolute paths or aliases leading out of the sandbox boundary.
+    pub sandbox: SandboxMode,
+    /// The handling of a storage write failing in the middle of a file write.
+    pub write_failure: WriteFailure,
+    /// The maximum number of elements in a path or an alias target, a deeper path fails with `PathTooDeep`.
+    /// It is stored with the file system like `max_dir_entries`.
+    pub max_path_depth: Option<usize>,
+    /// The maximum number of aliases followed while resolving a path, 16 by default.
+    /// A longer chain fails with `TooManySymbolicLinks`.
+    pub max_alias_hops: Option<usize>,
+    /// The instructions a budgeted operation may use before it returns a partial result, unlimited by default.
+    pub instruction_budget: Option<u64>,
+    /// The number of the entries of a directory the storage is asked to prefetch when the directory is opened.
+    pub dir_prefetch: Option<usize>,
+    /// The zeroing of the freed file chunks, they are not zeroed by default.
+    pub chunk_zeroing: ChunkZeroing,
+    /// The order of the names in the sorted directory listings.
+    /// It is stored with the file system by the first mount using it, the later mounts use the stored collation.
+    pub collation: Collation,
+    /// The comparator used by `Collation::Custom`, it must be a total order.
+    pub custom_collation: Option<fn(&str, &str) -> Ordering>,
+    /// The source of the current time in the unit of `times`, e.g. `ic_cdk::api::time` for the nanoseconds.
+    /// It is read by the calls without a time argument, like `FileSystem::expire`.
+    pub clock: Option<fn() -> u64>,
+}
+
+impl MountOptions {
+    // Compare two file names with the configured collation.
+    pub fn compare_names(&self, a: &str, b: &str) -> Ordering {
+        match (self.collation, self.custom_collation) {
+            (Collation::Binary, _) | (Collation::Custom, None) => a.cmp(b),
+            (Collation::AsciiCaseInsensitive, _) => {
+                let folded = |name: &str| {
+                    name.bytes()
+                        .map(|b| b.to_ascii_lowercase())
+                        .collect::<Vec<_>>()
+                };
+                folded(a).cmp(&folded(b)).then_with(|| a.cmp(b))
+            }
+            (Collation::Custom, Some(compare)) => compare(a, b),
+        }
+    }
+
+    // Normalize each element of a path with the configured name normalizer.
+    pub fn normalize_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let normalize: fn(&str) -> String = match self.name_normalizer {
+            NameNormalizer::Exact => return Cow::Borrowed(path),
+            NameNormalizer::Nfc => |name| name.nfc().collect(),
+            NameNormalizer::Lowercase => |name| name.to_lowercase(),
+            NameNormalizer::NfcLowercase => |name| name.to_lowercase().nfc().collect(),
+            NameNormalizer::Custom => match self.custom_normalizer {
+                Some(custom) => custom,
+                None => return Cow::Borrowed(path),
+            },
+        };
+
+        let normalized = path.split('/').map(normalize).collect::<Vec<_>>().join("/");
+
+        if normalized == path {
+            Cow::Borrowed(path)
+        } else {
+            Cow::Owned(normalized)
+        }
+    }
+}
+
+// The nodes a destructive operation removes, reported by its dry run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RemovalReport {
+    // The paths of the removed entries, a directory is listed before its contents.
+    pub paths: Vec<String>,
+    // The number of removed files and aliases.
+    pub files: u64,
+    pub directories: u64,
+    // The size of the file data that is released.
+    pub bytes: FileSize,
+}
+
+// A region of a sparse file holding data.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseExtent {
+    pub offset: FileSize,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+// File contents without the holes, the regions outside of the extents are read as zeros.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseFile {
+    pub size: FileSize,
+    pub extents: Vec<SparseExtent>,
+}
+
+// Write counters of a file.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    // The number of write calls.
+    pub writes: u64,
+    // The number of chunks modified, a chunk is counted once per each write touching it.
+    pub chunks_written: u64,
+    pub bytes_written: u64,
+}
+
+// Where an operation stopped by the instruction budget was left: the bytes read or written,
+// or the entries removed before it stopped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Continuation {
+    pub done: u64,
+}
+
+// The result of an operation limited by the instruction budget, a partial one is completed by the next calls.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Budgeted<T> {
+    Complete(T),
+    Partial { continuation: Continuation },
+}
+
+// The file bytes written by the callers against the bytes the storage wrote for them, including the rewrites
+// of whole chunks and of the metadata and directory entries.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteAmplification {
+    pub logical_bytes: u64,
+    pub physical: PhysicalWrites,
+}
+
+impl WriteAmplification {
+    // Get the number of the physical bytes written per a logical byte, if anything was written.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.logical_bytes == 0 {
+            return None;
+        }
+        Some(self.physical.total() as f64 / self.logical_bytes as f64)
+    }
+}
+
+// The layout and the limits of a mounted file system, the same values as the constants of this build
+// combined with the mount options, for the tools that do not link against the crate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LayoutInfo {
+    pub chunk_size: usize,
+    pub max_file_name: usize,
+    pub max_file_size: FileSize,
+    // The version of the metadata kept by the storage.
+    pub storage_version: u32,
+    pub max_dir_entries: Option<u64>,
+    pub max_path_depth: Option<usize>,
+    pub max_alias_hops: usize,
+}
+
+// The kind of a directory entry change.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+}
+
+// A change of a directory entry recorded by the directory changelog.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirChange {
+    pub generation: u64,
+    pub name: String,
+    pub kind: ChangeKind,
+}
+
+// The result of a changelog query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirChanges {
+    // The current generation of the changelog, to be passed to the next query.
+    pub generation: u64,
+    pub changes: Vec<DirChange>,
+    // False if some of the requested changes were already dropped from the changelog, the directory has to be listed again.
+    pub complete: bool,
+}
+
+// The identifier of a path watched by `FileSystem::watch`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WatchId(pub u64);
+
+// A change of a watched path reported by `FileSystem::poll_watches`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchEvent {
+    pub watch: WatchId,
+    pub path: String,
+    pub kind: ChangeKind,
+}
+
+// The way files are chosen for eviction from a cache directory.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // Evict the least recently read or written files first.
+    #[default]
+    Lru,
+}
+
+// Limits of a directory used as a bounded cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CachePolicy {
+    pub max_bytes: FileSize,
+    pub eviction: EvictionPolicy,
+}
+
+// A position in the order of directory entries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntryPosition<'a> {
+    First,
+    Last,
+    // Before the entry with the given name.
+    Before(&'a str),
+    // After the entry with the given name.
+    After(&'a str),
+}
+
+// Options controlling the removal of a directory entry.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RemoveOptions {
+    /// Some(true) - the entry must be a directory, Some(false) - the entry must not be a directory.
+    pub must_be_dir: Option<bool>,
+    /// Fail with `DirectoryNotEmpty` if the removed directory has any entries.
+    pub must_be_empty: bool,
+    /// Remove the directory together with its whole subtree.
+    pub recursive: bool,
+    /// Remove the file or the directory an alias at `path` leads to instead of the alias itself.
+    pub follow_symlinks: bool,
+    /// The zeroing of the freed chunks for this removal, by default the one of the mount options.
+    pub zeroing: Option<ChunkZeroing>,
+}
+
+impl RemoveOptions {
+    // Options matching the WASI `path_unlink_file` call.
+    pub fn file() -> Self {
+        Self {
+            must_be_dir: Some(false),
+            must_be_empty: false,
+            recursive: false,
+            follow_symlinks: false,
+            zeroing: None,
+        }
+    }
+
+    // Options matching the WASI `path_remove_directory` call.
+    pub fn dir() -> Self {
+        Self {
+            must_be_dir: Some(true),
+            must_be_empty: true,
+            recursive: false,
+            follow_symlinks: false,
+            zeroing: None,
+        }
+    }
+
+    // Options removing a directory with its whole subtree.
+    pub fn dir_all() -> Self {
+        Self {
+            must_be_dir: Some(true),
+            must_be_empty: false,
+            recursive: true,
+            follow_symlinks: false,
+            zeroing: None,
+        }
     }
 }
 
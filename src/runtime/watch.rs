@@ -0,0 +1,77 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    error::Error,
+    runtime::{structure_helpers::find_node, types::ChangeKind},
+    storage::{
+        types::{FileSize, Node},
+        Storage,
+    },
+};
+
+// The state of a watched path used to detect its changes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct WatchStamp {
+    node: Node,
+    size: FileSize,
+    modified: u64,
+    dir_generation: u64,
+}
+
+// A path watched for changes.
+// The changes are detected by comparing the node at the path with its last seen state, so several changes
+// between two polls are reported as one.
+pub struct Watch {
+    pub path: String,
+    stamp: Option<WatchStamp>,
+}
+
+impl Watch {
+    // Start watching a path relative to the `root` directory, the path does not need to exist.
+    pub fn new(root: Node, path: String, storage: &dyn Storage) -> Result<Self, Error> {
+        let stamp = read_stamp(root, &path, storage)?;
+        Ok(Self { path, stamp })
+    }
+
+    // Get the changes made since the last poll, `written` holds the nodes whose contents were changed in between.
+    pub fn poll(
+        &mut self,
+        root: Node,
+        written: &BTreeSet<Node>,
+        storage: &dyn Storage,
+    ) -> Result<Vec<ChangeKind>, Error> {
+        let current = read_stamp(root, &self.path, storage)?;
+
+        let changes = match (self.stamp, current) {
+            (None, None) => vec![],
+            (None, Some(_)) => vec![ChangeKind::Created],
+            (Some(_), None) => vec![ChangeKind::Removed],
+            (Some(old), Some(new)) if old.node != new.node => {
+                vec![ChangeKind::Removed, ChangeKind::Created]
+            }
+            (Some(old), Some(new)) if old != new || written.contains(&new.node) => {
+                vec![ChangeKind::Modified]
+            }
+            (Some(_), Some(_)) => vec![],
+        };
+
+        self.stamp = current;
+        Ok(changes)
+    }
+}
+
+fn read_stamp(root: Node, path: &str, storage: &dyn Storage) -> Result<Option<WatchStamp>, Error> {
+    let node = match find_node(root, path, storage) {
+        Ok(node) => node,
+        Err(Error::NotFound | Error::NotADirectory) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let metadata = storage.get_metadata(node)?;
+
+    Ok(Some(WatchStamp {
+        node,
+        size: metadata.size,
+        modified: metadata.times.modified,
+        dir_generation: metadata.dir_generation,
+    }))
+}
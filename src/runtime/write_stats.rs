@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    runtime::types::WriteStats,
+    storage::types::{FileSize, Node, FILE_CHUNK_SIZE},
+};
+
+// Write counters of the file nodes.
+#[derive(Default)]
+pub struct WriteStatsTable {
+    stats: BTreeMap<Node, WriteStats>,
+}
+
+impl WriteStatsTable {
+    // Count a write of `len` bytes at `offset`.
+    pub fn record(&mut self, node: Node, offset: FileSize, len: FileSize) {
+        if len == 0 {
+            return;
+        }
+
+        let chunk_size = FILE_CHUNK_SIZE as FileSize;
+        let first_chunk = offset / chunk_size;
+        let last_chunk = (offset + len - 1) / chunk_size;
+
+        let stats = self.stats.entry(node).or_default();
+        stats.writes += 1;
+        stats.bytes_written += len;
+        stats.chunks_written += last_chunk - first_chunk + 1;
+    }
+
+    // Forget the counters of a removed node.
+    pub fn remove(&mut self, node: Node) {
+        self.stats.remove(&node);
+    }
+
+    pub fn clear(&mut self) {
+        self.stats.clear();
+    }
+
+    // Get the `top_n` nodes with the most chunks written.
+    pub fn hot_files(&self, top_n: usize) -> Vec<(Node, WriteStats)> {
+        let mut result: Vec<(Node, WriteStats)> = self
+            .stats
+            .iter()
+            .map(|(node, stats)| (*node, *stats))
+            .collect();
+
+        result.sort_by(|(node1, stats1), (node2, stats2)| {
+            stats2
+                .chunks_written
+                .cmp(&stats1.chunks_written)
+                .then(node1.cmp(node2))
+        });
+        result.truncate(top_n);
+
+        result
+    }
+}
@@ -1,14 +1,34 @@
+use std::borrow::Cow;
+
 use crate::{
     error::Error,
-    storage::types::{DirEntry, DirEntryIndex, FileChunkIndex, FileSize, Metadata, Node},
+    storage::types::{
+        ChunkZeroing, DirEntry, DirEntryIndex, FileChunkIndex, FileSize, Metadata, Node,
+        PhysicalWrites, RecoveryReport, ReusableSpace, StorageClass, FILE_CHUNK_SIZE,
+    },
 };
 
+pub mod cached;
 pub mod dummy;
+pub mod encrypted;
+pub mod log_structured;
+pub mod metrics;
+pub mod quota;
 pub mod stable;
+pub mod tiered;
 pub mod transient;
 pub mod types;
 
 // Abstraction of the underlying storage layer.
+//
+// The trait is the extension point for custom backends and for adapters wrapping another storage,
+// like `CachedStorage`, `MetricsStorage`, `EncryptedStorage`, `QuotaStorage` or `TieredStorage`, which can be stacked in any order.
+// An implementation must follow these rules, the file system relies on them:
+// - a missing metadata, entry or chunk is reported with `NotFound`;
+// - a chunk read or write outside of the FILE_CHUNK_SIZE bytes of a chunk fails with `InvalidOffset`,
+//   the bytes of a chunk not written yet are read as zeros;
+// - a failed chunk write leaves the chunk as it was.
+// An adapter should forward the provided methods too, so that the optimized versions of the inner storage are used.
 pub trait Storage {
     // Get the root node ID of the storage
     fn root_node(&self) -> Node;
@@ -19,6 +39,10 @@ pub trait Storage {
     // Generate the next available node ID.
     fn new_node(&mut self) -> Node;
 
+    // Get the generation given to the nodes created now. It grows with every removed node, so that a node number
+    // given out again gets a newer generation and the handles of the removed node are stale.
+    fn node_generation(&self) -> u64;
+
     // Get the metadata associated with the node.
     fn get_metadata(&self, node: Node) -> Result<Metadata, Error>;
     // Update the metadata associated with the node.
@@ -42,7 +66,119 @@ pub trait Storage {
         buf: &mut [u8],
     ) -> Result<(), Error>;
     // Insert of update a selected file chunk with the data provided in buffer.
-    fn write_filechunk(&mut self, node: Node, index: FileChunkIndex, offset: FileSize, buf: &[u8]);
+    // A failed write, e.g. when the storage is full, must leave the chunk unchanged.
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error>;
     // Remove file chunk from a given file node.
     fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex);
+
+    // Get the contents of a whole file chunk, storages holding the chunk in heap memory can return it without copying.
+    fn get_filechunk(&mut self, node: Node, index: FileChunkIndex) -> Result<Cow<'_, [u8]>, Error> {
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+        self.read_filechunk(node, index, 0, &mut buf)?;
+        Ok(Cow::Owned(buf))
+    }
+
+    // Get the indices of the written chunks among the first `chunk_count` chunks of a node, in the ascending order.
+    // By default each chunk is probed, storages should list them directly.
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        (0..chunk_count)
+            .map(|index| index as FileChunkIndex)
+            .filter(|index| self.read_filechunk(node, *index, 0, &mut []).is_ok())
+            .collect()
+    }
+
+    // Move a whole file chunk to another node or position, replacing the chunk stored there.
+    // By default the chunk is copied, storages should relink it without copying the data.
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        let chunk = self.get_filechunk(from_node, from_index)?.into_owned();
+        if (from_node, from_index) == (to_node, to_index) {
+            return Ok(());
+        }
+        self.write_filechunk(to_node, to_index, 0, &chunk)?;
+        self.rm_filechunk(from_node, from_index);
+        Ok(())
+    }
+
+    // Hint that `count` chunks starting with `index` are going to be read soon.
+    // Storages keeping a chunk cache can load them in advance, by default it does nothing.
+    fn prefetch_filechunks(&self, _node: Node, _index: FileChunkIndex, _count: FileChunkIndex) {}
+
+    // Hint that `count` chunks starting with `index` are not going to be read soon.
+    // Storages keeping a chunk cache can drop them, by default it does nothing.
+    fn evict_filechunks(&self, _node: Node, _index: FileChunkIndex, _count: FileChunkIndex) {}
+
+    // Get the metadata of several nodes, the results are in the order of `nodes`.
+    // Storages keeping the metadata in an ordered map can look the nodes up in their key order.
+    fn get_metadata_many(&self, nodes: &[Node]) -> Vec<Result<Metadata, Error>> {
+        nodes.iter().map(|node| self.get_metadata(*node)).collect()
+    }
+
+    // Hint that the first `count` entries of the directory `node` are going to be read soon.
+    // Storages keeping a cache can load them in advance, by default it does nothing.
+    fn prefetch_direntries(&self, _node: Node, _count: usize) {}
+
+    // Keep the loaded chunks of the file `node` in the chunk cache until it is unpinned, they are not evicted
+    // for the other chunks. Storages without a chunk cache ignore it.
+    fn set_pinned(&mut self, _node: Node, _pinned: bool) {}
+
+    // Take the records that cannot be decoded out of the storage, so that the rest of the tree stays readable.
+    // The storages keeping the records in heap memory have nothing to recover.
+    fn recover(&mut self) -> RecoveryReport {
+        RecoveryReport::default()
+    }
+
+    // Get the bytes written to the memory since the storage was created, a changed chunk or record
+    // is usually rewritten whole. The storages keeping the data in heap memory report zeros.
+    fn physical_writes(&self) -> PhysicalWrites {
+        PhysicalWrites::default()
+    }
+
+    // Get the space of the removed records the storage keeps for the new ones.
+    // The storages keeping the data in heap memory free it and report zeros.
+    fn reusable_space(&self) -> ReusableSpace {
+        ReusableSpace::default()
+    }
+
+    // Record a node kept without directory entries, e.g. a file unlinked while opened, its data is removed on the
+    // next mount unless it is linked or removed before. The storages keeping the data in heap memory lose the
+    // nodes with it and record nothing.
+    fn add_orphan(&mut self, _node: Node) {}
+
+    // Forget a node recorded by `add_orphan`, it was linked again or its data was removed.
+    fn remove_orphan(&mut self, _node: Node) {}
+
+    // Get the nodes recorded by `add_orphan`.
+    fn orphans(&self) -> Vec<Node> {
+        Vec::new()
+    }
+
+    // Select how the freed chunks are overwritten, by default they are released as they are.
+    fn set_chunk_zeroing(&mut self, _zeroing: ChunkZeroing) {}
+
+    // Overwrite with zeros and release at most `max` of the chunks freed with `ChunkZeroing::Deferred`,
+    // returns the number of the chunks released.
+    fn zero_freed_chunks(&mut self, _max: usize) -> usize {
+        0
+    }
+
+    // Change the storage class of a node, storages keeping the contents apart move them to the other memory.
+    // By default the class is only recorded in the metadata.
+    fn set_storage_class(&mut self, node: Node, class: StorageClass) -> Result<(), Error> {
+        let mut metadata = self.get_metadata(node)?;
+        metadata.storage_class = class;
+        self.put_metadata(node, metadata);
+        Ok(())
+    }
 }
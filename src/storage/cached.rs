@@ -0,0 +1,588 @@
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+};
+
+use crate::{
+    error::Error,
+    storage::types::{
+        chunk_range, ChunkZeroing, DirEntry, DirEntryIndex, FileChunk, FileChunkIndex, FileSize,
+        Metadata, Node, PhysicalWrites, RecoveryReport, ReusableSpace, StorageClass,
+        FILE_CHUNK_SIZE,
+    },
+    storage::Storage,
+};
+
+// The default number of chunks kept in the cache.
+pub const DEFAULT_CACHE_CAPACITY: usize = 256;
+
+// The number of prefetched directory entries kept in the cache.
+pub const DIRENTRY_CACHE_CAPACITY: usize = 1024;
+
+// Statistics of the chunk cache usage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub cached_chunks: usize,
+    // the cached chunks of the pinned files, they are included in `cached_chunks`
+    pub pinned_chunks: usize,
+    pub cached_direntries: usize,
+}
+
+struct CacheEntry {
+    chunk: FileChunk,
+    last_used: u64,
+}
+
+// A least recently used cache of file chunks, the chunks of the pinned files are not counted and not evicted.
+struct ChunkCache {
+    capacity: usize,
+    tick: u64,
+    entries: BTreeMap<(Node, FileChunkIndex), CacheEntry>,
+    // the unpinned cached chunks ordered by the time of their last use
+    lru: BTreeMap<u64, (Node, FileChunkIndex)>,
+    pinned: BTreeSet<Node>,
+}
+
+impl ChunkCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            pinned: BTreeSet::new(),
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, key: (Node, FileChunkIndex)) -> Option<&FileChunk> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(&key)?;
+        if !self.pinned.contains(&key.0) {
+            self.lru.remove(&entry.last_used);
+            self.lru.insert(tick, key);
+        }
+        entry.last_used = tick;
+        Some(&entry.chunk)
+    }
+
+    fn insert(&mut self, key: (Node, FileChunkIndex), chunk: FileChunk) {
+        let pinned = self.pinned.contains(&key.0);
+        if self.capacity == 0 && !pinned {
+            return;
+        }
+
+        self.remove(key);
+
+        let last_used = self.next_tick();
+        if !pinned {
+            self.evict_to(self.capacity - 1);
+            self.lru.insert(last_used, key);
+        }
+        self.entries.insert(key, CacheEntry { chunk, last_used });
+    }
+
+    fn remove(&mut self, key: (Node, FileChunkIndex)) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+
+    // Drop the least recently used unpinned chunks until at most `len` of them are left.
+    fn evict_to(&mut self, len: usize) {
+        while self.lru.len() > len {
+            match self.lru.pop_first() {
+                Some((_, evicted)) => {
+                    self.entries.remove(&evicted);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn pin(&mut self, node: Node) {
+        if self.pinned.insert(node) {
+            for (_, entry) in self.entries.range((node, 0)..=(node, FileChunkIndex::MAX)) {
+                self.lru.remove(&entry.last_used);
+            }
+        }
+    }
+
+    // The chunks of the unpinned file are the least recently used ones again by their last use.
+    fn unpin(&mut self, node: Node) {
+        if self.pinned.remove(&node) {
+            for (key, entry) in self.entries.range((node, 0)..=(node, FileChunkIndex::MAX)) {
+                self.lru.insert(entry.last_used, *key);
+            }
+            self.evict_to(self.capacity);
+        }
+    }
+}
+
+// Storage adapter keeping the recently used file chunks of the inner storage in heap memory.
+// The prefetched directory entries are kept until they are changed, the oldest ones are dropped first.
+pub struct CachedStorage {
+    inner: Box<dyn Storage>,
+    cache: RefCell<ChunkCache>,
+    direntries: RefCell<BTreeMap<(Node, DirEntryIndex), DirEntry>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl CachedStorage {
+    // Create a new cache over the given storage holding up to `capacity` chunks.
+    pub fn new(inner: Box<dyn Storage>, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: RefCell::new(ChunkCache::new(capacity)),
+            direntries: RefCell::new(BTreeMap::new()),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    // Get the cache usage statistics.
+    pub fn stats(&self) -> CacheStats {
+        let cache = self.cache.borrow();
+        CacheStats {
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+            cached_chunks: cache.entries.len(),
+            pinned_chunks: cache.entries.len() - cache.lru.len(),
+            cached_direntries: self.direntries.borrow().len(),
+        }
+    }
+
+    pub fn inner(&self) -> &dyn Storage {
+        self.inner.as_ref()
+    }
+
+    // Get the wrapped storage back, the cached chunks are already written to it.
+    pub fn into_inner(self) -> Box<dyn Storage> {
+        self.inner
+    }
+
+    // Check if a given chunk is currently cached.
+    pub fn is_cached(&self, node: Node, index: FileChunkIndex) -> bool {
+        self.cache.borrow().entries.contains_key(&(node, index))
+    }
+
+    // Load the chunk from the inner storage into the cache.
+    fn load_filechunk(&self, node: Node, index: FileChunkIndex) -> Result<(), Error> {
+        let mut chunk = FileChunk::default();
+        self.inner
+            .read_filechunk(node, index, 0, &mut chunk.bytes)?;
+        self.cache.borrow_mut().insert((node, index), chunk);
+        Ok(())
+    }
+}
+
+impl Storage for CachedStorage {
+    fn root_node(&self) -> Node {
+        self.inner.root_node()
+    }
+
+    fn get_version(&self) -> u32 {
+        self.inner.get_version()
+    }
+
+    fn new_node(&mut self) -> Node {
+        self.inner.new_node()
+    }
+
+    fn node_generation(&self) -> u64 {
+        self.inner.node_generation()
+    }
+
+    fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
+        self.inner.get_metadata(node)
+    }
+
+    fn get_metadata_many(&self, nodes: &[Node]) -> Vec<Result<Metadata, Error>> {
+        self.inner.get_metadata_many(nodes)
+    }
+
+    fn put_metadata(&mut self, node: Node, metadata: Metadata) {
+        self.inner.put_metadata(node, metadata)
+    }
+
+    fn rm_metadata(&mut self, node: Node) {
+        self.cache.get_mut().unpin(node);
+        self.inner.rm_metadata(node)
+    }
+
+    fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
+        if let Some(entry) = self.direntries.borrow().get(&(node, index)) {
+            return Ok(entry.clone());
+        }
+        self.inner.get_direntry(node, index)
+    }
+
+    fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
+        self.direntries.get_mut().remove(&(node, index));
+        self.inner.put_direntry(node, index, entry)
+    }
+
+    fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
+        self.direntries.get_mut().remove(&(node, index));
+        self.inner.rm_direntry(node, index)
+    }
+
+    fn read_filechunk(
+        &self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let range = chunk_range(offset, buf.len())?;
+
+        if let Some(chunk) = self.cache.borrow_mut().get((node, index)) {
+            self.hits.set(self.hits.get() + 1);
+            buf.copy_from_slice(&chunk.bytes[range]);
+            return Ok(());
+        }
+
+        self.misses.set(self.misses.get() + 1);
+        self.load_filechunk(node, index)?;
+
+        match self.cache.borrow_mut().get((node, index)) {
+            Some(chunk) => buf.copy_from_slice(&chunk.bytes[range]),
+            // the cache has no capacity
+            None => self.inner.read_filechunk(node, index, offset, buf)?,
+        }
+
+        Ok(())
+    }
+
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        self.inner.write_filechunk(node, index, offset, buf)?;
+
+        if buf.len() == FILE_CHUNK_SIZE {
+            let mut chunk = FileChunk::default();
+            chunk.bytes.copy_from_slice(buf);
+            self.cache.get_mut().insert((node, index), chunk);
+        } else {
+            self.cache.get_mut().remove((node, index));
+        }
+        Ok(())
+    }
+
+    fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex) {
+        self.inner.rm_filechunk(node, index);
+        self.cache.get_mut().remove((node, index));
+    }
+
+    fn get_filechunk(&mut self, node: Node, index: FileChunkIndex) -> Result<Cow<'_, [u8]>, Error> {
+        if self.cache.get_mut().get((node, index)).is_some() {
+            self.hits.set(self.hits.get() + 1);
+        } else {
+            self.misses.set(self.misses.get() + 1);
+            self.load_filechunk(node, index)?;
+        }
+
+        match self.cache.get_mut().entries.get(&(node, index)) {
+            Some(entry) => Ok(Cow::Borrowed(&entry.chunk.bytes)),
+            // the cache has no capacity
+            None => {
+                let mut buf = vec![0; FILE_CHUNK_SIZE];
+                self.inner.read_filechunk(node, index, 0, &mut buf)?;
+                Ok(Cow::Owned(buf))
+            }
+        }
+    }
+
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        self.inner.filechunk_indices(node, chunk_count)
+    }
+
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        let cache = self.cache.get_mut();
+        cache.remove((from_node, from_index));
+        cache.remove((to_node, to_index));
+        self.inner
+            .move_filechunk(from_node, from_index, to_node, to_index)
+    }
+
+    // The chunks of the recovered nodes can be removed, the cache is emptied but the pins are kept.
+    fn recover(&mut self) -> RecoveryReport {
+        let cache = self.cache.get_mut();
+        let pinned = std::mem::take(&mut cache.pinned);
+        *cache = ChunkCache::new(cache.capacity);
+        cache.pinned = pinned;
+        self.direntries.get_mut().clear();
+        self.inner.recover()
+    }
+
+    fn physical_writes(&self) -> PhysicalWrites {
+        self.inner.physical_writes()
+    }
+
+    fn reusable_space(&self) -> ReusableSpace {
+        self.inner.reusable_space()
+    }
+
+    fn set_chunk_zeroing(&mut self, zeroing: ChunkZeroing) {
+        self.inner.set_chunk_zeroing(zeroing)
+    }
+
+    fn zero_freed_chunks(&mut self, max: usize) -> usize {
+        self.inner.zero_freed_chunks(max)
+    }
+
+    fn add_orphan(&mut self, node: Node) {
+        self.inner.add_orphan(node)
+    }
+
+    fn remove_orphan(&mut self, node: Node) {
+        self.inner.remove_orphan(node)
+    }
+
+    fn orphans(&self) -> Vec<Node> {
+        self.inner.orphans()
+    }
+
+    fn set_storage_class(&mut self, node: Node, class: StorageClass) -> Result<(), Error> {
+        self.inner.set_storage_class(node, class)
+    }
+
+    fn prefetch_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        for index in index..index.saturating_add(count) {
+            if !self.is_cached(node, index) {
+                // chunks that were never written have nothing to prefetch
+                let _ = self.load_filechunk(node, index);
+            }
+        }
+    }
+
+    // The chunks of the pinned files are kept.
+    fn evict_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        let mut cache = self.cache.borrow_mut();
+        if cache.pinned.contains(&node) {
+            return;
+        }
+        let range = (node, index)..(node, index.saturating_add(count));
+        let keys: Vec<_> = cache.entries.range(range).map(|(key, _)| *key).collect();
+        for key in keys {
+            cache.remove(key);
+        }
+    }
+
+    fn set_pinned(&mut self, node: Node, pinned: bool) {
+        if pinned {
+            self.cache.get_mut().pin(node);
+        } else {
+            self.cache.get_mut().unpin(node);
+        }
+    }
+
+    // Follow the entry list of the directory from its first entry.
+    fn prefetch_direntries(&self, node: Node, count: usize) {
+        let Ok(metadata) = self.inner.get_metadata(node) else {
+            return;
+        };
+
+        let mut direntries = self.direntries.borrow_mut();
+        let mut next = metadata.first_dir_entry;
+        for _ in 0..count.min(DIRENTRY_CACHE_CAPACITY) {
+            let Some(index) = next else {
+                break;
+            };
+            let entry = match direntries.get(&(node, index)) {
+                Some(entry) => entry.clone(),
+                None => match self.inner.get_direntry(node, index) {
+                    Ok(entry) => entry,
+                    Err(_) => break,
+                },
+            };
+            next = entry.next_entry;
+
+            while direntries.len() >= DIRENTRY_CACHE_CAPACITY {
+                direntries.pop_first();
+            }
+            direntries.insert((node, index), entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{transient::TransientStorage, types::FileName};
+
+    use super::*;
+
+    #[test]
+    fn read_through_the_cache() {
+        let mut storage = CachedStorage::new(Box::new(TransientStorage::new()), 2);
+        let node = storage.new_node();
+
+        storage.write_filechunk(node, 0, 0, &[42; 10]).unwrap();
+
+        let mut buf = [0; 10];
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        assert_eq!(buf, [42; 10]);
+
+        let stats = storage.stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, 1);
+
+        // the cached chunk is updated on write
+        storage.write_filechunk(node, 0, 5, &[1; 5]).unwrap();
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        assert_eq!(buf, [42, 42, 42, 42, 42, 1, 1, 1, 1, 1]);
+
+        storage.rm_filechunk(node, 0);
+        assert!(!storage.is_cached(node, 0));
+        assert_eq!(
+            storage.read_filechunk(node, 0, 0, &mut buf),
+            Err(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn least_recently_used_chunk_is_evicted() {
+        let mut storage = CachedStorage::new(Box::new(TransientStorage::new()), 2);
+        let node = storage.new_node();
+
+        let mut buf = [0; 1];
+        for index in 0..3 {
+            storage
+                .write_filechunk(node, index, 0, &[index as u8])
+                .unwrap();
+        }
+
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        storage.read_filechunk(node, 1, 0, &mut buf).unwrap();
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        storage.read_filechunk(node, 2, 0, &mut buf).unwrap();
+
+        assert!(storage.is_cached(node, 0));
+        assert!(!storage.is_cached(node, 1));
+        assert!(storage.is_cached(node, 2));
+    }
+
+    #[test]
+    fn pinned_chunks_are_not_evicted() {
+        let mut storage = CachedStorage::new(Box::new(TransientStorage::new()), 2);
+        let hot = storage.new_node();
+        let other = storage.new_node();
+
+        storage.set_pinned(hot, true);
+        storage.write_filechunk(hot, 0, 0, &[1]).unwrap();
+        storage.prefetch_filechunks(hot, 0, 1);
+        let mut buf = [0; 1];
+        for index in 0..4 {
+            storage.write_filechunk(other, index, 0, &[2]).unwrap();
+            storage.read_filechunk(other, index, 0, &mut buf).unwrap();
+        }
+
+        // the pinned chunk is kept apart from the two chunks the cache holds
+        assert!(storage.is_cached(hot, 0));
+        let stats = storage.stats();
+        assert_eq!(stats.cached_chunks, 3);
+        assert_eq!(stats.pinned_chunks, 1);
+
+        // a hint to drop the chunks keeps the pinned ones
+        storage.evict_filechunks(hot, 0, 1);
+        storage.evict_filechunks(other, 0, FileChunkIndex::MAX);
+        assert!(storage.is_cached(hot, 0));
+        assert_eq!(storage.stats().cached_chunks, 1);
+
+        // once unpinned it is the least recently used chunk
+        storage.read_filechunk(other, 3, 0, &mut buf).unwrap();
+        storage.read_filechunk(other, 2, 0, &mut buf).unwrap();
+        storage.set_pinned(hot, false);
+        assert!(!storage.is_cached(hot, 0));
+        assert_eq!(storage.stats().pinned_chunks, 0);
+    }
+
+    #[test]
+    fn prefetch_loads_existing_chunks() {
+        let mut storage = CachedStorage::new(Box::new(TransientStorage::new()), 8);
+        let node = storage.new_node();
+
+        storage.write_filechunk(node, 0, 0, &[1]).unwrap();
+        storage.write_filechunk(node, 1, 0, &[2]).unwrap();
+
+        storage.prefetch_filechunks(node, 0, 4);
+
+        assert!(storage.is_cached(node, 0));
+        assert!(storage.is_cached(node, 1));
+        assert_eq!(storage.stats().cached_chunks, 2);
+    }
+
+    #[test]
+    fn prefetched_direntries_are_cached() {
+        let mut storage = CachedStorage::new(Box::new(TransientStorage::new()), 2);
+        let root = storage.root_node();
+
+        let entry = |name: &str, next_entry| DirEntry {
+            name: FileName::new(name.as_bytes()).unwrap(),
+            node: 10,
+            next_entry,
+            prev_entry: None,
+        };
+        storage.put_direntry(root, 1, entry("a", Some(2)));
+        storage.put_direntry(root, 2, entry("b", Some(3)));
+        storage.put_direntry(root, 3, entry("c", None));
+        let mut metadata = storage.get_metadata(root).unwrap();
+        metadata.first_dir_entry = Some(1);
+        metadata.last_dir_entry = Some(3);
+        storage.put_metadata(root, metadata);
+
+        storage.prefetch_direntries(root, 2);
+        assert_eq!(storage.stats().cached_direntries, 2);
+        assert_eq!(storage.get_direntry(root, 2).unwrap().name.bytes[0], b'b');
+
+        // a changed entry is read from the inner storage again
+        storage.put_direntry(root, 2, entry("d", Some(3)));
+        assert_eq!(storage.stats().cached_direntries, 1);
+        assert_eq!(storage.get_direntry(root, 2).unwrap().name.bytes[0], b'd');
+        storage.rm_direntry(root, 1);
+        assert!(matches!(
+            storage.get_direntry(root, 1),
+            Err(Error::NotFound)
+        ));
+        assert_eq!(storage.stats().cached_direntries, 0);
+    }
+
+    #[test]
+    fn get_cached_filechunk_is_borrowed() {
+        use crate::storage::stable::StableStorage;
+        use ic_stable_structures::DefaultMemoryImpl;
+
+        let inner = StableStorage::new(DefaultMemoryImpl::default());
+        let mut storage = CachedStorage::new(Box::new(inner), 2);
+        let node = storage.new_node();
+
+        storage.write_filechunk(node, 0, 0, &[42; 10]).unwrap();
+
+        let chunk = storage.get_filechunk(node, 0).unwrap();
+        assert!(matches!(chunk, Cow::Borrowed(_)));
+        assert_eq!(chunk[0..10], [42; 10]);
+        assert_eq!(storage.stats().misses, 1);
+
+        storage.get_filechunk(node, 0).unwrap();
+        assert_eq!(storage.stats().hits, 1);
+    }
+}
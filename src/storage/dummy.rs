@@ -28,6 +28,10 @@ impl Storage for DummyStorage {
         panic!("Not supported")
     }
 
+    fn node_generation(&self) -> u64 {
+        panic!("Not supported")
+    }
+
     fn get_version(&self) -> u32 {
         0
     }
@@ -72,7 +76,7 @@ impl Storage for DummyStorage {
         _index: FileChunkIndex,
         _offset: FileSize,
         _buf: &[u8],
-    ) {
+    ) -> Result<(), Error> {
         panic!("Not supported")
     }
 
@@ -85,7 +89,9 @@ impl Storage for DummyStorage {
 mod tests {
 
     use super::*;
-    use crate::storage::types::{FileType, Times};
+    use crate::storage::types::{
+        Collation, FileType, FsLimits, NameNormalizer, StorageClass, Times,
+    };
 
     #[test]
     #[should_panic]
@@ -102,6 +108,17 @@ mod tests {
                 times: Times::default(),
                 first_dir_entry: Some(42),
                 last_dir_entry: Some(24),
+                next_dir_entry: None,
+                parent: None,
+                generation: 0,
+                dir_generation: 0,
+                http: None,
+                expires_at: None,
+                name_normalizer: NameNormalizer::Exact,
+                storage_class: StorageClass::Stable,
+                collation: Collation::Binary,
+                region: false,
+                limits: FsLimits::default(),
             },
         )
     }
@@ -120,7 +137,7 @@ mod tests {
         let mut storage = DummyStorage::new();
         let node = storage.new_node();
 
-        let _ = storage.rm_metadata(node);
+        storage.rm_metadata(node);
     }
 
     #[test]
@@ -175,7 +192,7 @@ mod tests {
     #[should_panic]
     fn write_filechunk_panic() {
         let mut storage = DummyStorage::new();
-        storage.write_filechunk(0, 0, 0, &[]);
+        let _ = storage.write_filechunk(0, 0, 0, &[]);
     }
 
     #[test]
@@ -0,0 +1,279 @@
+// Storage adapter encrypting the file chunks of the inner storage.
+// Each chunk is XORed with a keystream of SHA-256 blocks derived from the key, the node and the chunk index,
+// so that any byte range of a chunk can be read or written without touching the rest of it.
+// The metadata and the directory entries, the file names included, are stored as is. The keystream of a chunk
+// is the same for all its versions: the adapter hides the contents from reading the raw memory, but comparing
+// two versions of a chunk reveals where they differ.
+
+use std::borrow::Cow;
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Error,
+    storage::types::{
+        chunk_range, ChunkZeroing, DirEntry, DirEntryIndex, FileChunkIndex, FileSize, Metadata,
+        Node, PhysicalWrites, RecoveryReport, ReusableSpace, StorageClass, FILE_CHUNK_SIZE,
+    },
+    storage::Storage,
+};
+
+const BLOCK_SIZE: usize = 32;
+
+pub struct EncryptedStorage {
+    inner: Box<dyn Storage>,
+    key: [u8; 32],
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Box<dyn Storage>, key: [u8; 32]) -> Self {
+        Self { inner, key }
+    }
+
+    pub fn inner(&self) -> &dyn Storage {
+        self.inner.as_ref()
+    }
+
+    pub fn into_inner(self) -> Box<dyn Storage> {
+        self.inner
+    }
+
+    // XOR the bytes at `offset` of a chunk with its keystream.
+    fn apply_keystream(&self, node: Node, index: FileChunkIndex, offset: usize, buf: &mut [u8]) {
+        let mut position = offset;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let block = position / BLOCK_SIZE;
+            let keystream = Sha256::new()
+                .chain_update(self.key)
+                .chain_update(node.to_le_bytes())
+                .chain_update(index.to_le_bytes())
+                .chain_update((block as u64).to_le_bytes())
+                .finalize();
+
+            let start = position % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - start).min(buf.len() - done);
+            for (byte, key) in buf[done..done + len].iter_mut().zip(&keystream[start..]) {
+                *byte ^= key;
+            }
+
+            position += len;
+            done += len;
+        }
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn root_node(&self) -> Node {
+        self.inner.root_node()
+    }
+
+    fn get_version(&self) -> u32 {
+        self.inner.get_version()
+    }
+
+    fn new_node(&mut self) -> Node {
+        self.inner.new_node()
+    }
+
+    fn node_generation(&self) -> u64 {
+        self.inner.node_generation()
+    }
+
+    fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
+        self.inner.get_metadata(node)
+    }
+
+    fn get_metadata_many(&self, nodes: &[Node]) -> Vec<Result<Metadata, Error>> {
+        self.inner.get_metadata_many(nodes)
+    }
+
+    fn put_metadata(&mut self, node: Node, metadata: Metadata) {
+        self.inner.put_metadata(node, metadata)
+    }
+
+    fn rm_metadata(&mut self, node: Node) {
+        self.inner.rm_metadata(node)
+    }
+
+    fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
+        self.inner.get_direntry(node, index)
+    }
+
+    fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
+        self.inner.put_direntry(node, index, entry)
+    }
+
+    fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
+        self.inner.rm_direntry(node, index)
+    }
+
+    fn read_filechunk(
+        &self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let range = chunk_range(offset, buf.len())?;
+        self.inner.read_filechunk(node, index, offset, buf)?;
+        self.apply_keystream(node, index, range.start, buf);
+        Ok(())
+    }
+
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let range = chunk_range(offset, buf.len())?;
+
+        // a new chunk is written whole, so that its unwritten bytes are read back as zeros
+        if self.inner.read_filechunk(node, index, 0, &mut []).is_err() {
+            let mut chunk = vec![0; FILE_CHUNK_SIZE];
+            chunk[range].copy_from_slice(buf);
+            self.apply_keystream(node, index, 0, &mut chunk);
+            return self.inner.write_filechunk(node, index, 0, &chunk);
+        }
+
+        let mut encrypted = buf.to_vec();
+        self.apply_keystream(node, index, range.start, &mut encrypted);
+        self.inner.write_filechunk(node, index, offset, &encrypted)
+    }
+
+    fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex) {
+        self.inner.rm_filechunk(node, index)
+    }
+
+    fn get_filechunk(&mut self, node: Node, index: FileChunkIndex) -> Result<Cow<'_, [u8]>, Error> {
+        let mut chunk = self.inner.get_filechunk(node, index)?.into_owned();
+        self.apply_keystream(node, index, 0, &mut chunk);
+        Ok(Cow::Owned(chunk))
+    }
+
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        self.inner.filechunk_indices(node, chunk_count)
+    }
+
+    // The keystream depends on the chunk position, a moved chunk is decrypted and encrypted again.
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        let chunk = self.get_filechunk(from_node, from_index)?.into_owned();
+        if (from_node, from_index) == (to_node, to_index) {
+            return Ok(());
+        }
+        self.write_filechunk(to_node, to_index, 0, &chunk)?;
+        self.rm_filechunk(from_node, from_index);
+        Ok(())
+    }
+
+    fn prefetch_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        self.inner.prefetch_filechunks(node, index, count)
+    }
+
+    fn evict_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        self.inner.evict_filechunks(node, index, count)
+    }
+
+    fn prefetch_direntries(&self, node: Node, count: usize) {
+        self.inner.prefetch_direntries(node, count)
+    }
+
+    fn set_pinned(&mut self, node: Node, pinned: bool) {
+        self.inner.set_pinned(node, pinned)
+    }
+
+    fn recover(&mut self) -> RecoveryReport {
+        self.inner.recover()
+    }
+
+    fn physical_writes(&self) -> PhysicalWrites {
+        self.inner.physical_writes()
+    }
+
+    fn reusable_space(&self) -> ReusableSpace {
+        self.inner.reusable_space()
+    }
+
+    fn set_chunk_zeroing(&mut self, zeroing: ChunkZeroing) {
+        self.inner.set_chunk_zeroing(zeroing)
+    }
+
+    fn zero_freed_chunks(&mut self, max: usize) -> usize {
+        self.inner.zero_freed_chunks(max)
+    }
+
+    fn add_orphan(&mut self, node: Node) {
+        self.inner.add_orphan(node)
+    }
+
+    fn remove_orphan(&mut self, node: Node) {
+        self.inner.remove_orphan(node)
+    }
+
+    fn orphans(&self) -> Vec<Node> {
+        self.inner.orphans()
+    }
+
+    fn set_storage_class(&mut self, node: Node, class: StorageClass) -> Result<(), Error> {
+        self.inner.set_storage_class(node, class)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::transient::TransientStorage;
+
+    use super::*;
+
+    #[test]
+    fn chunks_are_encrypted() {
+        let mut storage = EncryptedStorage::new(Box::new(TransientStorage::new()), [7; 32]);
+        let node = storage.new_node();
+
+        let data: Vec<u8> = (0..100).collect();
+        storage.write_filechunk(node, 0, 10, &data).unwrap();
+
+        let mut raw = vec![0; 100];
+        storage
+            .inner()
+            .read_filechunk(node, 0, 10, &mut raw)
+            .unwrap();
+        assert_ne!(raw, data);
+
+        // any range is decrypted
+        let mut buf = vec![0; 50];
+        storage.read_filechunk(node, 0, 43, &mut buf).unwrap();
+        assert_eq!(buf, data[33..83]);
+
+        // the unwritten bytes are read as zeros
+        let chunk = storage.get_filechunk(node, 0).unwrap().into_owned();
+        assert_eq!(chunk.len(), FILE_CHUNK_SIZE);
+        assert_eq!(&chunk[..10], &[0; 10]);
+        assert_eq!(&chunk[10..110], &data[..]);
+
+        // a moved chunk is readable at the new position
+        storage.move_filechunk(node, 0, node, 3).unwrap();
+        let mut buf = vec![0; 100];
+        storage.read_filechunk(node, 3, 10, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(
+            storage.read_filechunk(node, 0, 0, &mut []),
+            Err(Error::NotFound)
+        );
+
+        // another key reads garbage
+        let other = EncryptedStorage::new(storage.into_inner(), [8; 32]);
+        let mut buf = vec![0; 100];
+        other.read_filechunk(node, 3, 10, &mut buf).unwrap();
+        assert_ne!(buf, data);
+    }
+}
@@ -0,0 +1,441 @@
+// Storage keeping the file chunks in an append-only log.
+// A chunk is never overwritten in place: each write appends the new version of the whole chunk at the head
+// of the log and the index is updated to point to it, the old version becomes a dead slot. The writes are spread
+// over the memory instead of hitting the same pages, and a failed append leaves the previous version in place.
+// The dead slots are reclaimed by `compact`, which is not called automatically since it moves all the live chunks.
+// The metadata and the directory entries are kept in B-trees, like in `StableStorage`.
+
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    BTreeMap, Memory,
+};
+
+use crate::error::Error;
+
+use super::{
+    types::{
+        chunk_range, Collation, DirEntry, DirEntryIndex, Encoded, FileChunkIndex, FileSize,
+        FileType, FsLimits, Metadata, NameNormalizer, Node, PhysicalWrites, StorageClass, Times,
+        FILE_CHUNK_SIZE,
+    },
+    Storage,
+};
+
+const ROOT_NODE: Node = 0;
+const FS_VERSION: u32 = 1;
+
+const METADATA_MEMORY_INDEX: MemoryId = MemoryId::new(236);
+const DIRENTRY_MEMORY_INDEX: MemoryId = MemoryId::new(237);
+const CHUNK_INDEX_MEMORY_INDEX: MemoryId = MemoryId::new(238);
+const LOG_MEMORY_INDEX: MemoryId = MemoryId::new(239);
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+// The log starts with a header holding the magic bytes and the number of the appended slots.
+const LOG_MAGIC: &[u8; 8] = b"SFSLOG\x01\x00";
+const LOG_HEADER_SIZE: u64 = 16;
+
+// The position of a chunk version in the log.
+type Slot = u64;
+
+// Statistics of the chunk log.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LogStats {
+    // number of slots holding the current version of a chunk
+    pub live_chunks: u64,
+    // number of slots holding a replaced or removed chunk version
+    pub dead_chunks: u64,
+}
+
+pub struct LogStorage<M: Memory> {
+    metadata: BTreeMap<Node, Encoded<Metadata>, VirtualMemory<M>>,
+    direntry: BTreeMap<(Node, DirEntryIndex), Encoded<DirEntry>, VirtualMemory<M>>,
+    chunk_index: BTreeMap<(Node, FileChunkIndex), Slot, VirtualMemory<M>>,
+    log: VirtualMemory<M>,
+    // the slot the next chunk version is appended to
+    head: Slot,
+    next_node: Node,
+    // The generation of the created nodes, it continues after the newest stored generation.
+    generation: u64,
+    writes: PhysicalWrites,
+    // It is not used, but is needed to keep other memories alive.
+    _memory_manager: MemoryManager<M>,
+}
+
+impl<M: Memory> LogStorage<M> {
+    pub fn new(memory: M) -> Self {
+        Self::new_with_memory_indices(
+            memory,
+            METADATA_MEMORY_INDEX,
+            DIRENTRY_MEMORY_INDEX,
+            CHUNK_INDEX_MEMORY_INDEX,
+            LOG_MEMORY_INDEX,
+        )
+    }
+
+    pub fn new_with_memory_indices(
+        memory: M,
+        metadata_id: MemoryId,
+        direntry_id: MemoryId,
+        chunk_index_id: MemoryId,
+        log_id: MemoryId,
+    ) -> Self {
+        let memory_manager = MemoryManager::init(memory);
+        let log = memory_manager.get(log_id);
+
+        let head = if log.size() == 0 {
+            if log.grow(1) < 0 {
+                panic!("Failed to allocate the chunk log");
+            }
+            log.write(0, LOG_MAGIC);
+            log.write(LOG_MAGIC.len() as u64, &0u64.to_le_bytes());
+            0
+        } else {
+            let mut header = [0; LOG_HEADER_SIZE as usize];
+            log.read(0, &mut header);
+            if &header[..LOG_MAGIC.len()] != LOG_MAGIC {
+                panic!("The memory does not hold a chunk log");
+            }
+            Slot::from_le_bytes(header[LOG_MAGIC.len()..].try_into().unwrap())
+        };
+
+        let metadata: BTreeMap<Node, Encoded<Metadata>, _> =
+            BTreeMap::init(memory_manager.get(metadata_id));
+        let next_node = metadata
+            .last_key_value()
+            .map_or(ROOT_NODE + 1, |(node, _)| node + 1);
+        // the removals are not stored, the nodes created after a reload get a generation newer than any stored one
+        let generation = metadata
+            .iter()
+            .filter_map(|(_, record)| record.decode().ok())
+            .map(|metadata| metadata.generation + 1)
+            .max()
+            .unwrap_or(0);
+
+        let mut result = Self {
+            metadata,
+            direntry: BTreeMap::init(memory_manager.get(direntry_id)),
+            chunk_index: BTreeMap::init(memory_manager.get(chunk_index_id)),
+            log,
+            head,
+            next_node,
+            generation,
+            writes: PhysicalWrites::default(),
+            _memory_manager: memory_manager,
+        };
+
+        if let Err(Error::NotFound) = result.get_metadata(ROOT_NODE) {
+            let metadata = Metadata {
+                node: ROOT_NODE,
+                file_type: FileType::Directory,
+                link_count: 1,
+                size: 0,
+                times: Times::default(),
+                first_dir_entry: None,
+                last_dir_entry: None,
+                next_dir_entry: None,
+                parent: None,
+                generation: 0,
+                dir_generation: 0,
+                http: None,
+                expires_at: None,
+                name_normalizer: NameNormalizer::Exact,
+                storage_class: StorageClass::Stable,
+                collation: Collation::Binary,
+                region: false,
+                limits: FsLimits::default(),
+            };
+            result.put_metadata(ROOT_NODE, metadata);
+        }
+
+        result
+    }
+
+    // Get the statistics of the chunk log.
+    pub fn stats(&self) -> LogStats {
+        let live_chunks = self.chunk_index.len();
+        LogStats {
+            live_chunks,
+            dead_chunks: self.head - live_chunks,
+        }
+    }
+
+    // Move the live chunks to the start of the log, so that the dead slots are reused by the next writes.
+    // The chunks keep their order in the log, each one is moved at most once.
+    pub fn compact(&mut self) {
+        let mut live: Vec<(Slot, (Node, FileChunkIndex))> = self
+            .chunk_index
+            .iter()
+            .map(|(key, slot)| (slot, key))
+            .collect();
+        live.sort();
+
+        let mut chunk = vec![0; FILE_CHUNK_SIZE];
+        for (target, (slot, key)) in live.iter().enumerate() {
+            let target = target as Slot;
+            if target == *slot {
+                continue;
+            }
+            // the target slot is before the source one, it is either dead or already moved
+            self.log.read(Self::slot_offset(*slot), &mut chunk);
+            self.log.write(Self::slot_offset(target), &chunk);
+            self.chunk_index.insert(*key, target);
+            self.writes.chunk_bytes += FILE_CHUNK_SIZE as u64;
+        }
+
+        self.set_head(live.len() as Slot);
+    }
+
+    fn slot_offset(slot: Slot) -> u64 {
+        LOG_HEADER_SIZE + slot * FILE_CHUNK_SIZE as u64
+    }
+
+    fn set_head(&mut self, head: Slot) {
+        self.head = head;
+        self.log.write(LOG_MAGIC.len() as u64, &head.to_le_bytes());
+    }
+
+    // Append a chunk version to the log, the memory is grown when needed.
+    fn append(&mut self, chunk: &[u8]) -> Result<Slot, Error> {
+        let slot = self.head;
+        let end = Self::slot_offset(slot + 1);
+        let size = self.log.size() * WASM_PAGE_SIZE;
+        if end > size && self.log.grow((end - size).div_ceil(WASM_PAGE_SIZE)) < 0 {
+            return Err(Error::StorageFull);
+        }
+
+        self.log.write(Self::slot_offset(slot), chunk);
+        self.set_head(slot + 1);
+        self.writes.chunk_bytes += chunk.len() as u64;
+        Ok(slot)
+    }
+}
+
+impl<M: Memory> Storage for LogStorage<M> {
+    // Get the root node ID of the storage.
+    fn root_node(&self) -> Node {
+        ROOT_NODE
+    }
+
+    // Generate the next available node ID.
+    fn new_node(&mut self) -> Node {
+        let result = self.next_node;
+        self.next_node += 1;
+        result
+    }
+
+    fn node_generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn get_version(&self) -> u32 {
+        FS_VERSION
+    }
+
+    // Get the metadata associated with the node.
+    fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
+        self.metadata.get(&node).ok_or(Error::NotFound)?.decode()
+    }
+
+    // Update the metadata associated with the node.
+    fn put_metadata(&mut self, node: Node, metadata: Metadata) {
+        self.next_node = self.next_node.max(node + 1);
+        let record = Encoded::new(&metadata);
+        self.writes.metadata_bytes += record.raw().len() as u64;
+        self.metadata.insert(node, record);
+    }
+
+    // Remove the metadata associated with the node.
+    fn rm_metadata(&mut self, node: Node) {
+        if self.metadata.remove(&node).is_some() {
+            self.generation += 1;
+        }
+    }
+
+    // Retrieve the DirEntry instance given the Node and DirEntryIndex.
+    fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
+        self.direntry
+            .get(&(node, index))
+            .ok_or(Error::NotFound)?
+            .decode()
+    }
+
+    // Update or insert the DirEntry instance given the Node and DirEntryIndex.
+    fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
+        let record = Encoded::new(&entry);
+        self.writes.direntry_bytes += record.raw().len() as u64;
+        self.direntry.insert((node, index), record);
+    }
+
+    // Remove the DirEntry instance given the Node and DirEntryIndex.
+    fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
+        self.direntry.remove(&(node, index));
+    }
+
+    // Fill the buffer contents with data of a chosen file chunk.
+    fn read_filechunk(
+        &self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let slot = self
+            .chunk_index
+            .get(&(node, index))
+            .ok_or(Error::NotFound)?;
+        let range = chunk_range(offset, buf.len())?;
+        self.log
+            .read(Self::slot_offset(slot) + range.start as u64, buf);
+        Ok(())
+    }
+
+    // Append the new version of a chunk to the log.
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let range = chunk_range(offset, buf.len())?;
+
+        let mut chunk = vec![0; FILE_CHUNK_SIZE];
+        if let Some(slot) = self.chunk_index.get(&(node, index)) {
+            self.log.read(Self::slot_offset(slot), &mut chunk);
+        }
+        chunk[range].copy_from_slice(buf);
+
+        let slot = self.append(&chunk)?;
+        self.chunk_index.insert((node, index), slot);
+        Ok(())
+    }
+
+    // Remove file chunk from a given file node, its slot becomes dead.
+    fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex) {
+        self.chunk_index.remove(&(node, index));
+    }
+
+    // Get the indices of the written chunks of a node from the index.
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        self.chunk_index
+            .range((node, 0)..=(node, FileChunkIndex::MAX))
+            .map(|((_, index), _)| index)
+            .take_while(|index| (*index as FileSize) < chunk_count)
+            .collect()
+    }
+
+    // Move a chunk by pointing the index to its slot, the data is not copied.
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        let slot = self
+            .chunk_index
+            .get(&(from_node, from_index))
+            .ok_or(Error::NotFound)?;
+        if (from_node, from_index) == (to_node, to_index) {
+            return Ok(());
+        }
+
+        self.chunk_index.remove(&(from_node, from_index));
+        self.chunk_index.insert((to_node, to_index), slot);
+        Ok(())
+    }
+
+    fn physical_writes(&self) -> PhysicalWrites {
+        self.writes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    use super::*;
+
+    #[test]
+    fn chunks_are_appended_and_compacted() {
+        let memory = DefaultMemoryImpl::default();
+        let mut storage = LogStorage::new(memory.clone());
+        let node = storage.new_node();
+
+        storage.write_filechunk(node, 0, 0, &[1; 10]).unwrap();
+        storage.write_filechunk(node, 1, 0, &[2; 10]).unwrap();
+        storage.write_filechunk(node, 0, 5, &[3; 10]).unwrap();
+        storage.write_filechunk(node, 2, 0, &[4; 10]).unwrap();
+
+        // the overwritten version stays in the log
+        assert_eq!(
+            storage.stats(),
+            LogStats {
+                live_chunks: 3,
+                dead_chunks: 1
+            }
+        );
+        let mut buf = [0; 20];
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        assert_eq!(buf[..15], [1, 1, 1, 1, 1, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3]);
+        assert_eq!(buf[15..], [0; 5]);
+
+        storage.move_filechunk(node, 2, node, 1).unwrap();
+        storage.rm_filechunk(node, 0);
+        assert_eq!(storage.filechunk_indices(node, 10), vec![1]);
+        assert_eq!(storage.stats().dead_chunks, 3);
+
+        storage.compact();
+        assert_eq!(
+            storage.stats(),
+            LogStats {
+                live_chunks: 1,
+                dead_chunks: 0
+            }
+        );
+        storage.read_filechunk(node, 1, 0, &mut buf[..10]).unwrap();
+        assert_eq!(buf[..10], [4; 10]);
+
+        // the log is loaded after reopening
+        let mut storage = LogStorage::new(memory);
+        storage.read_filechunk(node, 1, 0, &mut buf[..10]).unwrap();
+        assert_eq!(buf[..10], [4; 10]);
+        storage.write_filechunk(node, 3, 0, &[5; 10]).unwrap();
+        assert_eq!(storage.stats().live_chunks, 2);
+    }
+
+    #[test]
+    fn file_system_on_log_storage() {
+        use crate::fs::{FdStat, FileSystem, OpenFlags, Whence};
+
+        let memory = DefaultMemoryImpl::default();
+        let mut fs = FileSystem::new(Box::new(LogStorage::new(memory.clone()))).unwrap();
+        let root = fs.root_fd();
+        let fd = fs
+            .create_file(root, "log.txt", FdStat::default(), 0)
+            .unwrap();
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        fs.write(fd, &data).unwrap();
+        fs.seek(fd, 100, Whence::SET).unwrap();
+        fs.write(fd, &[0; 10]).unwrap();
+        fs.close(fd).unwrap();
+        drop(fs);
+
+        let mut fs = FileSystem::new(Box::new(LogStorage::new(memory))).unwrap();
+        let fd = fs
+            .open_or_create(
+                fs.root_fd(),
+                "log.txt",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+        let mut buf = vec![0; data.len()];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(buf[..100], data[..100]);
+        assert_eq!(buf[100..110], [0; 10]);
+        assert_eq!(buf[110..], data[110..]);
+    }
+}
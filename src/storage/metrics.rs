@@ -0,0 +1,289 @@
+use std::{borrow::Cow, cell::Cell};
+
+use crate::{
+    error::Error,
+    storage::types::{
+        ChunkZeroing, DirEntry, DirEntryIndex, FileChunkIndex, FileSize, Metadata, Node,
+        PhysicalWrites, RecoveryReport, ReusableSpace, StorageClass,
+    },
+    storage::Storage,
+};
+
+// The numbers of the calls passed to the inner storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StorageMetrics {
+    pub metadata_reads: u64,
+    pub metadata_writes: u64,
+    pub direntry_reads: u64,
+    pub direntry_writes: u64,
+    pub chunk_reads: u64,
+    pub chunk_writes: u64,
+    pub chunk_removals: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // The calls failed with an error.
+    pub errors: u64,
+}
+
+// Storage adapter counting the calls passed to the inner storage.
+pub struct MetricsStorage {
+    inner: Box<dyn Storage>,
+    metrics: Cell<StorageMetrics>,
+}
+
+impl MetricsStorage {
+    pub fn new(inner: Box<dyn Storage>) -> Self {
+        Self {
+            inner,
+            metrics: Cell::new(StorageMetrics::default()),
+        }
+    }
+
+    // Get the numbers of the calls since the adapter was created or the metrics were reset.
+    pub fn metrics(&self) -> StorageMetrics {
+        self.metrics.get()
+    }
+
+    pub fn reset_metrics(&self) {
+        self.metrics.set(StorageMetrics::default());
+    }
+
+    pub fn inner(&self) -> &dyn Storage {
+        self.inner.as_ref()
+    }
+
+    pub fn into_inner(self) -> Box<dyn Storage> {
+        self.inner
+    }
+
+    fn record(&self, update: impl FnOnce(&mut StorageMetrics)) {
+        let mut metrics = self.metrics.get();
+        update(&mut metrics);
+        self.metrics.set(metrics);
+    }
+
+    // Record a successful call with `update`, or count the error.
+    fn count<T>(
+        &self,
+        result: Result<T, Error>,
+        update: impl FnOnce(&mut StorageMetrics),
+    ) -> Result<T, Error> {
+        match &result {
+            Ok(_) => self.record(update),
+            Err(_) => self.record(|m| m.errors += 1),
+        }
+        result
+    }
+}
+
+impl Storage for MetricsStorage {
+    fn root_node(&self) -> Node {
+        self.inner.root_node()
+    }
+
+    fn get_version(&self) -> u32 {
+        self.inner.get_version()
+    }
+
+    fn new_node(&mut self) -> Node {
+        self.inner.new_node()
+    }
+
+    fn node_generation(&self) -> u64 {
+        self.inner.node_generation()
+    }
+
+    fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
+        self.count(self.inner.get_metadata(node), |m| m.metadata_reads += 1)
+    }
+
+    fn get_metadata_many(&self, nodes: &[Node]) -> Vec<Result<Metadata, Error>> {
+        self.inner
+            .get_metadata_many(nodes)
+            .into_iter()
+            .map(|result| self.count(result, |m| m.metadata_reads += 1))
+            .collect()
+    }
+
+    fn put_metadata(&mut self, node: Node, metadata: Metadata) {
+        self.inner.put_metadata(node, metadata);
+        self.record(|m| m.metadata_writes += 1);
+    }
+
+    fn rm_metadata(&mut self, node: Node) {
+        self.inner.rm_metadata(node);
+        self.record(|m| m.metadata_writes += 1);
+    }
+
+    fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
+        self.count(self.inner.get_direntry(node, index), |m| {
+            m.direntry_reads += 1
+        })
+    }
+
+    fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
+        self.inner.put_direntry(node, index, entry);
+        self.record(|m| m.direntry_writes += 1);
+    }
+
+    fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
+        self.inner.rm_direntry(node, index);
+        self.record(|m| m.direntry_writes += 1);
+    }
+
+    fn read_filechunk(
+        &self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let result = self.inner.read_filechunk(node, index, offset, buf);
+        self.count(result, |m| {
+            m.chunk_reads += 1;
+            m.bytes_read += buf.len() as u64;
+        })
+    }
+
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let result = self.inner.write_filechunk(node, index, offset, buf);
+        self.count(result, |m| {
+            m.chunk_writes += 1;
+            m.bytes_written += buf.len() as u64;
+        })
+    }
+
+    fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex) {
+        self.inner.rm_filechunk(node, index);
+        self.record(|m| m.chunk_removals += 1);
+    }
+
+    fn get_filechunk(&mut self, node: Node, index: FileChunkIndex) -> Result<Cow<'_, [u8]>, Error> {
+        let mut metrics = self.metrics.get();
+        let result = self.inner.get_filechunk(node, index);
+        match &result {
+            Ok(chunk) => {
+                metrics.chunk_reads += 1;
+                metrics.bytes_read += chunk.len() as u64;
+            }
+            Err(_) => metrics.errors += 1,
+        }
+        self.metrics.set(metrics);
+        result
+    }
+
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        self.inner.filechunk_indices(node, chunk_count)
+    }
+
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        let result = self
+            .inner
+            .move_filechunk(from_node, from_index, to_node, to_index);
+        self.count(result, |m| m.chunk_writes += 1)
+    }
+
+    fn prefetch_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        self.inner.prefetch_filechunks(node, index, count)
+    }
+
+    fn evict_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        self.inner.evict_filechunks(node, index, count)
+    }
+
+    fn prefetch_direntries(&self, node: Node, count: usize) {
+        self.inner.prefetch_direntries(node, count)
+    }
+
+    fn set_pinned(&mut self, node: Node, pinned: bool) {
+        self.inner.set_pinned(node, pinned)
+    }
+
+    fn recover(&mut self) -> RecoveryReport {
+        self.inner.recover()
+    }
+
+    fn physical_writes(&self) -> PhysicalWrites {
+        self.inner.physical_writes()
+    }
+
+    fn reusable_space(&self) -> ReusableSpace {
+        self.inner.reusable_space()
+    }
+
+    fn set_chunk_zeroing(&mut self, zeroing: ChunkZeroing) {
+        self.inner.set_chunk_zeroing(zeroing)
+    }
+
+    fn zero_freed_chunks(&mut self, max: usize) -> usize {
+        self.inner.zero_freed_chunks(max)
+    }
+
+    fn add_orphan(&mut self, node: Node) {
+        self.inner.add_orphan(node)
+    }
+
+    fn remove_orphan(&mut self, node: Node) {
+        self.inner.remove_orphan(node)
+    }
+
+    fn orphans(&self) -> Vec<Node> {
+        self.inner.orphans()
+    }
+
+    fn set_storage_class(&mut self, node: Node, class: StorageClass) -> Result<(), Error> {
+        self.inner.set_storage_class(node, class)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::transient::TransientStorage;
+
+    use super::*;
+
+    #[test]
+    fn calls_are_counted() {
+        let mut storage = MetricsStorage::new(Box::new(TransientStorage::new()));
+        let node = storage.new_node();
+
+        storage.write_filechunk(node, 0, 0, &[1; 10]).unwrap();
+        storage.write_filechunk(node, 1, 5, &[2; 3]).unwrap();
+        let mut buf = [0; 4];
+        storage.read_filechunk(node, 0, 2, &mut buf).unwrap();
+        assert_eq!(
+            storage.read_filechunk(node, 9, 0, &mut buf),
+            Err(Error::NotFound)
+        );
+        storage.rm_filechunk(node, 1);
+        assert_eq!(storage.get_metadata(node).map(|_| ()), Err(Error::NotFound));
+
+        assert_eq!(
+            storage.metrics(),
+            StorageMetrics {
+                chunk_reads: 1,
+                chunk_writes: 2,
+                chunk_removals: 1,
+                bytes_read: 4,
+                bytes_written: 13,
+                errors: 2,
+                ..Default::default()
+            }
+        );
+
+        storage.reset_metrics();
+        assert_eq!(storage.metrics(), StorageMetrics::default());
+    }
+}
@@ -0,0 +1,274 @@
+use std::borrow::Cow;
+
+use crate::{
+    error::Error,
+    storage::types::{
+        ChunkZeroing, DirEntry, DirEntryIndex, FileChunkIndex, FileSize, Metadata, Node,
+        PhysicalWrites, RecoveryReport, ReusableSpace, StorageClass, FILE_CHUNK_SIZE,
+    },
+    storage::Storage,
+};
+
+// Storage adapter limiting the space taken by the file chunks of the inner storage.
+// Each stored chunk takes FILE_CHUNK_SIZE bytes, a write adding a chunk over the limit fails with `StorageFull`.
+// The chunks stored before the adapter was created are not counted unless the usage is set with `set_used_bytes`.
+pub struct QuotaStorage {
+    inner: Box<dyn Storage>,
+    max_bytes: FileSize,
+    used_bytes: FileSize,
+}
+
+impl QuotaStorage {
+    pub fn new(inner: Box<dyn Storage>, max_bytes: FileSize) -> Self {
+        Self {
+            inner,
+            max_bytes,
+            used_bytes: 0,
+        }
+    }
+
+    pub fn max_bytes(&self) -> FileSize {
+        self.max_bytes
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: FileSize) {
+        self.max_bytes = max_bytes;
+    }
+
+    // The space taken by the chunks added through the adapter.
+    pub fn used_bytes(&self) -> FileSize {
+        self.used_bytes
+    }
+
+    pub fn set_used_bytes(&mut self, used_bytes: FileSize) {
+        self.used_bytes = used_bytes;
+    }
+
+    pub fn inner(&self) -> &dyn Storage {
+        self.inner.as_ref()
+    }
+
+    pub fn into_inner(self) -> Box<dyn Storage> {
+        self.inner
+    }
+
+    fn is_stored(&self, node: Node, index: FileChunkIndex) -> bool {
+        self.inner.read_filechunk(node, index, 0, &mut []).is_ok()
+    }
+
+    // Reserve the space of a new chunk.
+    fn reserve_chunk(&mut self) -> Result<(), Error> {
+        let used_bytes = self.used_bytes + FILE_CHUNK_SIZE as FileSize;
+        if used_bytes > self.max_bytes {
+            return Err(Error::StorageFull);
+        }
+        self.used_bytes = used_bytes;
+        Ok(())
+    }
+
+    fn release_chunk(&mut self) {
+        self.used_bytes = self.used_bytes.saturating_sub(FILE_CHUNK_SIZE as FileSize);
+    }
+}
+
+impl Storage for QuotaStorage {
+    fn root_node(&self) -> Node {
+        self.inner.root_node()
+    }
+
+    fn get_version(&self) -> u32 {
+        self.inner.get_version()
+    }
+
+    fn new_node(&mut self) -> Node {
+        self.inner.new_node()
+    }
+
+    fn node_generation(&self) -> u64 {
+        self.inner.node_generation()
+    }
+
+    fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
+        self.inner.get_metadata(node)
+    }
+
+    fn get_metadata_many(&self, nodes: &[Node]) -> Vec<Result<Metadata, Error>> {
+        self.inner.get_metadata_many(nodes)
+    }
+
+    fn put_metadata(&mut self, node: Node, metadata: Metadata) {
+        self.inner.put_metadata(node, metadata)
+    }
+
+    fn rm_metadata(&mut self, node: Node) {
+        self.inner.rm_metadata(node)
+    }
+
+    fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
+        self.inner.get_direntry(node, index)
+    }
+
+    fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
+        self.inner.put_direntry(node, index, entry)
+    }
+
+    fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
+        self.inner.rm_direntry(node, index)
+    }
+
+    fn read_filechunk(
+        &self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        self.inner.read_filechunk(node, index, offset, buf)
+    }
+
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        if self.is_stored(node, index) {
+            return self.inner.write_filechunk(node, index, offset, buf);
+        }
+
+        self.reserve_chunk()?;
+        let result = self.inner.write_filechunk(node, index, offset, buf);
+        if result.is_err() {
+            self.release_chunk();
+        }
+        result
+    }
+
+    fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex) {
+        if self.is_stored(node, index) {
+            self.release_chunk();
+        }
+        self.inner.rm_filechunk(node, index)
+    }
+
+    fn get_filechunk(&mut self, node: Node, index: FileChunkIndex) -> Result<Cow<'_, [u8]>, Error> {
+        self.inner.get_filechunk(node, index)
+    }
+
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        self.inner.filechunk_indices(node, chunk_count)
+    }
+
+    // A chunk moved over a stored one frees the space of the replaced chunk.
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        let replaced =
+            (from_node, from_index) != (to_node, to_index) && self.is_stored(to_node, to_index);
+        self.inner
+            .move_filechunk(from_node, from_index, to_node, to_index)?;
+        if replaced {
+            self.release_chunk();
+        }
+        Ok(())
+    }
+
+    fn prefetch_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        self.inner.prefetch_filechunks(node, index, count)
+    }
+
+    fn evict_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        self.inner.evict_filechunks(node, index, count)
+    }
+
+    fn prefetch_direntries(&self, node: Node, count: usize) {
+        self.inner.prefetch_direntries(node, count)
+    }
+
+    fn set_pinned(&mut self, node: Node, pinned: bool) {
+        self.inner.set_pinned(node, pinned)
+    }
+
+    fn recover(&mut self) -> RecoveryReport {
+        self.inner.recover()
+    }
+
+    fn physical_writes(&self) -> PhysicalWrites {
+        self.inner.physical_writes()
+    }
+
+    fn reusable_space(&self) -> ReusableSpace {
+        self.inner.reusable_space()
+    }
+
+    fn set_chunk_zeroing(&mut self, zeroing: ChunkZeroing) {
+        self.inner.set_chunk_zeroing(zeroing)
+    }
+
+    fn zero_freed_chunks(&mut self, max: usize) -> usize {
+        self.inner.zero_freed_chunks(max)
+    }
+
+    fn add_orphan(&mut self, node: Node) {
+        self.inner.add_orphan(node)
+    }
+
+    fn remove_orphan(&mut self, node: Node) {
+        self.inner.remove_orphan(node)
+    }
+
+    fn orphans(&self) -> Vec<Node> {
+        self.inner.orphans()
+    }
+
+    fn set_storage_class(&mut self, node: Node, class: StorageClass) -> Result<(), Error> {
+        self.inner.set_storage_class(node, class)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::transient::TransientStorage;
+
+    use super::*;
+
+    #[test]
+    fn new_chunks_are_limited() {
+        let max_bytes = FILE_CHUNK_SIZE as FileSize * 2;
+        let mut storage = QuotaStorage::new(Box::new(TransientStorage::new()), max_bytes);
+        let node = storage.new_node();
+
+        storage.write_filechunk(node, 0, 0, &[1; 10]).unwrap();
+        storage.write_filechunk(node, 1, 0, &[2; 10]).unwrap();
+        assert_eq!(storage.used_bytes(), max_bytes);
+
+        // the stored chunks can be overwritten, adding one fails
+        storage.write_filechunk(node, 0, 5, &[3; 10]).unwrap();
+        assert_eq!(
+            storage.write_filechunk(node, 2, 0, &[4; 10]),
+            Err(Error::StorageFull)
+        );
+        assert_eq!(
+            storage.read_filechunk(node, 2, 0, &mut []),
+            Err(Error::NotFound)
+        );
+
+        // moving a chunk over another one frees its space
+        storage.move_filechunk(node, 0, node, 1).unwrap();
+        assert_eq!(storage.used_bytes(), max_bytes / 2);
+        storage.rm_filechunk(node, 1);
+        storage.rm_filechunk(node, 1);
+        assert_eq!(storage.used_bytes(), 0);
+
+        storage.set_max_bytes(0);
+        assert_eq!(
+            storage.write_filechunk(node, 0, 0, &[1]),
+            Err(Error::StorageFull)
+        );
+    }
+}
@@ -2,13 +2,15 @@ use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     BTreeMap, Memory,
 };
+use sha2::{Digest, Sha256};
 
 use crate::error::Error;
 
 use super::{
     types::{
-        DirEntry, DirEntryIndex, FileChunk, FileChunkIndex, FileSize, FileType, Metadata, Node,
-        Times,
+        chunk_range, ChunkZeroing, Collation, DirEntry, DirEntryIndex, Encoded, FileChunk,
+        FileChunkIndex, FileSize, FileType, FsLimits, Metadata, NameNormalizer, Node,
+        PhysicalWrites, RecoveryReport, ReusableSpace, StorageClass, Times, FILE_CHUNK_SIZE,
     },
     Storage,
 };
@@ -19,14 +21,177 @@ const FS_VERSION: u32 = 1;
 const METADATA_MEMORY_INDEX: MemoryId = MemoryId::new(230);
 const DIRENTRY_MEMORY_INDEX: MemoryId = MemoryId::new(231);
 const FILECHUNK_MEMORY_INDEX: MemoryId = MemoryId::new(232);
+const CHUNK_REF_MEMORY_INDEX: MemoryId = MemoryId::new(233);
+const BLOB_MEMORY_INDEX: MemoryId = MemoryId::new(234);
+const BLOB_REFCOUNT_MEMORY_INDEX: MemoryId = MemoryId::new(235);
+const ZERO_QUEUE_MEMORY_INDEX: MemoryId = MemoryId::new(236);
+const ORPHAN_MEMORY_INDEX: MemoryId = MemoryId::new(237);
+const REUSABLE_MEMORY_INDEX: MemoryId = MemoryId::new(238);
+const DEDUP_MODE_MEMORY_INDEX: MemoryId = MemoryId::new(239);
+const NODE_COUNTER_MEMORY_INDEX: MemoryId = MemoryId::new(240);
+
+// The start of the header the memory manager keeps at the beginning of the memory: the magic, the layout version,
+// the number of the allocated buckets and the bucket size in pages. The buckets follow the first page.
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+const MEMORY_MANAGER_LAYOUT_VERSION: u8 = 1;
+const MEMORY_MANAGER_HEADER_SIZE: usize = 8;
+
+// Check that the memory manager can be initialized over the memory, it traps on a memory it cannot use.
+// An empty memory is grown to hold the header, a memory holding no memory manager is initialized from scratch.
+fn check_memory<M: Memory>(memory: &M) -> Result<(), Error> {
+    if memory.size() == 0 {
+        if memory.grow(1) < 0 {
+            return Err(Error::StorageTooSmall);
+        }
+        return Ok(());
+    }
+
+    let mut header = [0; MEMORY_MANAGER_HEADER_SIZE];
+    memory.read(0, &mut header);
+    if &header[..3] != MEMORY_MANAGER_MAGIC {
+        return Ok(());
+    }
+    if header[3] != MEMORY_MANAGER_LAYOUT_VERSION {
+        return Err(Error::UnsupportedStorageLayout);
+    }
+
+    let buckets = u16::from_le_bytes([header[4], header[5]]) as u64;
+    let bucket_size_in_pages = u16::from_le_bytes([header[6], header[7]]) as u64;
+    if memory.size() < 1 + buckets * bucket_size_in_pages {
+        return Err(Error::StorageTooSmall);
+    }
+
+    Ok(())
+}
+
+// Count a removed record as a free slot of its store.
+fn free_slot<T>(slots: &mut u64, removed: Option<T>) -> Option<T> {
+    if removed.is_some() {
+        *slots += 1;
+    }
+    removed
+}
+
+// Count a new record as taking a free slot of its store, a replaced record keeps its own.
+fn take_slot<T>(slots: &mut u64, replaced: Option<T>) {
+    if replaced.is_none() {
+        *slots = slots.saturating_sub(1);
+    }
+}
+
+// SHA-256 hash of the chunk contents.
+type ChunkHash = [u8; 32];
+
+// Statistics of the deduplicated chunk store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    // number of file chunks referencing a stored blob
+    pub chunk_refs: u64,
+    // number of distinct chunk contents stored
+    pub unique_chunks: u64,
+}
+
+// Content addressed chunk store, the file chunks reference blobs shared by all the chunks with identical contents.
+// Only the chunks are content addressed: the directory entries keep referencing the nodes, and each node keeps
+// its own metadata and chunk references.
+struct DedupIndex<M: Memory> {
+    chunk_ref: BTreeMap<(Node, FileChunkIndex), ChunkHash, VirtualMemory<M>>,
+    blob: BTreeMap<ChunkHash, FileChunk, VirtualMemory<M>>,
+    blob_refcount: BTreeMap<ChunkHash, u64, VirtualMemory<M>>,
+    // the slots of the removed blobs
+    freed_blobs: u64,
+    // overwrite the removed blobs with zeros
+    zero_freed: bool,
+}
+
+impl<M: Memory> DedupIndex<M> {
+    fn init(memory_manager: &MemoryManager<M>) -> Self {
+        Self {
+            chunk_ref: BTreeMap::init(memory_manager.get(CHUNK_REF_MEMORY_INDEX)),
+            blob: BTreeMap::init(memory_manager.get(BLOB_MEMORY_INDEX)),
+            blob_refcount: BTreeMap::init(memory_manager.get(BLOB_REFCOUNT_MEMORY_INDEX)),
+            freed_blobs: 0,
+            zero_freed: false,
+        }
+    }
+
+    fn get(&self, key: (Node, FileChunkIndex)) -> Option<FileChunk> {
+        let hash = self.chunk_ref.get(&key)?;
+        self.blob.get(&hash)
+    }
+
+    // Insert a chunk and get the number of the bytes stored, the contents are stored only for a new blob.
+    fn insert(&mut self, key: (Node, FileChunkIndex), chunk: FileChunk) -> u64 {
+        let hash: ChunkHash = Sha256::digest(chunk.bytes).into();
+
+        if self.chunk_ref.get(&key) == Some(hash) {
+            return 0;
+        }
+
+        self.remove(key);
+
+        let mut written = (hash.len() + std::mem::size_of::<u64>()) as u64;
+        let refcount = self.blob_refcount.get(&hash).unwrap_or(0);
+        if refcount == 0 {
+            take_slot(&mut self.freed_blobs, self.blob.insert(hash, chunk));
+            written += FILE_CHUNK_SIZE as u64;
+        }
+        self.blob_refcount.insert(hash, refcount + 1);
+        self.chunk_ref.insert(key, hash);
+        written
+    }
+
+    fn remove(&mut self, key: (Node, FileChunkIndex)) {
+        let Some(hash) = self.chunk_ref.remove(&key) else {
+            return;
+        };
+
+        match self.blob_refcount.get(&hash).unwrap_or(0) {
+            0 | 1 => {
+                self.blob_refcount.remove(&hash);
+                if self.zero_freed && self.blob.contains_key(&hash) {
+                    self.blob.insert(hash, FileChunk::default());
+                }
+                free_slot(&mut self.freed_blobs, self.blob.remove(&hash));
+            }
+            refcount => {
+                self.blob_refcount.insert(hash, refcount - 1);
+            }
+        }
+    }
+}
 
 #[repr(C)]
 pub struct StableStorage<M: Memory> {
     version: u32,
-    metadata: BTreeMap<Node, Metadata, VirtualMemory<M>>,
-    direntry: BTreeMap<(Node, DirEntryIndex), DirEntry, VirtualMemory<M>>,
+    // the records are decoded when read, a corrupted record is reported instead of trapping
+    metadata: BTreeMap<Node, Encoded<Metadata>, VirtualMemory<M>>,
+    direntry: BTreeMap<(Node, DirEntryIndex), Encoded<DirEntry>, VirtualMemory<M>>,
     filechunk: BTreeMap<(Node, FileChunkIndex), FileChunk, VirtualMemory<M>>,
     next_node: Node,
+    // The deduplicated chunks, present if the chunk store was ever used in this memory.
+    dedup: Option<DedupIndex<M>>,
+    // Store the written chunks deduplicated, the mode is stored in its own memory once it is turned on.
+    dedup_writes: bool,
+    zeroing: ChunkZeroing,
+    // The plain chunks freed with the deferred zeroing, present once a chunk was freed so.
+    // The chunks stay in their store until they are zeroed, they are read as missing.
+    zero_queue: Option<BTreeMap<(Node, FileChunkIndex), (), VirtualMemory<M>>>,
+    // The nodes kept without directory entries, present once a node was kept so.
+    orphans: Option<BTreeMap<Node, (), VirtualMemory<M>>>,
+    writes: PhysicalWrites,
+    // The node numbers below the stored counter are not given out again, even if their metadata was removed.
+    // The counter is stored with the node generation when a node is removed, the memory is empty until then.
+    node_counter: VirtualMemory<M>,
+    // The generation of the created nodes, the number of the removed nodes.
+    generation: u64,
+    // The slots of the removed records since the storage was created, the chunks of the deduplicated
+    // store are counted by its index.
+    reusable: ReusableSpace,
+    // The reusable slots and the freed blobs are stored when they change, the memory is empty until
+    // a record is removed. The memories written by the older versions start counting from zero.
+    reusable_counter: VirtualMemory<M>,
+    stored_reusable: [u64; 4],
     // It is not used, but is needed to keep other memories alive.
     _memory_manager: MemoryManager<M>,
 }
@@ -47,19 +212,121 @@ impl<M: Memory> StableStorage<M> {
         direntry_id: MemoryId,
         filechunk_id: MemoryId,
     ) -> Self {
+        match Self::try_new_with_memory_indices(memory, metadata_id, direntry_id, filechunk_id) {
+            Ok(result) => result,
+            Err(err) => panic!("Cannot mount the stable storage: {:?}", err),
+        }
+    }
+
+    // Create a storage over the memory, or fail if the memory is too small for the data it claims to hold,
+    // cannot grow to hold the memory manager header or holds an unknown memory manager layout.
+    pub fn try_new(memory: M) -> Result<Self, Error> {
+        Self::try_new_with_memory_indices(
+            memory,
+            METADATA_MEMORY_INDEX,
+            DIRENTRY_MEMORY_INDEX,
+            FILECHUNK_MEMORY_INDEX,
+        )
+    }
+
+    pub fn try_new_with_memory_indices(
+        memory: M,
+        metadata_id: MemoryId,
+        direntry_id: MemoryId,
+        filechunk_id: MemoryId,
+    ) -> Result<Self, Error> {
+        check_memory(&memory)?;
         let memory_manager = MemoryManager::init(memory);
 
+        // load the chunk store written by an earlier deduplicating instance
+        let dedup = if memory_manager.get(CHUNK_REF_MEMORY_INDEX).size() > 0 {
+            Some(DedupIndex::init(&memory_manager))
+        } else {
+            None
+        };
+
+        // a memory deduplicated once keeps deduplicating the writes
+        let dedup_mode = memory_manager.get(DEDUP_MODE_MEMORY_INDEX);
+        let dedup_writes = dedup.is_some() && dedup_mode.size() > 0 && {
+            let mut mode = [0; 1];
+            dedup_mode.read(0, &mut mode);
+            mode[0] == 1
+        };
+
+        let zero_queue = if memory_manager.get(ZERO_QUEUE_MEMORY_INDEX).size() > 0 {
+            Some(BTreeMap::init(memory_manager.get(ZERO_QUEUE_MEMORY_INDEX)))
+        } else {
+            None
+        };
+
+        let orphans = if memory_manager.get(ORPHAN_MEMORY_INDEX).size() > 0 {
+            Some(BTreeMap::init(memory_manager.get(ORPHAN_MEMORY_INDEX)))
+        } else {
+            None
+        };
+
+        let metadata: BTreeMap<Node, Encoded<Metadata>, _> =
+            BTreeMap::init(memory_manager.get(metadata_id));
+        // the nodes of an initialized memory are not reused
+        let next_node = metadata
+            .last_key_value()
+            .map_or(ROOT_NODE + 1, |(node, _)| node + 1);
+
+        let node_counter = memory_manager.get(NODE_COUNTER_MEMORY_INDEX);
+        let mut stored_next_node = 0;
+        // the counters of the older versions hold no generation, it is read as 0
+        let mut generation = 0;
+        if node_counter.size() > 0 {
+            let mut buf = [0; 8];
+            node_counter.read(0, &mut buf);
+            stored_next_node = Node::from_le_bytes(buf);
+            node_counter.read(8, &mut buf);
+            generation = u64::from_le_bytes(buf);
+        }
+        let next_node = next_node.max(stored_next_node);
+
+        let reusable_counter = memory_manager.get(REUSABLE_MEMORY_INDEX);
+        let mut stored_reusable = [0; 4];
+        if reusable_counter.size() > 0 {
+            let mut buf = [0; 8];
+            for (position, counter) in stored_reusable.iter_mut().enumerate() {
+                reusable_counter.read(position as u64 * 8, &mut buf);
+                *counter = u64::from_le_bytes(buf);
+            }
+        }
+        let [chunks, metadata_records, direntry_records, freed_blobs] = stored_reusable;
+        let mut dedup = dedup;
+        if let Some(dedup) = &mut dedup {
+            dedup.freed_blobs = freed_blobs;
+        }
+
         let mut result = Self {
             version: FS_VERSION,
-            metadata: BTreeMap::init(memory_manager.get(metadata_id)),
+            metadata,
             direntry: BTreeMap::init(memory_manager.get(direntry_id)),
             filechunk: BTreeMap::init(memory_manager.get(filechunk_id)),
-            next_node: ROOT_NODE + 1,
+            next_node,
+            dedup,
+            dedup_writes,
+            zeroing: ChunkZeroing::Never,
+            zero_queue,
+            orphans,
+            writes: PhysicalWrites::default(),
+            node_counter,
+            generation,
+            reusable: ReusableSpace {
+                chunks,
+                metadata_records,
+                direntry_records,
+            },
+            reusable_counter,
+            stored_reusable,
             _memory_manager: memory_manager,
         };
 
         match result.get_metadata(ROOT_NODE) {
-            Ok(_) => {}
+            // a corrupted root is replaced by `recover`
+            Ok(_) | Err(Error::DataCorrupted) => {}
             Err(Error::NotFound) => {
                 let metadata = Metadata {
                     node: ROOT_NODE,
@@ -69,6 +336,17 @@ impl<M: Memory> StableStorage<M> {
                     times: Times::default(),
                     first_dir_entry: None,
                     last_dir_entry: None,
+                    next_dir_entry: None,
+                    parent: None,
+                    generation: 0,
+                    dir_generation: 0,
+                    http: None,
+                    expires_at: None,
+                    name_normalizer: NameNormalizer::Exact,
+                    storage_class: StorageClass::Stable,
+                    collation: Collation::Binary,
+                    region: false,
+                    limits: FsLimits::default(),
                 };
                 result.put_metadata(ROOT_NODE, metadata);
             }
@@ -77,8 +355,189 @@ impl<M: Memory> StableStorage<M> {
             }
         }
 
+        Ok(result)
+    }
+
+    // Create a storage keeping the identical file chunks only once, the chunks are indexed by their content hash.
+    // The contents are compared chunk by chunk: two files share a chunk only if the chunk holds the same bytes
+    // at the same chunk offsets, shifted data is stored again. The files are not addressed by a hash of their
+    // whole contents, a copy of a file is still a node of its own with a directory entry naming that node.
+    // Reading the chunks written earlier without deduplication is still supported. The mode is stored with
+    // the storage, `new` keeps deduplicating the writes to a memory created with deduplication.
+    pub fn new_with_dedup(memory: M) -> Self {
+        let mut result = Self::new(memory);
+        if result.dedup.is_none() {
+            result.dedup = Some(DedupIndex::init(&result._memory_manager));
+        }
+        result.dedup_writes = true;
+
+        let dedup_mode = result._memory_manager.get(DEDUP_MODE_MEMORY_INDEX);
+        if dedup_mode.size() > 0 || dedup_mode.grow(1) >= 0 {
+            dedup_mode.write(0, &[1]);
+        }
         result
     }
+
+    // Get the statistics of the deduplicated chunk store.
+    pub fn dedup_stats(&self) -> DedupStats {
+        match &self.dedup {
+            Some(dedup) => DedupStats {
+                chunk_refs: dedup.chunk_ref.len(),
+                unique_chunks: dedup.blob.len(),
+            },
+            None => DedupStats::default(),
+        }
+    }
+
+    fn get_filechunk_entry(&self, node: Node, index: FileChunkIndex) -> Option<FileChunk> {
+        if self.is_queued((node, index)) {
+            return None;
+        }
+        if let Some(chunk) = self.filechunk.get(&(node, index)) {
+            return Some(chunk);
+        }
+        self.dedup.as_ref()?.get((node, index))
+    }
+
+    // A chunk waiting to be zeroed was freed already.
+    fn is_queued(&self, key: (Node, FileChunkIndex)) -> bool {
+        self.zero_queue
+            .as_ref()
+            .is_some_and(|queue| queue.contains_key(&key))
+    }
+
+    // Remove a chunk from the plain store, it is overwritten with zeros first if `zero` is set
+    // or if it was waiting to be zeroed.
+    fn release_chunk(&mut self, key: (Node, FileChunkIndex), zero: bool) {
+        let queued = match &mut self.zero_queue {
+            Some(queue) => queue.remove(&key).is_some(),
+            None => false,
+        };
+        if (zero || queued) && self.filechunk.contains_key(&key) {
+            self.filechunk.insert(key, FileChunk::default());
+            self.writes.chunk_bytes += FILE_CHUNK_SIZE as u64;
+        }
+        free_slot(&mut self.reusable.chunks, self.filechunk.remove(&key));
+    }
+
+    // Free a chunk of the plain store following the zeroing policy.
+    fn free_chunk(&mut self, key: (Node, FileChunkIndex)) {
+        match self.zeroing {
+            ChunkZeroing::Never => self.release_chunk(key, false),
+            ChunkZeroing::Immediate => self.release_chunk(key, true),
+            ChunkZeroing::Deferred => {
+                if self.filechunk.contains_key(&key) {
+                    self.zero_queue
+                        .get_or_insert_with(|| {
+                            BTreeMap::init(self._memory_manager.get(ZERO_QUEUE_MEMORY_INDEX))
+                        })
+                        .insert(key, ());
+                }
+            }
+        }
+    }
+
+    // Link the readable entries of a directory into one list again, the chains cut by the removed entries
+    // are joined in the order of their first entries.
+    fn relink_dir(&mut self, dir: Node, metadata: &mut Metadata) {
+        let entries: std::collections::BTreeMap<DirEntryIndex, DirEntry> = self
+            .direntry
+            .range((dir, 0)..=(dir, DirEntryIndex::MAX))
+            .filter_map(|((_, index), record)| Some((index, record.decode().ok()?)))
+            .collect();
+
+        // an entry starts a chain unless its previous entry links to it
+        let is_head = |index: DirEntryIndex, entry: &DirEntry| match entry.prev_entry {
+            None => true,
+            Some(prev) => entries.get(&prev).and_then(|prev| prev.next_entry) != Some(index),
+        };
+
+        // the original first entry goes first, then the other chains and the entries left in cycles
+        let mut starts: Vec<DirEntryIndex> = Vec::new();
+        starts.extend(
+            metadata
+                .first_dir_entry
+                .filter(|index| entries.contains_key(index)),
+        );
+        starts.extend(
+            entries
+                .iter()
+                .filter(|(index, entry)| is_head(**index, entry))
+                .map(|(index, _)| *index),
+        );
+        starts.extend(entries.keys().copied());
+
+        let mut order = Vec::with_capacity(entries.len());
+        let mut visited = std::collections::BTreeSet::new();
+        for start in starts {
+            let mut next = Some(start);
+            while let Some(index) = next.filter(|index| entries.contains_key(index)) {
+                if !visited.insert(index) {
+                    break;
+                }
+                order.push(index);
+                next = entries[&index].next_entry;
+            }
+        }
+
+        for (position, index) in order.iter().enumerate() {
+            let mut entry = entries[index].clone();
+            entry.prev_entry = position.checked_sub(1).map(|prev| order[prev]);
+            entry.next_entry = order.get(position + 1).copied();
+            take_slot(
+                &mut self.reusable.direntry_records,
+                self.direntry.insert((dir, *index), Encoded::new(&entry)),
+            );
+        }
+
+        metadata.first_dir_entry = order.first().copied();
+        metadata.last_dir_entry = order.last().copied();
+        metadata.size = order.len() as FileSize;
+        metadata.dir_generation += 1;
+    }
+
+    fn rm_node_chunks(&mut self, node: Node) {
+        let range = (node, 0)..=(node, FileChunkIndex::MAX);
+
+        let keys: Vec<_> = self
+            .filechunk
+            .range(range.clone())
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.free_chunk(key);
+        }
+
+        if let Some(dedup) = &mut self.dedup {
+            let keys: Vec<_> = dedup.chunk_ref.range(range).map(|(key, _)| key).collect();
+            for key in keys {
+                dedup.remove(key);
+            }
+        }
+    }
+
+    // Store the counters of the reusable slots if they changed.
+    fn store_reusable(&mut self) {
+        let counters = [
+            self.reusable.chunks,
+            self.reusable.metadata_records,
+            self.reusable.direntry_records,
+            self.dedup.as_ref().map_or(0, |dedup| dedup.freed_blobs),
+        ];
+        if counters == self.stored_reusable {
+            return;
+        }
+        if self.reusable_counter.size() == 0 && self.reusable_counter.grow(1) < 0 {
+            return;
+        }
+
+        let mut buf = [0; 32];
+        for (position, counter) in counters.iter().enumerate() {
+            buf[position * 8..(position + 1) * 8].copy_from_slice(&counter.to_le_bytes());
+        }
+        self.reusable_counter.write(0, &buf);
+        self.stored_reusable = counters;
+    }
 }
 
 impl<M: Memory> Storage for StableStorage<M> {
@@ -94,39 +553,113 @@ impl<M: Memory> Storage for StableStorage<M> {
         result
     }
 
+    fn node_generation(&self) -> u64 {
+        self.generation
+    }
+
     fn get_version(&self) -> u32 {
         self.version
     }
 
     // Get the metadata associated with the node.
     fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
-        self.metadata.get(&node).ok_or(Error::NotFound)
+        self.metadata.get(&node).ok_or(Error::NotFound)?.decode()
+    }
+
+    // Look the nodes up in their key order, the nodes close to each other are read by one range scan.
+    fn get_metadata_many(&self, nodes: &[Node]) -> Vec<Result<Metadata, Error>> {
+        let mut order: Vec<usize> = (0..nodes.len()).collect();
+        order.sort_by_key(|position| nodes[*position]);
+
+        let mut result: Vec<Result<Metadata, Error>> =
+            nodes.iter().map(|_| Err(Error::NotFound)).collect();
+        let mut position = 0;
+        while position < order.len() {
+            // the run of the nodes within a range twice as long as their count
+            let first = nodes[order[position]];
+            let mut end = position + 1;
+            while end < order.len() && nodes[order[end]] - first < 2 * (end - position + 1) as Node
+            {
+                end += 1;
+            }
+            let last = nodes[order[end - 1]];
+
+            if end - position == 1 {
+                result[order[position]] = self.get_metadata(first);
+            } else {
+                let mut run = order[position..end].iter().peekable();
+                for (node, record) in self.metadata.range(first..=last) {
+                    while let Some(index) = run.next_if(|index| nodes[**index] <= node) {
+                        if nodes[*index] == node {
+                            result[*index] = record.decode();
+                        }
+                    }
+                }
+            }
+            position = end;
+        }
+
+        result
     }
 
     // Update the metadata associated with the node.
     fn put_metadata(&mut self, node: Node, metadata: Metadata) {
         self.next_node = self.next_node.max(node + 1);
-        self.metadata.insert(node, metadata);
+        let record = Encoded::new(&metadata);
+        self.writes.metadata_bytes += record.raw().len() as u64;
+        take_slot(
+            &mut self.reusable.metadata_records,
+            self.metadata.insert(node, record),
+        );
+        self.store_reusable();
     }
 
     // Remove the metadata associated with the node.
     fn rm_metadata(&mut self, node: Node) {
-        self.metadata.remove(&node);
+        let removed = self.metadata.remove(&node);
+        if removed.is_none() {
+            return;
+        }
+        free_slot(&mut self.reusable.metadata_records, removed);
+        self.store_reusable();
+
+        // the last nodes would be given out again after a reload, and with the old generation
+        self.generation += 1;
+        if self.node_counter.size() == 0 && self.node_counter.grow(1) < 0 {
+            return;
+        }
+        let mut counter = [0; 16];
+        counter[..8].copy_from_slice(&self.next_node.to_le_bytes());
+        counter[8..].copy_from_slice(&self.generation.to_le_bytes());
+        self.node_counter.write(0, &counter);
     }
 
     // Retrieve the DirEntry instance given the Node and DirEntryIndex.
     fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
-        self.direntry.get(&(node, index)).ok_or(Error::NotFound)
+        self.direntry
+            .get(&(node, index))
+            .ok_or(Error::NotFound)?
+            .decode()
     }
 
     // Update or insert the DirEntry instance given the Node and DirEntryIndex.
     fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
-        self.direntry.insert((node, index), entry);
+        let record = Encoded::new(&entry);
+        self.writes.direntry_bytes += record.raw().len() as u64;
+        take_slot(
+            &mut self.reusable.direntry_records,
+            self.direntry.insert((node, index), record),
+        );
+        self.store_reusable();
     }
 
     // Remove the DirEntry instance given the Node and DirEntryIndex.
     fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
-        self.direntry.remove(&(node, index));
+        free_slot(
+            &mut self.reusable.direntry_records,
+            self.direntry.remove(&(node, index)),
+        );
+        self.store_reusable();
     }
 
     // Fill the buffer contents with data of a chosen file chunk.
@@ -137,33 +670,518 @@ impl<M: Memory> Storage for StableStorage<M> {
         offset: FileSize,
         buf: &mut [u8],
     ) -> Result<(), Error> {
-        let value = self.filechunk.get(&(node, index)).ok_or(Error::NotFound)?;
-        buf.copy_from_slice(&value.bytes[offset as usize..offset as usize + buf.len()]);
+        let value = self
+            .get_filechunk_entry(node, index)
+            .ok_or(Error::NotFound)?;
+        let range = chunk_range(offset, buf.len())?;
+        buf.copy_from_slice(&value.bytes[range]);
         Ok(())
     }
 
     // Insert of update a selected file chunk with the data provided in buffer.
-    fn write_filechunk(&mut self, node: Node, index: FileChunkIndex, offset: FileSize, buf: &[u8]) {
-        let mut entry = self.filechunk.get(&(node, index)).unwrap_or_default();
-        entry.bytes[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
-        self.filechunk.insert((node, index), entry);
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let range = chunk_range(offset, buf.len())?;
+        let mut entry = self.get_filechunk_entry(node, index).unwrap_or_default();
+        entry.bytes[range].copy_from_slice(buf);
+
+        // a chunk is kept in only one of the stores
+        if self.dedup.is_some() && self.dedup_writes {
+            self.release_chunk((node, index), false);
+            if let Some(dedup) = &mut self.dedup {
+                self.writes.chunk_bytes += dedup.insert((node, index), entry);
+            }
+            self.store_reusable();
+            return Ok(());
+        }
+
+        if let Some(dedup) = &mut self.dedup {
+            dedup.remove((node, index));
+        }
+        // the chunk waiting to be zeroed is overwritten in place
+        if let Some(queue) = &mut self.zero_queue {
+            queue.remove(&(node, index));
+        }
+        take_slot(
+            &mut self.reusable.chunks,
+            self.filechunk.insert((node, index), entry),
+        );
+        self.store_reusable();
+        self.writes.chunk_bytes += FILE_CHUNK_SIZE as u64;
+        Ok(())
     }
 
     // Remove file chunk from a given file node.
     fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex) {
-        self.filechunk.remove(&(node, index));
+        self.free_chunk((node, index));
+        if let Some(dedup) = &mut self.dedup {
+            dedup.remove((node, index));
+        }
+        self.store_reusable();
+    }
+
+    // Move a chunk within its store, a deduplicated chunk keeps referencing the same blob.
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        let from = (from_node, from_index);
+        let to = (to_node, to_index);
+
+        if from == to {
+            return self.read_filechunk(from_node, from_index, 0, &mut []);
+        }
+
+        let moved = if self.is_queued(from) {
+            None
+        } else {
+            self.filechunk.remove(&from)
+        };
+        if let Some(chunk) = free_slot(&mut self.reusable.chunks, moved) {
+            if let Some(dedup) = &mut self.dedup {
+                dedup.remove(to);
+            }
+            if let Some(queue) = &mut self.zero_queue {
+                queue.remove(&to);
+            }
+            take_slot(&mut self.reusable.chunks, self.filechunk.insert(to, chunk));
+            self.store_reusable();
+            self.writes.chunk_bytes += FILE_CHUNK_SIZE as u64;
+            return Ok(());
+        }
+
+        let dedup = self.dedup.as_mut().ok_or(Error::NotFound)?;
+        let hash = dedup.chunk_ref.get(&from).ok_or(Error::NotFound)?;
+        // the replaced chunk is released first, it can reference the same blob
+        dedup.remove(to);
+        dedup.chunk_ref.remove(&from);
+        dedup.chunk_ref.insert(to, hash);
+        self.release_chunk(to, false);
+        self.store_reusable();
+        self.writes.chunk_bytes += hash.len() as u64;
+
+        Ok(())
+    }
+
+    // Replace the corrupted metadata with empty nodes and unlink the corrupted directory entries.
+    // A node with directory entries becomes an empty directory they are linked to again, other nodes become
+    // empty files. The nodes referenced only by the removed entries are not reachable anymore.
+    fn recover(&mut self) -> RecoveryReport {
+        let mut report = RecoveryReport::default();
+
+        for (node, record) in self.metadata.iter() {
+            if record.decode().is_err() {
+                report.metadata.push((node, record.raw().to_vec()));
+            }
+        }
+        for (key, record) in self.direntry.iter() {
+            if record.decode().is_err() {
+                report.direntries.push((key, record.raw().to_vec()));
+            }
+        }
+
+        for (key, _) in report.direntries.iter() {
+            free_slot(
+                &mut self.reusable.direntry_records,
+                self.direntry.remove(key),
+            );
+        }
+
+        let mut dirs: Vec<Node> = report.direntries.iter().map(|((dir, _), _)| *dir).collect();
+
+        for (node, _) in report.metadata.iter() {
+            let node = *node;
+            let has_entries = self
+                .direntry
+                .range((node, 0)..=(node, DirEntryIndex::MAX))
+                .next()
+                .is_some();
+            let is_dir = node == ROOT_NODE || has_entries;
+
+            if is_dir {
+                dirs.push(node);
+            } else {
+                self.rm_node_chunks(node);
+            }
+
+            let metadata = Metadata {
+                node,
+                file_type: if is_dir {
+                    FileType::Directory
+                } else {
+                    FileType::RegularFile
+                },
+                link_count: 1,
+                size: 0,
+                times: Times::default(),
+                first_dir_entry: None,
+                last_dir_entry: None,
+                next_dir_entry: None,
+                parent: None,
+                generation: 0,
+                dir_generation: 0,
+                http: None,
+                expires_at: None,
+                name_normalizer: NameNormalizer::Exact,
+                storage_class: StorageClass::Stable,
+                collation: Collation::Binary,
+                region: false,
+                limits: FsLimits::default(),
+            };
+            take_slot(
+                &mut self.reusable.metadata_records,
+                self.metadata.insert(node, Encoded::new(&metadata)),
+            );
+        }
+
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs {
+            if let Ok(mut metadata) = self.get_metadata(dir) {
+                self.relink_dir(dir, &mut metadata);
+                take_slot(
+                    &mut self.reusable.metadata_records,
+                    self.metadata.insert(dir, Encoded::new(&metadata)),
+                );
+            }
+        }
+        self.store_reusable();
+
+        report
+    }
+
+    fn physical_writes(&self) -> PhysicalWrites {
+        self.writes
+    }
+
+    fn set_chunk_zeroing(&mut self, zeroing: ChunkZeroing) {
+        self.zeroing = zeroing;
+        if let Some(dedup) = &mut self.dedup {
+            dedup.zero_freed = zeroing != ChunkZeroing::Never;
+        }
+    }
+
+    fn zero_freed_chunks(&mut self, max: usize) -> usize {
+        let Some(queue) = &self.zero_queue else {
+            return 0;
+        };
+        let keys: Vec<_> = queue.iter().take(max).map(|(key, _)| key).collect();
+        for key in &keys {
+            self.release_chunk(*key, true);
+        }
+        self.store_reusable();
+        keys.len()
+    }
+
+    fn add_orphan(&mut self, node: Node) {
+        self.orphans
+            .get_or_insert_with(|| BTreeMap::init(self._memory_manager.get(ORPHAN_MEMORY_INDEX)))
+            .insert(node, ());
+    }
+
+    fn remove_orphan(&mut self, node: Node) {
+        if let Some(orphans) = &mut self.orphans {
+            orphans.remove(&node);
+        }
+    }
+
+    fn orphans(&self) -> Vec<Node> {
+        self.orphans.as_ref().map_or(Vec::new(), |orphans| {
+            orphans.iter().map(|(node, _)| node).collect()
+        })
+    }
+
+    fn reusable_space(&self) -> ReusableSpace {
+        let freed_blobs = self.dedup.as_ref().map_or(0, |dedup| dedup.freed_blobs);
+        ReusableSpace {
+            chunks: self.reusable.chunks + freed_blobs,
+            ..self.reusable
+        }
+    }
+
+    // Get the indices of the written chunks of a node from both chunk stores.
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        let range = (node, 0)..=(node, FileChunkIndex::MAX);
+
+        let mut result: Vec<FileChunkIndex> = self
+            .filechunk
+            .range(range.clone())
+            .map(|((_, index), _)| index)
+            .take_while(|index| (*index as FileSize) < chunk_count)
+            .filter(|index| !self.is_queued((node, *index)))
+            .collect();
+
+        if let Some(dedup) = &self.dedup {
+            result.extend(
+                dedup
+                    .chunk_ref
+                    .range(range)
+                    .map(|((_, index), _)| index)
+                    .take_while(|index| (*index as FileSize) < chunk_count),
+            );
+            result.sort();
+        }
+
+        result
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use ic_stable_structures::DefaultMemoryImpl;
+    use std::{cell::RefCell, rc::Rc};
+
+    use ic_stable_structures::{DefaultMemoryImpl, VectorMemory};
 
     use crate::storage::types::FileName;
 
     use super::*;
 
+    const WASM_PAGE_SIZE: u64 = 65536;
+
+    // A memory that cannot grow past its initial size.
+    struct FixedMemory(RefCell<Vec<u8>>);
+
+    impl Memory for FixedMemory {
+        fn size(&self) -> u64 {
+            self.0.borrow().len() as u64 / WASM_PAGE_SIZE
+        }
+
+        fn grow(&self, _pages: u64) -> i64 {
+            -1
+        }
+
+        fn read(&self, offset: u64, dst: &mut [u8]) {
+            let offset = offset as usize;
+            dst.copy_from_slice(&self.0.borrow()[offset..offset + dst.len()]);
+        }
+
+        fn write(&self, offset: u64, src: &[u8]) {
+            let offset = offset as usize;
+            self.0.borrow_mut()[offset..offset + src.len()].copy_from_slice(src);
+        }
+    }
+
+    #[test]
+    fn removed_records_are_reusable() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let node = storage.new_node();
+        let mut metadata = storage.get_metadata(ROOT_NODE).unwrap();
+        metadata.node = node;
+        storage.put_metadata(node, metadata.clone());
+        for index in 0..3 {
+            storage.write_filechunk(node, index, 0, &[1; 10]).unwrap();
+        }
+
+        storage.rm_filechunk(node, 0);
+        storage.rm_filechunk(node, 1);
+        storage.rm_filechunk(node, 1);
+        storage.rm_metadata(node);
+        assert_eq!(
+            storage.reusable_space(),
+            ReusableSpace {
+                chunks: 2,
+                metadata_records: 1,
+                direntry_records: 0,
+            }
+        );
+        assert_eq!(
+            storage.reusable_space().chunk_bytes(),
+            2 * FILE_CHUNK_SIZE as u64
+        );
+
+        // the new records take the freed slots, the rewritten ones keep theirs
+        storage.write_filechunk(node, 2, 0, &[2; 10]).unwrap();
+        storage.write_filechunk(node, 5, 0, &[2; 10]).unwrap();
+        storage.put_metadata(node, metadata);
+        assert_eq!(storage.reusable_space().chunks, 1);
+        assert_eq!(storage.reusable_space().metadata_records, 0);
+
+        // the deduplicated chunks free a slot when the last reference to the contents is removed
+        let mut storage = StableStorage::new_with_dedup(DefaultMemoryImpl::default());
+        storage.write_filechunk(node, 0, 0, &[1; 10]).unwrap();
+        storage.write_filechunk(node, 1, 0, &[1; 10]).unwrap();
+        storage.rm_filechunk(node, 0);
+        assert_eq!(storage.reusable_space().chunks, 0);
+        storage.rm_filechunk(node, 1);
+        assert_eq!(storage.reusable_space().chunks, 1);
+    }
+
+    #[test]
+    fn reusable_space_survives_reload() {
+        let memory = DefaultMemoryImpl::default();
+        let mut storage = StableStorage::new_with_dedup(memory.clone());
+        let node = storage.new_node();
+        let mut metadata = storage.get_metadata(ROOT_NODE).unwrap();
+        metadata.node = node;
+        storage.put_metadata(node, metadata);
+        for index in 0..3 {
+            storage
+                .write_filechunk(node, index, 0, &[index as u8; 10])
+                .unwrap();
+        }
+        storage.rm_filechunk(node, 0);
+        storage.rm_metadata(node);
+        let reusable = storage.reusable_space();
+        assert_eq!(reusable.chunks, 1);
+        assert_eq!(reusable.metadata_records, 1);
+
+        // the freed blobs are counted again after the upgrade
+        let mut storage = StableStorage::new_with_dedup(memory.clone());
+        assert_eq!(storage.reusable_space(), reusable);
+        storage.rm_filechunk(node, 1);
+        let reusable = storage.reusable_space();
+        assert_eq!(reusable.chunks, 2);
+
+        let storage = StableStorage::new(memory);
+        assert_eq!(storage.reusable_space(), reusable);
+    }
+
+    #[test]
+    fn unusable_memories_are_rejected() {
+        // an empty or a zeroed memory is initialized
+        let storage = StableStorage::try_new(VectorMemory::default()).unwrap();
+        assert!(storage.get_metadata(ROOT_NODE).is_ok());
+        let zeroed = Rc::new(RefCell::new(vec![0; 2 * WASM_PAGE_SIZE as usize]));
+        assert!(StableStorage::try_new(zeroed).is_ok());
+
+        assert_eq!(
+            StableStorage::try_new(FixedMemory(RefCell::new(Vec::new()))).map(|_| ()),
+            Err(Error::StorageTooSmall)
+        );
+
+        // a memory cut off after the memory manager header
+        let memory = VectorMemory::default();
+        let mut storage = StableStorage::new(memory.clone());
+        let node = storage.new_node();
+        let mut metadata = storage.get_metadata(ROOT_NODE).unwrap();
+        metadata.node = node;
+        metadata.file_type = FileType::RegularFile;
+        storage.put_metadata(node, metadata);
+        storage.write_filechunk(node, 0, 0, &[1; 10]).unwrap();
+        let bytes = memory.borrow().clone();
+
+        let truncated = Rc::new(RefCell::new(bytes[..WASM_PAGE_SIZE as usize].to_vec()));
+        assert_eq!(
+            StableStorage::try_new(truncated).map(|_| ()),
+            Err(Error::StorageTooSmall)
+        );
+
+        let mut unknown = bytes.clone();
+        unknown[3] = MEMORY_MANAGER_LAYOUT_VERSION + 1;
+        assert_eq!(
+            StableStorage::try_new(Rc::new(RefCell::new(unknown))).map(|_| ()),
+            Err(Error::UnsupportedStorageLayout)
+        );
+
+        let mut storage = StableStorage::try_new(Rc::new(RefCell::new(bytes))).unwrap();
+        assert!(storage.new_node() > node);
+        let mut buf = [0; 10];
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        assert_eq!(buf, [1; 10]);
+    }
+
+    #[test]
+    fn removed_nodes_are_not_given_out_again() {
+        let memory = DefaultMemoryImpl::default();
+        let mut storage = StableStorage::new(memory.clone());
+        let root = storage.get_metadata(ROOT_NODE).unwrap();
+        let mut last = ROOT_NODE;
+        for _ in 0..3 {
+            last = storage.new_node();
+            storage.put_metadata(
+                last,
+                Metadata {
+                    node: last,
+                    ..root.clone()
+                },
+            );
+        }
+        storage.rm_metadata(last);
+
+        let mut storage = StableStorage::new(memory);
+        assert_eq!(storage.new_node(), last + 1);
+    }
+
+    #[test]
+    fn node_generations_survive_reload() {
+        let memory = DefaultMemoryImpl::default();
+        let mut storage = StableStorage::new(memory.clone());
+        assert_eq!(storage.node_generation(), 0);
+        let root = storage.get_metadata(ROOT_NODE).unwrap();
+        let node = storage.new_node();
+        storage.put_metadata(
+            node,
+            Metadata {
+                node,
+                ..root.clone()
+            },
+        );
+        storage.rm_metadata(node);
+        storage.rm_metadata(node);
+        assert_eq!(storage.node_generation(), 1);
+
+        // the removal of an earlier node is stored too
+        let mut storage = StableStorage::new(memory.clone());
+        assert_eq!(storage.node_generation(), 1);
+        let first = storage.new_node();
+        let second = storage.new_node();
+        for node in [first, second] {
+            storage.put_metadata(
+                node,
+                Metadata {
+                    node,
+                    ..root.clone()
+                },
+            );
+        }
+        storage.rm_metadata(first);
+
+        let storage = StableStorage::new(memory);
+        assert_eq!(storage.node_generation(), 2);
+        assert!(storage.get_metadata(second).is_ok());
+    }
+
+    #[test]
+    fn metadata_is_fetched_in_batches() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.get_metadata(ROOT_NODE).unwrap();
+        for node in [1, 2, 3, 5, 100] {
+            let mut metadata = root.clone();
+            metadata.node = node;
+            metadata.size = node * 10;
+            storage.put_metadata(node, metadata);
+        }
+
+        let nodes = [100, 3, 4, 1, 3, 2000, 5];
+        let sizes: Vec<_> = storage
+            .get_metadata_many(&nodes)
+            .into_iter()
+            .map(|metadata| metadata.map(|metadata| metadata.size))
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                Ok(1000),
+                Ok(30),
+                Err(Error::NotFound),
+                Ok(10),
+                Ok(30),
+                Err(Error::NotFound),
+                Ok(50)
+            ]
+        );
+        assert!(storage.get_metadata_many(&[]).is_empty());
+    }
+
     #[test]
     fn read_and_write_filechunk() {
         let mut storage = StableStorage::new(DefaultMemoryImpl::default());
@@ -178,6 +1196,17 @@ mod tests {
                 times: Times::default(),
                 first_dir_entry: Some(42),
                 last_dir_entry: Some(24),
+                next_dir_entry: None,
+                parent: None,
+                generation: 0,
+                dir_generation: 0,
+                http: None,
+                expires_at: None,
+                name_normalizer: NameNormalizer::Exact,
+                storage_class: StorageClass::Stable,
+                collation: Collation::Binary,
+                region: false,
+                limits: FsLimits::default(),
             },
         );
         let metadata = storage.get_metadata(node).unwrap();
@@ -186,12 +1215,58 @@ mod tests {
         assert_eq!(metadata.link_count, 1);
         assert_eq!(metadata.first_dir_entry, Some(42));
         assert_eq!(metadata.last_dir_entry, Some(24));
-        storage.write_filechunk(node, 0, 0, &[42; 10]);
+        storage.write_filechunk(node, 0, 0, &[42; 10]).unwrap();
         let mut buf = [0; 10];
         storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
         assert_eq!(buf, [42; 10]);
     }
 
+    #[test]
+    fn freed_chunks_are_zeroed_by_policy() {
+        let memory = VectorMemory::default();
+        let mut storage = StableStorage::new(memory.clone());
+        let node = storage.new_node();
+        let secret = [0x5a; 64];
+        let leaked = |memory: &VectorMemory| {
+            memory
+                .borrow()
+                .windows(secret.len())
+                .any(|window| window == secret)
+        };
+
+        // by default the freed chunk stays in the memory
+        storage.write_filechunk(node, 0, 0, &secret).unwrap();
+        storage.rm_filechunk(node, 0);
+        assert!(leaked(&memory));
+
+        storage.write_filechunk(node, 0, 0, &secret).unwrap();
+        storage.set_chunk_zeroing(ChunkZeroing::Immediate);
+        storage.rm_filechunk(node, 0);
+        assert!(!leaked(&memory));
+
+        // the deferred chunk is gone for the readers but only zeroed by the maintenance
+        storage.set_chunk_zeroing(ChunkZeroing::Deferred);
+        storage.write_filechunk(node, 1, 0, &secret).unwrap();
+        storage.rm_filechunk(node, 1);
+        let mut buf = [0; 64];
+        assert_eq!(
+            storage.read_filechunk(node, 1, 0, &mut buf),
+            Err(Error::NotFound)
+        );
+        assert_eq!(storage.filechunk_indices(node, 2), Vec::<u32>::new());
+        assert!(leaked(&memory));
+
+        // the queue is kept across an upgrade
+        let mut storage = StableStorage::new(memory.clone());
+        assert_eq!(
+            storage.read_filechunk(node, 1, 0, &mut buf),
+            Err(Error::NotFound)
+        );
+        assert_eq!(storage.zero_freed_chunks(10), 1);
+        assert_eq!(storage.zero_freed_chunks(10), 0);
+        assert!(!leaked(&memory));
+    }
+
     #[test]
     fn read_and_write_direntry() {
         let mut storage = StableStorage::new(DefaultMemoryImpl::default());
@@ -215,4 +1290,174 @@ mod tests {
         assert_eq!(direntry.next_entry, Some(42));
         assert_eq!(direntry.prev_entry, Some(24));
     }
+
+    #[test]
+    fn identical_chunks_are_stored_once() {
+        let mut storage = StableStorage::new_with_dedup(DefaultMemoryImpl::default());
+        let node1 = storage.new_node();
+        let node2 = storage.new_node();
+
+        for node in [node1, node2] {
+            storage.write_filechunk(node, 0, 0, &[1; 100]).unwrap();
+            storage.write_filechunk(node, 1, 0, &[2; 100]).unwrap();
+        }
+        assert_eq!(
+            storage.dedup_stats(),
+            DedupStats {
+                chunk_refs: 4,
+                unique_chunks: 2
+            }
+        );
+
+        // changing a shared chunk does not affect the other file
+        storage.write_filechunk(node2, 0, 50, &[3; 10]).unwrap();
+        let mut buf = [0; 100];
+        storage.read_filechunk(node1, 0, 0, &mut buf).unwrap();
+        assert_eq!(buf, [1; 100]);
+        storage
+            .read_filechunk(node2, 0, 45, &mut buf[..10])
+            .unwrap();
+        assert_eq!(buf[..10], [1, 1, 1, 1, 1, 3, 3, 3, 3, 3]);
+        assert_eq!(storage.dedup_stats().unique_chunks, 3);
+
+        storage.rm_filechunk(node1, 0);
+        storage.rm_filechunk(node1, 1);
+        assert_eq!(
+            storage.dedup_stats(),
+            DedupStats {
+                chunk_refs: 2,
+                unique_chunks: 2
+            }
+        );
+        assert_eq!(
+            storage.read_filechunk(node1, 0, 0, &mut buf),
+            Err(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn dedup_store_is_loaded_after_reopening() {
+        let memory = DefaultMemoryImpl::default();
+
+        let mut storage = StableStorage::new_with_dedup(memory.clone());
+        let node = storage.new_node();
+        storage.write_filechunk(node, 0, 0, &[7; 10]).unwrap();
+
+        // the stored mode is used by a storage opened without deduplication
+        let mut storage = StableStorage::new(memory.clone());
+        let mut buf = [0; 10];
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        assert_eq!(buf, [7; 10]);
+
+        storage.write_filechunk(node, 0, 0, &[8; 5]).unwrap();
+        storage.write_filechunk(node, 1, 0, &[8; 5]).unwrap();
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        assert_eq!(buf, [8, 8, 8, 8, 8, 7, 7, 7, 7, 7]);
+        assert_eq!(
+            storage.dedup_stats(),
+            DedupStats {
+                chunk_refs: 2,
+                unique_chunks: 2
+            }
+        );
+        assert_eq!(storage.filechunk.len(), 0);
+    }
+
+    #[test]
+    fn plain_chunks_are_deduplicated_once_the_mode_is_on() {
+        let memory = DefaultMemoryImpl::default();
+        let mut storage = StableStorage::new(memory.clone());
+        let node = storage.new_node();
+        storage.write_filechunk(node, 0, 0, &[7; 10]).unwrap();
+        drop(storage);
+
+        // the plain chunk stays readable, the new writes are deduplicated
+        let mut storage = StableStorage::new_with_dedup(memory.clone());
+        storage.write_filechunk(node, 1, 0, &[7; 10]).unwrap();
+        drop(storage);
+
+        let mut storage = StableStorage::new(memory);
+        storage.write_filechunk(node, 2, 0, &[7; 10]).unwrap();
+        let mut buf = [0; 10];
+        for index in 0..3 {
+            storage.read_filechunk(node, index, 0, &mut buf).unwrap();
+            assert_eq!(buf, [7; 10]);
+        }
+        assert_eq!(storage.filechunk.len(), 1);
+        assert_eq!(
+            storage.dedup_stats(),
+            DedupStats {
+                chunk_refs: 2,
+                unique_chunks: 1
+            }
+        );
+    }
+
+    #[test]
+    fn corrupted_records_are_recovered() {
+        use crate::fs::{FileSystem, OpenFlags};
+
+        let memory = DefaultMemoryImpl::default();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root = fs.root_fd();
+        for name in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+            fs.apply_file(root, name, name.as_bytes(), 0).unwrap();
+        }
+        drop(fs);
+
+        let mut storage = StableStorage::new(memory);
+        let entries: Vec<((Node, DirEntryIndex), DirEntry)> = storage
+            .direntry
+            .range((ROOT_NODE, 0)..=(ROOT_NODE, DirEntryIndex::MAX))
+            .map(|(key, record)| (key, record.decode().unwrap()))
+            .collect();
+        let b_key = entries[1].0;
+        let d_node = entries[3].1.node;
+
+        let garbage = vec![0xff, 0x00];
+        storage
+            .direntry
+            .insert(b_key, Encoded::from_raw(garbage.clone()));
+        storage
+            .metadata
+            .insert(d_node, Encoded::from_raw(garbage.clone()));
+
+        // the corrupted records are reported instead of trapping
+        assert_eq!(
+            storage.get_metadata(d_node).map(|_| ()),
+            Err(Error::DataCorrupted)
+        );
+        assert_eq!(
+            storage.get_direntry(b_key.0, b_key.1).map(|_| ()),
+            Err(Error::DataCorrupted)
+        );
+
+        let report = storage.recover();
+        assert_eq!(report.metadata, vec![(d_node, garbage.clone())]);
+        assert_eq!(report.direntries, vec![(b_key, garbage)]);
+        assert_eq!(storage.recover(), RecoveryReport::default());
+
+        // the rest of the tree is readable
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        let root = fs.root_fd();
+        let names: Vec<String> = fs
+            .list_dir(root)
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize])
+                    .into_owned()
+            })
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        assert_eq!(names, vec!["a.txt", "c.txt", "d.txt"]);
+
+        assert_eq!(fs.open_metadata(root, "d.txt").unwrap().size, 0);
+        let fd = fs
+            .open_or_create(root, "c.txt", Default::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        let mut buf = [0; 5];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, b"c.txt");
+    }
 }
@@ -0,0 +1,365 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use crate::{
+    error::Error,
+    storage::transient::TransientStorage,
+    storage::types::{
+        ChunkZeroing, DirEntry, DirEntryIndex, FileChunkIndex, FileSize, FileType, Metadata, Node,
+        PhysicalWrites, RecoveryReport, ReusableSpace, StorageClass, FILE_CHUNK_SIZE,
+    },
+    storage::Storage,
+};
+
+// Storage adapter keeping the contents of the transient files in heap memory, everything else goes to the inner storage.
+// The metadata and the directory entries of the transient nodes stay in the inner storage, so after an upgrade
+// the transient files are still in the tree but their contents are lost and they are read as empty files.
+pub struct TieredStorage {
+    inner: Box<dyn Storage>,
+    // The chunks of the transient files.
+    heap: TransientStorage,
+    // The storage classes of the nodes, read from the metadata on the first chunk access.
+    classes: RefCell<BTreeMap<Node, StorageClass>>,
+    // The transient nodes whose contents are held in `heap`, the other transient nodes lost them on upgrade.
+    live: BTreeSet<Node>,
+}
+
+impl TieredStorage {
+    pub fn new(inner: Box<dyn Storage>) -> Self {
+        Self {
+            inner,
+            heap: TransientStorage::new(),
+            classes: RefCell::new(BTreeMap::new()),
+            live: BTreeSet::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &dyn Storage {
+        self.inner.as_ref()
+    }
+
+    pub fn into_inner(self) -> Box<dyn Storage> {
+        self.inner
+    }
+
+    fn class(&self, node: Node) -> StorageClass {
+        if let Some(class) = self.classes.borrow().get(&node) {
+            return *class;
+        }
+
+        let class = self
+            .inner
+            .get_metadata(node)
+            .map(|metadata| metadata.storage_class)
+            .unwrap_or_default();
+        self.classes.borrow_mut().insert(node, class);
+        class
+    }
+
+    fn tier(&self, node: Node) -> &dyn Storage {
+        match self.class(node) {
+            StorageClass::Stable => self.inner.as_ref(),
+            StorageClass::Transient => &self.heap,
+        }
+    }
+
+    fn tier_mut(&mut self, node: Node) -> &mut dyn Storage {
+        match self.class(node) {
+            StorageClass::Stable => self.inner.as_mut(),
+            StorageClass::Transient => &mut self.heap,
+        }
+    }
+
+    // A transient file whose contents were lost on upgrade is empty.
+    fn restore(&self, node: Node, mut metadata: Metadata) -> Metadata {
+        if metadata.storage_class == StorageClass::Transient
+            && metadata.file_type == FileType::RegularFile
+            && !self.live.contains(&node)
+        {
+            metadata.size = 0;
+        }
+        metadata
+    }
+}
+
+impl Storage for TieredStorage {
+    fn root_node(&self) -> Node {
+        self.inner.root_node()
+    }
+
+    fn get_version(&self) -> u32 {
+        self.inner.get_version()
+    }
+
+    fn new_node(&mut self) -> Node {
+        self.inner.new_node()
+    }
+
+    fn node_generation(&self) -> u64 {
+        self.inner.node_generation()
+    }
+
+    fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
+        let metadata = self.inner.get_metadata(node)?;
+        Ok(self.restore(node, metadata))
+    }
+
+    fn get_metadata_many(&self, nodes: &[Node]) -> Vec<Result<Metadata, Error>> {
+        self.inner
+            .get_metadata_many(nodes)
+            .into_iter()
+            .zip(nodes)
+            .map(|(result, node)| result.map(|metadata| self.restore(*node, metadata)))
+            .collect()
+    }
+
+    fn put_metadata(&mut self, node: Node, metadata: Metadata) {
+        match metadata.storage_class {
+            StorageClass::Stable => self.live.remove(&node),
+            StorageClass::Transient => self.live.insert(node),
+        };
+        self.classes.get_mut().insert(node, metadata.storage_class);
+        self.inner.put_metadata(node, metadata);
+    }
+
+    fn rm_metadata(&mut self, node: Node) {
+        self.live.remove(&node);
+        self.classes.get_mut().remove(&node);
+        self.inner.rm_metadata(node);
+    }
+
+    fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
+        self.inner.get_direntry(node, index)
+    }
+
+    fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
+        self.inner.put_direntry(node, index, entry)
+    }
+
+    fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
+        self.inner.rm_direntry(node, index)
+    }
+
+    fn read_filechunk(
+        &self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        self.tier(node).read_filechunk(node, index, offset, buf)
+    }
+
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        self.tier_mut(node)
+            .write_filechunk(node, index, offset, buf)
+    }
+
+    fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex) {
+        self.tier_mut(node).rm_filechunk(node, index)
+    }
+
+    fn get_filechunk(&mut self, node: Node, index: FileChunkIndex) -> Result<Cow<'_, [u8]>, Error> {
+        self.tier_mut(node).get_filechunk(node, index)
+    }
+
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        self.tier(node).filechunk_indices(node, chunk_count)
+    }
+
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        if self.class(from_node) == self.class(to_node) {
+            return self
+                .tier_mut(from_node)
+                .move_filechunk(from_node, from_index, to_node, to_index);
+        }
+
+        let chunk = self
+            .tier_mut(from_node)
+            .get_filechunk(from_node, from_index)?
+            .into_owned();
+        self.tier_mut(to_node)
+            .write_filechunk(to_node, to_index, 0, &chunk)?;
+        self.tier_mut(from_node).rm_filechunk(from_node, from_index);
+        Ok(())
+    }
+
+    fn prefetch_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        self.tier(node).prefetch_filechunks(node, index, count)
+    }
+
+    fn evict_filechunks(&self, node: Node, index: FileChunkIndex, count: FileChunkIndex) {
+        self.tier(node).evict_filechunks(node, index, count)
+    }
+
+    fn prefetch_direntries(&self, node: Node, count: usize) {
+        self.inner.prefetch_direntries(node, count)
+    }
+
+    fn set_pinned(&mut self, node: Node, pinned: bool) {
+        self.inner.set_pinned(node, pinned)
+    }
+
+    fn recover(&mut self) -> RecoveryReport {
+        self.inner.recover()
+    }
+
+    fn physical_writes(&self) -> PhysicalWrites {
+        self.inner.physical_writes()
+    }
+
+    fn reusable_space(&self) -> ReusableSpace {
+        self.inner.reusable_space()
+    }
+
+    fn set_chunk_zeroing(&mut self, zeroing: ChunkZeroing) {
+        self.inner.set_chunk_zeroing(zeroing)
+    }
+
+    fn zero_freed_chunks(&mut self, max: usize) -> usize {
+        self.inner.zero_freed_chunks(max)
+    }
+
+    fn add_orphan(&mut self, node: Node) {
+        self.inner.add_orphan(node)
+    }
+
+    fn remove_orphan(&mut self, node: Node) {
+        self.inner.remove_orphan(node)
+    }
+
+    fn orphans(&self) -> Vec<Node> {
+        self.inner.orphans()
+    }
+
+    // The chunks are moved before the class is recorded, a failed move leaves the file as it was.
+    fn set_storage_class(&mut self, node: Node, class: StorageClass) -> Result<(), Error> {
+        let mut metadata = self.get_metadata(node)?;
+        if metadata.storage_class == class {
+            return Ok(());
+        }
+
+        let chunk_count = metadata.size.div_ceil(FILE_CHUNK_SIZE as FileSize);
+        let indices = self.tier(node).filechunk_indices(node, chunk_count);
+        let (from, to): (&mut dyn Storage, &mut dyn Storage) = match class {
+            StorageClass::Stable => (&mut self.heap, self.inner.as_mut()),
+            StorageClass::Transient => (self.inner.as_mut(), &mut self.heap),
+        };
+
+        for (moved, index) in indices.iter().enumerate() {
+            let chunk = from.get_filechunk(node, *index)?.into_owned();
+            if let Err(err) = to.write_filechunk(node, *index, 0, &chunk) {
+                for index in &indices[..moved] {
+                    to.rm_filechunk(node, *index);
+                }
+                return Err(err);
+            }
+        }
+        for index in &indices {
+            from.rm_filechunk(node, *index);
+        }
+
+        metadata.storage_class = class;
+        self.put_metadata(node, metadata);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::VectorMemory;
+
+    use crate::storage::{stable::StableStorage, types::Times};
+
+    use super::*;
+
+    fn file(node: Node, size: FileSize, storage_class: StorageClass) -> Metadata {
+        Metadata {
+            node,
+            file_type: FileType::RegularFile,
+            link_count: 1,
+            size,
+            times: Times::default(),
+            storage_class,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn transient_contents_stay_in_heap() {
+        let memory = VectorMemory::default();
+        let mut storage = TieredStorage::new(Box::new(StableStorage::new(memory.clone())));
+
+        let stable = storage.new_node();
+        storage.put_metadata(stable, file(stable, 3, StorageClass::Stable));
+        storage.write_filechunk(stable, 0, 0, b"abc").unwrap();
+
+        let scratch = storage.new_node();
+        storage.put_metadata(scratch, file(scratch, 3, StorageClass::Transient));
+        storage.write_filechunk(scratch, 0, 0, b"xyz").unwrap();
+
+        let mut buf = [0; 3];
+        storage.read_filechunk(scratch, 0, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"xyz");
+        assert_eq!(
+            storage.inner().read_filechunk(scratch, 0, 0, &mut buf),
+            Err(Error::NotFound)
+        );
+        assert_eq!(storage.get_metadata(scratch).unwrap().size, 3);
+
+        // after an upgrade the transient file is empty, the stable one is kept
+        let storage = TieredStorage::new(Box::new(StableStorage::new(memory)));
+        assert_eq!(storage.get_metadata(scratch).unwrap().size, 0);
+        assert_eq!(
+            storage.read_filechunk(scratch, 0, 0, &mut buf),
+            Err(Error::NotFound)
+        );
+        assert_eq!(storage.get_metadata(stable).unwrap().size, 3);
+        storage.read_filechunk(stable, 0, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+    }
+
+    #[test]
+    fn storage_class_change_moves_the_chunks() {
+        let mut storage = TieredStorage::new(Box::new(TransientStorage::new()));
+        let node = storage.new_node();
+        storage.put_metadata(node, file(node, 5, StorageClass::Stable));
+        storage.write_filechunk(node, 0, 0, b"hello").unwrap();
+
+        storage
+            .set_storage_class(node, StorageClass::Transient)
+            .unwrap();
+        assert_eq!(
+            storage.inner().filechunk_indices(node, 1),
+            Vec::<u32>::new()
+        );
+        assert_eq!(storage.filechunk_indices(node, 1), vec![0]);
+
+        storage
+            .set_storage_class(node, StorageClass::Stable)
+            .unwrap();
+        assert_eq!(storage.inner().filechunk_indices(node, 1), vec![0]);
+        let mut buf = [0; 5];
+        storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(
+            storage.get_metadata(node).unwrap().storage_class,
+            StorageClass::Stable
+        );
+    }
+}
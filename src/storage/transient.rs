@@ -1,10 +1,10 @@
-use std::collections::BTreeMap;
+use std::{borrow::Cow, collections::BTreeMap};
 
 use crate::{
     error::Error,
     storage::types::{
-        DirEntry, DirEntryIndex, FileChunk, FileChunkIndex, FileSize, FileType, Metadata, Node,
-        Times,
+        chunk_range, Collation, DirEntry, DirEntryIndex, FileChunk, FileChunkIndex, FileSize,
+        FileType, FsLimits, Metadata, NameNormalizer, Node, StorageClass, Times,
     },
     storage::Storage,
 };
@@ -25,6 +25,8 @@ pub struct TransientStorage {
     filechunk: BTreeMap<(Node, FileChunkIndex), FileChunk>,
     // Next node ID.
     next_node: Node,
+    // The generation of the created nodes, the number of the removed nodes.
+    generation: u64,
 }
 
 impl TransientStorage {
@@ -38,12 +40,24 @@ impl TransientStorage {
             times: Times::default(),
             first_dir_entry: None,
             last_dir_entry: None,
+            next_dir_entry: None,
+            parent: None,
+            generation: 0,
+            dir_generation: 0,
+            http: None,
+            expires_at: None,
+            name_normalizer: NameNormalizer::Exact,
+            storage_class: StorageClass::Stable,
+            collation: Collation::Binary,
+            region: false,
+            limits: FsLimits::default(),
         };
         let mut result = Self {
             metadata: Default::default(),
             direntry: Default::default(),
             filechunk: Default::default(),
             next_node: ROOT_NODE + 1,
+            generation: 0,
         };
         result.put_metadata(ROOT_NODE, metadata);
         result
@@ -68,6 +82,10 @@ impl Storage for TransientStorage {
         result
     }
 
+    fn node_generation(&self) -> u64 {
+        self.generation
+    }
+
     // Get the metadata associated with the node.
     fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
         let value = self.metadata.get(&node).ok_or(Error::NotFound)?;
@@ -82,7 +100,9 @@ impl Storage for TransientStorage {
 
     // Remove the metadata associated with the node.
     fn rm_metadata(&mut self, node: Node) {
-        self.metadata.remove(&node);
+        if self.metadata.remove(&node).is_some() {
+            self.generation += 1;
+        }
     }
 
     // Retrieve the DirEntry instance given the Node and DirEntryIndex.
@@ -110,20 +130,59 @@ impl Storage for TransientStorage {
         buf: &mut [u8],
     ) -> Result<(), Error> {
         let value = self.filechunk.get(&(node, index)).ok_or(Error::NotFound)?;
-        buf.copy_from_slice(&value.bytes[offset as usize..offset as usize + buf.len()]);
+        let range = chunk_range(offset, buf.len())?;
+        buf.copy_from_slice(&value.bytes[range]);
         Ok(())
     }
 
     // Insert of update a selected file chunk with the data provided in buffer.
-    fn write_filechunk(&mut self, node: Node, index: FileChunkIndex, offset: FileSize, buf: &[u8]) {
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let range = chunk_range(offset, buf.len())?;
         let entry = self.filechunk.entry((node, index)).or_default();
-        entry.bytes[offset as usize..offset as usize + buf.len()].copy_from_slice(buf)
+        entry.bytes[range].copy_from_slice(buf);
+        Ok(())
     }
 
     // Remove file chunk from a given file node.
     fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex) {
         self.filechunk.remove(&(node, index));
     }
+
+    // Get the indices of the written chunks of a node.
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        self.filechunk
+            .range((node, 0)..=(node, FileChunkIndex::MAX))
+            .map(|((_, index), _)| *index)
+            .take_while(|index| (*index as FileSize) < chunk_count)
+            .collect()
+    }
+
+    // Get the contents of a whole file chunk without copying.
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        let chunk = self
+            .filechunk
+            .remove(&(from_node, from_index))
+            .ok_or(Error::NotFound)?;
+        self.filechunk.insert((to_node, to_index), chunk);
+        Ok(())
+    }
+
+    fn get_filechunk(&mut self, node: Node, index: FileChunkIndex) -> Result<Cow<'_, [u8]>, Error> {
+        let value = self.filechunk.get(&(node, index)).ok_or(Error::NotFound)?;
+        Ok(Cow::Borrowed(&value.bytes))
+    }
 }
 
 #[cfg(test)]
@@ -144,11 +203,45 @@ mod tests {
                 times: Times::default(),
                 first_dir_entry: None,
                 last_dir_entry: None,
+                next_dir_entry: None,
+                parent: None,
+                generation: 0,
+                dir_generation: 0,
+                http: None,
+                expires_at: None,
+                name_normalizer: NameNormalizer::Exact,
+                storage_class: StorageClass::Stable,
+                collation: Collation::Binary,
+                region: false,
+                limits: FsLimits::default(),
             },
         );
-        storage.write_filechunk(node, 0, 0, &[42; 10]);
+        storage.write_filechunk(node, 0, 0, &[42; 10]).unwrap();
         let mut buf = [0; 10];
         storage.read_filechunk(node, 0, 0, &mut buf).unwrap();
         assert_eq!(buf, [42; 10]);
+
+        // reading past the chunk end is rejected
+        assert_eq!(
+            storage.read_filechunk(
+                node,
+                0,
+                crate::storage::types::FILE_CHUNK_SIZE as FileSize - 5,
+                &mut buf
+            ),
+            Err(Error::InvalidOffset)
+        );
+    }
+
+    #[test]
+    fn get_filechunk_is_borrowed() {
+        let mut storage = TransientStorage::default();
+        let node = storage.new_node();
+        storage.write_filechunk(node, 0, 0, &[42; 10]).unwrap();
+
+        let chunk = storage.get_filechunk(node, 0).unwrap();
+        assert!(matches!(chunk, Cow::Borrowed(_)));
+        assert_eq!(chunk[0..10], [42; 10]);
+        assert_eq!(chunk.len(), crate::storage::types::FILE_CHUNK_SIZE);
     }
 }
@@ -1,6 +1,8 @@
+use std::{marker::PhantomData, ops::Range};
+
 use crate::error::Error;
 use ic_stable_structures::storable::Bound;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 pub const FILE_CHUNK_SIZE: usize = 4096;
 pub const MAX_FILE_NAME: usize = 255;
@@ -15,6 +17,19 @@ pub type FileSize = u64;
 // An index of a file chunk.
 pub type FileChunkIndex = u32;
 
+// The maximum file size, limited by the number of chunks addressable by a FileChunkIndex.
+pub const MAX_FILE_SIZE: FileSize =
+    (FileChunkIndex::MAX as FileSize + 1) * FILE_CHUNK_SIZE as FileSize;
+
+// Get the range of `len` bytes at `offset` inside a file chunk, a range not fitting into a chunk is rejected.
+pub fn chunk_range(offset: FileSize, len: usize) -> Result<Range<usize>, Error> {
+    let start = usize::try_from(offset).map_err(|_| Error::InvalidOffset)?;
+    match start.checked_add(len) {
+        Some(end) if end <= FILE_CHUNK_SIZE => Ok(start..end),
+        _ => Err(Error::InvalidOffset),
+    }
+}
+
 // A file consists of multiple file chunks.
 #[derive(Clone, Debug)]
 pub struct FileChunk {
@@ -30,14 +45,16 @@ impl Default for FileChunk {
 }
 
 impl ic_stable_structures::Storable for FileChunk {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Borrowed(&self.bytes)
     }
 
+    // A stored chunk of a wrong size is cut or padded with zeros instead of trapping.
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Self {
-            bytes: bytes.as_ref().try_into().unwrap(),
-        }
+        let mut chunk = Self::default();
+        let len = bytes.len().min(FILE_CHUNK_SIZE);
+        chunk.bytes[..len].copy_from_slice(&bytes[..len]);
+        chunk
     }
 
     const BOUND: Bound = Bound::Bounded {
@@ -56,10 +73,43 @@ pub struct Metadata {
     pub times: Times,
     pub first_dir_entry: Option<DirEntryIndex>,
     pub last_dir_entry: Option<DirEntryIndex>,
+    // The index of the next added directory entry, none for the directories that had their entries before it was kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_dir_entry: Option<DirEntryIndex>,
+    // The parent directory node, only maintained for directories.
+    #[serde(default)]
+    pub parent: Option<Node>,
+    // Distinguishes different nodes that were assigned the same node number.
+    #[serde(default)]
+    pub generation: u64,
+    // Directory change stamp, incremented each time an entry is added to or removed from the directory.
+    #[serde(default)]
+    pub dir_generation: u64,
+    // The HTTP serving parameters of the node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<HttpMetadata>,
+    // The time after which the file is removed by an expiry sweep.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    // The file name normalizer of the file system, only stored in the root directory.
+    #[serde(default, skip_serializing_if = "NameNormalizer::is_exact")]
+    pub name_normalizer: NameNormalizer,
+    // Where the file contents are kept, see `TieredStorage`.
+    #[serde(default, skip_serializing_if = "StorageClass::is_stable")]
+    pub storage_class: StorageClass,
+    // The order of the names in the sorted listings of the file system, only stored in the root directory.
+    #[serde(default, skip_serializing_if = "Collation::is_binary")]
+    pub collation: Collation,
+    // A fixed-size region file, all its chunks are stored when it is created and it cannot be resized.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub region: bool,
+    // The limits of the tree kept by the file system, only stored in the root directory.
+    #[serde(default, skip_serializing_if = "FsLimits::is_none")]
+    pub limits: FsLimits,
 }
 
 impl ic_stable_structures::Storable for Metadata {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         let mut buf = vec![];
         ciborium::ser::into_writer(&self, &mut buf).unwrap();
         std::borrow::Cow::Owned(buf)
@@ -72,6 +122,124 @@ impl ic_stable_structures::Storable for Metadata {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+// HTTP metadata stored with a node, used when serving it as an asset.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HttpMetadata {
+    pub cache_control: Option<String>,
+    pub content_encoding: Option<String>,
+    pub content_type: Option<String>,
+    // Any other headers, in the order they should be sent.
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpMetadata {
+    // Get all the response headers described by the metadata.
+    pub fn response_headers(&self) -> Vec<(String, String)> {
+        let mut result = Vec::new();
+
+        let named = [
+            ("cache-control", &self.cache_control),
+            ("content-encoding", &self.content_encoding),
+            ("content-type", &self.content_type),
+        ];
+
+        for (name, value) in named {
+            if let Some(value) = value {
+                result.push((name.to_string(), value.clone()));
+            }
+        }
+
+        result.extend(self.headers.iter().cloned());
+
+        result
+    }
+}
+
+// The normalization applied to the file names when creating and looking up directory entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NameNormalizer {
+    // The names are used as given.
+    #[default]
+    Exact,
+    // Unicode normalization form C.
+    Nfc,
+    Lowercase,
+    // Unicode normalization form C of the lowercase name.
+    NfcLowercase,
+    // A normalizer provided by the application in the mount options.
+    Custom,
+}
+
+impl NameNormalizer {
+    pub fn is_exact(&self) -> bool {
+        *self == NameNormalizer::Exact
+    }
+}
+
+// The order of the file names in the sorted directory listings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Collation {
+    // The names are ordered by their bytes.
+    #[default]
+    Binary,
+    // The ASCII letters are ordered ignoring their case, the names equal this way are ordered by their bytes.
+    AsciiCaseInsensitive,
+    // A comparator provided by the application in the mount options.
+    Custom,
+}
+
+impl Collation {
+    pub fn is_binary(&self) -> bool {
+        *self == Collation::Binary
+    }
+}
+
+// The limits a file system tree was built with, a later mount cannot use other limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FsLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_dir_entries: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_path_depth: Option<u64>,
+}
+
+impl FsLimits {
+    pub fn is_none(&self) -> bool {
+        *self == FsLimits::default()
+    }
+}
+
+// The memory keeping the contents of a file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageClass {
+    // The contents are kept by the storage of the file system.
+    #[default]
+    Stable,
+    // The contents are kept in heap memory and are lost on upgrade, the file is then read as empty.
+    // Only the storages supporting it keep the data apart, the others store it as the stable files.
+    Transient,
+}
+
+impl StorageClass {
+    pub fn is_stable(&self) -> bool {
+        *self == StorageClass::Stable
+    }
+}
+
+// The handling of the freed file chunks, the storage memory keeps their contents until it is reused otherwise.
+// Only the storages keeping the chunks in stable memory zero them, the others ignore it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChunkZeroing {
+    // The chunks are released as they are, the fastest.
+    #[default]
+    Never,
+    // The chunks are overwritten with zeros when they are freed.
+    Immediate,
+    // The chunks are released and overwritten later by `zero_freed_chunks`, e.g. in a maintenance call.
+    // They are read as missing in the meantime.
+    Deferred,
+}
+
 // The type of a node.
 #[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FileType {
@@ -105,7 +273,7 @@ impl From<FileType> for u8 {
 }
 
 // The time stats of a node.
-#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Times {
     pub accessed: u64,
     pub modified: u64,
@@ -135,7 +303,7 @@ fn deserialize_file_name<'de, D>(deserializer: D) -> Result<[u8; MAX_FILE_NAME],
 where
     D: serde::Deserializer<'de>,
 {
-    let bytes: Vec<u8> = serde_bytes::deserialize(deserializer).unwrap();
+    let bytes: Vec<u8> = serde_bytes::deserialize(deserializer)?;
     let len = bytes.len();
     let bytes_array: [u8; MAX_FILE_NAME] = bytes
         .try_into()
@@ -181,7 +349,7 @@ pub struct DirEntry {
 }
 
 impl ic_stable_structures::Storable for DirEntry {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         let mut buf = vec![];
         ciborium::ser::into_writer(&self, &mut buf).unwrap();
         std::borrow::Cow::Owned(buf)
@@ -193,3 +361,185 @@ impl ic_stable_structures::Storable for DirEntry {
 
     const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
 }
+
+// A record kept in its serialized form, so that a corrupted record is reported when decoded instead of trapping.
+// The stored bytes are the same as of the record itself.
+#[derive(Clone, Debug)]
+pub struct Encoded<T> {
+    bytes: Vec<u8>,
+    _record: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Encoded<T> {
+    pub fn new(record: &T) -> Self {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(record, &mut bytes).unwrap();
+        Self::from_raw(bytes)
+    }
+
+    pub fn from_raw(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            _record: PhantomData,
+        }
+    }
+
+    // Decode the record, a record that cannot be decoded is reported as `Error::DataCorrupted`.
+    pub fn decode(&self) -> Result<T, Error> {
+        ciborium::de::from_reader(self.bytes.as_slice()).map_err(|_| Error::DataCorrupted)
+    }
+
+    pub fn raw(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<T> ic_stable_structures::Storable for Encoded<T> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Borrowed(&self.bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            bytes: bytes.into_owned(),
+            _record: PhantomData,
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// The records that could not be decoded and were taken out of the storage by a recovery, with their raw bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    // The nodes with the corrupted metadata, they are replaced with empty files.
+    pub metadata: Vec<(Node, Vec<u8>)>,
+    // The corrupted directory entries, they are unlinked from their directories.
+    pub direntries: Vec<((Node, DirEntryIndex), Vec<u8>)>,
+}
+
+// The bytes a storage wrote to its memory: the sizes of the stored values, whole chunks and encoded records,
+// without the bookkeeping of the underlying data structures.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhysicalWrites {
+    pub chunk_bytes: u64,
+    pub metadata_bytes: u64,
+    pub direntry_bytes: u64,
+}
+
+impl PhysicalWrites {
+    pub fn total(&self) -> u64 {
+        self.chunk_bytes + self.metadata_bytes + self.direntry_bytes
+    }
+
+    // Get the bytes written since the `earlier` counters were taken.
+    pub fn since(&self, earlier: &PhysicalWrites) -> PhysicalWrites {
+        PhysicalWrites {
+            chunk_bytes: self.chunk_bytes.saturating_sub(earlier.chunk_bytes),
+            metadata_bytes: self.metadata_bytes.saturating_sub(earlier.metadata_bytes),
+            direntry_bytes: self.direntry_bytes.saturating_sub(earlier.direntry_bytes),
+        }
+    }
+}
+
+// The slots of the records removed from a storage since it was created. The memory of a canister does not shrink,
+// the removed records leave their space in the data structures and the new records of the same kind take it
+// before the memory grows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReusableSpace {
+    pub chunks: u64,
+    pub metadata_records: u64,
+    pub direntry_records: u64,
+}
+
+impl ReusableSpace {
+    pub fn chunk_bytes(&self) -> u64 {
+        self.chunks * FILE_CHUNK_SIZE as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::Storable;
+
+    use crate::test_utils::FuzzRng;
+
+    use super::*;
+
+    fn sample_metadata() -> Metadata {
+        Metadata {
+            node: 7,
+            file_type: FileType::RegularFile,
+            link_count: 1,
+            size: 12345,
+            times: Times::default(),
+            first_dir_entry: Some(1),
+            last_dir_entry: Some(2),
+            next_dir_entry: None,
+            parent: Some(0),
+            generation: 3,
+            dir_generation: 4,
+            http: Some(HttpMetadata {
+                content_type: Some("text/html".to_string()),
+                ..Default::default()
+            }),
+            expires_at: Some(99),
+            name_normalizer: NameNormalizer::Exact,
+            storage_class: StorageClass::Stable,
+            collation: Collation::Binary,
+            region: false,
+            limits: FsLimits::default(),
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_do_not_trap_the_decoders() {
+        let mut rng = FuzzRng::new(42);
+
+        let metadata = Encoded::new(&sample_metadata()).raw().to_vec();
+        let entry = Encoded::new(&DirEntry {
+            name: FileName::new(b"index.html").unwrap(),
+            node: 7,
+            next_entry: Some(3),
+            prev_entry: None,
+        })
+        .raw()
+        .to_vec();
+
+        for _ in 0..5000 {
+            // random bytes, and valid records with flipped bytes or cut off
+            let len = rng.below(300) as usize;
+            let mut inputs = vec![rng.bytes(len)];
+            for valid in [&metadata, &entry] {
+                let mut flipped = valid.clone();
+                let pos = rng.below(flipped.len() as u64) as usize;
+                flipped[pos] ^= 1 << rng.below(8);
+                inputs.push(flipped);
+                inputs.push(valid[..rng.below(valid.len() as u64) as usize].to_vec());
+            }
+
+            for input in inputs {
+                let _ = Encoded::<Metadata>::from_raw(input.clone()).decode();
+                let _ = Encoded::<DirEntry>::from_raw(input.clone()).decode();
+                let _ = FileChunk::from_bytes(input.into());
+            }
+        }
+
+        assert_eq!(
+            Encoded::<Metadata>::from_raw(metadata)
+                .decode()
+                .unwrap()
+                .size,
+            12345
+        );
+    }
+
+    #[test]
+    fn chunk_ranges_are_checked() {
+        assert_eq!(chunk_range(0, FILE_CHUNK_SIZE), Ok(0..FILE_CHUNK_SIZE));
+        assert_eq!(chunk_range(10, 5), Ok(10..15));
+        assert_eq!(chunk_range(1, FILE_CHUNK_SIZE), Err(Error::InvalidOffset));
+        assert_eq!(chunk_range(u64::MAX, 1), Err(Error::InvalidOffset));
+        assert_eq!(chunk_range(0, usize::MAX), Err(Error::InvalidOffset));
+    }
+}
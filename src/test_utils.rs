@@ -1,17 +1,252 @@
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{Duration, UNIX_EPOCH},
+};
+
 use ic_stable_structures::DefaultMemoryImpl;
 
-use crate::{fs::FileSystem, storage::stable::StableStorage};
+use crate::{
+    error::Error,
+    fs::{FdStat, FileSystem, MountOptions, TimeConfig},
+    storage::{stable::StableStorage, transient::TransientStorage},
+};
 
 #[cfg(test)]
+use crate::storage::types::{DirEntry, DirEntryIndex, FileChunkIndex, FileSize, Metadata, Node};
+
+// A clock set by hand for the tests of the time-dependent features, e.g. the expiry or the access times.
+// The clones share the time, so a clone can be given to the code under test while the test moves it forward.
+// The time is in the unit of the stored times, the nanoseconds by default.
+#[derive(Clone, Debug, Default)]
+pub struct MockClock(Rc<Cell<u64>>);
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        Self(Rc::new(Cell::new(now)))
+    }
+
+    pub fn now(&self) -> u64 {
+        self.0.get()
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.set(now);
+    }
+
+    // Move the clock forward by `ticks`, returns the new time.
+    pub fn advance(&self, ticks: u64) -> u64 {
+        self.0.set(self.0.get().saturating_add(ticks));
+        self.0.get()
+    }
+
+    // Move the clock forward by `duration` counted in the unit of `times`, returns the new time.
+    pub fn advance_by(&self, duration: Duration, times: TimeConfig) -> u64 {
+        self.advance(times.time_from_system(UNIX_EPOCH + times.epoch + duration))
+    }
+
+    // The time `ticks` after now, e.g. the expiry of a file living for `ticks`.
+    pub fn after(&self, ticks: u64) -> u64 {
+        self.0.get().saturating_add(ticks)
+    }
+
+    // Move the clock forward by `ticks` and remove the files expired by then, returns the number removed.
+    pub fn advance_and_expire(&self, fs: &mut FileSystem, ticks: u64) -> Result<usize, Error> {
+        fs.expire_now(self.advance(ticks))
+    }
+}
+
 pub fn test_fs() -> FileSystem {
     let storage = StableStorage::new(DefaultMemoryImpl::default());
     FileSystem::new(Box::new(storage)).unwrap()
 }
 
-#[cfg(test)]
 pub fn test_fs_transient() -> FileSystem {
-    use crate::storage::transient::TransientStorage;
-
     let storage = TransientStorage::new();
     FileSystem::new(Box::new(storage)).unwrap()
 }
+
+pub fn test_fs_strict_posix() -> FileSystem {
+    let storage = TransientStorage::new();
+    let options = MountOptions {
+        strict_posix: true,
+        ..Default::default()
+    };
+    FileSystem::new_with_options(Box::new(storage), options).unwrap()
+}
+
+// Build a file system in stable storage holding the files of a fixture, e.g. `&[("a/b.txt", b"text")]`.
+// The missing directories on the paths are created, a path ending with '/' is an empty directory.
+pub fn fs_with_tree(tree: &[(&str, &[u8])]) -> FileSystem {
+    let mut fs = test_fs();
+    let root = fs.root_fd();
+
+    for (path, content) in tree {
+        if path.ends_with('/') {
+            let fd = fs.create_dir_all(root, path, FdStat::default(), 0).unwrap();
+            fs.close(fd).unwrap();
+        } else {
+            fs.apply_file(root, path, content, 0).unwrap();
+        }
+    }
+
+    fs
+}
+
+// A deterministic pseudo-random generator (xorshift64) driving the fuzz tests.
+#[cfg(test)]
+pub struct FuzzRng(u64);
+
+#[cfg(test)]
+impl FuzzRng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // A number in `0..n`, `n` must not be 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+// A transient storage failing the writes of new chunks with `StorageFull` once the budget of new chunks is used up,
+// the budget is shared so that it can be changed after the storage is moved into a file system.
+#[cfg(test)]
+pub struct FaultyStorage {
+    inner: TransientStorage,
+    budget: Rc<Cell<Option<usize>>>,
+    // the writes into the stored chunks are counted too
+    count_overwrites: bool,
+}
+
+#[cfg(test)]
+impl FaultyStorage {
+    pub fn new(budget: Rc<Cell<Option<usize>>>) -> Self {
+        Self {
+            inner: TransientStorage::new(),
+            budget,
+            count_overwrites: false,
+        }
+    }
+
+    // A storage failing any chunk write once the budget is used up, not only the writes of new chunks.
+    pub fn counting_overwrites(budget: Rc<Cell<Option<usize>>>) -> Self {
+        Self {
+            count_overwrites: true,
+            ..Self::new(budget)
+        }
+    }
+}
+
+#[cfg(test)]
+impl crate::storage::Storage for FaultyStorage {
+    fn root_node(&self) -> Node {
+        self.inner.root_node()
+    }
+
+    fn get_version(&self) -> u32 {
+        self.inner.get_version()
+    }
+
+    fn new_node(&mut self) -> Node {
+        self.inner.new_node()
+    }
+
+    fn node_generation(&self) -> u64 {
+        self.inner.node_generation()
+    }
+
+    fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
+        self.inner.get_metadata(node)
+    }
+
+    fn put_metadata(&mut self, node: Node, metadata: Metadata) {
+        self.inner.put_metadata(node, metadata)
+    }
+
+    fn rm_metadata(&mut self, node: Node) {
+        self.inner.rm_metadata(node)
+    }
+
+    fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
+        self.inner.get_direntry(node, index)
+    }
+
+    fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
+        self.inner.put_direntry(node, index, entry)
+    }
+
+    fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
+        self.inner.rm_direntry(node, index)
+    }
+
+    fn read_filechunk(
+        &self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        self.inner.read_filechunk(node, index, offset, buf)
+    }
+
+    fn write_filechunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let stored = self.inner.read_filechunk(node, index, 0, &mut []).is_ok();
+        match self.budget.get() {
+            _ if stored && !self.count_overwrites => {}
+            Some(0) => return Err(Error::StorageFull),
+            Some(left) => self.budget.set(Some(left - 1)),
+            None => {}
+        }
+        self.inner.write_filechunk(node, index, offset, buf)
+    }
+
+    fn rm_filechunk(&mut self, node: Node, index: FileChunkIndex) {
+        self.inner.rm_filechunk(node, index)
+    }
+
+    // a moved chunk keeps its space, it is not counted in the budget
+    fn move_filechunk(
+        &mut self,
+        from_node: Node,
+        from_index: FileChunkIndex,
+        to_node: Node,
+        to_index: FileChunkIndex,
+    ) -> Result<(), Error> {
+        self.inner
+            .move_filechunk(from_node, from_index, to_node, to_index)
+    }
+
+    fn filechunk_indices(&self, node: Node, chunk_count: FileSize) -> Vec<FileChunkIndex> {
+        self.inner.filechunk_indices(node, chunk_count)
+    }
+
+    fn add_orphan(&mut self, node: Node) {
+        self.inner.add_orphan(node)
+    }
+
+    fn remove_orphan(&mut self, node: Node) {
+        self.inner.remove_orphan(node)
+    }
+
+    fn orphans(&self) -> Vec<Node> {
+        self.inner.orphans()
+    }
+}
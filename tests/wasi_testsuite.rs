@@ -0,0 +1,249 @@
+// A subset of the wasi-testsuite filesystem cases, expressed directly over the FileSystem API.
+// Each case records whether it is expected to pass, a regression or a newly passing case fails the run.
+
+use stable_fs::{
+    error::Error,
+    fs::{FdStat, FileSystem, MountOptions, OpenFlags, RemoveOptions, Whence},
+    storage::{transient::TransientStorage, types::FileType},
+};
+
+fn test_fs_strict_posix() -> FileSystem {
+    let options = MountOptions {
+        strict_posix: true,
+        ..Default::default()
+    };
+    FileSystem::new_with_options(Box::new(TransientStorage::new()), options).unwrap()
+}
+
+type CaseResult = Result<(), String>;
+
+fn check(condition: bool, message: &str) -> CaseResult {
+    if condition {
+        Ok(())
+    } else {
+        Err(message.to_string())
+    }
+}
+
+fn err(err: Error) -> String {
+    format!("unexpected error: {:?}", err)
+}
+
+fn close_preopen(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    fs.close(root_fd).map_err(err)?;
+    check(
+        fs.metadata(root_fd) == Err(Error::NotFound),
+        "closed preopen fd is still valid",
+    )
+}
+
+fn directory_seek(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    let dir_fd = fs
+        .create_dir(root_fd, "dir", FdStat::default(), 0)
+        .map_err(err)?;
+    check(
+        fs.seek(dir_fd, 0, Whence::CUR) == Err(Error::InvalidFileDescriptor),
+        "seek on a directory must fail with EBADF",
+    )
+}
+
+fn file_seek_tell(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    let fd = fs
+        .create_file(root_fd, "file", FdStat::default(), 0)
+        .map_err(err)?;
+    fs.write(fd, &[0; 100]).map_err(err)?;
+    check(fs.tell(fd) == Ok(100), "tell after write")?;
+    check(fs.seek(fd, -50, Whence::CUR) == Ok(50), "seek back")?;
+    check(
+        fs.seek(fd, 1000, Whence::SET) == Ok(1000),
+        "seek past the end",
+    )?;
+    check(
+        fs.seek(fd, -1, Whence::SET) == Err(Error::InvalidOffset),
+        "seek before the start",
+    )
+}
+
+fn file_truncation(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    let fd = fs
+        .create_file(root_fd, "file", FdStat::default(), 0)
+        .map_err(err)?;
+    fs.write(fd, &[1; 100]).map_err(err)?;
+    fs.close(fd).map_err(err)?;
+
+    let fd = fs
+        .open_or_create(root_fd, "file", FdStat::default(), OpenFlags::TRUNCATE, 0)
+        .map_err(err)?;
+    check(
+        fs.metadata(fd).map_err(err)?.size == 0,
+        "file is not truncated",
+    )
+}
+
+fn path_open_create_existing(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    fs.create_file(root_fd, "file", FdStat::default(), 0)
+        .map_err(err)?;
+    let res = fs.open_or_create(
+        root_fd,
+        "file",
+        FdStat::default(),
+        OpenFlags::CREATE | OpenFlags::EXCLUSIVE,
+        0,
+    );
+    check(
+        res == Err(Error::FileAlreadyExists),
+        "O_CREAT | O_EXCL on an existing file",
+    )
+}
+
+fn path_open_dirfd_not_dir(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    let fd = fs
+        .create_file(root_fd, "file", FdStat::default(), 0)
+        .map_err(err)?;
+    let res = fs.open_or_create(fd, "other", FdStat::default(), OpenFlags::CREATE, 0);
+    check(res == Err(Error::NotADirectory), "file used as a dirfd")
+}
+
+fn path_rename(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    let fd = fs
+        .create_file(root_fd, "file", FdStat::default(), 0)
+        .map_err(err)?;
+    fs.close(fd).map_err(err)?;
+    let fd = fs.rename(root_fd, "file", root_fd, "file2").map_err(err)?;
+    fs.close(fd).map_err(err)?;
+    check(
+        fs.open_metadata(root_fd, "file") == Err(Error::NotFound),
+        "old name still exists",
+    )?;
+    check(
+        fs.open_metadata(root_fd, "file2").is_ok(),
+        "new name does not exist",
+    )
+}
+
+fn path_rename_file_over_existing(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    for name in ["file1", "file2"] {
+        let fd = fs
+            .create_file(root_fd, name, FdStat::default(), 0)
+            .map_err(err)?;
+        fs.close(fd).map_err(err)?;
+    }
+    let fd = fs.rename(root_fd, "file1", root_fd, "file2").map_err(err)?;
+    fs.close(fd).map_err(err)
+}
+
+fn readdir(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    let dir_fd = fs
+        .create_dir(root_fd, "dir", FdStat::default(), 0)
+        .map_err(err)?;
+    fs.create_file(dir_fd, "file", FdStat::default(), 0)
+        .map_err(err)?;
+    fs.create_dir(dir_fd, "nested", FdStat::default(), 0)
+        .map_err(err)?;
+
+    let entries = fs.list_dir(dir_fd).map_err(err)?;
+    check(entries.len() == 2, "unexpected number of entries")?;
+
+    let nested = fs.metadata_from_node(entries[1].node).map_err(err)?;
+    check(nested.file_type == FileType::Directory, "wrong entry type")
+}
+
+fn remove_nonempty_directory(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    let fd = fs
+        .create_file(root_fd, "dir/file", FdStat::default(), 0)
+        .map_err(err)?;
+    fs.close(fd).map_err(err)?;
+    check(
+        fs.remove(root_fd, "dir", RemoveOptions::dir()) == Err(Error::DirectoryNotEmpty),
+        "non-empty directory was removed",
+    )
+}
+
+fn renumber(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    let fd1 = fs
+        .create_file(root_fd, "file1", FdStat::default(), 0)
+        .map_err(err)?;
+    let fd2 = fs
+        .create_file(root_fd, "file2", FdStat::default(), 0)
+        .map_err(err)?;
+    let node1 = fs.metadata(fd1).map_err(err)?.node;
+    fs.renumber(fd1, fd2).map_err(err)?;
+    check(
+        fs.metadata(fd2).map_err(err)?.node == node1,
+        "renumbered fd points to the wrong node",
+    )?;
+    check(
+        fs.metadata(fd1) == Err(Error::NotFound),
+        "source fd is still open",
+    )
+}
+
+fn unlink_file_trailing_slashes(fs: &mut FileSystem) -> CaseResult {
+    let root_fd = fs.root_fd();
+    let fd = fs
+        .create_file(root_fd, "file", FdStat::default(), 0)
+        .map_err(err)?;
+    fs.close(fd).map_err(err)?;
+    check(
+        fs.remove_file(root_fd, "file/") == Err(Error::NotADirectory),
+        "trailing slash on a file must fail with ENOTDIR",
+    )
+}
+
+// Test case name, the test function and if the case is expected to pass.
+type Case = (&'static str, fn(&mut FileSystem) -> CaseResult, bool);
+
+const CASES: &[Case] = &[
+    ("close_preopen", close_preopen, true),
+    ("directory_seek", directory_seek, true),
+    ("file_seek_tell", file_seek_tell, true),
+    ("file_truncation", file_truncation, true),
+    ("path_open_create_existing", path_open_create_existing, true),
+    ("path_open_dirfd_not_dir", path_open_dirfd_not_dir, true),
+    ("path_rename", path_rename, true),
+    (
+        "path_rename_file_over_existing",
+        path_rename_file_over_existing,
+        true,
+    ),
+    ("readdir", readdir, true),
+    ("remove_nonempty_directory", remove_nonempty_directory, true),
+    ("renumber", renumber, true),
+    (
+        "unlink_file_trailing_slashes",
+        unlink_file_trailing_slashes,
+        true,
+    ),
+];
+
+#[test]
+fn run_wasi_testsuite_subset() {
+    let mut unexpected = vec![];
+
+    for (name, case, expected_to_pass) in CASES {
+        let mut fs = test_fs_strict_posix();
+        let result = case(&mut fs);
+
+        if result.is_ok() != *expected_to_pass {
+            unexpected.push((*name, result));
+        }
+    }
+
+    // a newly passing case should be marked as expected to pass
+    assert!(
+        unexpected.is_empty(),
+        "unexpected results: {:?}",
+        unexpected
+    );
+}